@@ -0,0 +1,2 @@
+-- Scopes granted to the stored access token, to tell when consent is missing
+ALTER TABLE users ADD COLUMN scopes text[] NOT NULL DEFAULT '{}';
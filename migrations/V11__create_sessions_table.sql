@@ -0,0 +1,9 @@
+CREATE TABLE sessions (
+  -- SHA-256 of the secret in the cookie, the table alone can't sign anyone in
+  id varchar(64) PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
@@ -0,0 +1,15 @@
+CREATE TABLE api_keys (
+  id serial PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  name varchar(255) NOT NULL,
+  -- SHA-256 of the key, which is only shown once when created
+  key_hash varchar(64) NOT NULL UNIQUE,
+  -- The start of the key, to tell keys apart
+  prefix varchar(16) NOT NULL,
+  scopes text[] NOT NULL,
+  last_used_at timestamptz,
+  revoked_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX api_keys_user_id_idx ON api_keys (user_id);
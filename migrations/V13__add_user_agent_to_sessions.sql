@@ -0,0 +1,2 @@
+-- Tells the devices signed in apart, to revoke the sessions of one
+ALTER TABLE sessions ADD COLUMN user_agent varchar(512);
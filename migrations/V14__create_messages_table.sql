@@ -0,0 +1,24 @@
+-- The metadata of the messages of each mailbox, to list them without asking
+-- Graph. Kept up to date by the indexer and delta syncs.
+CREATE TABLE messages (
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  -- The Graph id of the message
+  id text NOT NULL,
+  internet_message_id text,
+  folder_id text NOT NULL,
+  subject text NOT NULL,
+  sender text,
+  received_at timestamptz NOT NULL,
+  is_read boolean NOT NULL,
+  flag_status varchar(32) NOT NULL,
+  -- The message as Graph lists it without its body
+  data jsonb NOT NULL,
+  synced_at timestamptz NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (user_id, id)
+);
+
+CREATE INDEX messages_user_id_received_at_idx ON messages (user_id, received_at DESC);
+
+-- When the whole mailbox was last cached, NULL while the cache can't be
+-- trusted
+ALTER TABLE users ADD COLUMN messages_synced_at timestamptz;
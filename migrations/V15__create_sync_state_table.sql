@@ -0,0 +1,10 @@
+-- The delta tokens become part of the sync state of each folder, keeping the
+-- positions syncs already reached
+ALTER TABLE delta_tokens RENAME TO sync_state;
+ALTER TRIGGER delta_tokens_modified_at_trigger ON sync_state RENAME TO sync_state_modified_at_trigger;
+
+-- Folders can have been synced without getting a token back yet
+ALTER TABLE sync_state ALTER COLUMN delta_token DROP NOT NULL;
+ALTER TABLE sync_state ADD COLUMN last_synced_at timestamptz;
+-- How many messages the last sync got, the whole folder when it started over
+ALTER TABLE sync_state ADD COLUMN item_count integer NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Per account preferences of the user, like the label clients show
+ALTER TABLE accounts ADD COLUMN settings jsonb NOT NULL DEFAULT '{}';
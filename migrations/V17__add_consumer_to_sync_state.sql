@@ -0,0 +1,5 @@
+-- The search index syncs folders on its own, so it keeps its own positions
+-- apart from the client of the delta endpoint
+ALTER TABLE sync_state ADD COLUMN consumer varchar(32) NOT NULL DEFAULT 'api';
+ALTER TABLE sync_state DROP CONSTRAINT delta_tokens_pkey;
+ALTER TABLE sync_state ADD PRIMARY KEY (user_id, consumer, folder_id);
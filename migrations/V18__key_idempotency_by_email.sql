@@ -0,0 +1,4 @@
+-- Idempotency keys are now owned by the verified email of the user, which
+-- lets deleting a user delete them too. Keys owned by token subjects, sessions
+-- and API keys can't be matched anymore, and only lived a day anyway.
+DELETE FROM idempotency_keys;
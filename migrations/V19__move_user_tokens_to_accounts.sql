@@ -0,0 +1,20 @@
+-- The tokens of the user's own mailbox move to its account, the one whose
+-- address is their email, so accounts hold every token.
+ALTER TABLE accounts ADD COLUMN scopes text[] NOT NULL DEFAULT '{}';
+
+INSERT INTO accounts (user_id, provider, address, access_token, refresh_token, scopes)
+SELECT id, 'graph', email, access_token, refresh_token, scopes
+FROM users
+WHERE access_token IS NOT NULL AND refresh_token IS NOT NULL
+ON CONFLICT (user_id, address)
+  DO UPDATE SET
+    provider = EXCLUDED.provider,
+    access_token = EXCLUDED.access_token,
+    refresh_token = EXCLUDED.refresh_token,
+    scopes = EXCLUDED.scopes,
+    expires_at = NULL;
+
+ALTER TABLE users
+  DROP COLUMN access_token,
+  DROP COLUMN refresh_token,
+  DROP COLUMN scopes;
@@ -0,0 +1,16 @@
+CREATE TABLE accounts (
+  id serial PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  provider varchar(50) NOT NULL,
+  address varchar(255) NOT NULL,
+  access_token varchar(5000) NOT NULL,
+  refresh_token varchar(5000) NOT NULL,
+  created_at timestamp NOT NULL DEFAULT NOW(),
+  updated_at timestamp NOT NULL DEFAULT NOW(),
+  UNIQUE (user_id, address)
+);
+
+CREATE TRIGGER accounts_modified_at_trigger
+  BEFORE UPDATE ON accounts
+  FOR EACH ROW
+  EXECUTE FUNCTION update_users_modified_at ();
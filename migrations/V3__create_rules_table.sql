@@ -0,0 +1,21 @@
+CREATE TABLE rules (
+  id serial PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  name varchar(255) NOT NULL,
+  conditions jsonb NOT NULL DEFAULT '[]',
+  actions jsonb NOT NULL DEFAULT '[]',
+  enabled boolean NOT NULL DEFAULT TRUE,
+  position integer NOT NULL,
+  created_at timestamp NOT NULL DEFAULT NOW(),
+  updated_at timestamp NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX rules_user_id_position_idx ON rules (user_id, position);
+
+CREATE TRIGGER rules_modified_at_trigger
+  BEFORE UPDATE ON rules
+  FOR EACH ROW
+  EXECUTE FUNCTION update_users_modified_at ();
+
+-- Emails received before this time were already checked against the rules
+ALTER TABLE users ADD COLUMN rules_applied_at timestamptz;
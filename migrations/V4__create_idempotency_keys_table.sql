@@ -0,0 +1,12 @@
+CREATE TABLE idempotency_keys (
+  owner varchar(255) NOT NULL,
+  key varchar(255) NOT NULL,
+  method varchar(10) NOT NULL,
+  path varchar(2048) NOT NULL,
+  -- NULL until the first request gets a response
+  status smallint,
+  content_type varchar(255),
+  body bytea,
+  created_at timestamptz NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (owner, key)
+);
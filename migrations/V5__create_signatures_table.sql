@@ -0,0 +1,18 @@
+CREATE TABLE signatures (
+  id serial PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  name varchar(255) NOT NULL,
+  html text NOT NULL,
+  -- Derived from the HTML version when missing
+  text text,
+  is_default boolean NOT NULL DEFAULT FALSE,
+  created_at timestamp NOT NULL DEFAULT NOW(),
+  updated_at timestamp NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX signatures_user_id_default_idx ON signatures (user_id) WHERE is_default;
+
+CREATE TRIGGER signatures_modified_at_trigger
+  BEFORE UPDATE ON signatures
+  FOR EACH ROW
+  EXECUTE FUNCTION update_users_modified_at ();
@@ -0,0 +1,13 @@
+CREATE TABLE delta_tokens (
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  folder_id varchar(255) NOT NULL,
+  delta_token text NOT NULL,
+  created_at timestamp NOT NULL DEFAULT NOW(),
+  updated_at timestamp NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (user_id, folder_id)
+);
+
+CREATE TRIGGER delta_tokens_modified_at_trigger
+  BEFORE UPDATE ON delta_tokens
+  FOR EACH ROW
+  EXECUTE FUNCTION update_users_modified_at ();
@@ -0,0 +1,16 @@
+CREATE TABLE subscriptions (
+  -- The id Graph gave the subscription
+  id varchar(255) PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  client_state varchar(255) NOT NULL,
+  expires_at timestamptz NOT NULL,
+  created_at timestamp NOT NULL DEFAULT NOW(),
+  updated_at timestamp NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX subscriptions_user_id_idx ON subscriptions (user_id);
+
+CREATE TRIGGER subscriptions_modified_at_trigger
+  BEFORE UPDATE ON subscriptions
+  FOR EACH ROW
+  EXECUTE FUNCTION update_users_modified_at ();
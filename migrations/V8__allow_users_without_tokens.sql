@@ -0,0 +1,3 @@
+-- Mailboxes reached with the app's own token, in app-only mode, never sign in
+ALTER TABLE users ALTER COLUMN access_token DROP NOT NULL;
+ALTER TABLE users ALTER COLUMN refresh_token DROP NOT NULL;
@@ -0,0 +1,2 @@
+-- Google access tokens are opaque, their expiration comes with them
+ALTER TABLE accounts ADD COLUMN expires_at timestamptz;
@@ -297,17 +297,7 @@ pub async fn dequeue(client: &mut Client) -> Result<Option<Task>, TaskError> {
         .await?;
 
     if let Some(row) = row {
-        let interval_ms: Option<i64> = row.get(5);
-        let interval = interval_ms.map(|i| Duration::from_millis(i as u64)); // Convert i64 to Duration
-
-        let task = Task {
-            id: row.get(0),
-            name: row.get(1),
-            data: row.get(2),
-            status: row.get(3),
-            run_at: row.get(4),
-            interval,
-        };
+        let task = task_from_row(&row);
 
         tx.execute(
             "UPDATE task_queue SET status = 'processing', updated_at = NOW() WHERE id = $1",
@@ -323,6 +313,72 @@ pub async fn dequeue(client: &mut Client) -> Result<Option<Task>, TaskError> {
     }
 }
 
+/// Lists the tasks in the queue, the ones scheduled last first, optionally only
+/// the ones with the given status.
+pub async fn list_tasks(
+    client: &Client,
+    status: Option<&str>,
+    limit: i64,
+) -> Result<Vec<Task>, TaskError> {
+    let rows = client
+        .query(
+            "SELECT id, name, task_data, status, run_at, interval FROM task_queue WHERE $1::VARCHAR IS NULL OR status = $1 ORDER BY run_at DESC LIMIT $2",
+            &[&status, &limit],
+        )
+        .await?;
+    Ok(rows.iter().map(task_from_row).collect())
+}
+
+/// Counts the tasks in the queue by status.
+pub async fn count_tasks(client: &Client) -> Result<HashMap<TaskStatus, i64>, TaskError> {
+    let rows = client
+        .query(
+            "SELECT status, COUNT(*) FROM task_queue GROUP BY status",
+            &[],
+        )
+        .await?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+/// Deletes a task from the queue, returning whether it existed. A task already
+/// being processed still runs to completion.
+pub async fn delete_task(client: &Client, task_id: TaskId) -> Result<bool, TaskError> {
+    let deleted = client
+        .execute("DELETE FROM task_queue WHERE id = $1", &[&task_id])
+        .await?;
+    Ok(deleted > 0)
+}
+
+/// Deletes the tasks whose data has `value` at `key`, returning how many there
+/// were. Tasks already being processed still run to completion.
+pub async fn delete_tasks_with_data(
+    client: &Client,
+    key: &str,
+    value: &str,
+) -> Result<u64, TaskError> {
+    let deleted = client
+        .execute(
+            "DELETE FROM task_queue WHERE task_data->>$1 = $2",
+            &[&key, &value],
+        )
+        .await?;
+    Ok(deleted)
+}
+
+fn task_from_row(row: &tokio_postgres::Row) -> Task {
+    let interval_ms: Option<i64> = row.get(5);
+    let interval = interval_ms.map(|i| Duration::from_millis(i as u64)); // Convert i64 to Duration
+
+    Task {
+        id: row.get(0),
+        name: row.get(1),
+        data: row.get(2),
+        status: row.get(3),
+        run_at: row.get(4),
+        interval,
+    }
+}
+
 /// Marks a task as complete and reschedules it if it has an interval.
 pub async fn complete_task(
     client: &Client,
@@ -0,0 +1,65 @@
+use axum::{
+    http::{uri::PathAndQuery, Request, Uri},
+    middleware::Next,
+    response::Response,
+};
+
+/// Mail routes prefixed with this path and an account id act on that linked
+/// account instead of the mailbox of the bearer token.
+const ACCOUNTS_PREFIX: &str = "/api/accounts/";
+
+/// The linked account a request acts on, set by [`scope_account`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccountId(pub i32);
+
+/// Middleware rewriting `/api/accounts/:account_id/*rest` to `/api/*rest`,
+/// keeping the account id as a request extension for [`super::AuthedUser`].
+///
+/// It has to run before routing so every mail route is available per account
+/// without duplicating the route table.
+pub async fn scope_account<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    if let Some((account_id, uri)) = account_uri(req.uri()) {
+        *req.uri_mut() = uri;
+        req.extensions_mut().insert(account_id);
+    }
+    next.run(req).await
+}
+
+fn account_uri(uri: &Uri) -> Option<(AccountId, Uri)> {
+    let (account_id, rest) = uri.path().strip_prefix(ACCOUNTS_PREFIX)?.split_once('/')?;
+    let account_id = account_id.parse().ok()?;
+    if rest.is_empty() {
+        return None;
+    }
+
+    let path_and_query = match uri.query() {
+        Some(query) => format!("/api/{rest}?{query}"),
+        None => format!("/api/{rest}"),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).ok()?);
+    let uri = Uri::from_parts(parts).ok()?;
+
+    Some((AccountId(account_id), uri))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_account_uri() {
+        let uri: Uri = "/api/accounts/42/emails/abc/move/Archive?page=2"
+            .parse()
+            .unwrap();
+        let (account_id, uri) = account_uri(&uri).unwrap();
+        assert_eq!(account_id, AccountId(42));
+        assert_eq!(uri, "/api/emails/abc/move/Archive?page=2");
+
+        // The account routes themselves aren't rewritten
+        assert!(account_uri(&"/api/accounts/42".parse().unwrap()).is_none());
+        assert!(account_uri(&"/api/accounts/42/".parse().unwrap()).is_none());
+        assert!(account_uri(&"/api/accounts/abc/emails".parse().unwrap()).is_none());
+        assert!(account_uri(&"/api/emails".parse().unwrap()).is_none());
+    }
+}
@@ -0,0 +1,246 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, Query},
+    http::{request::Parts, StatusCode},
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use postgres_queue::{Task, TaskId};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::info;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::database::{Database, User};
+
+use super::{authed_user::AuthedUser, error::AppError};
+
+/// Emails of the users allowed on the admin routes.
+#[derive(Clone, Debug, Default)]
+pub struct Admins(Arc<Vec<String>>);
+
+impl Admins {
+    pub fn new(emails: Vec<String>) -> Self {
+        Self(Arc::new(
+            emails
+                .into_iter()
+                .map(|email| email.trim().to_lowercase())
+                .filter(|email| !email.is_empty())
+                .collect(),
+        ))
+    }
+
+    fn contains(&self, email: &str) -> bool {
+        self.0.iter().any(|admin| admin == &email.to_lowercase())
+    }
+}
+
+/// An authenticated user listed as an admin, rejected with `403 Forbidden`
+/// otherwise.
+pub struct AdminUser {
+    pub email: String,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AdminUser
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = AuthedUser::from_request_parts(parts, state).await?;
+        let Extension(admins) = Extension::<Admins>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| AppError::Other(e.into()))?;
+        if !admins.contains(&user.email) {
+            return Err(AppError::Forbidden("admin access required".to_string()));
+        }
+        Ok(AdminUser { email: user.email })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+pub struct TasksQuery {
+    /// Only lists tasks with this status, like `queued` or `failed`
+    status: Option<String>,
+    #[serde(default = "default_tasks_limit")]
+    limit: i64,
+}
+
+fn default_tasks_limit() -> i64 {
+    100
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TaskResponse {
+    id: TaskId,
+    name: String,
+    data: serde_json::Value,
+    status: String,
+    run_at: DateTime<Utc>,
+    /// Seconds between runs of recurring tasks
+    interval: Option<u64>,
+}
+
+impl From<Task> for TaskResponse {
+    fn from(task: Task) -> Self {
+        Self {
+            id: task.id,
+            name: task.name,
+            data: task.data,
+            status: task.status,
+            run_at: task.run_at,
+            interval: task.interval.map(|interval| interval.as_secs()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TasksResponse {
+    /// Number of tasks in the queue by status
+    counts: HashMap<String, i64>,
+    tasks: Vec<TaskResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct EnqueuedTaskResponse {
+    task_id: TaskId,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/users/{email}/reindex",
+    tag = "admin",
+    params(("email" = String, Path, description = "Email of the user to reindex")),
+    responses(
+        (status = 202, body = EnqueuedTaskResponse),
+        (status = 403, description = "Not an admin")
+    )
+)]
+pub async fn post_reindex(
+    admin: AdminUser,
+    Extension(db): Extension<Database>,
+    Path(email): Path<String>,
+) -> Result<(StatusCode, Json<EnqueuedTaskResponse>), AppError> {
+    let client = db.get().await?;
+    if User::find(&client, &email).await?.is_none() {
+        return Err(AppError::NotFound(format!("user {email} not found")));
+    }
+
+    info!("{} enqueuing a full index of {email}...", admin.email);
+    let task_id = postgres_queue::enqueue(
+        &client,
+        "full_index",
+        json!({ "user_email": email }),
+        Utc::now(),
+        None,
+    )
+    .await
+    .map_err(anyhow::Error::from)?;
+    Ok((StatusCode::ACCEPTED, Json(EnqueuedTaskResponse { task_id })))
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RegisteredUserResponse {
+    id: i32,
+    email: String,
+}
+
+/// Registers a mailbox for app-only mode, where it's reached with the app's
+/// own token so its user never signs in.
+#[utoipa::path(
+    put,
+    path = "/api/admin/users/{email}",
+    tag = "admin",
+    params(("email" = String, Path, description = "Email of the mailbox to register")),
+    responses(
+        (status = 200, body = RegisteredUserResponse),
+        (status = 403, description = "Not an admin")
+    )
+)]
+pub async fn put_user(
+    admin: AdminUser,
+    Extension(db): Extension<Database>,
+    Path(email): Path<String>,
+) -> Result<Json<RegisteredUserResponse>, AppError> {
+    info!("{} registering the mailbox of {email}...", admin.email);
+    let client = db.get().await?;
+    let user = User::register(&client, &email).await?;
+    Ok(Json(RegisteredUserResponse {
+        id: user.id.unwrap(),
+        email: user.email,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/tasks",
+    tag = "admin",
+    params(TasksQuery),
+    responses(
+        (status = 200, body = TasksResponse),
+        (status = 403, description = "Not an admin")
+    )
+)]
+pub async fn get_tasks(
+    _: AdminUser,
+    Extension(db): Extension<Database>,
+    Query(query): Query<TasksQuery>,
+) -> Result<Json<TasksResponse>, AppError> {
+    let client = db.read().await?;
+    let counts = postgres_queue::count_tasks(&client)
+        .await
+        .map_err(anyhow::Error::from)?;
+    let tasks = postgres_queue::list_tasks(&client, query.status.as_deref(), query.limit)
+        .await
+        .map_err(anyhow::Error::from)?;
+
+    Ok(Json(TasksResponse {
+        counts,
+        tasks: tasks.into_iter().map(TaskResponse::from).collect(),
+    }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/admin/tasks/{id}",
+    tag = "admin",
+    params(("id" = i32, Path, description = "Task id")),
+    responses(
+        (status = 204, description = "Task deleted"),
+        (status = 403, description = "Not an admin"),
+        (status = 404, description = "No such task")
+    )
+)]
+pub async fn delete_task(
+    admin: AdminUser,
+    Extension(db): Extension<Database>,
+    Path(task_id): Path<TaskId>,
+) -> Result<StatusCode, AppError> {
+    info!("{} deleting task {task_id}...", admin.email);
+    let client = db.get().await?;
+    if postgres_queue::delete_task(&client, task_id)
+        .await
+        .map_err(anyhow::Error::from)?
+    {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!("task {task_id} not found")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admins() {
+        let admins = Admins::new(vec![" Admin@Example.com".to_string(), "".to_string()]);
+        assert!(admins.contains("admin@example.com"));
+        assert!(!admins.contains("user@example.com"));
+        assert!(!Admins::default().contains(""));
+    }
+}
@@ -0,0 +1,183 @@
+use axum::{
+    extract::Path,
+    http::{header, HeaderMap, Method, StatusCode},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::database::{ApiKey, Database};
+
+use super::{
+    authed_user::{registered_user_id, AuthedUser},
+    error::AppError,
+    session::{hash_secret, random_secret},
+};
+
+/// Starts every key, so they're recognizable in scripts and secret scanners.
+const KEY_PREFIX: &str = "pst_";
+
+/// How much of the key is kept in clear to tell keys apart.
+const DISPLAYED_PREFIX_LENGTH: usize = 8;
+
+/// `read` allows safe requests, `write` the others.
+const SCOPES: [&str; 2] = ["read", "write"];
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ApiKeyRequest {
+    name: String,
+    /// `read` and `write`
+    scopes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreatedApiKeyResponse {
+    /// The key, only shown this once
+    key: String,
+    api_key: ApiKey,
+}
+
+/// The key of an `Authorization: ApiKey <key>` header.
+pub fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("ApiKey ")
+        .map(str::trim)
+}
+
+/// Whether a key with `scopes` may make a request with `method`.
+pub fn allows(scopes: &[String], method: &Method) -> bool {
+    let needed = if method.is_safe() { "read" } else { "write" };
+    scopes.iter().any(|scope| scope == needed)
+}
+
+/// Keys can't manage keys, or a read only one could make a write one.
+#[allow(clippy::result_large_err)]
+fn require_signed_in(api_key: Option<i32>) -> Result<(), AppError> {
+    match api_key {
+        Some(_) => Err(AppError::Forbidden(
+            "API keys can't manage API keys, sign in instead".to_string(),
+        )),
+        None => Ok(()),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/me/api-keys",
+    tag = "profile",
+    responses((status = 200, body = [ApiKey]))
+)]
+pub async fn get_api_keys(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+) -> Result<Json<Vec<ApiKey>>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    Ok(Json(ApiKey::list(&db.read().await?, user_id).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/me/api-keys",
+    tag = "profile",
+    request_body = ApiKeyRequest,
+    responses((status = 201, body = CreatedApiKeyResponse))
+)]
+pub async fn post_api_key(
+    AuthedUser { user, api_key, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Json(mut data): Json<ApiKeyRequest>,
+) -> Result<(StatusCode, Json<CreatedApiKeyResponse>), AppError> {
+    require_signed_in(api_key)?;
+    let user_id = registered_user_id(user.as_ref())?;
+    if data.name.trim().is_empty() {
+        return Err(AppError::BadRequest("name can't be empty".to_string()));
+    }
+    data.scopes.sort();
+    data.scopes.dedup();
+    if data.scopes.is_empty() {
+        return Err(AppError::BadRequest("scopes can't be empty".to_string()));
+    }
+    if let Some(scope) = data
+        .scopes
+        .iter()
+        .find(|scope| !SCOPES.contains(&scope.as_str()))
+    {
+        return Err(AppError::BadRequest(format!(
+            "unknown scope {scope}, use read or write"
+        )));
+    }
+
+    let key = format!("{KEY_PREFIX}{}", random_secret());
+    let api_key = ApiKey::create(
+        &db.get().await?,
+        user_id,
+        data.name.trim(),
+        &hash_secret(&key),
+        &key[..DISPLAYED_PREFIX_LENGTH],
+        &data.scopes,
+    )
+    .await?;
+    info!("Created API key {} for user {user_id}", api_key.id);
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedApiKeyResponse { key, api_key }),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/me/api-keys/{id}",
+    tag = "profile",
+    params(("id" = i32, Path, description = "API key id")),
+    responses((status = 204, description = "API key revoked"))
+)]
+pub async fn delete_api_key(
+    AuthedUser { user, api_key, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    require_signed_in(api_key)?;
+    let user_id = registered_user_id(user.as_ref())?;
+    if !ApiKey::revoke(&db.get().await?, user_id, id).await? {
+        return Err(AppError::NotFound(format!("API key {id} not found")));
+    }
+    info!("Revoked API key {id} of user {user_id}");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_api_key() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(api_key(&headers), None);
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer abc"),
+        );
+        assert_eq!(api_key(&headers), None);
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("ApiKey pst_abc"),
+        );
+        assert_eq!(api_key(&headers), Some("pst_abc"));
+    }
+
+    #[test]
+    fn test_allows() {
+        let read = vec!["read".to_string()];
+        assert!(allows(&read, &Method::GET));
+        assert!(!allows(&read, &Method::DELETE));
+        let write = vec!["write".to_string()];
+        assert!(!allows(&write, &Method::GET));
+        assert!(allows(&write, &Method::POST));
+    }
+}
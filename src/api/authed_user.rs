@@ -0,0 +1,245 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    headers::{authorization::Bearer, Authorization, HeaderMapExt},
+    http::{request::Parts, HeaderMap},
+    Extension,
+};
+
+use crate::{
+    auth::consent_url,
+    backend::Provider,
+    database::{Account, ApiKey, Database, DatabaseError, Session, User},
+    graph::{self, FolderCache, GraphClient, TokenProvider},
+    token::{get_payload_field, granted_scopes, missing_scopes, UserTokenProvider},
+};
+
+use super::{
+    accounts::AccountId,
+    api_keys,
+    error::AppError,
+    jwt::TokenValidator,
+    refresh::account_access_token,
+    session::{self, hash_secret},
+};
+
+/// The user making the request, extracted from the bearer token, an
+/// `Authorization: ApiKey` header, or else from the session cookie.
+///
+/// The token must be signed by Microsoft for Graph, carry the user's email and
+/// not be expired. API keys and sessions act with the tokens stored for their
+/// user, which are refreshed as needed. `user` holds the
+/// database record when the user already registered their tokens through
+/// `/api/token`, and `graph` is a client ready to call Graph on their behalf.
+///
+/// On account scoped routes `graph` acts on the linked account instead, which
+/// requires the user to be registered and the account to be hosted on Graph.
+pub struct AuthedUser {
+    pub email: String,
+    pub access_token: String,
+    pub user: Option<User>,
+    pub graph: GraphClient,
+    /// The scopes granted to the token `graph` acts with
+    pub scopes: Vec<String>,
+    /// The id of the API key the request was made with
+    pub api_key: Option<i32>,
+}
+
+impl AuthedUser {
+    /// Fails with the URL to consent to them when the token lacks some of the
+    /// `required` scopes, rather than letting Graph answer with a bare 403.
+    /// Tokens without a `scp` claim are left for Graph to judge.
+    #[allow(clippy::result_large_err)]
+    pub fn require_scopes(&self, required: &[&str]) -> Result<(), AppError> {
+        if self.scopes.is_empty() {
+            return Ok(());
+        }
+        let missing = missing_scopes(&self.scopes, required);
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        Err(AppError::ReconsentRequired {
+            missing_scopes: missing.iter().map(|scope| scope.to_string()).collect(),
+            authorize_url: consent_url(&missing).ok().map(String::from),
+        })
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthedUser
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Extension(db) = Extension::<Database>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| AppError::Other(e.into()))?;
+        let client = db.get().await?;
+
+        let bearer = parts.headers.typed_get::<Authorization<Bearer>>();
+        let key_hash = api_keys::api_key(&parts.headers).map(hash_secret);
+        let mut api_key_id = None;
+        let (email, access_token) = match (bearer, key_hash) {
+            (Some(Authorization(bearer)), _) => {
+                let access_token = bearer.token().to_owned();
+                let Extension(validator) =
+                    Extension::<TokenValidator>::from_request_parts(parts, state)
+                        .await
+                        .map_err(|e| AppError::Other(e.into()))?;
+                let email = validator
+                    .validate(&access_token)
+                    .await
+                    .map_err(|err| AppError::Unauthorized(err.to_string()))?
+                    .unique_name;
+                (email, access_token)
+            }
+            (None, Some(key_hash)) => {
+                let api_key = ApiKey::find_active(&client, &key_hash)
+                    .await?
+                    .ok_or_else(|| AppError::Unauthorized("invalid API key".to_string()))?;
+                if !api_keys::allows(&api_key.scopes, &parts.method) {
+                    return Err(AppError::Forbidden(format!(
+                        "API key lacks the scope for {} requests",
+                        parts.method
+                    )));
+                }
+                api_key.touch(&client).await?;
+                api_key_id = Some(api_key.id);
+                let access_token = stored_access_token(&db, &api_key.user_email).await?;
+                (api_key.user_email, access_token)
+            }
+            (None, None) => {
+                let session_id = session::session_id(&parts.headers).ok_or_else(|| {
+                    AppError::Unauthorized(
+                        "missing bearer token, API key or session cookie".to_string(),
+                    )
+                })?;
+                let session = Session::find(&client, &session_id)
+                    .await?
+                    .ok_or_else(|| AppError::Unauthorized("session expired".to_string()))?;
+                let access_token = stored_access_token(&db, &session.user_email).await?;
+                (session.user_email, access_token)
+            }
+        };
+        let user = User::find(&client, &email).await?;
+
+        let Extension(folder_cache) = Extension::<FolderCache>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| AppError::Other(e.into()))?;
+        let (scopes, graph) = match parts.extensions.get::<AccountId>() {
+            Some(&AccountId(account_id)) => {
+                let user_id = registered_user_id(user.as_ref())?;
+                let account = Account::find(&client, user_id, account_id)
+                    .await?
+                    .ok_or_else(|| AppError::NotFound(format!("account {account_id} not found")))?;
+                // The handlers call Graph directly, linked accounts hosted
+                // elsewhere have their tokens but no client to serve them
+                let provider: Provider = account.provider.parse()?;
+                if provider != Provider::Graph {
+                    return Err(AppError::NotImplemented(format!(
+                        "{} {} isn't available for {} accounts",
+                        parts.method,
+                        parts.uri.path(),
+                        provider.name()
+                    )));
+                }
+                let access_token = account_access_token(&client, &account).await?;
+                (
+                    granted_scopes(&access_token),
+                    graph_client(access_token, folder_cache, &account.address),
+                )
+            }
+            None => (
+                granted_scopes(&access_token),
+                graph_client(access_token.clone(), folder_cache, &email),
+            ),
+        };
+
+        Ok(Self {
+            email,
+            access_token,
+            user,
+            graph,
+            scopes,
+            api_key: api_key_id,
+        })
+    }
+}
+
+/// The access token stored for the user, refreshed when it's about to expire.
+async fn stored_access_token(db: &Database, email: &str) -> Result<String, AppError> {
+    UserTokenProvider::new(db.clone(), email.to_string())
+        .access_token()
+        .await
+        .map_err(|err| AppError::Unauthorized(format!("can't reach the mailbox of {email}: {err}")))
+}
+
+/// The email of the user the request was authenticated as, to be called once
+/// [`AuthedUser`] accepted it, the bearer token isn't validated again.
+pub async fn authenticated_email(
+    client: &deadpool_postgres::Client,
+    headers: &HeaderMap,
+) -> Result<Option<String>, DatabaseError> {
+    if let Some(auth) = headers.typed_get::<Authorization<Bearer>>() {
+        return Ok(get_payload_field(auth.token(), "unique_name").ok());
+    }
+    if let Some(key) = api_keys::api_key(headers) {
+        let api_key = ApiKey::find_active(client, &hash_secret(key)).await?;
+        return Ok(api_key.map(|api_key| api_key.user_email));
+    }
+    let Some(session_id) = session::session_id(headers) else {
+        return Ok(None);
+    };
+    let session = Session::find(client, &session_id).await?;
+    Ok(session.map(|session| session.user_email))
+}
+
+/// The email of the user sending the request, once their credentials check
+/// out, for keying per user state in the middlewares running before
+/// [`AuthedUser`]. `None` when the request isn't authenticated. Expired bearer
+/// tokens are taken when `allow_expired`, their signature still tells who they
+/// were issued to.
+pub async fn verified_email(
+    db: &Database,
+    validator: &TokenValidator,
+    headers: &HeaderMap,
+    allow_expired: bool,
+) -> Result<Option<String>, DatabaseError> {
+    if let Some(auth) = headers.typed_get::<Authorization<Bearer>>() {
+        let claims = match allow_expired {
+            true => validator.validate_expired(auth.token()).await,
+            false => validator.validate(auth.token()).await,
+        };
+        return Ok(claims.ok().map(|claims| claims.unique_name));
+    }
+    if let Some(key) = api_keys::api_key(headers) {
+        let api_key = ApiKey::find_active(&db.get().await?, &hash_secret(key)).await?;
+        return Ok(api_key.map(|api_key| api_key.user_email));
+    }
+    let Some(session_id) = session::session_id(headers) else {
+        return Ok(None);
+    };
+    let session = Session::find(&db.get().await?, &session_id).await?;
+    Ok(session.map(|session| session.user_email))
+}
+
+fn graph_client(access_token: String, folder_cache: FolderCache, mailbox: &str) -> GraphClient {
+    GraphClient::builder(access_token)
+        .client(graph::shared_http_client())
+        .folder_cache(folder_cache, mailbox)
+        .build()
+}
+
+/// Returns the id of a user who registered their tokens, linked accounts and
+/// other stored data need one.
+#[allow(clippy::result_large_err)]
+pub fn registered_user_id(user: Option<&User>) -> Result<i32, AppError> {
+    user.and_then(|user| user.id).ok_or_else(not_registered)
+}
+
+fn not_registered() -> AppError {
+    AppError::Unauthorized("user has no tokens registered, use /api/token first".to_string())
+}
@@ -0,0 +1,117 @@
+use std::str::FromStr;
+
+use anyhow::bail;
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+use super::{idempotency, refresh, request_id};
+
+/// Which cross-origin requests the API accepts. A `None` list allows any value.
+#[derive(Clone, Debug, Default)]
+pub struct CorsConfig {
+    origins: Option<Vec<HeaderValue>>,
+    methods: Option<Vec<Method>>,
+    headers: Option<Vec<HeaderName>>,
+    allow_credentials: bool,
+}
+
+impl CorsConfig {
+    /// Builds the policy from lists of origins, methods and headers, where `*`
+    /// allows any value.
+    ///
+    /// Browsers refuse credentialed responses with a wildcard origin, so
+    /// `allow_credentials` requires the origins to be listed explicitly.
+    pub fn new(
+        origins: &[String],
+        methods: &[String],
+        headers: &[String],
+        allow_credentials: bool,
+    ) -> anyhow::Result<Self> {
+        let origins = parse_list(origins, |origin| Ok(HeaderValue::from_str(origin)?))?;
+        if allow_credentials && origins.is_none() {
+            bail!("CORS credentials can't be allowed for any origin, list the allowed origins");
+        }
+
+        Ok(Self {
+            origins,
+            methods: parse_list(methods, |method| Ok(Method::from_str(method)?))?,
+            headers: parse_list(headers, |header| Ok(HeaderName::from_str(header)?))?,
+            allow_credentials,
+        })
+    }
+
+    pub fn layer(&self) -> CorsLayer {
+        let origin = match &self.origins {
+            Some(origins) => AllowOrigin::list(origins.clone()),
+            None => AllowOrigin::any(),
+        };
+
+        // A wildcard isn't valid along with credentials, mirroring the request
+        // allows the same methods and headers
+        let methods = match (&self.methods, self.allow_credentials) {
+            (Some(methods), _) => AllowMethods::list(methods.clone()),
+            (None, true) => AllowMethods::mirror_request(),
+            (None, false) => AllowMethods::any(),
+        };
+        let headers = match (&self.headers, self.allow_credentials) {
+            (Some(headers), _) => AllowHeaders::list(headers.clone()),
+            (None, true) => AllowHeaders::mirror_request(),
+            (None, false) => AllowHeaders::any(),
+        };
+
+        CorsLayer::new()
+            .allow_origin(origin)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(self.allow_credentials)
+            .expose_headers([
+                HeaderName::from_static(refresh::ACCESS_TOKEN_HEADER),
+                HeaderName::from_static(idempotency::IDEMPOTENT_REPLAYED_HEADER),
+                request_id::REQUEST_ID_HEADER,
+                header::ETAG,
+            ])
+    }
+}
+
+/// Parses every value of `list`, returning `None` when it allows anything.
+fn parse_list<T>(
+    list: &[String],
+    parse: impl Fn(&str) -> anyhow::Result<T>,
+) -> anyhow::Result<Option<Vec<T>>> {
+    if list.is_empty() || list.iter().any(|value| value.trim() == "*") {
+        return Ok(None);
+    }
+
+    list.iter()
+        .map(|value| parse(value.trim()))
+        .collect::<anyhow::Result<_>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_cors_config() {
+        let any = strings(&["*"]);
+        assert!(CorsConfig::new(&any, &any, &any, false).is_ok());
+        assert!(CorsConfig::new(&any, &any, &any, true).is_err());
+
+        let config = CorsConfig::new(
+            &strings(&["https://mail.example.com"]),
+            &strings(&["GET", "POST"]),
+            &any,
+            true,
+        )
+        .unwrap();
+        assert_eq!(config.methods, Some(vec![Method::GET, Method::POST]));
+        assert!(config.headers.is_none());
+
+        assert!(CorsConfig::new(&any, &strings(&["NOT A METHOD"]), &any, false).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use axum::{response::Html, Json};
+use utoipa::{
+    openapi::security::{
+        ApiKey as ApiKeyScheme, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme,
+    },
+    Modify, OpenApi,
+};
+
+use crate::{
+    database::{
+        Account, AccountSettings, ApiKey, Rule, Session, Signature, User, WebhookSubscription,
+    },
+    graph::{
+        AttachmentMeta, AutomaticRepliesSetting, Body, BulkResult, Category,
+        ClassificationOverride, DateTimeTimeZone, Email, EmailAddress, EmailAddressWrapper, Event,
+        EventResponse, Flag, FlagStatus, Folder, Importance, InferenceClassification, Location,
+        MailboxSettings, MessageRule, Profile, ResponseStatus, TimeZoneBase, WorkingHours,
+    },
+    rules::{Action, Condition},
+};
+
+use super::admin::{EnqueuedTaskResponse, RegisteredUserResponse, TaskResponse, TasksResponse};
+use super::api_keys::{ApiKeyRequest, CreatedApiKeyResponse};
+
+use super::{
+    AttachmentRequest, CategoriesRequest, ClassificationOverrideRequest, CreateFolderRequest,
+    DeltaResponse, EmailsPage, FolderCountResponse, ForwardRequest, LinkAccountRequest,
+    MovedEmailResponse, PhishingReportResponse, ReplyRequest, RespondEventRequest,
+    RuleOrderRequest, RuleRequest, ScheduledEmailResponse, SendEmailRequest, SignatureRequest,
+    SnoozeRequest, SnoozeResponse, TokenInfoResponse, TokenRequest, UpdateDraftRequest,
+    UpdateEmailRequest, UpdateFolderRequest,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "postars", description = "Email API backed by Microsoft Graph"),
+    paths(
+        super::get_profile,
+        super::delete_me,
+        super::get_mailbox_settings,
+        super::patch_mailbox_settings,
+        super::post_token,
+        super::get_token_info,
+        super::session::post_session,
+        super::session::get_session,
+        super::session::delete_session,
+        super::session::get_sessions,
+        super::session::delete_sessions,
+        super::session::delete_user_session,
+        super::api_keys::get_api_keys,
+        super::api_keys::post_api_key,
+        super::api_keys::delete_api_key,
+        super::admin::put_user,
+        super::admin::post_reindex,
+        super::admin::get_tasks,
+        super::admin::delete_task,
+        super::subscriptions::get_subscriptions,
+        super::subscriptions::post_subscription,
+        super::subscriptions::post_renew_subscription,
+        super::subscriptions::delete_subscription,
+        super::subscriptions::post_notifications,
+        super::get_accounts,
+        super::post_account,
+        super::get_account,
+        super::patch_account,
+        super::delete_account,
+        super::get_signatures,
+        super::post_signature,
+        super::get_signature,
+        super::put_signature,
+        super::delete_signature,
+        super::get_rules,
+        super::post_rule,
+        super::put_rules_order,
+        super::get_rule,
+        super::put_rule,
+        super::delete_rule,
+        super::get_events,
+        super::get_event,
+        super::post_event_response,
+        super::get_categories,
+        super::get_classification_overrides,
+        super::post_classification_override,
+        super::delete_classification_override,
+        super::get_outlook_rules,
+        super::post_outlook_rule,
+        super::patch_outlook_rule,
+        super::delete_outlook_rule,
+        super::get_search,
+        super::ws::get_ws,
+        super::get_emails,
+        super::post_email,
+        super::delete_scheduled_email,
+        super::put_bulk_move,
+        super::put_bulk_read,
+        super::put_bulk_unread,
+        super::put_bulk_archive,
+        super::put_bulk_spam,
+        super::put_bulk_delete,
+        super::get_email,
+        super::patch_email,
+        super::delete_email,
+        super::put_move,
+        super::post_reply,
+        super::post_reply_all,
+        super::post_forward,
+        super::get_email_mime,
+        super::get_conversation,
+        super::get_attachments,
+        super::get_attachment,
+        super::put_read,
+        super::put_unread,
+        super::put_archive,
+        super::put_mark_spam,
+        super::put_focused,
+        super::put_other,
+        super::put_phishing,
+        super::put_snooze,
+        super::put_categories,
+        super::post_draft,
+        super::get_draft,
+        super::patch_draft,
+        super::delete_draft,
+        super::post_send_draft,
+        super::post_draft_attachments,
+        super::get_folders,
+        super::post_folder,
+        super::get_folder_counts,
+        super::patch_folder,
+        super::delete_folder,
+        super::get_folder_delta,
+        super::get_folder_emails,
+    ),
+    components(schemas(
+        Account,
+        AccountSettings,
+        Action,
+        ApiKey,
+        ApiKeyRequest,
+        AttachmentMeta,
+        AttachmentRequest,
+        AutomaticRepliesSetting,
+        Body,
+        BulkResult,
+        CategoriesRequest,
+        Category,
+        ClassificationOverride,
+        ClassificationOverrideRequest,
+        Condition,
+        CreateFolderRequest,
+        CreatedApiKeyResponse,
+        DateTimeTimeZone,
+        DeltaResponse,
+        Email,
+        EmailAddress,
+        EmailAddressWrapper,
+        EmailsPage,
+        EnqueuedTaskResponse,
+        Event,
+        EventResponse,
+        Flag,
+        FlagStatus,
+        Folder,
+        FolderCountResponse,
+        ForwardRequest,
+        Importance,
+        InferenceClassification,
+        LinkAccountRequest,
+        Location,
+        MailboxSettings,
+        MessageRule,
+        MovedEmailResponse,
+        PhishingReportResponse,
+        Profile,
+        RegisteredUserResponse,
+        ReplyRequest,
+        RespondEventRequest,
+        ResponseStatus,
+        Rule,
+        RuleOrderRequest,
+        RuleRequest,
+        ScheduledEmailResponse,
+        SendEmailRequest,
+        Session,
+        Signature,
+        SignatureRequest,
+        SnoozeRequest,
+        SnoozeResponse,
+        TaskResponse,
+        TasksResponse,
+        TimeZoneBase,
+        TokenInfoResponse,
+        TokenRequest,
+        UpdateDraftRequest,
+        UpdateEmailRequest,
+        UpdateFolderRequest,
+        User,
+        WebhookSubscription,
+        WorkingHours,
+    )),
+    modifiers(&BearerAuth),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+pub struct ApiDoc;
+
+/// Registers the Graph access token as the bearer authentication scheme, and
+/// API keys, sent as `Authorization: ApiKey <key>`.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKeyScheme::Header(ApiKeyValue::new("Authorization"))),
+        );
+    }
+}
+
+/// Swagger UI page, assets are loaded from a CDN so nothing is bundled in the
+/// binary.
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8" />
+    <title>postars API</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@4/swagger-ui.css" />
+  </head>
+  <body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@4/swagger-ui-bundle.js"></script>
+    <script>
+      window.ui = SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#swagger-ui" });
+    </script>
+  </body>
+</html>
+"##;
+
+pub async fn get_openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+pub async fn get_docs() -> Html<&'static str> {
+    Html(SWAGGER_UI)
+}
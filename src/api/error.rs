@@ -3,14 +3,28 @@ use axum::response::{IntoResponse, Response};
 use reqwest::StatusCode;
 use tracing::error;
 
+use crate::backend::BackendError;
 use crate::database::DatabaseError;
 use crate::graph::GraphClientError;
 
+use super::request_id;
+
 pub enum AppError {
     GraphClient(GraphClientError),
     Database(DatabaseError),
     Other(anyhow::Error),
     BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    /// The route isn't served for the provider of the account
+    NotImplemented(String),
+    /// The token lacks scopes the route needs, the user has to sign in again
+    /// and consent to them
+    ReconsentRequired {
+        missing_scopes: Vec<String>,
+        authorize_url: Option<String>,
+    },
 }
 
 impl From<GraphClientError> for AppError {
@@ -19,6 +33,12 @@ impl From<GraphClientError> for AppError {
     }
 }
 
+impl From<BackendError> for AppError {
+    fn from(inner: BackendError) -> Self {
+        AppError::BadRequest(inner.to_string())
+    }
+}
+
 impl From<DatabaseError> for AppError {
     fn from(inner: DatabaseError) -> Self {
         AppError::Database(inner)
@@ -35,11 +55,22 @@ impl From<anyhow::Error> for AppError {
 pub struct CustomError {
     message: String,
     status: StatusCode,
+    /// Fields added to the body next to the message
+    details: serde_json::Map<String, serde_json::Value>,
 }
 
 impl CustomError {
     pub fn new(message: String, status: StatusCode) -> Self {
-        Self { message, status }
+        Self {
+            message,
+            status,
+            details: serde_json::Map::new(),
+        }
+    }
+
+    pub fn with_detail(mut self, key: &str, value: serde_json::Value) -> Self {
+        self.details.insert(key.to_string(), value);
+        self
     }
 }
 
@@ -48,9 +79,14 @@ impl IntoResponse for CustomError {
         let message = self.message;
         let status = self.status;
 
-        // Create a JSON response with the error message and the given status code
-        let json = axum::Json(serde_json::json!({ "message": message }));
-        let mut response = json.into_response();
+        // Create a JSON response with the error message and the given status code,
+        // along with the request id to correlate client reports with the logs
+        let mut body = self.details;
+        body.insert("message".to_string(), message.into());
+        if let Some(request_id) = request_id::current() {
+            body.insert("request_id".to_string(), request_id.into());
+        }
+        let mut response = axum::Json(body).into_response();
         *response.status_mut() = status;
         response
     }
@@ -58,6 +94,21 @@ impl IntoResponse for CustomError {
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        if let AppError::ReconsentRequired {
+            missing_scopes,
+            authorize_url,
+        } = self
+        {
+            return CustomError::new(
+                format!("Consent required for {}", missing_scopes.join(", ")),
+                StatusCode::FORBIDDEN,
+            )
+            .with_detail("error", "reconsent_required".into())
+            .with_detail("missing_scopes", missing_scopes.into())
+            .with_detail("authorize_url", authorize_url.into())
+            .into_response();
+        }
+
         let (status, message) = match self {
             AppError::GraphClient(GraphClientError::Request(status)) => {
                 error!("Request error: {}", status);
@@ -69,6 +120,13 @@ impl IntoResponse for AppError {
                 };
                 (status, message)
             }
+            AppError::GraphClient(GraphClientError::Api(err)) => {
+                error!("Graph API error: {err} ({:?})", err.inner_error);
+                (err.status, format!("{}: {}", err.code, err.message))
+            }
+            AppError::GraphClient(err @ GraphClientError::MessageNotFound(_)) => {
+                (StatusCode::NOT_FOUND, err.to_string())
+            }
             AppError::Database(err) => {
                 let message = err.to_string();
                 error!("Database error: {:?}", err);
@@ -85,6 +143,11 @@ impl IntoResponse for AppError {
                 (StatusCode::INTERNAL_SERVER_ERROR, message)
             }
             AppError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::Unauthorized(message) => (StatusCode::UNAUTHORIZED, message),
+            AppError::Forbidden(message) => (StatusCode::FORBIDDEN, message),
+            AppError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            AppError::NotImplemented(message) => (StatusCode::NOT_IMPLEMENTED, message),
+            AppError::ReconsentRequired { .. } => unreachable!("answered above"),
         };
 
         let error_response = CustomError::new(message, status);
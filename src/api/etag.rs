@@ -0,0 +1,70 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use axum::{
+    headers::{ETag, HeaderMapExt, IfNoneMatch},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::graph::Email;
+
+/// Computes a weak ETag for a listing of emails out of their ids and last
+/// modification times, plus whatever else identifies the listing (like the
+/// page requested).
+///
+/// The tag is weak since the body can be compressed on the way out.
+pub fn emails_etag(emails: &[Email], listing: impl Hash) -> ETag {
+    let mut hasher = DefaultHasher::new();
+    listing.hash(&mut hasher);
+    for email in emails {
+        email.id.hash(&mut hasher);
+        email.last_modified_date_time.hash(&mut hasher);
+    }
+
+    format!("W/\"{:016x}\"", hasher.finish())
+        .parse()
+        .expect("hex digits are a valid etag")
+}
+
+/// Responds with `304 Not Modified` when the client already has the current
+/// version of `value`, or with the JSON and its ETag otherwise.
+pub fn conditional_json<T: Serialize>(
+    if_none_match: Option<IfNoneMatch>,
+    etag: ETag,
+    value: T,
+) -> Response {
+    let mut response = match if_none_match {
+        Some(if_none_match) if !if_none_match.precondition_passes(&etag) => {
+            StatusCode::NOT_MODIFIED.into_response()
+        }
+        _ => Json(value).into_response(),
+    };
+    response.headers_mut().typed_insert(etag);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conditional_json() {
+        let etag: ETag = "W/\"abc\"".parse().unwrap();
+
+        let response = conditional_json(None, etag.clone(), "body");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().typed_get::<ETag>(), Some(etag.clone()));
+
+        let response = conditional_json(Some(IfNoneMatch::from(etag.clone())), etag.clone(), "");
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let other: ETag = "W/\"def\"".parse().unwrap();
+        let response = conditional_json(Some(IfNoneMatch::from(other)), etag, "body");
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,270 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use tracing::warn;
+
+use crate::database::{Database, IdempotencyKey};
+
+use super::{
+    accounts::AccountId, authed_user::verified_email, error::AppError, error::CustomError,
+    jwt::TokenValidator,
+};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Set on responses replayed from a previous request with the same key.
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Middleware making retries of mutating requests sent with an
+/// `Idempotency-Key` header safe: the first request with a key is handled and
+/// its response stored, later ones with the same key get that response back
+/// without sending or moving anything again.
+///
+/// Keys are scoped to the user, once their credentials are verified, and
+/// expire after a day. Server errors, and clients disconnecting before the
+/// response is stored, free the key so the request can be retried.
+pub async fn idempotency(
+    Extension(db): Extension<Database>,
+    Extension(validator): Extension<TokenValidator>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(key) = idempotency_key(req.method(), req.headers()) else {
+        return next.run(req).await;
+    };
+    let key = match key {
+        Ok(key) => key,
+        Err(message) => return CustomError::new(message, StatusCode::BAD_REQUEST).into_response(),
+    };
+    // Unauthenticated requests are rejected further down, without ever seeing
+    // a stored response
+    let email = match verified_email(&db, &validator, req.headers(), false).await {
+        Ok(Some(email)) => email,
+        Ok(None) => return next.run(req).await,
+        Err(err) => return AppError::from(err).into_response(),
+    };
+
+    let owner = match req.extensions().get::<AccountId>() {
+        Some(AccountId(account_id)) => format!("{email}/{account_id}"),
+        None => email,
+    };
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+
+    match handle(&db, &owner, &key, &method, &path, req, next).await {
+        Ok(response) => response,
+        Err(err) => err.into_response(),
+    }
+}
+
+async fn handle(
+    db: &Database,
+    owner: &str,
+    key: &str,
+    method: &str,
+    path: &str,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, AppError> {
+    let client = db.get().await?;
+    if !IdempotencyKey::claim(&client, owner, key, method, path).await? {
+        return Ok(replay(
+            IdempotencyKey::find(&client, owner, key).await?,
+            method,
+            path,
+        ));
+    }
+
+    let mut claim = Claim {
+        db: db.clone(),
+        owner: owner.to_string(),
+        key: key.to_string(),
+        settled: false,
+    };
+
+    let response = next.run(req).await;
+    let (parts, body) = response.into_parts();
+    if parts.status.is_server_error() {
+        IdempotencyKey::release(&client, owner, key).await?;
+        claim.settled = true;
+        return Ok(Response::from_parts(parts, body));
+    }
+
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("Failed to read the response for idempotency key {key}: {err}");
+            IdempotencyKey::release(&client, owner, key).await?;
+            claim.settled = true;
+            return Err(anyhow::anyhow!("failed to read the response").into());
+        }
+    };
+    let content_type = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    IdempotencyKey::complete(
+        &client,
+        owner,
+        key,
+        parts.status.as_u16() as i16,
+        content_type,
+        &body,
+    )
+    .await?;
+    claim.settled = true;
+
+    Ok(Response::from_parts(
+        parts,
+        axum::body::boxed(Body::from(body)),
+    ))
+}
+
+/// A key claimed by the request being handled. Unless its response was stored
+/// or the key freed already, the key is freed when the claim is dropped: the
+/// client went away, which cancels the request, or storing the response
+/// failed. Otherwise retries would be refused until the key expires.
+struct Claim {
+    db: Database,
+    owner: String,
+    key: String,
+    settled: bool,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if self.settled {
+            return;
+        }
+        let db = self.db.clone();
+        let owner = std::mem::take(&mut self.owner);
+        let key = std::mem::take(&mut self.key);
+        tokio::spawn(async move {
+            let released = match db.get().await {
+                Ok(client) => IdempotencyKey::release(&client, &owner, &key).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = released {
+                warn!("Failed to free idempotency key {key}: {err}");
+            }
+        });
+    }
+}
+
+/// Returns the key of a mutating request, or the reason it's invalid.
+fn idempotency_key(method: &Method, headers: &HeaderMap) -> Option<Result<String, String>> {
+    if !matches!(
+        *method,
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    ) {
+        return None;
+    }
+
+    let key = headers.get(IDEMPOTENCY_KEY_HEADER)?;
+    Some(match key.to_str().map(str::trim) {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => Ok(key.to_string()),
+        _ => Err(format!(
+            "Idempotency-Key must be between 1 and {MAX_KEY_LENGTH} visible characters"
+        )),
+    })
+}
+
+/// Responds to a request whose key is already taken.
+fn replay(stored: Option<IdempotencyKey>, method: &str, path: &str) -> Response {
+    let Some(stored) = stored else {
+        // The key expired or was released in the meantime
+        return CustomError::new(
+            "The request with this Idempotency-Key failed, retry it".to_string(),
+            StatusCode::CONFLICT,
+        )
+        .into_response();
+    };
+    if stored.method != method || stored.path != path {
+        return CustomError::new(
+            "Idempotency-Key was already used for another request".to_string(),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        )
+        .into_response();
+    }
+    let Some(status) = stored
+        .status
+        .and_then(|s| StatusCode::from_u16(s as u16).ok())
+    else {
+        return CustomError::new(
+            "A request with this Idempotency-Key is still being processed".to_string(),
+            StatusCode::CONFLICT,
+        )
+        .into_response();
+    };
+
+    let mut response = (status, Bytes::from(stored.body.unwrap_or_default())).into_response();
+    let headers = response.headers_mut();
+    headers.remove(header::CONTENT_TYPE);
+    if let Some(content_type) = stored
+        .content_type
+        .and_then(|content_type| HeaderValue::from_str(&content_type).ok())
+    {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    headers.insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idempotency_key() {
+        let mut headers = HeaderMap::new();
+        assert!(idempotency_key(&Method::POST, &headers).is_none());
+
+        headers.insert(IDEMPOTENCY_KEY_HEADER, HeaderValue::from_static(" abc "));
+        assert_eq!(
+            idempotency_key(&Method::POST, &headers),
+            Some(Ok("abc".to_string()))
+        );
+        // Safe methods don't need one
+        assert!(idempotency_key(&Method::GET, &headers).is_none());
+
+        headers.insert(IDEMPOTENCY_KEY_HEADER, HeaderValue::from_static(""));
+        assert!(matches!(
+            idempotency_key(&Method::DELETE, &headers),
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn test_replay() {
+        let stored = || IdempotencyKey {
+            method: "POST".to_string(),
+            path: "/api/emails".to_string(),
+            status: Some(202),
+            content_type: None,
+            body: Some(vec![]),
+        };
+
+        let response = replay(Some(stored()), "POST", "/api/emails");
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(
+            response.headers().get(IDEMPOTENT_REPLAYED_HEADER).unwrap(),
+            "true"
+        );
+
+        let response = replay(Some(stored()), "DELETE", "/api/emails/1");
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let in_progress = IdempotencyKey {
+            status: None,
+            ..stored()
+        };
+        let response = replay(Some(in_progress), "POST", "/api/emails");
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use base64::URL_SAFE_NO_PAD;
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::info;
+
+use crate::{graph, token};
+
+/// Signing keys are fetched again after this long, to follow their rotation.
+const KEYS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Tokens signed with an unknown key fetch the keys again at most this often,
+/// so forged tokens can't flood the keys endpoint.
+const KEYS_MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Application id of Microsoft Graph, the audience of the tokens issued for it.
+const GRAPH_APP_ID: &str = "00000003-0000-0000-c000-000000000000";
+
+#[derive(Debug, Error)]
+pub enum JwtError {
+    #[error("malformed token")]
+    Malformed,
+
+    #[error("invalid token: {0}")]
+    Invalid(#[from] jsonwebtoken::errors::Error),
+
+    #[error("token expired")]
+    Expired,
+
+    #[error("token signed with unknown key {0}")]
+    UnknownKey(String),
+
+    #[error("token issued by {0}, not Microsoft")]
+    Issuer(String),
+
+    #[error("failed to fetch the signing keys: {0}")]
+    Keys(#[from] reqwest::Error),
+}
+
+/// The claims of an access token postars relies on.
+#[derive(Debug, Deserialize)]
+pub struct Claims {
+    /// The email of the user
+    pub unique_name: String,
+    /// The tenant that issued the token
+    #[serde(default)]
+    pub tid: String,
+    #[serde(default)]
+    pub iss: String,
+}
+
+/// Checks that bearer tokens were issued by Microsoft for Graph before their
+/// claims are trusted, with the signing keys of the cloud, cached.
+#[derive(Clone)]
+pub struct TokenValidator {
+    /// `None` when validation is disabled.
+    keys: Option<Arc<KeyCache>>,
+}
+
+impl TokenValidator {
+    pub fn new() -> Self {
+        let jwks_url = format!("{}/common/discovery/v2.0/keys", graph::endpoints().login);
+        Self {
+            keys: Some(Arc::new(KeyCache::new(jwks_url))),
+        }
+    }
+
+    /// Trusts the payload of tokens as is, only checking their expiration. For
+    /// development against made up tokens, never in production.
+    pub fn insecure() -> Self {
+        Self { keys: None }
+    }
+
+    /// Verifies the signature, audience, issuer and expiration of the token.
+    pub async fn validate(&self, token: &str) -> Result<Claims, JwtError> {
+        self.decode(token, true).await
+    }
+
+    /// Verifies the token like [`Self::validate`], accepting it once expired.
+    /// The signature still proves who the token was issued to, which is enough
+    /// to refresh it.
+    pub async fn validate_expired(&self, token: &str) -> Result<Claims, JwtError> {
+        self.decode(token, false).await
+    }
+
+    async fn decode(&self, token: &str, validate_exp: bool) -> Result<Claims, JwtError> {
+        let Some(keys) = &self.keys else {
+            if validate_exp
+                && token::get_expiration(token).is_ok_and(|exp| exp <= chrono::Utc::now())
+            {
+                return Err(JwtError::Expired);
+            }
+            let payload = token::get_payload(token).map_err(|_| JwtError::Malformed)?;
+            return serde_json::from_value(payload).map_err(|_| JwtError::Malformed);
+        };
+
+        let token = hash_nonce(token)?;
+        let kid = decode_header(&token)?.kid.ok_or(JwtError::Malformed)?;
+        let key = DecodingKey::from_jwk(&keys.find(&kid).await?)?;
+
+        let graph_resource = graph::endpoints().graph_resource();
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[
+            GRAPH_APP_ID.to_string(),
+            format!("{graph_resource}/"),
+            graph_resource,
+        ]);
+        validation.validate_exp = validate_exp;
+        let claims = decode::<Claims>(&token, &key, &validation)?.claims;
+
+        if !is_tenant_issuer(&claims) {
+            return Err(JwtError::Issuer(claims.iss));
+        }
+        Ok(claims)
+    }
+}
+
+impl Default for TokenValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The signing keys of the cloud, fetched again when they get old or a token
+/// is signed with a key they don't have yet.
+struct KeyCache {
+    url: String,
+    keys: RwLock<Option<(JwkSet, Instant)>>,
+}
+
+impl KeyCache {
+    fn new(url: String) -> Self {
+        Self {
+            url,
+            keys: RwLock::new(None),
+        }
+    }
+
+    async fn find(&self, kid: &str) -> Result<Jwk, JwtError> {
+        if let Some((keys, fetched_at)) = self.keys.read().await.as_ref() {
+            if fetched_at.elapsed() < KEYS_TTL {
+                if let Some(key) = keys.find(kid) {
+                    return Ok(key.clone());
+                }
+            }
+        }
+
+        let mut keys = self.keys.write().await;
+        let stale = keys.as_ref().map_or(true, |(_, fetched_at)| {
+            fetched_at.elapsed() >= KEYS_MIN_REFETCH_INTERVAL
+        });
+        if stale {
+            info!("Fetching token signing keys from {}...", self.url);
+            let fetched = graph::shared_http_client()
+                .get(&self.url)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<JwkSet>()
+                .await?;
+            *keys = Some((fetched, Instant::now()));
+        }
+
+        keys.as_ref()
+            .and_then(|(keys, _)| keys.find(kid))
+            .cloned()
+            .ok_or_else(|| JwtError::UnknownKey(kid.to_string()))
+    }
+}
+
+/// Graph signs its tokens with the SHA-256 of the `nonce` of their header in
+/// place of the nonce itself, so the header is put back in that form first.
+fn hash_nonce(token: &str) -> Result<String, JwtError> {
+    let (header, rest) = token.split_once('.').ok_or(JwtError::Malformed)?;
+    let decoded =
+        base64::decode_config(header, URL_SAFE_NO_PAD).map_err(|_| JwtError::Malformed)?;
+    let mut header: Value = serde_json::from_slice(&decoded).map_err(|_| JwtError::Malformed)?;
+    let Some(nonce) = header.get("nonce").and_then(Value::as_str) else {
+        return Ok(token.to_string());
+    };
+
+    header["nonce"] = Value::String(format!("{:x}", Sha256::digest(nonce.as_bytes())));
+    let header = serde_json::to_vec(&header).map_err(|_| JwtError::Malformed)?;
+    Ok(format!(
+        "{}.{rest}",
+        base64::encode_config(header, URL_SAFE_NO_PAD)
+    ))
+}
+
+/// Whether the token was issued by the tenant it claims, through the v1 or v2
+/// endpoint.
+fn is_tenant_issuer(claims: &Claims) -> bool {
+    let iss = claims.iss.trim_end_matches('/');
+    !claims.tid.is_empty()
+        && iss.starts_with("https://")
+        && (iss.ends_with(&format!("/{}", claims.tid))
+            || iss.ends_with(&format!("/{}/v2.0", claims.tid)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_nonce() {
+        let encode = |json: &str| base64::encode_config(json, URL_SAFE_NO_PAD);
+
+        let token = format!(
+            "{}.payload.signature",
+            encode(r#"{"typ":"JWT","nonce":"abc","alg":"RS256","kid":"k1"}"#)
+        );
+        let hashed = hash_nonce(&token).unwrap();
+        assert_eq!(
+            hashed,
+            format!(
+                "{}.payload.signature",
+                encode(
+                    r#"{"typ":"JWT","nonce":"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad","alg":"RS256","kid":"k1"}"#
+                )
+            )
+        );
+
+        let token = format!(
+            "{}.payload.signature",
+            encode(r#"{"typ":"JWT","alg":"RS256"}"#)
+        );
+        assert_eq!(hash_nonce(&token).unwrap(), token);
+        assert!(hash_nonce("garbage").is_err());
+    }
+
+    #[test]
+    fn test_is_tenant_issuer() {
+        let claims = |iss: &str, tid: &str| Claims {
+            unique_name: "jane@example.com".to_string(),
+            tid: tid.to_string(),
+            iss: iss.to_string(),
+        };
+        assert!(is_tenant_issuer(&claims(
+            "https://sts.windows.net/t1/",
+            "t1"
+        )));
+        assert!(is_tenant_issuer(&claims(
+            "https://login.microsoftonline.com/t1/v2.0",
+            "t1"
+        )));
+        assert!(!is_tenant_issuer(&claims(
+            "https://sts.windows.net/t2/",
+            "t1"
+        )));
+        assert!(!is_tenant_issuer(&claims("https://sts.windows.net//", "")));
+    }
+}
@@ -0,0 +1,264 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{header, Request},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    database::{Database, Message, User},
+    graph::{BulkResult, Email, EmailFields, Page},
+    token,
+};
+
+use super::{accounts::AccountId, authed_user::authenticated_email};
+
+/// Listings are served from the cache for this long after the mailbox was
+/// fully synced, Graph is asked again after that.
+const MESSAGE_CACHE_MAX_AGE_MINUTES: i64 = 30;
+
+/// Mutating requests on these paths change messages, like moves and flags.
+const MESSAGE_PATHS: &[&str] = &[
+    "/api/emails",
+    "/api/drafts",
+    "/api/folders",
+    "/api/conversations",
+];
+
+/// Returns a page of the cached mailbox, the latest first, skipping the
+/// `offset` first emails, or `None` when the cache is stale and Graph has to
+/// be asked.
+pub async fn cached_emails_page(
+    db: &Database,
+    user: &User,
+    offset: usize,
+    page_size: usize,
+) -> anyhow::Result<Option<Page<Email>>> {
+    let Some(user_id) = user.id else {
+        return Ok(None);
+    };
+    let client = db.read().await?;
+    let fresh = user
+        .messages_synced_at(&client)
+        .await?
+        .is_some_and(|synced_at| {
+            Utc::now() - synced_at < Duration::minutes(MESSAGE_CACHE_MAX_AGE_MINUTES)
+        });
+    if !fresh {
+        return Ok(None);
+    }
+
+    let (items, total) = Message::page(&client, user_id, page_size as i64, offset as i64).await?;
+    let total = total as u64;
+    Ok(Some(Page {
+        has_more: ((offset + items.len()) as u64) < total,
+        items,
+        total: Some(total),
+    }))
+}
+
+/// Marks the cache of the user stale until the next sync, for changes it
+/// can't follow message by message.
+pub async fn invalidate(db: &Database, email: &str) -> anyhow::Result<()> {
+    let client = db.get().await?;
+    if let Some(user) = User::find(&client, email).await? {
+        user.set_messages_synced_at(&client, None).await?;
+    }
+    Ok(())
+}
+
+/// Brings the cached messages with these ids up to date after they changed,
+/// dropping the ones gone from the mailbox.
+pub async fn refresh(db: &Database, user: &User, email_ids: &[String]) -> anyhow::Result<()> {
+    let Some(user_id) = user.id else {
+        return Ok(());
+    };
+    if email_ids.is_empty() {
+        return Ok(());
+    }
+    let graph = token::mailbox_client(db.clone(), &user.email).build();
+    let (emails, missing) = graph
+        .get_emails_by_ids(email_ids, EmailFields::WithoutBody)
+        .await?;
+    let client = db.get().await?;
+    Message::upsert_many(&client, user_id, &emails).await?;
+    Message::delete_many(&client, user_id, &missing).await?;
+    Ok(())
+}
+
+/// Middleware keeping the cache of the user current once a request changing
+/// their messages succeeds. The messages the request touched are cached again
+/// in place, out of the response when it has them or else from Graph. Only
+/// changes the cache can't follow, like sending mail or deleting a folder, mark
+/// it stale.
+pub async fn invalidate_on_change(
+    Extension(db): Extension<Database>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let changes_messages = !req.method().is_safe()
+        // Linked accounts aren't cached
+        && req.extensions().get::<AccountId>().is_none()
+        && MESSAGE_PATHS
+            .iter()
+            .any(|path| req.uri().path().starts_with(path));
+    if !changes_messages {
+        return next.run(req).await;
+    }
+
+    let path = req.uri().path().to_string();
+    let headers = req.headers().clone();
+    let response = next.run(req).await;
+    if !response.status().is_success() {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let is_json = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    let body = match is_json {
+        true => match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("Failed to read the response to update the message cache: {err}");
+                return Response::from_parts(parts, axum::body::boxed(Body::empty()));
+            }
+        },
+        false => Bytes::new(),
+    };
+
+    let updated = async {
+        let client = db.get().await?;
+        let Some(email) = authenticated_email(&client, &headers).await? else {
+            return anyhow::Ok(());
+        };
+        let Some(user) = User::find(&client, &email).await? else {
+            return Ok(());
+        };
+        let Some(user_id) = user.id else {
+            return Ok(());
+        };
+
+        match changed_messages(&path, &body) {
+            Some(changes) => {
+                Message::upsert_many(&client, user_id, &changes.emails).await?;
+                Message::delete_many(&client, user_id, &changes.gone).await?;
+                if let Err(err) = refresh(&db, &user, &changes.refetch).await {
+                    warn!("Failed to refresh the messages {path} changed: {err:?}");
+                    user.set_messages_synced_at(&client, None).await?;
+                }
+            }
+            None => {
+                info!("{path} changed the messages of {email}, invalidating their cache");
+                user.set_messages_synced_at(&client, None).await?;
+            }
+        }
+        Ok(())
+    };
+    if let Err(err) = updated.await {
+        warn!("Failed to update the message cache: {err:?}");
+    }
+    Response::from_parts(parts, axum::body::boxed(Body::from(body)))
+}
+
+/// What a successful request did to the cached messages.
+#[derive(Debug, Default)]
+struct MessageChanges {
+    /// Emails as they are now, returned by the request
+    emails: Vec<Email>,
+    /// Ids which don't exist anymore, like the old ids of moved emails
+    gone: Vec<String>,
+    /// Ids of emails to get again from Graph
+    refetch: Vec<String>,
+}
+
+/// The emails touched by the request to `path`, out of its response body, or
+/// `None` when they can't be told apart, as when mail was sent.
+fn changed_messages(path: &str, body: &[u8]) -> Option<MessageChanges> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let (id, action) = match segments.as_slice() {
+        // New drafts
+        ["api", "drafts"] => (None, None),
+        ["api", "emails" | "drafts", id] => (Some(*id), None),
+        ["api", "emails" | "drafts", id, action, ..] => (Some(*id), Some(*action)),
+        _ => return None,
+    };
+    // Sending creates messages the response doesn't tell about
+    if matches!(action, Some("send" | "reply" | "reply_all" | "forward")) {
+        return None;
+    }
+
+    let mut changes = MessageChanges::default();
+    if let Ok(results) = serde_json::from_slice::<Vec<BulkResult>>(body) {
+        for result in results
+            .into_iter()
+            .filter(|result| (200..300).contains(&result.status))
+        {
+            match result.new_id {
+                Some(new_id) => {
+                    changes.gone.push(result.id);
+                    changes.refetch.push(new_id);
+                }
+                None => changes.refetch.push(result.id),
+            }
+        }
+        return Some(changes);
+    }
+
+    let email = serde_json::from_slice::<Email>(body).ok().or_else(|| {
+        serde_json::from_slice::<MovedEmail>(body)
+            .ok()
+            .map(|moved| moved.email)
+    });
+    match (email, id) {
+        // Moves give the email a new id unless ids are immutable
+        (Some(email), Some(id)) if email.id != id => {
+            changes.gone.push(id.to_string());
+            changes.emails.push(email);
+        }
+        (Some(email), _) => changes.emails.push(email),
+        // Like deletes, which don't answer with the email
+        (None, Some(id)) => changes.refetch.push(id.to_string()),
+        (None, None) => return None,
+    }
+    Some(changes)
+}
+
+/// The responses of archiving and the like, carrying the moved email.
+#[derive(Deserialize)]
+struct MovedEmail {
+    email: Email,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_messages() {
+        let bulk = br#"[
+            {"id": "a", "status": 200},
+            {"id": "b", "status": 201, "new_id": "b2"},
+            {"id": "c", "status": 404, "error": "not found"}
+        ]"#;
+        let changes = changed_messages("/api/emails/read", bulk).unwrap();
+        assert_eq!(changes.gone, vec!["b"]);
+        assert_eq!(changes.refetch, vec!["a", "b2"]);
+
+        let changes = changed_messages("/api/emails/msg-1", b"").unwrap();
+        assert_eq!(changes.refetch, vec!["msg-1"]);
+        assert!(changes.gone.is_empty() && changes.emails.is_empty());
+
+        assert!(changed_messages("/api/emails", b"").is_none());
+        assert!(changed_messages("/api/emails/msg-1/reply", b"").is_none());
+        assert!(changed_messages("/api/drafts", b"").is_none());
+        assert!(changed_messages("/api/folders/inbox", b"").is_none());
+    }
+}
@@ -1,113 +1,1520 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 use axum::{
+    body::{Bytes, StreamBody},
     debug_handler,
-    extract::{Path, Query},
-    headers::{authorization::Bearer, Authorization},
-    routing::{get, post, put},
-    Extension, Json, Router, TypedHeader,
+    extract::{multipart::Field, DefaultBodyLimit, Multipart, Path, Query},
+    headers::IfNoneMatch,
+    http::{header, Extensions, HeaderMap, StatusCode, Version},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch, post, put},
+    Extension, Json, Router, ServiceExt, TypedHeader,
 };
 use axum_error::*;
 use axum_extra::routing::SpaRouter;
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+use tokio::io::AsyncWriteExt;
+use tower::Layer;
+use tower_http::compression::{
+    predicate::{Predicate, SizeAbove},
+    CompressionLayer,
+};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{info, warn};
+use utoipa::{IntoParams, ToSchema};
 
 use crate::{
-    database::{Database, User},
-    graph::{Email, Folder, GraphClient, Profile},
-    index::search,
-    token::get_payload_field,
+    auth::refresh_provider_token,
+    backend::Provider,
+    database::{Account, AccountSettings, Database, Message, Rule, Signature, SyncState, User},
+    events,
+    graph::{
+        append_to_html_body, AttachmentMeta, Body, BulkResult, Category, ClassificationOverride,
+        DraftUpdate, Email, EmailAddress, EmailAddressWrapper, EmailFields, Event, EventResponse,
+        FileAttachment, FlagStatus, Folder, FolderCache, FolderCount, GraphClient, GraphQuery,
+        Importance, InferenceClassification, MailboxSettings, MessagePatch, MessageRule,
+        MoveOutcome, OutgoingMessage, PageOptions, Profile, LARGE_ATTACHMENT_THRESHOLD,
+    },
+    index::{self, search},
+    metrics::{DATABASE_METRICS, GRAPH_METRICS},
+    rules::{self, Action, Condition},
+    send_later,
+    snooze::snooze,
+    token::{get_expiration, get_payload, is_expiring},
+    user_data,
 };
 
+pub use self::admin::Admins;
+pub use self::cors::CorsConfig;
+pub use self::jwt::TokenValidator;
+pub use self::rate_limit::RateLimit;
+pub use self::subscriptions::{NotificationCertificate, NotificationUrl};
+
+use self::accounts::AccountId;
+use self::authed_user::{registered_user_id, AuthedUser};
 use self::error::AppError;
+use self::rate_limit::RateLimiter;
+use self::ws::{EventBus, ServerEvent};
 
+mod accounts;
+mod admin;
+mod api_keys;
+mod authed_user;
+mod cors;
+mod docs;
 mod error;
+mod etag;
+mod idempotency;
+mod jwt;
+mod message_cache;
+mod rate_limit;
+mod refresh;
+mod request_id;
+mod session;
+mod subscriptions;
+mod ws;
+
+/// Responses with these content types are compressed when the client supports it,
+/// binary content like attachments is already compressed most of the time.
+const COMPRESSIBLE_CONTENT_TYPES: &[&str] = &[
+    "application/json",
+    "application/javascript",
+    "text/",
+    "image/svg+xml",
+];
+
+/// Responses smaller than this aren't worth compressing
+const MIN_COMPRESSION_SIZE: u16 = 1024;
+
+/// Line width of bodies converted to plain text
+const TEXT_BODY_WIDTH: usize = 80;
+
+/// Graph accepts attachments of up to 150 MB through upload sessions
+const MAX_ATTACHMENT_UPLOAD: usize = 150 * 1024 * 1024;
+
+/// How long folder ids resolved from names are reused across requests
+const FOLDER_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Scopes the calendar routes need on top of the mail ones granted at sign in
+const CALENDAR_READ_SCOPES: &[&str] = &["Calendars.Read"];
+const CALENDAR_WRITE_SCOPES: &[&str] = &["Calendars.ReadWrite"];
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 struct TokenRequest {
     refresh_token: String,
 }
 
+/// What the server makes of the access token, to sign in again before it
+/// stops working.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct TokenInfoResponse {
+    email: String,
+    /// The claims of the access token, `None` when it can't be decoded
+    #[schema(value_type = Option<Object>)]
+    claims: Option<serde_json::Value>,
+    expires_at: Option<DateTime<Utc>>,
+    /// Whether it expires soon enough to be refreshed
+    expiring: bool,
+    scopes: Vec<String>,
+    /// Whether a refresh token is registered, to get new access tokens from
+    has_refresh_token: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct LinkAccountRequest {
+    /// Refresh token of the account to link, exchanged for an access token
+    refresh_token: String,
+    /// `graph` for Outlook accounts, or `gmail`
+    #[serde(default = "default_provider")]
+    provider: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct SendEmailRequest {
+    #[serde(default)]
+    to: Vec<String>,
+    #[serde(default)]
+    cc: Vec<String>,
+    #[serde(default)]
+    bcc: Vec<String>,
+    #[serde(default)]
+    subject: String,
+    #[serde(default)]
+    body: String,
+    /// Either `html` or `text`, defaults to `html`
+    #[serde(default = "default_body_type")]
+    body_type: String,
+    #[serde(default)]
+    attachments: Vec<AttachmentRequest>,
+    /// Sends the email later instead of right away, times in the past send it
+    /// right away
+    send_at: Option<DateTime<Utc>>,
+    /// Signature appended to the body, the default one when absent
+    signature_id: Option<i32>,
+    /// Sends the body as is, without a signature
+    #[serde(default)]
+    no_signature: bool,
+    /// Keeps a copy in the Sent Items folder
+    #[serde(default = "default_true")]
+    save_to_sent_items: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+struct DeltaQuery {
+    /// Starts over, returning every email in the folder
+    #[serde(default)]
+    reset: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct DeltaResponse {
+    /// Emails added to the folder or modified since the last sync
+    changed: Vec<Email>,
+    /// Ids of the emails deleted or moved out of the folder since the last sync
+    removed: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct SignatureRequest {
+    name: String,
+    html: String,
+    /// Used on plain text emails, derived from `html` when absent
+    text: Option<String>,
+    /// Appended to emails unless another signature is picked
+    #[serde(default)]
+    is_default: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct ScheduledEmailResponse {
+    /// Queue task sending the email, used to cancel it
+    task_id: i32,
+    send_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct UpdateDraftRequest {
+    to: Option<Vec<String>>,
+    cc: Option<Vec<String>>,
+    bcc: Option<Vec<String>>,
+    subject: Option<String>,
+    body: Option<String>,
+    /// Either `html` or `text`, defaults to `html`
+    #[serde(default = "default_body_type")]
+    body_type: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+struct PaginationQuery {
+    #[serde(default)]
+    page: usize,
+    #[serde(default = "default_page_size")]
+    page_size: usize,
+}
+
+#[derive(Debug, Hash, Serialize, Deserialize, IntoParams)]
+struct ListingQuery {
+    /// `false` leaves the bodies out, making large listings much faster
+    #[serde(default = "default_true")]
+    body: bool,
+    /// OData filter on the emails, like `isRead eq false`
+    filter: Option<String>,
+    /// OData ordering of the emails, like `receivedDateTime desc`
+    orderby: Option<String>,
+    /// Includes the name, size and type of the attachments of each email
+    #[serde(default)]
+    attachments: bool,
+}
+
+impl ListingQuery {
+    fn fields(&self) -> EmailFields {
+        if self.body {
+            EmailFields::WithBody
+        } else {
+            EmailFields::WithoutBody
+        }
+    }
+
+    fn graph_query(&self) -> GraphQuery {
+        let mut query = GraphQuery::new();
+        if let Some(filter) = &self.filter {
+            query = query.filter(filter);
+        }
+        if let Some(orderby) = &self.orderby {
+            query = query.order_by(orderby);
+        }
+        if self.attachments {
+            query = query.attachments();
+        }
+        query
+    }
+
+    /// Whether the message cache can serve the listing, it only has the
+    /// latest first metadata.
+    fn cacheable(&self) -> bool {
+        !self.body && self.filter.is_none() && self.orderby.is_none() && !self.attachments
+    }
+}
+
+#[derive(Debug, Hash, Serialize, Deserialize, IntoParams)]
+struct FolderPageQuery {
+    /// Emails fetched from Graph per request, up to 1000
+    page_size: Option<usize>,
+    /// Maximum number of emails returned
+    #[serde(default = "default_max_items")]
+    max_items: usize,
+}
+
+impl FolderPageQuery {
+    fn page_options(&self) -> PageOptions {
+        let options = PageOptions::new().max_items(self.max_items);
+        match self.page_size {
+            Some(page_size) => options.page_size(page_size.clamp(1, MAX_PAGE_SIZE)),
+            None => options,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+struct FoldersQuery {
+    /// Includes the child folders of each folder
+    #[serde(default)]
+    nested: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[aliases(EmailsPage = PagedResponse<Email>)]
+struct PagedResponse<T> {
+    items: Vec<T>,
+    total: Option<u64>,
+    page: usize,
+    page_size: usize,
+    /// The page to request next, absent on the last page
+    next: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct FolderCountResponse {
+    folder_id: String,
+    display_name: String,
+    total: u32,
+    unread: u32,
+}
+
+impl From<FolderCount> for FolderCountResponse {
+    fn from(count: FolderCount) -> Self {
+        Self {
+            folder_id: count.id,
+            display_name: count.display_name,
+            total: count.total_item_count,
+            unread: count.unread_item_count,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct CreateFolderRequest {
+    display_name: String,
+    parent_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct UpdateFolderRequest {
+    display_name: Option<String>,
+    /// Moves the folder under this parent folder
+    parent_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+struct EmailQuery {
+    /// `text` converts HTML bodies to plain text
+    #[serde(default)]
+    format: BodyFormat,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum BodyFormat {
+    /// The body as stored in the mailbox
+    #[default]
+    Original,
+    Text,
+}
+
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+struct MoveQuery {
+    /// Folder the email is in, looked up when absent
+    from: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+struct MoveEmailQuery {
+    /// `Message-ID` of the email, finds it again when its id went stale
+    /// because another client moved it
+    internet_message_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct MovedEmailResponse {
+    email: Email,
+    /// Folder the email was moved from, moving it back there undoes the move
+    previous_folder_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct RuleRequest {
+    name: String,
+    #[serde(default)]
+    conditions: Vec<Condition>,
+    actions: Vec<Action>,
+    #[serde(default = "default_true")]
+    enabled: bool,
+    /// Rules apply in ascending position, new rules go last by default
+    position: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct RuleOrderRequest {
+    /// Ids of the rules in the order they should apply, the ones left out go
+    /// after them
+    ids: Vec<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+struct EventsQuery {
+    /// Start of the time range, defaults to now
+    start: Option<DateTime<Utc>>,
+    /// End of the time range, defaults to 30 days after the start
+    end: Option<DateTime<Utc>>,
+    /// `false` lists every event regardless of the range, with recurring
+    /// events as a single series instead of their occurrences
+    #[serde(default = "default_true")]
+    expand: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct RespondEventRequest {
+    response: EventResponse,
+    #[serde(default)]
+    comment: String,
+    /// Whether the organizer is notified of the response
+    #[serde(default = "default_true")]
+    send_response: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct CategoriesRequest {
+    /// Display names of the categories, replacing the ones the email has
+    categories: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct ClassificationOverrideRequest {
+    /// Address of the sender whose emails are classified
+    address: String,
+    name: Option<String>,
+    classify_as: InferenceClassification,
+}
+
+/// Properties to change on an email, the ones left out are kept.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct UpdateEmailRequest {
+    is_read: Option<bool>,
+    importance: Option<Importance>,
+    flag_status: Option<FlagStatus>,
+    /// Flags the email for follow-up by this date
+    flag_due: Option<DateTime<Utc>>,
+    /// When the follow-up starts, defaults to now
+    flag_start: Option<DateTime<Utc>>,
+    /// Display names of the categories, replacing the ones the email has
+    categories: Option<Vec<String>>,
+    inference_classification: Option<InferenceClassification>,
+}
+
+impl From<UpdateEmailRequest> for MessagePatch {
+    fn from(data: UpdateEmailRequest) -> Self {
+        let mut patch = MessagePatch::new();
+        if let Some(is_read) = data.is_read {
+            patch = patch.read(is_read);
+        }
+        if let Some(importance) = data.importance {
+            patch = patch.importance(importance);
+        }
+        if let Some(flag_status) = data.flag_status {
+            patch = patch.flag(flag_status);
+        }
+        if let Some(due) = data.flag_due {
+            patch = patch.flag_due(data.flag_start.unwrap_or_else(Utc::now), due);
+        }
+        if let Some(categories) = data.categories {
+            patch = patch.categories(&categories);
+        }
+        if let Some(classification) = data.inference_classification {
+            patch = patch.inference_classification(classification);
+        }
+        patch
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct SnoozeRequest {
+    /// When the email goes back to the inbox
+    until: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct SnoozeResponse {
+    email: Email,
+    until: DateTime<Utc>,
+    /// Queue task moving the email back to the inbox
+    task_id: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct PhishingReportResponse {
+    #[serde(flatten)]
+    moved: MovedEmailResponse,
+    /// Sender whose future emails go straight to junk, absent when the email
+    /// has no sender address
+    blocked_sender: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+struct DeleteQuery {
+    #[serde(default)]
+    permanent: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct ReplyRequest {
+    /// HTML content placed above the quoted original message
+    body: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct ForwardRequest {
+    to: Vec<String>,
+    /// HTML content placed above the forwarded message
+    #[serde(default)]
+    body: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct AttachmentRequest {
+    name: String,
+    content_type: String,
+    /// Base64 encoded content of the file
+    content: String,
+}
+
+/// Graph doesn't return more than 1000 messages per request
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Number of results of searches on Graph.
+const GRAPH_SEARCH_TOP: usize = 50;
+
+fn default_page_size() -> usize {
+    50
+}
+
+fn default_max_items() -> usize {
+    500
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_provider() -> String {
+    "graph".to_string()
+}
+
+fn default_body_type() -> String {
+    "html".to_string()
+}
+
+fn recipients(addresses: &[String]) -> Vec<EmailAddressWrapper> {
+    addresses
+        .iter()
+        .map(|address| EmailAddressWrapper::new(address))
+        .collect()
+}
+
+impl From<SendEmailRequest> for OutgoingMessage {
+    fn from(request: SendEmailRequest) -> Self {
+        OutgoingMessage {
+            subject: request.subject,
+            body: Body {
+                content_type: request.body_type,
+                content: request.body,
+            },
+            to_recipients: recipients(&request.to),
+            cc_recipients: recipients(&request.cc),
+            bcc_recipients: recipients(&request.bcc),
+            attachments: request
+                .attachments
+                .into_iter()
+                .map(|a| FileAttachment::new(a.name, a.content_type, a.content))
+                .collect(),
+        }
+    }
+}
+
+impl From<UpdateDraftRequest> for DraftUpdate {
+    fn from(request: UpdateDraftRequest) -> Self {
+        DraftUpdate {
+            subject: request.subject,
+            body: request.body.map(|content| Body {
+                content_type: request.body_type,
+                content,
+            }),
+            to_recipients: request.to.as_deref().map(recipients),
+            cc_recipients: request.cc.as_deref().map(recipients),
+            bcc_recipients: request.bcc.as_deref().map(recipients),
+        }
+    }
+}
+
 pub struct Server {
     addr: SocketAddr,
     database_url: String,
+    read_database_url: Option<String>,
+    /// Whether to apply the pending migrations when starting
+    migrate: bool,
+    rate_limit: RateLimit,
+    cors: CorsConfig,
+    tls: Option<TlsConfig>,
+    admins: Admins,
+    notification_url: NotificationUrl,
+    notification_certificate: NotificationCertificate,
+    token_validator: TokenValidator,
+}
+
+/// Certificate and private key, in PEM format, used to serve HTTPS directly.
+struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
 }
 
 impl Server {
     pub fn new(addr: SocketAddr, database_url: String) -> Self {
-        Self { addr, database_url }
+        Self {
+            addr,
+            database_url,
+            read_database_url: None,
+            migrate: true,
+            rate_limit: RateLimit::new(120, Duration::from_secs(60)),
+            cors: CorsConfig::default(),
+            tls: None,
+            admins: Admins::default(),
+            notification_url: NotificationUrl::default(),
+            notification_certificate: NotificationCertificate::default(),
+            token_validator: TokenValidator::new(),
+        }
+    }
+
+    /// Sends listings to a read-only replica of the database.
+    pub fn with_read_database_url(mut self, read_database_url: String) -> Self {
+        self.read_database_url = Some(read_database_url);
+        self
+    }
+
+    pub fn with_migrations(mut self, migrate: bool) -> Self {
+        self.migrate = migrate;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    pub fn with_cors(mut self, cors: CorsConfig) -> Self {
+        self.cors = cors;
+        self
+    }
+
+    pub fn with_admins(mut self, admins: Admins) -> Self {
+        self.admins = admins;
+        self
+    }
+
+    pub fn with_token_validator(mut self, token_validator: TokenValidator) -> Self {
+        self.token_validator = token_validator;
+        self
+    }
+
+    pub fn with_notification_url(mut self, notification_url: NotificationUrl) -> Self {
+        self.notification_url = notification_url;
+        self
+    }
+
+    /// Subscribes to rich notifications, carrying the messages encrypted for
+    /// the certificate.
+    pub fn with_notification_certificate(
+        mut self,
+        notification_certificate: NotificationCertificate,
+    ) -> Self {
+        self.notification_certificate = notification_certificate;
+        self
+    }
+
+    pub fn with_tls(mut self, cert_path: PathBuf, key_path: PathBuf) -> Self {
+        self.tls = Some(TlsConfig {
+            cert_path,
+            key_path,
+        });
+        self
     }
 
     pub async fn start(&self) -> anyhow::Result<()> {
         info!("Connecting to database...");
-        let db = Database::new(self.database_url.clone()).await?;
+        let mut db = Database::new(self.database_url.clone()).await?;
+        if let Some(read_database_url) = &self.read_database_url {
+            info!("Reading listings from the replica...");
+            db = db.with_read_replica(read_database_url)?;
+        }
 
-        info!("Running migrations...");
-        db.migrate().await?;
+        if self.migrate {
+            info!("Running migrations...");
+            db.migrate().await?;
+        }
 
-        info!("Listening on {}", self.addr);
-        Ok(axum::Server::bind(&self.addr)
-            .serve(self.routes(db).into_make_service())
-            .await?)
+        info!("Initializing task queue...");
+        let queue = postgres_queue::connect(&self.database_url).await?;
+        postgres_queue::initialize_database(&queue).await?;
+
+        // Changes announced by any process are pushed to the clients of this one
+        let bus = EventBus::new();
+        let listener_bus = bus.clone();
+        tokio::spawn(events::listen(self.database_url.clone(), move |change| {
+            listener_bus.publish(
+                &change.user_email,
+                ServerEvent::EmailChanged {
+                    change_type: change.change_type,
+                    id: change.id,
+                },
+            )
+        }));
+
+        // Account scoped routes are rewritten before they reach the router
+        let app = middleware::from_fn(accounts::scope_account)
+            .layer(self.routes(db, bus))
+            // The remote address rate limits requests without credentials
+            .into_make_service_with_connect_info::<SocketAddr>();
+        match &self.tls {
+            Some(tls) => {
+                let config = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path).await?;
+                info!("Listening on https://{}", self.addr);
+                axum_server::bind_rustls(self.addr, config)
+                    .serve(app)
+                    .await?;
+            }
+            None => {
+                info!("Listening on http://{}", self.addr);
+                axum::Server::bind(&self.addr).serve(app).await?;
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn routes(&self, db: Database) -> Router {
+    pub fn routes(&self, db: Database, bus: EventBus) -> Router {
         Router::new()
-            .route("/api/me", get(get_profile))
+            .route("/metrics", get(get_metrics))
+            .route("/api/openapi.json", get(docs::get_openapi))
+            .route("/api/docs", get(docs::get_docs))
+            .route("/api/me", get(get_profile).delete(delete_me))
+            .route(
+                "/api/me/mailbox-settings",
+                get(get_mailbox_settings).patch(patch_mailbox_settings),
+            )
             .route("/api/token", post(post_token))
+            .route("/api/token/info", get(get_token_info))
+            .route(
+                "/api/session",
+                get(session::get_session)
+                    .post(session::post_session)
+                    .delete(session::delete_session),
+            )
+            .route("/api/admin/users/:email", put(admin::put_user))
+            .route("/api/admin/users/:email/reindex", post(admin::post_reindex))
+            .route("/api/admin/tasks", get(admin::get_tasks))
+            .route("/api/admin/tasks/:id", delete(admin::delete_task))
+            .route("/api/accounts", get(get_accounts).post(post_account))
+            .route(
+                "/api/accounts/:account_id",
+                get(get_account).patch(patch_account).delete(delete_account),
+            )
+            .route(
+                "/api/me/subscriptions",
+                get(subscriptions::get_subscriptions).post(subscriptions::post_subscription),
+            )
+            .route(
+                "/api/me/subscriptions/:id",
+                delete(subscriptions::delete_subscription),
+            )
+            .route(
+                "/api/me/subscriptions/:id/renew",
+                post(subscriptions::post_renew_subscription),
+            )
+            .route(
+                "/api/notifications",
+                post(subscriptions::post_notifications),
+            )
+            .route(
+                "/api/me/sessions",
+                get(session::get_sessions).delete(session::delete_sessions),
+            )
+            .route("/api/me/sessions/:id", delete(session::delete_user_session))
+            .route(
+                "/api/me/api-keys",
+                get(api_keys::get_api_keys).post(api_keys::post_api_key),
+            )
+            .route("/api/me/api-keys/:id", delete(api_keys::delete_api_key))
+            .route(
+                "/api/me/signatures",
+                get(get_signatures).post(post_signature),
+            )
+            .route(
+                "/api/me/signatures/:id",
+                get(get_signature)
+                    .put(put_signature)
+                    .delete(delete_signature),
+            )
+            .route("/api/rules", get(get_rules).post(post_rule))
+            .route("/api/rules/order", put(put_rules_order))
+            .route(
+                "/api/rules/:id",
+                get(get_rule).put(put_rule).delete(delete_rule),
+            )
+            .route(
+                "/api/outlook/rules",
+                get(get_outlook_rules).post(post_outlook_rule),
+            )
+            .route(
+                "/api/outlook/rules/:id",
+                patch(patch_outlook_rule).delete(delete_outlook_rule),
+            )
+            .route("/api/calendar/events", get(get_events))
+            .route("/api/calendar/events/:id", get(get_event))
+            .route(
+                "/api/calendar/events/:id/respond",
+                post(post_event_response),
+            )
+            .route("/api/categories", get(get_categories))
+            .route(
+                "/api/classification/overrides",
+                get(get_classification_overrides).post(post_classification_override),
+            )
+            .route(
+                "/api/classification/overrides/:id",
+                delete(delete_classification_override),
+            )
             .route("/api/search", get(get_search))
-            .route("/api/emails", get(get_emails))
+            .route("/api/ws", get(ws::get_ws))
+            .route("/api/emails", get(get_emails).post(post_email))
             .route("/api/emails/move/:folder", put(put_bulk_move))
-            .route("/api/emails/:id", get(get_email))
+            .route("/api/emails/read", put(put_bulk_read))
+            .route("/api/emails/unread", put(put_bulk_unread))
+            .route("/api/emails/archive", put(put_bulk_archive))
+            .route("/api/emails/spam", put(put_bulk_spam))
+            .route("/api/emails/delete", put(put_bulk_delete))
+            .route(
+                "/api/emails/scheduled/:task_id",
+                delete(delete_scheduled_email),
+            )
+            .route(
+                "/api/emails/:id",
+                get(get_email).patch(patch_email).delete(delete_email),
+            )
             .route("/api/emails/:id/move/:folder", put(put_move))
+            .route("/api/emails/:id/reply", post(post_reply))
+            .route("/api/emails/:id/reply_all", post(post_reply_all))
+            .route("/api/emails/:id/forward", post(post_forward))
+            .route("/api/emails/:id/mime", get(get_email_mime))
+            .route("/api/conversations/:id", get(get_conversation))
+            .route("/api/emails/:id/attachments", get(get_attachments))
+            .route(
+                "/api/emails/:id/attachments/:attachment_id",
+                get(get_attachment),
+            )
+            .route("/api/emails/:id/read", put(put_read))
+            .route("/api/emails/:id/unread", put(put_unread))
             .route("/api/emails/:id/archive", put(put_archive))
             .route("/api/emails/:id/spam", put(put_mark_spam))
-            .route("/api/folders", get(get_folders))
+            .route("/api/emails/:id/focused", put(put_focused))
+            .route("/api/emails/:id/other", put(put_other))
+            .route("/api/emails/:id/phishing", put(put_phishing))
+            .route("/api/emails/:id/snooze", put(put_snooze))
+            .route("/api/emails/:id/categories", put(put_categories))
+            .route("/api/drafts", post(post_draft))
+            .route(
+                "/api/drafts/:id",
+                get(get_draft).patch(patch_draft).delete(delete_draft),
+            )
+            .route("/api/drafts/:id/send", post(post_send_draft))
+            .route(
+                "/api/drafts/:id/attachments",
+                post(post_draft_attachments).layer(DefaultBodyLimit::max(MAX_ATTACHMENT_UPLOAD)),
+            )
+            .route("/api/folders", get(get_folders).post(post_folder))
+            .route("/api/folders/counts", get(get_folder_counts))
+            .route(
+                "/api/folders/:id",
+                patch(patch_folder).delete(delete_folder),
+            )
+            .route("/api/folders/:id/delta", get(get_folder_delta))
             .route("/api/:folder/emails", get(get_folder_emails))
             .merge(SpaRouter::new("/", "public").index_file("index.html"))
+            .layer(middleware::from_fn(message_cache::invalidate_on_change))
+            .layer(middleware::from_fn(idempotency::idempotency))
+            .layer(middleware::from_fn(refresh::refresh_expired_token))
+            .layer(middleware::from_fn(rate_limit::rate_limit))
+            .layer(middleware::from_fn(request_id::scope_request_id))
             .layer(Extension(db))
-            .layer(
-                CorsLayer::new()
-                    .allow_origin(AllowOrigin::any())
-                    .allow_methods(AllowMethods::any())
-                    .allow_headers(AllowHeaders::any()),
-            )
-            .layer(TraceLayer::new_for_http())
+            .layer(Extension(RateLimiter::new(self.rate_limit)))
+            .layer(Extension(self.admins.clone()))
+            .layer(Extension(self.token_validator.clone()))
+            .layer(Extension(self.notification_url.clone()))
+            .layer(Extension(self.notification_certificate.clone()))
+            .layer(Extension(bus))
+            .layer(Extension(FolderCache::new(FOLDER_CACHE_TTL)))
+            .layer(self.cors.layer())
+            .layer(CompressionLayer::new().compress_when(
+                SizeAbove::new(MIN_COMPRESSION_SIZE).and(is_compressible_content_type),
+            ))
+            .layer(PropagateRequestIdLayer::new(request_id::REQUEST_ID_HEADER))
+            .layer(TraceLayer::new_for_http().make_span_with(request_id::make_span))
+            // Keeps the id sent by the client, if any
+            .layer(SetRequestIdLayer::new(
+                request_id::REQUEST_ID_HEADER,
+                MakeRequestUuid,
+            ))
+    }
+}
+
+fn is_compressible_content_type(
+    _: StatusCode,
+    _: Version,
+    headers: &HeaderMap,
+    _: &Extensions,
+) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|content_type| {
+            COMPRESSIBLE_CONTENT_TYPES
+                .iter()
+                .any(|allowed| content_type.starts_with(allowed))
+        })
+        .unwrap_or(false)
+}
+
+/// Metrics of the calls made to Graph and of the database, in the Prometheus
+/// text format. Only admins can read them, scrapers use an API key of one.
+async fn get_metrics(_: admin::AdminUser) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        GRAPH_METRICS.render() + &DATABASE_METRICS.render(),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/me",
+    tag = "profile",
+    responses((status = 200, body = Profile))
+)]
+async fn get_profile(AuthedUser { graph, .. }: AuthedUser) -> Result<Json<Profile>, AppError> {
+    Ok(Json(graph.get_user_profile().await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/me",
+    tag = "profile",
+    responses(
+        (status = 204, description = "Everything stored about the user was deleted"),
+        (status = 403, description = "API keys can't delete the user")
+    )
+)]
+async fn delete_me(
+    AuthedUser {
+        email,
+        user,
+        api_key,
+        ..
+    }: AuthedUser,
+    Extension(db): Extension<Database>,
+) -> Result<impl IntoResponse, AppError> {
+    if api_key.is_some() {
+        return Err(AppError::Forbidden(
+            "API keys can't delete the user, sign in instead".to_string(),
+        ));
     }
+    registered_user_id(user.as_ref())?;
+    user_data::delete_user(&db, &email).await?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(header::SET_COOKIE, session::session_cookie("", 0))],
+    ))
 }
 
-async fn get_profile(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
-) -> Result<Json<Profile>, AppError> {
-    let client = GraphClient::new(access_code.token().to_owned());
-    Ok(Json(client.get_user_profile().await?))
+#[utoipa::path(
+    get,
+    path = "/api/me/mailbox-settings",
+    tag = "profile",
+    responses((status = 200, body = MailboxSettings))
+)]
+async fn get_mailbox_settings(
+    AuthedUser { graph, .. }: AuthedUser,
+) -> Result<Json<MailboxSettings>, AppError> {
+    Ok(Json(graph.get_mailbox_settings().await?))
 }
 
+#[utoipa::path(
+    patch,
+    path = "/api/me/mailbox-settings",
+    tag = "profile",
+    request_body = MailboxSettings,
+    responses((status = 200, body = MailboxSettings))
+)]
+async fn patch_mailbox_settings(
+    AuthedUser { graph, .. }: AuthedUser,
+    Json(settings): Json<MailboxSettings>,
+) -> Result<Json<MailboxSettings>, AppError> {
+    info!("Updating mailbox settings with {settings:?}...");
+    Ok(Json(graph.update_mailbox_settings(&settings).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/token",
+    tag = "profile",
+    request_body = TokenRequest,
+    responses((status = 200, body = User))
+)]
 #[debug_handler]
 async fn post_token(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    AuthedUser {
+        email,
+        access_token,
+        scopes,
+        ..
+    }: AuthedUser,
     Extension(db): Extension<Database>,
     Json(data): Json<TokenRequest>,
 ) -> Result<Json<User>, AppError> {
-    let access_token = access_code.token().to_owned();
-    let email = get_payload_field(&access_token, "unique_name")?;
-    let client = db.get().await?;
-
     // TODO: do we need expiration time?
-    let user =
-        User::upsert_with_tokens(&client, &email, &access_token, &data.refresh_token).await?;
+    let user = db
+        .transaction(|tx| {
+            let (email, access_token, scopes) =
+                (email.clone(), access_token.clone(), scopes.clone());
+            let refresh_token = data.refresh_token.clone();
+            Box::pin(async move {
+                User::upsert_with_tokens(tx, &email, &access_token, &refresh_token, &scopes).await
+            })
+        })
+        .await?;
 
     Ok(Json(user))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/token/info",
+    tag = "profile",
+    responses((status = 200, body = TokenInfoResponse))
+)]
+async fn get_token_info(
+    AuthedUser {
+        email,
+        access_token,
+        user,
+        scopes,
+        ..
+    }: AuthedUser,
+) -> Json<TokenInfoResponse> {
+    Json(TokenInfoResponse {
+        email,
+        claims: get_payload(&access_token).ok(),
+        expires_at: get_expiration(&access_token).ok(),
+        expiring: is_expiring(&access_token),
+        scopes,
+        has_refresh_token: user.is_some_and(|user| user.refresh_token.is_some()),
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/accounts",
+    tag = "accounts",
+    responses((status = 200, body = [Account]))
+)]
+async fn get_accounts(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+) -> Result<Json<Vec<Account>>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    Ok(Json(Account::list(&db.read().await?, user_id).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/accounts",
+    tag = "accounts",
+    request_body = LinkAccountRequest,
+    responses((status = 201, body = Account))
+)]
+async fn post_account(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Json(data): Json<LinkAccountRequest>,
+) -> Result<(StatusCode, Json<Account>), AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let oauth_provider = data.provider.parse::<Provider>()?.oauth_provider();
+
+    let token = refresh_provider_token(oauth_provider, &data.refresh_token)
+        .await
+        .map_err(|err| AppError::BadRequest(err.to_string()))?;
+    let address = oauth_provider
+        .address(&token.access_code)
+        .await
+        .map_err(|err| AppError::BadRequest(err.to_string()))?;
+    let refresh_token = token.refresh_code.as_deref().unwrap_or(&data.refresh_token);
+
+    let account = Account::upsert(
+        &db.get().await?,
+        user_id,
+        &data.provider,
+        &address,
+        &token.access_code,
+        refresh_token,
+        token.expires_at,
+    )
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(account)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/accounts/{account_id}",
+    tag = "accounts",
+    params(("account_id" = i32, Path, description = "Linked account id")),
+    responses((status = 200, body = Account))
+)]
+async fn get_account(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(account_id): Path<i32>,
+) -> Result<Json<Account>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let account = Account::find(&db.get().await?, user_id, account_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("account {account_id} not found")))?;
+    Ok(Json(account))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/accounts/{account_id}",
+    tag = "accounts",
+    params(("account_id" = i32, Path, description = "Linked account id")),
+    request_body = AccountSettings,
+    responses((status = 200, body = Account))
+)]
+async fn patch_account(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(account_id): Path<i32>,
+    Json(settings): Json<AccountSettings>,
+) -> Result<Json<Account>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let client = db.get().await?;
+    let mut account = Account::find(&client, user_id, account_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("account {account_id} not found")))?;
+    account.update_settings(&client, settings).await?;
+    Ok(Json(account))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/accounts/{account_id}",
+    tag = "accounts",
+    params(("account_id" = i32, Path, description = "Linked account id")),
+    responses((status = 204, description = "Account unlinked"))
+)]
+async fn delete_account(
+    AuthedUser { email, user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(account_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let client = db.get().await?;
+    let account = Account::find(&client, user_id, account_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("account {account_id} not found")))?;
+    // It holds the user's own tokens, which go with the user
+    if account.address == email {
+        return Err(AppError::BadRequest(
+            "the account of the signed-in mailbox can't be unlinked, use DELETE /api/me"
+                .to_string(),
+        ));
+    }
+    Account::delete(&client, user_id, account_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/me/signatures",
+    tag = "signatures",
+    responses((status = 200, body = [Signature]))
+)]
+async fn get_signatures(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+) -> Result<Json<Vec<Signature>>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    Ok(Json(Signature::list(&db.read().await?, user_id).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/me/signatures",
+    tag = "signatures",
+    request_body = SignatureRequest,
+    responses((status = 201, body = Signature))
+)]
+async fn post_signature(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Json(data): Json<SignatureRequest>,
+) -> Result<(StatusCode, Json<Signature>), AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let signature = Signature::create(
+        &db.get().await?,
+        user_id,
+        &data.name,
+        &data.html,
+        data.text.as_deref(),
+        data.is_default,
+    )
+    .await?;
+    Ok((StatusCode::CREATED, Json(signature)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/me/signatures/{id}",
+    tag = "signatures",
+    params(("id" = i32, Path, description = "Signature id")),
+    responses((status = 200, body = Signature))
+)]
+async fn get_signature(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(id): Path<i32>,
+) -> Result<Json<Signature>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let signature = Signature::find(&db.get().await?, user_id, id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("signature {id} not found")))?;
+    Ok(Json(signature))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/me/signatures/{id}",
+    tag = "signatures",
+    params(("id" = i32, Path, description = "Signature id")),
+    request_body = SignatureRequest,
+    responses((status = 200, body = Signature))
+)]
+async fn put_signature(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(id): Path<i32>,
+    Json(data): Json<SignatureRequest>,
+) -> Result<Json<Signature>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let client = db.get().await?;
+    let mut signature = Signature::find(&client, user_id, id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("signature {id} not found")))?;
+
+    signature.name = data.name;
+    signature.html = data.html;
+    signature.text = data.text;
+    signature.is_default = data.is_default;
+    signature.update(&client).await?;
+
+    Ok(Json(signature))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/me/signatures/{id}",
+    tag = "signatures",
+    params(("id" = i32, Path, description = "Signature id")),
+    responses((status = 204, description = "Signature deleted"))
+)]
+async fn delete_signature(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    if !Signature::delete(&db.get().await?, user_id, id).await? {
+        return Err(AppError::NotFound(format!("signature {id} not found")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Appends the signature to a body of `body_type`, after the usual `-- `
+/// delimiter on plain text bodies.
+fn append_signature(body: &str, body_type: &str, signature: &Signature) -> String {
+    if body_type.eq_ignore_ascii_case("text") {
+        let text = match &signature.text {
+            Some(text) => text.clone(),
+            None => html2text::from_read(signature.html.as_bytes(), TEXT_BODY_WIDTH),
+        };
+        format!("{}\n\n-- \n{}", body, text.trim_end())
+    } else {
+        append_to_html_body(body, &format!("<br><br>{}", signature.html))
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/rules",
+    tag = "rules",
+    responses((status = 200, body = [Rule]))
+)]
+async fn get_rules(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+) -> Result<Json<Vec<Rule>>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    Ok(Json(Rule::list(&db.read().await?, user_id).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/rules",
+    tag = "rules",
+    request_body = RuleRequest,
+    responses((status = 201, body = Rule))
+)]
+async fn post_rule(
+    AuthedUser { email, user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Json(data): Json<RuleRequest>,
+) -> Result<(StatusCode, Json<Rule>), AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let client = db.get().await?;
+
+    let rule = Rule::create(
+        &client,
+        user_id,
+        &data.name,
+        &data.conditions,
+        &data.actions,
+        data.enabled,
+        data.position,
+    )
+    .await?;
+    rules::schedule(&client, &email).await?;
+
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/rules/order",
+    tag = "rules",
+    request_body = RuleOrderRequest,
+    responses((status = 200, body = [Rule]))
+)]
+async fn put_rules_order(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Json(data): Json<RuleOrderRequest>,
+) -> Result<Json<Vec<Rule>>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let client = db.get().await?;
+
+    let rules = Rule::list(&client, user_id).await?;
+    if let Some(id) = data
+        .ids
+        .iter()
+        .find(|id| !rules.iter().any(|rule| rule.id == **id))
+    {
+        return Err(AppError::NotFound(format!("rule {id} not found")));
+    }
+    Ok(Json(Rule::reorder(&client, user_id, &data.ids).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/rules/{id}",
+    tag = "rules",
+    params(("id" = i32, Path, description = "Rule id")),
+    responses((status = 200, body = Rule))
+)]
+async fn get_rule(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(id): Path<i32>,
+) -> Result<Json<Rule>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let rule = Rule::find(&db.get().await?, user_id, id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("rule {id} not found")))?;
+    Ok(Json(rule))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/rules/{id}",
+    tag = "rules",
+    params(("id" = i32, Path, description = "Rule id")),
+    request_body = RuleRequest,
+    responses((status = 200, body = Rule))
+)]
+async fn put_rule(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(id): Path<i32>,
+    Json(data): Json<RuleRequest>,
+) -> Result<Json<Rule>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let client = db.get().await?;
+    let mut rule = Rule::find(&client, user_id, id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("rule {id} not found")))?;
+
+    rule.name = data.name;
+    rule.conditions = data.conditions;
+    rule.actions = data.actions;
+    rule.enabled = data.enabled;
+    rule.position = data.position.unwrap_or(rule.position);
+    rule.update(&client).await?;
+
+    Ok(Json(rule))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/rules/{id}",
+    tag = "rules",
+    params(("id" = i32, Path, description = "Rule id")),
+    responses((status = 204, description = "Rule deleted"))
+)]
+async fn delete_rule(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    if !Rule::delete(&db.get().await?, user_id, id).await? {
+        return Err(AppError::NotFound(format!("rule {id} not found")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/outlook/rules",
+    tag = "rules",
+    responses((status = 200, body = [MessageRule]))
+)]
+async fn get_outlook_rules(
+    AuthedUser { graph, .. }: AuthedUser,
+) -> Result<Json<Vec<MessageRule>>, AppError> {
+    Ok(Json(graph.get_message_rules().await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/outlook/rules",
+    tag = "rules",
+    request_body = MessageRule,
+    responses((status = 201, body = MessageRule))
+)]
+async fn post_outlook_rule(
+    AuthedUser { graph, .. }: AuthedUser,
+    Json(rule): Json<MessageRule>,
+) -> Result<(StatusCode, Json<MessageRule>), AppError> {
+    if rule.display_name.is_none() || rule.actions.is_none() {
+        return Err(AppError::BadRequest(
+            "an Outlook rule needs a displayName and actions".to_string(),
+        ));
+    }
+
+    info!("Creating Outlook rule {:?}...", rule.display_name);
+    Ok((
+        StatusCode::CREATED,
+        Json(graph.create_message_rule(&rule).await?),
+    ))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/outlook/rules/{id}",
+    tag = "rules",
+    params(("id" = String, Path, description = "Outlook rule id")),
+    request_body = MessageRule,
+    responses((status = 200, body = MessageRule))
+)]
+async fn patch_outlook_rule(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+    Json(rule): Json<MessageRule>,
+) -> Result<Json<MessageRule>, AppError> {
+    Ok(Json(graph.update_message_rule(&id, &rule).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/outlook/rules/{id}",
+    tag = "rules",
+    params(("id" = String, Path, description = "Outlook rule id")),
+    responses((status = 204, description = "Outlook rule deleted"))
+)]
+async fn delete_outlook_rule(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    graph.delete_message_rule(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/search",
+    tag = "emails",
+    params(
+        ("q" = String, Query, description = "Search term"),
+        ("source" = Option<String>, Query, description = "`graph` searches the mailbox on Graph with KQL instead of the local index, which may not be built yet")
+    ),
+    responses((status = 200, body = [Email]))
+)]
 async fn get_search(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    AuthedUser {
+        email, user, graph, ..
+    }: AuthedUser,
     Query(query): Query<serde_json::Value>,
 ) -> Result<Json<Vec<Email>>, AppError> {
-    let access_token = access_code.token().to_owned();
-    let email = get_payload_field(&access_token, "unique_name")?;
     info!("email: {}", email);
 
     // TODO: check profile email against token email for security
@@ -126,88 +1533,1304 @@ async fn get_search(
         .ok_or(AppError::BadRequest(
             "invalid search term, use q=<term> where term must be a string".to_string(),
         ))?;
-    Ok(Json(search(&email, term).await?))
+    if query.get("source").and_then(|source| source.as_str()) == Some("graph") {
+        return Ok(Json(graph.search_messages(term, GRAPH_SEARCH_TOP).await?));
+    }
+
+    let user = user.ok_or(AppError::Unauthorized(
+        "user has no tokens registered, use /api/token first".to_string(),
+    ))?;
+    Ok(Json(search(&user, term).await?))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/emails",
+    tag = "emails",
+    params(PaginationQuery, ListingQuery),
+    responses(
+        (status = 200, body = EmailsPage),
+        (status = 304, description = "The page matches the ETag in If-None-Match")
+    )
+)]
 async fn get_emails(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
-) -> Result<Json<Vec<Email>>, AppError> {
-    let client = GraphClient::new(access_code.token().to_owned());
-    Ok(Json(client.get_user_emails().await?))
+    user: AuthedUser,
+    Extension(db): Extension<Database>,
+    account: Option<Extension<AccountId>>,
+    Query(query): Query<PaginationQuery>,
+    Query(listing): Query<ListingQuery>,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+) -> Result<Response, AppError> {
+    if query.page_size == 0 || query.page_size > MAX_PAGE_SIZE {
+        return Err(AppError::BadRequest(format!(
+            "page_size must be between 1 and {MAX_PAGE_SIZE}"
+        )));
+    }
+    // The offset is bound by what Postgres takes
+    let offset = query
+        .page
+        .checked_mul(query.page_size)
+        .filter(|&offset| i64::try_from(offset).is_ok())
+        .ok_or_else(|| AppError::BadRequest("page is too large".to_string()))?;
+
+    let cached = match &user.user {
+        Some(registered) if listing.cacheable() && account.is_none() => {
+            message_cache::cached_emails_page(&db, registered, offset, query.page_size).await?
+        }
+        _ => None,
+    };
+    let page = match cached {
+        Some(page) => page,
+        None => {
+            let graph_query = listing
+                .graph_query()
+                .top(query.page_size)
+                .skip(offset)
+                .count();
+            user.graph
+                .get_user_emails_page(&graph_query, listing.fields())
+                .await?
+        }
+    };
+
+    let etag = etag::emails_etag(
+        &page.items,
+        (query.page, query.page_size, page.total, &listing),
+    );
+    let response = PagedResponse {
+        items: page.items,
+        total: page.total,
+        page: query.page,
+        page_size: query.page_size,
+        next: page.has_more.then_some(query.page + 1),
+    };
+    Ok(etag::conditional_json(
+        if_none_match.map(|TypedHeader(header)| header),
+        etag,
+        response,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/emails",
+    tag = "emails",
+    request_body = SendEmailRequest,
+    responses((
+        status = 202,
+        body = ScheduledEmailResponse,
+        description = "Email accepted for delivery, the body is only returned for scheduled emails"
+    ))
+)]
+async fn post_email(
+    AuthedUser {
+        email: user_email,
+        user,
+        graph,
+        ..
+    }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Json(mut data): Json<SendEmailRequest>,
+) -> Result<Response, AppError> {
+    if data.to.is_empty() && data.cc.is_empty() && data.bcc.is_empty() {
+        return Err(AppError::BadRequest(
+            "at least one recipient is required".to_string(),
+        ));
+    }
+
+    if !data.no_signature {
+        let signature = match (data.signature_id, user.as_ref()) {
+            (Some(id), user) => {
+                let user_id = registered_user_id(user)?;
+                Some(
+                    Signature::find(&db.get().await?, user_id, id)
+                        .await?
+                        .ok_or_else(|| AppError::NotFound(format!("signature {id} not found")))?,
+                )
+            }
+            (
+                None,
+                Some(User {
+                    id: Some(user_id), ..
+                }),
+            ) => Signature::find_default(&db.get().await?, *user_id).await?,
+            (None, _) => None,
+        };
+        if let Some(signature) = signature {
+            data.body = append_signature(&data.body, &data.body_type, &signature);
+        }
+    }
+
+    match data.send_at {
+        Some(send_at) if send_at > Utc::now() => {
+            // The task refreshes the stored tokens to send the email
+            registered_user_id(user.as_ref())?;
+            info!("Scheduling email to {:?} at {send_at}...", data.to);
+            let client = db.get().await?;
+            let save_to_sent_items = data.save_to_sent_items;
+            let task_id = send_later::schedule(
+                &client,
+                &user_email,
+                data.into(),
+                save_to_sent_items,
+                send_at,
+            )
+            .await?;
+            Ok((
+                StatusCode::ACCEPTED,
+                Json(ScheduledEmailResponse { task_id, send_at }),
+            )
+                .into_response())
+        }
+        _ => {
+            info!("Sending email to {:?}...", data.to);
+            let save_to_sent_items = data.save_to_sent_items;
+            graph.send_mail(&data.into(), save_to_sent_items).await?;
+            Ok(StatusCode::ACCEPTED.into_response())
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/emails/scheduled/{task_id}",
+    tag = "emails",
+    params(("task_id" = i32, Path, description = "Task id of the scheduled email")),
+    responses(
+        (status = 204, description = "Scheduled email canceled"),
+        (status = 404, description = "No such scheduled email, or it's already being sent")
+    )
+)]
+async fn delete_scheduled_email(
+    AuthedUser {
+        email: user_email, ..
+    }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(task_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    info!("Canceling scheduled email {task_id}...");
+    let client = db.get().await?;
+    if send_later::cancel(&client, &user_email, task_id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!(
+            "scheduled email {task_id} not found"
+        )))
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/emails/{id}/reply",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id")),
+    request_body = ReplyRequest,
+    responses((status = 202, description = "Reply accepted for delivery"))
+)]
+async fn post_reply(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(email_id): Path<String>,
+    Json(data): Json<ReplyRequest>,
+) -> Result<StatusCode, AppError> {
+    graph.send_reply(&email_id, &data.body).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/emails/{id}/reply_all",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id")),
+    request_body = ReplyRequest,
+    responses((status = 202, description = "Reply accepted for delivery"))
+)]
+async fn post_reply_all(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(email_id): Path<String>,
+    Json(data): Json<ReplyRequest>,
+) -> Result<StatusCode, AppError> {
+    graph.send_reply_all(&email_id, &data.body).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/emails/{id}/forward",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id")),
+    request_body = ForwardRequest,
+    responses((status = 202, description = "Forward accepted for delivery"))
+)]
+async fn post_forward(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(email_id): Path<String>,
+    Json(data): Json<ForwardRequest>,
+) -> Result<StatusCode, AppError> {
+    if data.to.is_empty() {
+        return Err(AppError::BadRequest(
+            "at least one recipient is required".to_string(),
+        ));
+    }
+
+    info!("Forwarding {email_id} to {:?}...", data.to);
+    graph
+        .send_forward(&email_id, &recipients(&data.to), &data.body)
+        .await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/drafts",
+    tag = "drafts",
+    request_body = SendEmailRequest,
+    responses((status = 201, body = Email))
+)]
+async fn post_draft(
+    AuthedUser { graph, .. }: AuthedUser,
+    Json(data): Json<SendEmailRequest>,
+) -> Result<(StatusCode, Json<Email>), AppError> {
+    let draft = graph.create_draft(&data.into()).await?;
+    Ok((StatusCode::CREATED, Json(draft)))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/drafts/{id}",
+    tag = "drafts",
+    params(("id" = String, Path, description = "Draft id")),
+    responses((status = 200, body = Email))
+)]
+async fn get_draft(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+) -> Result<Json<Email>, AppError> {
+    let draft = graph.get_email_by_id(&id).await?;
+    if !draft.is_draft {
+        return Err(AppError::BadRequest(format!("{id} is not a draft")));
+    }
+    Ok(Json(draft))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/drafts/{id}",
+    tag = "drafts",
+    params(("id" = String, Path, description = "Draft id")),
+    request_body = UpdateDraftRequest,
+    responses((status = 200, body = Email))
+)]
+async fn patch_draft(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+    Json(data): Json<UpdateDraftRequest>,
+) -> Result<Json<Email>, AppError> {
+    Ok(Json(graph.update_draft(&id, &data.into()).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/drafts/{id}",
+    tag = "drafts",
+    params(("id" = String, Path, description = "Draft id")),
+    responses((status = 204, description = "Draft deleted"))
+)]
+async fn delete_draft(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    graph.delete_draft(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/drafts/{id}/send",
+    tag = "drafts",
+    params(("id" = String, Path, description = "Draft id")),
+    responses((status = 202, description = "Draft accepted for delivery"))
+)]
+async fn post_send_draft(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    info!("Sending draft {id}...");
+    graph.send_draft(&id).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/drafts/{id}/attachments",
+    tag = "drafts",
+    params(("id" = String, Path, description = "Draft id")),
+    request_body(content = Vec<u8>, content_type = "multipart/form-data", description = "Files to attach"),
+    responses((status = 201, body = [AttachmentMeta]))
+)]
+async fn post_draft_attachments(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<Vec<AttachmentMeta>>), AppError> {
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::BadRequest(e.to_string()))?
+    {
+        let Some(name) = field.file_name().map(ToString::to_string) else {
+            continue;
+        };
+        let content_type = field
+            .content_type()
+            .unwrap_or("application/octet-stream")
+            .to_string();
+
+        match read_upload(field).await? {
+            Upload::Memory(content) => {
+                info!(
+                    "Attaching {name} ({} bytes) to draft {id}...",
+                    content.len()
+                );
+                graph
+                    .add_attachment(&id, &name, &content_type, &content)
+                    .await?;
+            }
+            Upload::Spooled { file, size } => {
+                info!("Attaching {name} ({size} bytes) to draft {id} in chunks...");
+                graph
+                    .upload_large_attachment(&id, &name, &content_type, file, size)
+                    .await?;
+            }
+        }
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(graph.list_attachments(&id).await?),
+    ))
+}
+
+/// A file of a multipart upload.
+enum Upload {
+    Memory(Vec<u8>),
+    /// Too large to be attached inline, kept on disk until it's uploaded
+    Spooled {
+        file: tokio::fs::File,
+        size: usize,
+    },
+}
+
+/// Reads a file field, spooling it to a temporary file once it outgrows what
+/// can be attached inline. Upload sessions need the size of the file before
+/// its first chunk, which multipart doesn't tell.
+async fn read_upload(mut field: Field<'_>) -> Result<Upload, AppError> {
+    let mut content = Vec::new();
+    while let Some(chunk) = next_chunk(&mut field).await? {
+        content.extend_from_slice(&chunk);
+        if content.len() > LARGE_ATTACHMENT_THRESHOLD {
+            break;
+        }
+    }
+    if content.len() <= LARGE_ATTACHMENT_THRESHOLD {
+        return Ok(Upload::Memory(content));
+    }
+
+    // Removed by the OS once the file is closed
+    let file = tempfile::tempfile().map_err(anyhow::Error::from)?;
+    let mut file = tokio::fs::File::from_std(file);
+    let mut size = content.len();
+    file.write_all(&content)
+        .await
+        .map_err(anyhow::Error::from)?;
+    drop(content);
+    while let Some(chunk) = next_chunk(&mut field).await? {
+        size += chunk.len();
+        file.write_all(&chunk).await.map_err(anyhow::Error::from)?;
+    }
+    file.flush().await.map_err(anyhow::Error::from)?;
+    Ok(Upload::Spooled { file, size })
+}
+
+async fn next_chunk(field: &mut Field<'_>) -> Result<Option<Bytes>, AppError> {
+    field
+        .chunk()
+        .await
+        .map_err(|e| AppError::BadRequest(e.to_string()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/calendar/events",
+    tag = "calendar",
+    params(EventsQuery),
+    responses((status = 200, body = [Event]), (status = 403, description = "Reconsent required, with the missing scopes and the authorize URL"))
+)]
+async fn get_events(
+    user: AuthedUser,
+    Query(query): Query<EventsQuery>,
+) -> Result<Json<Vec<Event>>, AppError> {
+    user.require_scopes(CALENDAR_READ_SCOPES)?;
+    let graph = user.graph;
+    if !query.expand {
+        let query = GraphQuery::new().order_by("start/dateTime");
+        return Ok(Json(graph.list_events(&query).await?));
+    }
+
+    let start = query.start.unwrap_or_else(Utc::now);
+    let end = query
+        .end
+        .unwrap_or_else(|| start + chrono::Duration::days(30));
+    if end <= start {
+        return Err(AppError::BadRequest("end must be after start".to_string()));
+    }
+
+    Ok(Json(graph.get_calendar_view(start, end).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/calendar/events/{id}",
+    tag = "calendar",
+    params(("id" = String, Path, description = "Event id")),
+    responses((status = 200, body = Event), (status = 403, description = "Reconsent required, with the missing scopes and the authorize URL"))
+)]
+async fn get_event(
+    user: AuthedUser,
+    Path(event_id): Path<String>,
+) -> Result<Json<Event>, AppError> {
+    user.require_scopes(CALENDAR_READ_SCOPES)?;
+    Ok(Json(user.graph.get_event(&event_id).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/calendar/events/{id}/respond",
+    tag = "calendar",
+    params(("id" = String, Path, description = "Event id")),
+    request_body = RespondEventRequest,
+    responses((status = 202, description = "Response sent"), (status = 403, description = "Reconsent required, with the missing scopes and the authorize URL"))
+)]
+async fn post_event_response(
+    user: AuthedUser,
+    Path(event_id): Path<String>,
+    Json(data): Json<RespondEventRequest>,
+) -> Result<StatusCode, AppError> {
+    user.require_scopes(CALENDAR_WRITE_SCOPES)?;
+    info!("Responding {:?} to event {event_id}...", data.response);
+    user.graph
+        .respond_to_event(&event_id, data.response, &data.comment, data.send_response)
+        .await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/categories",
+    tag = "categories",
+    responses((status = 200, body = [Category]))
+)]
+async fn get_categories(
+    AuthedUser { graph, .. }: AuthedUser,
+) -> Result<Json<Vec<Category>>, AppError> {
+    Ok(Json(graph.get_categories().await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/classification/overrides",
+    tag = "emails",
+    responses((status = 200, body = [ClassificationOverride]))
+)]
+async fn get_classification_overrides(
+    AuthedUser { graph, .. }: AuthedUser,
+) -> Result<Json<Vec<ClassificationOverride>>, AppError> {
+    Ok(Json(graph.get_classification_overrides().await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/classification/overrides",
+    tag = "emails",
+    request_body = ClassificationOverrideRequest,
+    responses((status = 201, body = ClassificationOverride))
+)]
+async fn post_classification_override(
+    AuthedUser { graph, .. }: AuthedUser,
+    Json(data): Json<ClassificationOverrideRequest>,
+) -> Result<(StatusCode, Json<ClassificationOverride>), AppError> {
+    if data.classify_as == InferenceClassification::Unknown {
+        return Err(AppError::BadRequest(
+            "classify_as must be focused or other".to_string(),
+        ));
+    }
+
+    info!(
+        "Classifying emails from {} as {:?}...",
+        data.address, data.classify_as
+    );
+    let sender = EmailAddress {
+        name: data.name.unwrap_or_else(|| data.address.clone()),
+        address: Some(data.address),
+    };
+    Ok((
+        StatusCode::CREATED,
+        Json(
+            graph
+                .create_classification_override(data.classify_as, &sender)
+                .await?,
+        ),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/classification/overrides/{id}",
+    tag = "emails",
+    params(("id" = String, Path, description = "Override id")),
+    responses((status = 204, description = "Override deleted"))
+)]
+async fn delete_classification_override(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    graph.delete_classification_override(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/folders",
+    tag = "folders",
+    params(FoldersQuery),
+    responses((status = 200, body = [Folder]))
+)]
 async fn get_folders(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    AuthedUser { graph, .. }: AuthedUser,
+    Query(query): Query<FoldersQuery>,
 ) -> Result<Json<Vec<Folder>>, AppError> {
-    let client = GraphClient::new(access_code.token().to_owned());
-    Ok(Json(client.get_user_folders().await?))
+    let graph_query = if query.nested {
+        GraphQuery::new().expand("childFolders")
+    } else {
+        GraphQuery::new()
+    };
+    Ok(Json(graph.get_user_folders(&graph_query).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/folders/counts",
+    tag = "folders",
+    responses((status = 200, body = [FolderCountResponse]))
+)]
+async fn get_folder_counts(
+    AuthedUser { graph, .. }: AuthedUser,
+) -> Result<Json<Vec<FolderCountResponse>>, AppError> {
+    let counts = graph.get_user_folder_counts().await?;
+    Ok(Json(counts.into_iter().map(Into::into).collect()))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/folders",
+    tag = "folders",
+    request_body = CreateFolderRequest,
+    responses((status = 201, body = Folder))
+)]
+async fn post_folder(
+    AuthedUser { mut graph, .. }: AuthedUser,
+    Json(data): Json<CreateFolderRequest>,
+) -> Result<(StatusCode, Json<Folder>), AppError> {
+    let folder = graph
+        .create_folder(data.parent_id.as_deref(), &data.display_name)
+        .await?;
+    Ok((StatusCode::CREATED, Json(folder)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/folders/{id}",
+    tag = "folders",
+    params(("id" = String, Path, description = "Folder id")),
+    request_body = UpdateFolderRequest,
+    responses((status = 200, body = Folder))
+)]
+async fn patch_folder(
+    AuthedUser { mut graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+    Json(data): Json<UpdateFolderRequest>,
+) -> Result<Json<Folder>, AppError> {
+    let mut folder = None;
+    if let Some(display_name) = data.display_name {
+        folder = Some(graph.rename_folder(&id, &display_name).await?);
+    }
+    if let Some(parent_id) = data.parent_id {
+        folder = Some(graph.move_folder(&id, &parent_id).await?);
+    }
+
+    folder.map(Json).ok_or(AppError::BadRequest(
+        "nothing to update, provide display_name and/or parent_id".to_string(),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/folders/{id}",
+    tag = "folders",
+    params(("id" = String, Path, description = "Folder id")),
+    responses((status = 204, description = "Folder deleted"))
+)]
+async fn delete_folder(
+    AuthedUser { mut graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    graph.delete_folder(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/folders/{id}/delta",
+    tag = "folders",
+    params(("id" = String, Path, description = "Folder well-known name, display name, path or id"), DeltaQuery),
+    responses((status = 200, body = DeltaResponse))
+)]
+async fn get_folder_delta(
+    AuthedUser {
+        user, mut graph, ..
+    }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(folder): Path<String>,
+    Query(query): Query<DeltaQuery>,
+) -> Result<Json<DeltaResponse>, AppError> {
+    // The sync position is kept per user and folder, so it's meant for a
+    // single syncing client
+    let user_id = registered_user_id(user.as_ref())?;
+    let folder_id = graph.get_folder_id_by_name(&folder).await?;
+    let client = db.get().await?;
+
+    let delta_token = match query.reset {
+        true => None,
+        false => SyncState::find(&client, user_id, SyncState::API, &folder_id)
+            .await?
+            .and_then(|state| state.delta_token),
+    };
+    let delta = graph
+        .get_messages_delta(&folder_id, delta_token.as_deref())
+        .await?;
+    let item_count = (delta.changed.len() + delta.removed.len()) as i32;
+    SyncState::record(
+        &client,
+        user_id,
+        SyncState::API,
+        &folder_id,
+        Some(&delta.delta_token),
+        item_count,
+    )
+    .await?;
+    Message::upsert_many(&client, user_id, &delta.changed).await?;
+    Message::delete_many(&client, user_id, &delta.removed).await?;
+
+    Ok(Json(DeltaResponse {
+        changed: delta.changed,
+        removed: delta.removed,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/{folder}/emails",
+    tag = "emails",
+    params(
+        ("folder" = String, Path, description = "Folder well-known name, display name, path or id"),
+        ListingQuery,
+        FolderPageQuery
+    ),
+    responses(
+        (status = 200, body = [Email]),
+        (status = 304, description = "The listing matches the ETag in If-None-Match")
+    )
+)]
 async fn get_folder_emails(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    AuthedUser { mut graph, .. }: AuthedUser,
     Path(folder): Path<String>,
-) -> Result<Json<Vec<Email>>, AppError> {
-    let mut client = GraphClient::new(access_code.token().to_owned());
-    Ok(Json(
-        client.get_user_emails_from_folder_by_name(&folder).await?,
+    Query(listing): Query<ListingQuery>,
+    Query(paging): Query<FolderPageQuery>,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+) -> Result<Response, AppError> {
+    let emails = graph
+        .get_user_emails_from_folder_by_name(
+            &folder,
+            &listing.graph_query(),
+            listing.fields(),
+            paging.page_options(),
+        )
+        .await?;
+    let etag = etag::emails_etag(&emails, (&folder, &listing, &paging));
+    Ok(etag::conditional_json(
+        if_none_match.map(|TypedHeader(header)| header),
+        etag,
+        emails,
     ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/emails/{id}",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id"), EmailQuery),
+    responses((status = 200, body = Email))
+)]
 async fn get_email(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    AuthedUser { graph, .. }: AuthedUser,
     Path(id): Path<String>,
+    Query(query): Query<EmailQuery>,
+) -> Result<Json<Email>, AppError> {
+    let mut email = graph.get_email_by_id(&id).await?;
+    if query.format == BodyFormat::Text {
+        email.body = email.body.into_text(TEXT_BODY_WIDTH);
+    }
+    Ok(Json(email))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/emails/{id}",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id")),
+    request_body = UpdateEmailRequest,
+    responses((status = 200, body = Email))
+)]
+async fn patch_email(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(email_id): Path<String>,
+    Json(data): Json<UpdateEmailRequest>,
 ) -> Result<Json<Email>, AppError> {
-    let client = GraphClient::new(access_code.token().to_owned());
-    Ok(Json(client.get_email_by_id(&id).await?))
+    info!("Updating {email_id} with {data:?}...");
+    Ok(Json(graph.update_message(&email_id, &data.into()).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/emails/{id}/attachments",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id")),
+    responses((status = 200, body = [AttachmentMeta]))
+)]
+async fn get_attachments(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<AttachmentMeta>>, AppError> {
+    Ok(Json(graph.list_attachments(&id).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/emails/{id}/attachments/{attachment_id}",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id"), ("attachment_id" = String, Path, description = "Attachment id")),
+    responses((status = 200, description = "Attachment content", content_type = "application/octet-stream"))
+)]
+async fn get_attachment(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path((id, attachment_id)): Path<(String, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    let attachment = graph.get_attachment(&id, &attachment_id).await?;
+    let stream = graph.get_attachment_content(&id, &attachment_id).await?;
+
+    let content_type = attachment
+        .content_type
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (
+                header::CONTENT_DISPOSITION,
+                attachment_disposition(&attachment.name),
+            ),
+        ],
+        StreamBody::new(stream),
+    ))
 }
 
+/// `Content-Disposition` of a downloaded attachment. `filename*` carries the
+/// name as is, percent encoded as RFC 5987 wants, and `filename` an ASCII
+/// fallback for the clients which don't read it.
+fn attachment_disposition(name: &str) -> String {
+    // Control characters aren't allowed in headers
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() => c,
+            _ => '_',
+        })
+        .collect();
+    let encoded: String = name
+        .bytes()
+        .map(|byte| {
+            // The attr-chars of RFC 5987 are the only ones left as they are
+            if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+                (byte as char).to_string()
+            } else {
+                format!("%{byte:02X}")
+            }
+        })
+        .collect();
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/emails/{id}/mime",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id")),
+    responses((status = 200, description = "The email in RFC 822 format, to save as an .eml file", content_type = "message/rfc822"))
+)]
+async fn get_email_mime(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let mime = graph.get_message_mime(&id).await?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "message/rfc822"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"message.eml\"",
+            ),
+        ],
+        mime,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/conversations/{id}",
+    tag = "emails",
+    params(("id" = String, Path, description = "Conversation id"), EmailQuery),
+    responses((status = 200, description = "The emails of the conversation in thread order", body = [Email]))
+)]
+async fn get_conversation(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+    Query(query): Query<EmailQuery>,
+) -> Result<Json<Vec<Email>>, AppError> {
+    let mut emails = graph
+        .get_conversation_messages(&id, EmailFields::WithBody)
+        .await?;
+    if query.format == BodyFormat::Text {
+        for email in &mut emails {
+            email.body = std::mem::take(&mut email.body).into_text(TEXT_BODY_WIDTH);
+        }
+    }
+    Ok(Json(emails))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/emails/{id}",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id"), DeleteQuery),
+    responses((status = 204, description = "Email deleted"))
+)]
+async fn delete_email(
+    AuthedUser { user, graph, .. }: AuthedUser,
+    Path(id): Path<String>,
+    Query(query): Query<DeleteQuery>,
+) -> Result<StatusCode, AppError> {
+    info!("Deleting {id} (permanent: {})...", query.permanent);
+    if query.permanent {
+        graph.permanently_delete_message(&id).await?;
+    } else {
+        graph.delete_message(&id).await?;
+    }
+    unindex(user.and_then(|user| user.id), &[id]).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Drops emails from the search index once they're gone from the mailbox or
+/// in Deleted Items. The mailbox already changed, so failures are only logged.
+async fn unindex(user_id: Option<i32>, email_ids: &[String]) {
+    let Some(user_id) = user_id else {
+        return;
+    };
+    if let Err(err) = index::delete_documents(user_id, email_ids).await {
+        warn!("Failed to delete {email_ids:?} from the search index: {err:?}");
+    }
+}
+
+/// The ids of the emails the bulk operation succeeded on.
+fn succeeded_ids(results: &[BulkResult]) -> Vec<String> {
+    results
+        .iter()
+        .filter(|result| (200..300).contains(&result.status))
+        .map(|result| result.id.clone())
+        .collect()
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/move/{folder}",
+    tag = "emails",
+    params(("folder" = String, Path, description = "Folder well-known name, display name, path or id")),
+    request_body = Vec<String>,
+    responses((status = 200, body = [BulkResult]))
+)]
 async fn put_bulk_move(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    AuthedUser {
+        user, mut graph, ..
+    }: AuthedUser,
     Path(folder): Path<String>,
     Json(email_ids): Json<Vec<String>>,
-) -> Result<Json<Vec<Email>>, AppError> {
+) -> Result<Json<Vec<BulkResult>>, AppError> {
     info!("Moving {email_ids:?} to {folder}...");
-    let mut client = GraphClient::new(access_code.token().to_owned());
-    Ok(Json(
-        client
-            .move_emails_to_folder_by_name(email_ids, &folder)
-            .await?,
-    ))
+    let results = graph.bulk_move_by_name(&email_ids, &folder).await?;
+    if graph.is_deleted_items(&folder).await? {
+        unindex(user.and_then(|user| user.id), &succeeded_ids(&results)).await;
+    }
+    Ok(Json(results))
 }
 
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/move/{folder}",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id"), ("folder" = String, Path, description = "Folder well-known name, display name, path or id"), MoveEmailQuery),
+    responses((status = 200, body = Email))
+)]
 async fn put_move(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    AuthedUser {
+        user, mut graph, ..
+    }: AuthedUser,
     Path((email_id, folder_name)): Path<(String, String)>,
+    Query(query): Query<MoveEmailQuery>,
 ) -> Result<Json<Email>, AppError> {
     info!("Moving {email_id} to {folder_name}...");
-    let mut client = GraphClient::new(access_code.token().to_owned());
+    let trashed = graph.is_deleted_items(&folder_name).await?;
+    let outcome = graph
+        .move_message_by_name(
+            &email_id,
+            query.internet_message_id.as_deref(),
+            &folder_name,
+        )
+        .await?;
+    if let MoveOutcome::AlreadyMoved(email) = &outcome {
+        info!("{email_id} was already in {folder_name} as {}", email.id);
+    }
+    if trashed {
+        unindex(user.and_then(|user| user.id), &[email_id]).await;
+    }
+    Ok(Json(outcome.into_email()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/read",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id")),
+    responses((status = 200, body = Email))
+)]
+async fn put_read(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(email_id): Path<String>,
+) -> Result<Json<Email>, AppError> {
+    Ok(Json(graph.set_read(&email_id, true).await?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/unread",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id")),
+    responses((status = 200, body = Email))
+)]
+async fn put_unread(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(email_id): Path<String>,
+) -> Result<Json<Email>, AppError> {
+    Ok(Json(graph.set_read(&email_id, false).await?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/read",
+    tag = "emails",
+    request_body = Vec<String>,
+    responses((status = 200, body = [BulkResult]))
+)]
+async fn put_bulk_read(
+    AuthedUser { graph, .. }: AuthedUser,
+    Json(email_ids): Json<Vec<String>>,
+) -> Result<Json<Vec<BulkResult>>, AppError> {
+    Ok(Json(graph.bulk_set_read(&email_ids, true).await?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/unread",
+    tag = "emails",
+    request_body = Vec<String>,
+    responses((status = 200, body = [BulkResult]))
+)]
+async fn put_bulk_unread(
+    AuthedUser { graph, .. }: AuthedUser,
+    Json(email_ids): Json<Vec<String>>,
+) -> Result<Json<Vec<BulkResult>>, AppError> {
+    Ok(Json(graph.bulk_set_read(&email_ids, false).await?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/archive",
+    tag = "emails",
+    request_body = Vec<String>,
+    responses((status = 200, body = [BulkResult]))
+)]
+async fn put_bulk_archive(
+    AuthedUser { mut graph, .. }: AuthedUser,
+    Json(email_ids): Json<Vec<String>>,
+) -> Result<Json<Vec<BulkResult>>, AppError> {
+    Ok(Json(graph.bulk_move_by_name(&email_ids, "archive").await?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/spam",
+    tag = "emails",
+    request_body = Vec<String>,
+    responses((status = 200, body = [BulkResult]))
+)]
+async fn put_bulk_spam(
+    AuthedUser { mut graph, .. }: AuthedUser,
+    Json(email_ids): Json<Vec<String>>,
+) -> Result<Json<Vec<BulkResult>>, AppError> {
     Ok(Json(
-        client
-            .move_email_to_folder_by_name(&email_id, &folder_name)
-            .await?,
+        graph.bulk_move_by_name(&email_ids, "junkemail").await?,
     ))
 }
 
+#[utoipa::path(
+    put,
+    path = "/api/emails/delete",
+    tag = "emails",
+    request_body = Vec<String>,
+    responses((status = 200, body = [BulkResult]))
+)]
+async fn put_bulk_delete(
+    AuthedUser { user, graph, .. }: AuthedUser,
+    Json(email_ids): Json<Vec<String>>,
+) -> Result<Json<Vec<BulkResult>>, AppError> {
+    let results = graph.bulk_delete(&email_ids).await?;
+    unindex(user.and_then(|user| user.id), &succeeded_ids(&results)).await;
+    Ok(Json(results))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/archive",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id"), MoveQuery),
+    responses((status = 200, body = MovedEmailResponse))
+)]
 async fn put_archive(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    AuthedUser { mut graph, .. }: AuthedUser,
+    Path(email_id): Path<String>,
+    Query(query): Query<MoveQuery>,
+) -> Result<Json<MovedEmailResponse>, AppError> {
+    Ok(Json(
+        move_from_current_folder(&mut graph, &email_id, query.from.as_deref(), "archive").await?,
+    ))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/spam",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id"), MoveQuery),
+    responses((status = 200, body = MovedEmailResponse))
+)]
+async fn put_mark_spam(
+    AuthedUser { mut graph, .. }: AuthedUser,
+    Path(email_id): Path<String>,
+    Query(query): Query<MoveQuery>,
+) -> Result<Json<MovedEmailResponse>, AppError> {
+    Ok(Json(
+        move_from_current_folder(&mut graph, &email_id, query.from.as_deref(), "junkemail").await?,
+    ))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/focused",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id")),
+    responses((status = 200, body = Email))
+)]
+async fn put_focused(
+    AuthedUser { graph, .. }: AuthedUser,
     Path(email_id): Path<String>,
 ) -> Result<Json<Email>, AppError> {
-    let mut client = GraphClient::new(access_code.token().to_owned());
     Ok(Json(
-        client
-            .move_email_to_folder_by_name(&email_id, "Archive")
+        graph
+            .set_inference_classification(&email_id, InferenceClassification::Focused)
             .await?,
     ))
 }
 
-async fn put_mark_spam(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/other",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id")),
+    responses((status = 200, body = Email))
+)]
+async fn put_other(
+    AuthedUser { graph, .. }: AuthedUser,
     Path(email_id): Path<String>,
 ) -> Result<Json<Email>, AppError> {
-    let mut client = GraphClient::new(access_code.token().to_owned());
     Ok(Json(
-        client
-            .move_email_to_folder_by_name(&email_id, "Junk Email")
+        graph
+            .set_inference_classification(&email_id, InferenceClassification::Other)
             .await?,
     ))
 }
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/phishing",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id"), MoveQuery),
+    responses((status = 200, body = PhishingReportResponse))
+)]
+async fn put_phishing(
+    AuthedUser { mut graph, .. }: AuthedUser,
+    Path(email_id): Path<String>,
+    Query(query): Query<MoveQuery>,
+) -> Result<Json<PhishingReportResponse>, AppError> {
+    info!("Reporting {email_id} as phishing...");
+    let moved =
+        move_from_current_folder(&mut graph, &email_id, query.from.as_deref(), "junkemail").await?;
+
+    // Unlike spam, the sender is blocked so their next attempts skip the inbox
+    let blocked_sender = moved
+        .email
+        .from
+        .as_ref()
+        .or(moved.email.sender.as_ref())
+        .and_then(|sender| sender.email_address.address.clone());
+    if let Some(address) = &blocked_sender {
+        let junk_folder_id = graph.get_folder_id_by_name("junkemail").await?;
+        graph.block_sender(address, &junk_folder_id).await?;
+    }
+
+    Ok(Json(PhishingReportResponse {
+        moved,
+        blocked_sender,
+    }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/snooze",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id")),
+    request_body = SnoozeRequest,
+    responses((status = 200, body = SnoozeResponse))
+)]
+async fn put_snooze(
+    AuthedUser {
+        email: user_email,
+        user,
+        mut graph,
+        ..
+    }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(email_id): Path<String>,
+    Json(data): Json<SnoozeRequest>,
+) -> Result<Json<SnoozeResponse>, AppError> {
+    // The task refreshes the stored tokens to move the email back
+    registered_user_id(user.as_ref())?;
+    if data.until <= Utc::now() {
+        return Err(AppError::BadRequest(
+            "until must be in the future".to_string(),
+        ));
+    }
+
+    info!("Snoozing {email_id} until {}...", data.until);
+    let client = db.get().await?;
+    let (email, task_id) = snooze(&mut graph, &client, &user_email, &email_id, data.until).await?;
+
+    Ok(Json(SnoozeResponse {
+        email,
+        until: data.until,
+        task_id,
+    }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/categories",
+    tag = "emails",
+    params(("id" = String, Path, description = "Email id")),
+    request_body = CategoriesRequest,
+    responses((status = 200, body = Email))
+)]
+async fn put_categories(
+    AuthedUser { graph, .. }: AuthedUser,
+    Path(email_id): Path<String>,
+    Json(data): Json<CategoriesRequest>,
+) -> Result<Json<Email>, AppError> {
+    info!(
+        "Setting categories of {email_id} to {:?}...",
+        data.categories
+    );
+    Ok(Json(
+        graph.set_categories(&email_id, &data.categories).await?,
+    ))
+}
+
+/// Moves an email to `folder_name`, keeping track of the folder it was in so
+/// the move can be undone. `from` spares looking the email up when the caller
+/// already knows where it is.
+async fn move_from_current_folder(
+    graph: &mut GraphClient,
+    email_id: &str,
+    from: Option<&str>,
+    folder_name: &str,
+) -> Result<MovedEmailResponse, AppError> {
+    let previous_folder_id = match from {
+        Some(from) => graph.get_folder_id_by_name(from).await?,
+        None => graph.get_email_folder_id(email_id).await?,
+    };
+    let email = graph
+        .move_email_to_folder_by_name(email_id, folder_name)
+        .await?;
+
+    Ok(MovedEmailResponse {
+        email,
+        previous_folder_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attachment_disposition() {
+        assert_eq!(
+            attachment_disposition("report.pdf"),
+            "attachment; filename=\"report.pdf\"; filename*=UTF-8''report.pdf"
+        );
+        assert_eq!(
+            attachment_disposition("a \"b\"\r\n.txt"),
+            "attachment; filename=\"a _b_.txt\"; filename*=UTF-8''a%20%22b%22.txt"
+        );
+        assert_eq!(
+            attachment_disposition("résumé.pdf"),
+            "attachment; filename=\"r_sum_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf"
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+
+use tracing::warn;
+
+use crate::database::Database;
+
+use super::{authed_user::verified_email, error::CustomError, jwt::TokenValidator};
+
+/// Number of buckets kept before idle ones are evicted.
+const MAX_TRACKED_USERS: usize = 10_000;
+
+/// Allows up to `burst` requests per user, refilled evenly over `window`.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub window: Duration,
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, window: Duration) -> Self {
+        Self { burst, window }
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.burst as f64 / self.window.as_secs_f64()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Token buckets keyed by the verified user, or the remote address of the
+/// requests which aren't authenticated.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Takes a token from the bucket of `key`, returning how long to wait for the
+    /// next one when the bucket is empty.
+    fn check(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let burst = self.limit.burst as f64;
+        let refill = self.limit.refill_per_sec();
+
+        if buckets.len() >= MAX_TRACKED_USERS && !buckets.contains_key(key) {
+            buckets.retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+                bucket.tokens + elapsed * refill < burst
+            });
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill).min(burst);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / refill))
+        }
+    }
+}
+
+/// Middleware rejecting requests with `429 Too Many Requests` once a user goes
+/// over their rate limit. Only verified credentials pick the user, so forged
+/// tokens can neither dodge the limit nor spend someone else's. Other requests
+/// are limited by remote address.
+pub async fn rate_limit<B>(
+    Extension(limiter): Extension<RateLimiter>,
+    Extension(db): Extension<Database>,
+    Extension(validator): Extension<TokenValidator>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // The token is refreshed further down when it expired
+    let email = verified_email(&db, &validator, req.headers(), true)
+        .await
+        .unwrap_or_else(|err| {
+            warn!("Failed to verify the credentials to rate limit: {err}");
+            None
+        });
+    let remote_addr = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let key = match (email, remote_addr) {
+        (Some(email), _) => format!("user:{email}"),
+        (None, Some(ip)) => format!("ip:{ip}"),
+        (None, None) => return next.run(req).await,
+    };
+
+    if let Err(wait) = limiter.check(&key, Instant::now()) {
+        let retry_after = wait.as_secs_f64().ceil() as u64;
+        let mut response = CustomError::new(
+            "Too many requests".to_string(),
+            StatusCode::TOO_MANY_REQUESTS,
+        )
+        .into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, retry_after.into());
+        return response;
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = RateLimiter::new(RateLimit::new(2, Duration::from_secs(10)));
+        let now = Instant::now();
+
+        assert!(limiter.check("user", now).is_ok());
+        assert!(limiter.check("user", now).is_ok());
+        let wait = limiter.check("user", now).unwrap_err();
+        assert_eq!(wait.as_secs(), 5);
+
+        // Other users have their own bucket
+        assert!(limiter.check("other", now).is_ok());
+
+        assert!(limiter.check("user", now + Duration::from_secs(5)).is_ok());
+    }
+}
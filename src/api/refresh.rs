@@ -0,0 +1,117 @@
+use axum::{
+    headers::{authorization::Bearer, Authorization, HeaderMapExt},
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use tracing::{info, warn};
+
+use crate::{
+    auth::{refresh_access_token, refresh_provider_token},
+    backend::Provider,
+    database::{Account, Database, User},
+    token::{expires_soon, is_expiring},
+};
+
+use super::jwt::TokenValidator;
+
+/// Response header carrying the new access token after a transparent refresh,
+/// clients should use it for their following requests.
+pub const ACCESS_TOKEN_HEADER: &str = "x-access-token";
+
+/// Middleware that replaces an expired bearer token with a fresh one, obtained
+/// with the refresh token stored for the user. When the token can't be refreshed
+/// the request goes through untouched and Graph rejects it as usual.
+pub async fn refresh_expired_token<B>(
+    Extension(db): Extension<Database>,
+    Extension(validator): Extension<TokenValidator>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(auth) = req.headers().typed_get::<Authorization<Bearer>>() else {
+        return next.run(req).await;
+    };
+
+    let new_token = match refreshed_token(&db, &validator, auth.token()).await {
+        Ok(Some(token)) => token,
+        Ok(None) => return next.run(req).await,
+        Err(err) => {
+            warn!("Failed to refresh access token: {err:?}");
+            return next.run(req).await;
+        }
+    };
+
+    match Authorization::bearer(&new_token) {
+        Ok(auth) => req.headers_mut().typed_insert(auth),
+        Err(_) => return next.run(req).await,
+    }
+
+    let mut response = next.run(req).await;
+    if let Ok(value) = HeaderValue::from_str(&new_token) {
+        response.headers_mut().insert(ACCESS_TOKEN_HEADER, value);
+    }
+    response
+}
+
+/// Returns a new access token when the given one is about to expire, or `None`
+/// when it's still valid.
+async fn refreshed_token(
+    db: &Database,
+    validator: &TokenValidator,
+    access_token: &str,
+) -> anyhow::Result<Option<String>> {
+    if !is_expiring(access_token) {
+        return Ok(None);
+    }
+
+    // Only the signature tells the token really belongs to the user
+    let email = validator.validate_expired(access_token).await?.unique_name;
+    let client = db.get().await?;
+    let Some(user) = User::find(&client, &email).await? else {
+        return Ok(None);
+    };
+    let Some(refresh_token) = user.refresh_token.as_deref() else {
+        return Ok(None);
+    };
+
+    info!("Access token for {email} expired, refreshing...");
+    let token = refresh_access_token(refresh_token).await?;
+    let refresh_token = token.refresh_code.as_deref().unwrap_or(refresh_token);
+    user.update_tokens(&client, &token.access_code, refresh_token, &token.scopes)
+        .await?;
+
+    Ok(Some(token.access_code))
+}
+
+/// Returns a valid access token for a linked account, refreshing and storing it
+/// when it's about to expire.
+pub async fn account_access_token(
+    client: &deadpool_postgres::Client,
+    account: &Account,
+) -> anyhow::Result<String> {
+    // Opaque tokens, like Google's, are judged by the expiration stored along
+    let expiring = match account.expires_at {
+        Some(expires_at) => expires_soon(expires_at),
+        None => is_expiring(&account.access_token),
+    };
+    if !expiring {
+        return Ok(account.access_token.clone());
+    }
+
+    info!(
+        "Access token for account {} expired, refreshing...",
+        account.address
+    );
+    let provider: Provider = account.provider.parse()?;
+    let token = refresh_provider_token(provider.oauth_provider(), &account.refresh_token).await?;
+    let refresh_token = token
+        .refresh_code
+        .as_deref()
+        .unwrap_or(&account.refresh_token);
+    account
+        .update_tokens(client, &token.access_code, refresh_token, token.expires_at)
+        .await?;
+
+    Ok(token.access_code)
+}
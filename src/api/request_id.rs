@@ -0,0 +1,47 @@
+use axum::{
+    http::{HeaderName, Request},
+    middleware::Next,
+    response::Response,
+};
+use tower_http::request_id::RequestId;
+use tracing::{info_span, Span};
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Returns the id of the request being handled, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID
+        .try_with(|id| id.clone())
+        .ok()
+        .filter(|id| !id.is_empty())
+}
+
+/// Middleware making the request id set by `SetRequestIdLayer` available to
+/// `current` while the request is handled, so errors can report it.
+pub async fn scope_request_id<B>(req: Request<B>, next: Next<B>) -> Response {
+    let id = request_id(&req).unwrap_or_default();
+    REQUEST_ID.scope(id, next.run(req)).await
+}
+
+/// Span of a request, carrying its id so every log line can be traced back to
+/// it. Only the path is recorded, query strings can carry access tokens.
+pub fn make_span<B>(req: &Request<B>) -> Span {
+    info_span!(
+        "request",
+        method = %req.method(),
+        path = %req.uri().path(),
+        version = ?req.version(),
+        request_id = request_id(req).unwrap_or_default(),
+    )
+}
+
+fn request_id<B>(req: &Request<B>) -> Option<String> {
+    req.extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(ToString::to_string)
+}
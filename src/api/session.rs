@@ -0,0 +1,227 @@
+use axum::{
+    extract::Path,
+    headers::{Cookie, HeaderMapExt},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chrono::{Duration, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::database::{Database, Session, User};
+
+use super::{
+    authed_user::{registered_user_id, AuthedUser},
+    error::AppError,
+    TokenRequest,
+};
+
+/// The cookie holding the secret of the browser session.
+pub const SESSION_COOKIE: &str = "postars_session";
+
+/// Sessions end after this long, the user signs in again then.
+const SESSION_LIFETIME_DAYS: i64 = 30;
+
+/// About 256 bits out of alphanumerics.
+const SECRET_LENGTH: usize = 43;
+
+/// The id of the session the cookie of the request signs in with, if any.
+pub fn session_id(headers: &HeaderMap) -> Option<String> {
+    let cookie = headers.typed_get::<Cookie>()?;
+    cookie.get(SESSION_COOKIE).map(hash_secret)
+}
+
+/// A new secret for the client to authenticate with, about 256 bits.
+pub fn random_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Secrets are stored by their hash, so reading the database isn't enough to
+/// sign in.
+pub fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// The cookie is out of reach of scripts and only sent by the app's own
+/// pages, to the API.
+pub fn session_cookie(secret: &str, max_age: i64) -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "{SESSION_COOKIE}={secret}; Path=/api; Max-Age={max_age}; HttpOnly; Secure; SameSite=Strict"
+    ))
+    .expect("session cookies are ASCII")
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/session",
+    tag = "profile",
+    request_body = TokenRequest,
+    responses((status = 201, body = Session, description = "Signed in, the session is in the cookie"))
+)]
+pub async fn post_session(
+    headers: HeaderMap,
+    AuthedUser {
+        email,
+        access_token,
+        scopes,
+        api_key,
+        ..
+    }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Json(data): Json<TokenRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if api_key.is_some() {
+        return Err(AppError::Forbidden(
+            "API keys can't start sessions".to_string(),
+        ));
+    }
+    let purged = Session::delete_expired(&db.get().await?).await?;
+    if purged > 0 {
+        info!("Deleted {purged} expired sessions");
+    }
+
+    let secret = random_secret();
+    let expires_at = Utc::now() + Duration::days(SESSION_LIFETIME_DAYS);
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok());
+    // The tokens and the session are saved together, a session is never left
+    // without the tokens it acts with
+    let session = db
+        .transaction(|tx| {
+            let (email, access_token, scopes) =
+                (email.clone(), access_token.clone(), scopes.clone());
+            let refresh_token = data.refresh_token.clone();
+            let id = hash_secret(&secret);
+            let user_agent = user_agent.map(ToString::to_string);
+            Box::pin(async move {
+                let user =
+                    User::upsert_with_tokens(tx, &email, &access_token, &refresh_token, &scopes)
+                        .await?;
+                let user_id = user.id.expect("saved users have an id");
+                Session::create(tx, &id, user_id, expires_at, user_agent.as_deref()).await
+            })
+        })
+        .await?;
+    info!("Started a session for {email} until {expires_at}");
+
+    let cookie = session_cookie(&secret, Duration::days(SESSION_LIFETIME_DAYS).num_seconds());
+    Ok((
+        StatusCode::CREATED,
+        [(header::SET_COOKIE, cookie)],
+        Json(session),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/session",
+    tag = "profile",
+    responses(
+        (status = 200, body = Session),
+        (status = 401, description = "No session, or it expired")
+    )
+)]
+pub async fn get_session(
+    headers: HeaderMap,
+    Extension(db): Extension<Database>,
+) -> Result<Json<Session>, AppError> {
+    let id = session_id(&headers)
+        .ok_or_else(|| AppError::Unauthorized("missing session cookie".to_string()))?;
+    let session = Session::find(&db.get().await?, &id)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("session expired".to_string()))?;
+    Ok(Json(session))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/session",
+    tag = "profile",
+    responses((status = 204, description = "Signed out, the cookie is cleared"))
+)]
+pub async fn delete_session(
+    headers: HeaderMap,
+    Extension(db): Extension<Database>,
+) -> Result<impl IntoResponse, AppError> {
+    if let Some(id) = session_id(&headers) {
+        Session::delete(&db.get().await?, &id).await?;
+    }
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(header::SET_COOKIE, session_cookie("", 0))],
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/me/sessions",
+    tag = "profile",
+    responses((status = 200, body = [Session]))
+)]
+pub async fn get_sessions(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+) -> Result<Json<Vec<Session>>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    Ok(Json(Session::list(&db.read().await?, user_id).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/me/sessions",
+    tag = "profile",
+    responses((status = 204, description = "Signed out of every device"))
+)]
+pub async fn delete_sessions(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+) -> Result<StatusCode, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let count = Session::delete_all(&db.get().await?, user_id).await?;
+    info!("Ended {count} sessions of user {user_id}");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/me/sessions/{id}",
+    tag = "profile",
+    params(("id" = String, Path, description = "Session id")),
+    responses((status = 204, description = "Device signed out"))
+)]
+pub async fn delete_user_session(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    if !Session::delete_for_user(&db.get().await?, user_id, &id).await? {
+        return Err(AppError::NotFound(format!("session {id} not found")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_id() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(session_id(&headers), None);
+
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("theme=dark; postars_session=secret"),
+        );
+        assert_eq!(session_id(&headers), Some(hash_secret("secret")));
+        assert_ne!(hash_secret("secret"), "secret");
+    }
+}
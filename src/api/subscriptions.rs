@@ -0,0 +1,330 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    database::{Database, Message, User, WebhookSubscription},
+    events::{self, MailboxChange},
+    graph::{
+        notifications::{self, Certificate, ChangeNotification},
+        Email,
+    },
+    index,
+};
+
+use super::{
+    authed_user::{registered_user_id, AuthedUser},
+    error::AppError,
+    message_cache,
+};
+
+/// Graph stops notifying about messages after at most 10080 minutes, the
+/// subscriptions are renewed a bit before that.
+const SUBSCRIPTION_LIFETIME_MINUTES: i64 = 10_000;
+
+/// Subscriptions including the messages last at most 1440 minutes.
+const RICH_SUBSCRIPTION_LIFETIME_MINUTES: i64 = 1_400;
+
+/// The changes to the user's messages subscribed to.
+const SUBSCRIPTION_CHANGE_TYPES: &str = "created,updated,deleted";
+
+const CLIENT_STATE_LENGTH: usize = 32;
+
+/// Public URL of the notifications endpoint, like
+/// `https://postars.example.com/api/notifications`, that Graph posts change
+/// notifications to. Subscriptions can't be created without one.
+#[derive(Clone, Debug, Default)]
+pub struct NotificationUrl(Option<Arc<String>>);
+
+impl NotificationUrl {
+    pub fn new(url: Option<String>) -> Self {
+        Self(url.map(Arc::new))
+    }
+}
+
+/// The certificate the messages included in notifications are encrypted for.
+/// Without one, notifications only tell which messages changed and they're
+/// fetched again.
+#[derive(Clone, Default)]
+pub struct NotificationCertificate(Option<Arc<Certificate>>);
+
+impl NotificationCertificate {
+    pub fn new(certificate: Option<Certificate>) -> Self {
+        Self(certificate.map(Arc::new))
+    }
+}
+
+fn subscription_expiration(certificate: &NotificationCertificate) -> DateTime<Utc> {
+    let lifetime = match certificate.0 {
+        Some(_) => RICH_SUBSCRIPTION_LIFETIME_MINUTES,
+        None => SUBSCRIPTION_LIFETIME_MINUTES,
+    };
+    Utc::now() + Duration::minutes(lifetime)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/me/subscriptions",
+    tag = "realtime",
+    responses((status = 200, body = [WebhookSubscription]))
+)]
+pub async fn get_subscriptions(
+    AuthedUser { user, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+) -> Result<Json<Vec<WebhookSubscription>>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    Ok(Json(
+        WebhookSubscription::list(&db.read().await?, user_id).await?,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/me/subscriptions",
+    tag = "realtime",
+    responses(
+        (status = 201, body = WebhookSubscription),
+        (status = 400, description = "The server has no notification URL configured")
+    )
+)]
+pub async fn post_subscription(
+    AuthedUser { user, graph, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Extension(notification_url): Extension<NotificationUrl>,
+    Extension(certificate): Extension<NotificationCertificate>,
+) -> Result<(StatusCode, Json<WebhookSubscription>), AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let Some(notification_url) = notification_url.0 else {
+        return Err(AppError::BadRequest(
+            "change notifications are not enabled on this server".to_string(),
+        ));
+    };
+
+    let client_state: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(CLIENT_STATE_LENGTH)
+        .map(char::from)
+        .collect();
+    let subscription = graph
+        .create_subscription(
+            &notification_url,
+            SUBSCRIPTION_CHANGE_TYPES,
+            subscription_expiration(&certificate),
+            &client_state,
+            certificate.0.as_deref(),
+        )
+        .await?;
+    info!(
+        "Created subscription {} until {}",
+        subscription.id, subscription.expiration_date_time
+    );
+
+    let client = db.get().await?;
+    WebhookSubscription::create(
+        &client,
+        &subscription.id,
+        user_id,
+        &client_state,
+        subscription.expiration_date_time,
+    )
+    .await?;
+    let subscription = WebhookSubscription::find(&client, &subscription.id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("subscription {} was not saved", subscription.id))?;
+    Ok((StatusCode::CREATED, Json(subscription)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/me/subscriptions/{id}/renew",
+    tag = "realtime",
+    params(("id" = String, Path, description = "Subscription id")),
+    responses((status = 200, body = WebhookSubscription))
+)]
+pub async fn post_renew_subscription(
+    AuthedUser { user, graph, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Extension(certificate): Extension<NotificationCertificate>,
+    Path(id): Path<String>,
+) -> Result<Json<WebhookSubscription>, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let client = db.get().await?;
+    let mut subscription = WebhookSubscription::find(&client, &id)
+        .await?
+        .filter(|subscription| subscription.user_id == user_id)
+        .ok_or_else(|| AppError::NotFound(format!("subscription {id} not found")))?;
+
+    let renewed = graph
+        .renew_subscription(&id, subscription_expiration(&certificate))
+        .await?;
+    subscription
+        .set_expires_at(&client, renewed.expiration_date_time)
+        .await?;
+    Ok(Json(subscription))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/me/subscriptions/{id}",
+    tag = "realtime",
+    params(("id" = String, Path, description = "Subscription id")),
+    responses((status = 204, description = "Subscription deleted"))
+)]
+pub async fn delete_subscription(
+    AuthedUser { user, graph, .. }: AuthedUser,
+    Extension(db): Extension<Database>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let user_id = registered_user_id(user.as_ref())?;
+    let client = db.get().await?;
+    WebhookSubscription::find(&client, &id)
+        .await?
+        .filter(|subscription| subscription.user_id == user_id)
+        .ok_or_else(|| AppError::NotFound(format!("subscription {id} not found")))?;
+
+    // Deleted on Graph first, the row is kept to retry with when it fails
+    match graph.delete_subscription(&id).await {
+        // Already expired on Graph's side
+        Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => {}
+        result => result?,
+    }
+    WebhookSubscription::delete(&client, user_id, &id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationQuery {
+    validation_token: Option<String>,
+}
+
+/// Receives the change notifications of the subscriptions. Graph validates the
+/// endpoint when subscribing by posting a `validationToken` it expects back.
+#[utoipa::path(
+    post,
+    path = "/api/notifications",
+    tag = "realtime",
+    params(("validationToken" = Option<String>, Query, description = "Sent by Graph to validate the endpoint")),
+    responses(
+        (status = 200, description = "The validation token", content_type = "text/plain"),
+        (status = 202, description = "Notifications accepted")
+    )
+)]
+pub async fn post_notifications(
+    Extension(db): Extension<Database>,
+    Extension(certificate): Extension<NotificationCertificate>,
+    Query(query): Query<NotificationQuery>,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    if let Some(token) = query.validation_token {
+        let token = notifications::validation_response(&token)
+            .map_err(|err| AppError::BadRequest(err.to_string()))?;
+        return Ok(([(header::CONTENT_TYPE, "text/plain")], token.to_string()).into_response());
+    }
+
+    let notifications =
+        notifications::decode(&body).map_err(|err| AppError::BadRequest(err.to_string()))?;
+    let client = db.get().await?;
+    for notification in notifications.value {
+        let Some(subscription) =
+            WebhookSubscription::find(&client, &notification.subscription_id).await?
+        else {
+            warn!(
+                "Notification for unknown subscription {}",
+                notification.subscription_id
+            );
+            continue;
+        };
+        if !notification.verify_client_state(&subscription.client_state) {
+            warn!(
+                "Notification for subscription {} with a wrong client state",
+                subscription.id
+            );
+            continue;
+        }
+        let Some(id) = notification.message_id() else {
+            warn!("Notification without a message: {notification:?}");
+            continue;
+        };
+
+        if notification.change_type == "deleted" {
+            Message::delete_many(&client, subscription.user_id, &[id.to_string()]).await?;
+            if let Err(err) = index::delete_documents(subscription.user_id, &[id.to_string()]).await
+            {
+                warn!("Failed to delete {id} from the search index: {err:?}");
+            }
+        } else if let Some(email) = included_email(&certificate, &notification) {
+            Message::upsert_many(&client, subscription.user_id, &[email]).await?;
+        } else {
+            // Graph wants its answer within seconds, the message is cached
+            // again in the background
+            let db = db.clone();
+            let user_email = subscription.user_email.clone();
+            let id = id.to_string();
+            tokio::spawn(async move {
+                if let Err(err) = refresh_message(&db, &user_email, id).await {
+                    warn!("Failed to refresh the message cache: {err:?}");
+                    if let Err(err) = message_cache::invalidate(&db, &user_email).await {
+                        warn!("Failed to invalidate the message cache: {err:?}");
+                    }
+                }
+            });
+        }
+
+        // Whichever server the client is connected to pushes it the change
+        events::publish(
+            &client,
+            &MailboxChange {
+                user_email: subscription.user_email.clone(),
+                change_type: notification.change_type.clone(),
+                id: id.to_string(),
+            },
+        )
+        .await?;
+    }
+
+    // Graph retries notifications that aren't acknowledged quickly
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+/// The message a rich notification carries, `None` without one or when it
+/// can't be read.
+fn included_email(
+    certificate: &NotificationCertificate,
+    notification: &ChangeNotification,
+) -> Option<Email> {
+    let certificate = certificate.0.as_ref()?;
+    let content = notification.encrypted_content.as_ref()?;
+    let resource = match certificate.decrypt(content)? {
+        Ok(resource) => resource,
+        Err(err) => {
+            warn!(
+                "Failed to decrypt a notification of subscription {}: {err}",
+                notification.subscription_id
+            );
+            return None;
+        }
+    };
+    serde_json::from_value(resource)
+        .map_err(|err| warn!("Notification with an unexpected message: {err}"))
+        .ok()
+}
+
+/// Caches the message a notification is about as it is now.
+async fn refresh_message(db: &Database, user_email: &str, id: String) -> anyhow::Result<()> {
+    let client = db.get().await?;
+    if let Some(user) = User::find(&client, user_email).await? {
+        message_cache::refresh(db, &user, &[id]).await?;
+    }
+    Ok(())
+}
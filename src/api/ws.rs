@@ -0,0 +1,286 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        Query, WebSocketUpgrade,
+    },
+    http::HeaderMap,
+    response::Response,
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+use crate::{
+    database::{self, Database, User},
+    graph::{Email, FolderCount, GraphClient, GraphClientError},
+    token::UserTokenProvider,
+};
+
+use super::{error::AppError, jwt::TokenValidator, session};
+
+/// How often each connection checks the mailbox for changes in folder counts.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Number of events a slow connection can lag behind before missing some.
+const EVENT_BUS_CAPACITY: usize = 256;
+
+/// Events pushed from the server to connected clients.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    FolderCounts {
+        folders: Vec<FolderCount>,
+    },
+    EmailUpdated {
+        email: Box<Email>,
+    },
+    /// Graph notified the email was `created`, `updated` or `deleted`
+    EmailChanged {
+        change_type: String,
+        id: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Commands sent by clients over the socket.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientCommand {
+    MarkRead { id: String },
+    MarkUnread { id: String },
+    Archive { id: String },
+    Refresh,
+}
+
+/// An event addressed to every connection of a given user.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub user_email: String,
+    pub event: ServerEvent,
+}
+
+/// Fans out mailbox events to all the websocket sessions of this server.
+#[derive(Clone)]
+pub struct EventBus(broadcast::Sender<Notification>);
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        Self(sender)
+    }
+
+    pub fn publish(&self, user_email: &str, event: ServerEvent) {
+        // An error only means there are no connected sessions right now
+        let _ = self.0.send(Notification {
+            user_email: user_email.to_string(),
+            event,
+        });
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.0.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WsQuery {
+    /// Browsers can't set headers on websocket requests, so the token is passed
+    /// as a query parameter instead. Not needed with a session cookie.
+    access_token: Option<String>,
+}
+
+/// Connections outlive the token they were opened with, so the mailbox is
+/// reached with the tokens stored for the user, refreshed as needed. Users who
+/// didn't register theirs through `/api/token` get the given token only.
+#[utoipa::path(
+    get,
+    path = "/api/ws",
+    tag = "realtime",
+    params((
+        "access_token" = Option<String>,
+        Query,
+        description = "Graph access token, unless signed in with a session cookie"
+    )),
+    responses((status = 101, description = "Switching to the websocket protocol"))
+)]
+pub async fn get_ws(
+    ws: WebSocketUpgrade,
+    Query(query): Query<WsQuery>,
+    headers: HeaderMap,
+    Extension(db): Extension<Database>,
+    Extension(bus): Extension<EventBus>,
+    Extension(validator): Extension<TokenValidator>,
+) -> Result<Response, AppError> {
+    let client = db.get().await?;
+    let user_email = match &query.access_token {
+        Some(access_token) => {
+            validator
+                .validate(access_token)
+                .await
+                .map_err(|err| AppError::Unauthorized(err.to_string()))?
+                .unique_name
+        }
+        None => {
+            let session_id = session::session_id(&headers).ok_or_else(|| {
+                AppError::Unauthorized("missing access token or session cookie".to_string())
+            })?;
+            database::Session::find(&client, &session_id)
+                .await?
+                .ok_or_else(|| AppError::Unauthorized("session expired".to_string()))?
+                .user_email
+        }
+    };
+
+    let registered = User::find(&client, &user_email)
+        .await?
+        .is_some_and(|user| user.refresh_token.is_some());
+    let graph = match (registered, query.access_token) {
+        (true, _) => {
+            GraphClient::with_token_provider(UserTokenProvider::new(db.clone(), user_email.clone()))
+        }
+        (false, Some(access_token)) => GraphClient::new(access_token),
+        (false, None) => {
+            return Err(AppError::Unauthorized(format!(
+                "can't reach the mailbox of {user_email}: no tokens stored"
+            )))
+        }
+    };
+    let session = Session {
+        client: graph,
+        user_email,
+        bus,
+        last_counts: None,
+    };
+    Ok(ws.on_upgrade(move |socket| session.run(socket)))
+}
+
+/// The actor backing a single websocket connection.
+struct Session {
+    client: GraphClient,
+    user_email: String,
+    bus: EventBus,
+    last_counts: Option<Vec<FolderCount>>,
+}
+
+impl Session {
+    async fn run(mut self, mut socket: WebSocket) {
+        info!("Websocket session started for {}", self.user_email);
+        let mut events = self.bus.subscribe();
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+
+        loop {
+            let result = tokio::select! {
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Text(text))) => self.handle_command(&text, &mut socket).await,
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => Ok(()),
+                    Some(Err(err)) => {
+                        debug!("Websocket error: {err}");
+                        break;
+                    }
+                },
+                notification = events.recv() => match notification {
+                    Ok(notification) if notification.user_email == self.user_email => {
+                        send(&mut socket, &notification.event).await
+                    }
+                    Ok(_) => Ok(()),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Websocket session lagged, {skipped} events skipped");
+                        self.push_counts(&mut socket, true).await
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = poll.tick() => self.push_counts(&mut socket, false).await,
+            };
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        info!("Websocket session ended for {}", self.user_email);
+    }
+
+    async fn handle_command(
+        &mut self,
+        text: &str,
+        socket: &mut WebSocket,
+    ) -> Result<(), axum::Error> {
+        let command: ClientCommand = match serde_json::from_str(text) {
+            Ok(command) => command,
+            Err(err) => {
+                let event = ServerEvent::Error {
+                    message: format!("invalid command: {err}"),
+                };
+                return send(socket, &event).await;
+            }
+        };
+
+        let result = match command {
+            ClientCommand::MarkRead { id } => self.client.set_read(&id, true).await,
+            ClientCommand::MarkUnread { id } => self.client.set_read(&id, false).await,
+            ClientCommand::Archive { id } => {
+                self.client
+                    .move_email_to_folder_by_name(&id, "archive")
+                    .await
+            }
+            ClientCommand::Refresh => return self.push_counts(socket, true).await,
+        };
+
+        match result {
+            Ok(email) => {
+                // Every session of this user, this one included, gets the update
+                self.bus.publish(
+                    &self.user_email,
+                    ServerEvent::EmailUpdated {
+                        email: Box::new(email),
+                    },
+                );
+                Ok(())
+            }
+            Err(err) => send(socket, &error_event(err)).await,
+        }
+    }
+
+    /// Sends the current folder counts, unless they're unchanged since the last
+    /// time they were sent and `force` is false.
+    async fn push_counts(
+        &mut self,
+        socket: &mut WebSocket,
+        force: bool,
+    ) -> Result<(), axum::Error> {
+        let counts = match self.client.get_user_folder_counts().await {
+            Ok(counts) => counts,
+            Err(err) => return send(socket, &error_event(err)).await,
+        };
+
+        if !force && self.last_counts.as_ref() == Some(&counts) {
+            return Ok(());
+        }
+
+        self.last_counts = Some(counts.clone());
+        send(socket, &ServerEvent::FolderCounts { folders: counts }).await
+    }
+}
+
+fn error_event(err: GraphClientError) -> ServerEvent {
+    ServerEvent::Error {
+        message: err.to_string(),
+    }
+}
+
+async fn send(socket: &mut WebSocket, event: &ServerEvent) -> Result<(), axum::Error> {
+    let json = serde_json::to_string(event).expect("events are always serializable");
+    socket.send(Message::Text(json)).await
+}
@@ -1,19 +1,54 @@
+use std::collections::HashMap;
 use std::env;
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpListener;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Html,
+    routing::get,
+    Router,
+};
 use chrono::{DateTime, Utc};
-use oauth2::basic::BasicClient;
-use oauth2::reqwest::http_client;
+use oauth2::basic::{BasicClient, BasicTokenResponse};
+use oauth2::reqwest::async_http_client;
 use oauth2::{
     AuthType, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge,
-    RedirectUrl, Scope, TokenResponse, TokenUrl,
+    RedirectUrl, RefreshToken, Scope, TokenResponse, TokenUrl,
 };
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::trace;
+use tokio::sync::oneshot;
+use tracing::{trace, warn};
 use url::Url;
 
+use crate::{graph, token};
+
+/// Saved tokens expiring within this many seconds are refreshed before use.
+const REFRESH_LEEWAY: i64 = 5 * 60;
+
+/// How long `auth` waits for the browser to come back from the sign in.
+const SIGN_IN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+const GOOGLE_AUTH_URL: &str = "https://accounts.google.com/o/oauth2/v2/auth";
+const GOOGLE_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const GOOGLE_USERINFO_URL: &str = "https://openidconnect.googleapis.com/v1/userinfo";
+/// Full mailbox access, the only scope Gmail accepts for IMAP over XOAUTH2.
+const GMAIL_SCOPE: &str = "https://mail.google.com/";
+/// The Exchange Online permission IMAP clients sign in with over XOAUTH2.
+const IMAP_PERMISSION: &str = "IMAP.AccessAsUser.All";
+
+pub const DEFAULT_TENANT: &str = "common";
+pub const DEFAULT_REDIRECT_URI: &str = "http://localhost:3003/redirect";
+/// Tenants standing for several organizations, which can't issue app-only
+/// tokens.
+const MULTI_TENANTS: [&str; 3] = ["common", "organizations", "consumers"];
+
+static AUTH_CONFIG: OnceLock<AuthConfig> = OnceLock::new();
+
 #[derive(Debug, Error)]
 pub enum AuthError {
     #[error("Invalid URL: {0}")]
@@ -24,13 +59,290 @@ pub enum AuthError {
 
     #[error("No token present")]
     NoTokenPresent,
+
+    #[error("No refresh token, authenticate again")]
+    NoRefreshToken,
+
+    #[error("Token exchange failed: {0}")]
+    TokenExchange(String),
+
+    #[error("Unknown OAuth provider: {0}")]
+    UnknownProvider(String),
+
+    #[error("Failed to get the address of the account: {0}")]
+    UserInfo(String),
+
+    #[error("Invalid auth configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("Sign in failed: {0}")]
+    Denied(String),
+
+    #[error("Timed out waiting for the sign in")]
+    Timeout,
+
+    #[error("Failed to receive the sign in: {0}")]
+    Callback(String),
+}
+
+/// How users sign in, set up once at startup with [`init`].
+#[derive(Clone, Debug)]
+pub struct AuthConfig {
+    /// `common`, `organizations`, `consumers`, or the id or domain of the
+    /// tenant of a single tenant app registration
+    pub tenant: String,
+    /// Registered with the app, the local server receiving the code listens on
+    /// its port
+    pub redirect_uri: String,
+    /// Replaces the scopes requested when signing in with Microsoft
+    pub scopes: Option<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            tenant: DEFAULT_TENANT.to_string(),
+            redirect_uri: DEFAULT_REDIRECT_URI.to_string(),
+            scopes: None,
+        }
+    }
+}
+
+impl AuthConfig {
+    fn validate(&self) -> Result<(), AuthError> {
+        if self.tenant.is_empty()
+            || !self
+                .tenant
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        {
+            return Err(AuthError::InvalidConfig(format!(
+                "tenant {:?} isn't an id or a domain",
+                self.tenant
+            )));
+        }
+
+        let redirect_uri = Url::parse(&self.redirect_uri).map_err(|err| {
+            AuthError::InvalidConfig(format!("redirect URI {}: {err}", self.redirect_uri))
+        })?;
+        if !matches!(redirect_uri.scheme(), "http" | "https") {
+            return Err(AuthError::InvalidConfig(format!(
+                "redirect URI {} isn't http or https",
+                self.redirect_uri
+            )));
+        }
+
+        if self
+            .scopes
+            .as_deref()
+            .is_some_and(|scopes| scopes.trim().is_empty())
+        {
+            return Err(AuthError::InvalidConfig("scopes are empty".to_string()));
+        }
+        Ok(())
+    }
+
+    /// The path the redirect URI points to.
+    fn redirect_path(&self) -> String {
+        Url::parse(&self.redirect_uri)
+            .map(|url| url.path().to_string())
+            .expect("redirect URI is validated")
+    }
+
+    /// The port the redirect URI points to.
+    fn redirect_port(&self) -> u16 {
+        Url::parse(&self.redirect_uri)
+            .ok()
+            .and_then(|url| url.port_or_known_default())
+            .expect("redirect URI is validated")
+    }
+}
+
+/// Validates and sets the auth configuration. Has no effect once it's in use.
+pub fn init(config: AuthConfig) -> Result<(), AuthError> {
+    config.validate()?;
+    if let Err(config) = AUTH_CONFIG.set(config) {
+        warn!("Auth already configured, ignoring {config:?}");
+    }
+    Ok(())
+}
+
+/// The configuration set with [`init`], the defaults otherwise.
+fn config() -> &'static AuthConfig {
+    AUTH_CONFIG.get_or_init(AuthConfig::default)
+}
+
+/// The identity services tokens are issued by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuthProvider {
+    /// Microsoft identity platform, for Graph and Outlook
+    #[default]
+    Microsoft,
+    /// Google, for Gmail over IMAP
+    Google,
+}
+
+impl OAuthProvider {
+    /// The environment variables holding the id and secret of the app
+    /// registered with the provider.
+    fn credential_vars(&self) -> (&'static str, &'static str) {
+        match self {
+            OAuthProvider::Microsoft => ("CLIENT_ID", "CLIENT_SECRET"),
+            OAuthProvider::Google => ("GOOGLE_CLIENT_ID", "GOOGLE_CLIENT_SECRET"),
+        }
+    }
+
+    fn urls(&self) -> Result<(AuthUrl, TokenUrl), AuthError> {
+        Ok(match self {
+            OAuthProvider::Microsoft => {
+                let login_url = &graph::endpoints().login;
+                let tenant = &config().tenant;
+                (
+                    AuthUrl::new(format!("{login_url}/{tenant}/oauth2/v2.0/authorize"))?,
+                    TokenUrl::new(format!("{login_url}/{tenant}/oauth2/v2.0/token"))?,
+                )
+            }
+            OAuthProvider::Google => (
+                AuthUrl::new(GOOGLE_AUTH_URL.to_string())?,
+                TokenUrl::new(GOOGLE_TOKEN_URL.to_string())?,
+            ),
+        })
+    }
+
+    /// The scopes requested when signing in.
+    fn scopes(&self) -> String {
+        match self {
+            OAuthProvider::Microsoft => {
+                if let Some(scopes) = &config().scopes {
+                    return scopes.clone();
+                }
+                // Consents to IMAP along with Graph, the code is then only
+                // exchanged for a Graph token, see `Resource`
+                let graph = graph::endpoints().graph_resource();
+                let outlook = &graph::endpoints().outlook;
+                format!(
+                    "openid profile email offline_access {graph}/Mail.Read {graph}/Mail.ReadWrite \
+                    {outlook}/{IMAP_PERMISSION}"
+                )
+            }
+            OAuthProvider::Google => format!("openid email {GMAIL_SCOPE}"),
+        }
+    }
+
+    /// Returns the address of the mailbox the access token was issued for.
+    pub async fn address(&self, access_token: &str) -> Result<String, AuthError> {
+        match self {
+            OAuthProvider::Microsoft => token::get_payload_field(access_token, "unique_name")
+                .map_err(|err| AuthError::UserInfo(err.to_string())),
+            // Google access tokens are opaque
+            OAuthProvider::Google => {
+                let userinfo: serde_json::Value = graph::shared_http_client()
+                    .get(GOOGLE_USERINFO_URL)
+                    .bearer_auth(access_token)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|err| AuthError::UserInfo(err.to_string()))?
+                    .json()
+                    .await
+                    .map_err(|err| AuthError::UserInfo(err.to_string()))?;
+                userinfo["email"]
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| AuthError::UserInfo("no email in the user info".to_string()))
+            }
+        }
+    }
+}
+
+/// The APIs a Microsoft sign in is consented for. Access tokens are only valid
+/// for a single one, the refresh token of the sign in gets one for each.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resource {
+    /// Microsoft Graph, for the API and the workers
+    Graph,
+    /// Exchange Online over IMAP, for IMAP clients like himalaya
+    Imap,
+}
+
+impl Resource {
+    /// The scopes requesting a token for the resource with the permissions
+    /// consented to at sign in.
+    fn scopes(self) -> String {
+        let endpoints = graph::endpoints();
+        match self {
+            Resource::Graph => format!("offline_access {}/.default", endpoints.graph_resource()),
+            Resource::Imap => format!("offline_access {}/{IMAP_PERMISSION}", endpoints.outlook),
+        }
+    }
+}
+
+impl FromStr for OAuthProvider {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "microsoft" => Ok(OAuthProvider::Microsoft),
+            "google" => Ok(OAuthProvider::Google),
+            _ => Err(AuthError::UnknownProvider(s.to_string())),
+        }
+    }
 }
 
 #[derive(Default, Serialize, Deserialize, Debug)]
 pub struct Token {
-    access_code: String,
-    refresh_code: Option<String>,
-    expires_at: Option<DateTime<Utc>>,
+    pub access_code: String,
+    pub refresh_code: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// The provider that issued the token, to refresh it with
+    #[serde(default)]
+    pub provider: OAuthProvider,
+    /// The scopes granted to the token
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl From<BasicTokenResponse> for Token {
+    fn from(token: BasicTokenResponse) -> Self {
+        let access_code = token.access_token().secret().to_string();
+        let refresh_code = token
+            .refresh_token()
+            .map(|token| token.secret().to_string());
+        let expires_at = token
+            .expires_in()
+            .and_then(|expires_in| chrono::Duration::from_std(expires_in).ok())
+            .map(|expires_in| Utc::now() + expires_in);
+        // Left out of responses granting exactly the requested scopes
+        let scopes = match token.scopes() {
+            Some(scopes) => scopes
+                .iter()
+                .map(|scope| scope.as_str().to_string())
+                .collect(),
+            None => token::granted_scopes(&access_code),
+        };
+
+        Token {
+            access_code,
+            refresh_code,
+            expires_at,
+            provider: OAuthProvider::default(),
+            scopes,
+        }
+    }
+}
+
+impl Token {
+    /// Whether the token expires soon enough to be refreshed first. Tokens
+    /// saved without an expiration are judged by their `exp` claim.
+    pub fn is_expiring(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => {
+                expires_at <= Utc::now() + chrono::Duration::seconds(REFRESH_LEEWAY)
+            }
+            None => token::is_expiring(&self.access_code),
+        }
+    }
 }
 
 #[derive(Default, Serialize, Deserialize)]
@@ -38,127 +350,383 @@ pub struct Config {
     auth_token: Option<Token>,
 }
 
-pub fn auth() -> Result<Token, AuthError> {
-    let client_id = ClientId::new(env::var("CLIENT_ID")?);
-    let client_secret = ClientSecret::new(env::var("CLIENT_SECRET")?);
-    let auth_url =
-        AuthUrl::new("https://login.microsoftonline.com/common/oauth2/v2.0/authorize".to_string())?;
-    let token_url =
-        TokenUrl::new("https://login.microsoftonline.com/common/oauth2/v2.0/token".to_string())?;
+/// Set up the config for the OAuth2 process of the provider.
+fn oauth_client(provider: OAuthProvider) -> Result<BasicClient, AuthError> {
+    let (client_id_var, client_secret_var) = provider.credential_vars();
+    let client_id = ClientId::new(env::var(client_id_var)?);
+    let client_secret = ClientSecret::new(env::var(client_secret_var)?);
+    let (auth_url, token_url) = provider.urls()?;
+
+    Ok(
+        BasicClient::new(client_id, Some(client_secret), auth_url, Some(token_url))
+            .set_auth_type(AuthType::RequestBody)
+            .set_redirect_uri(RedirectUrl::new(config().redirect_uri.clone())?),
+    )
+}
+
+/// Gets a token for the app itself through the client credentials grant, with
+/// the application permissions granted to it on the configured tenant, like
+/// reading every mailbox. App-only tokens can't be refreshed, a new one is
+/// requested instead.
+pub async fn app_token() -> Result<Token, AuthError> {
+    let tenant = &config().tenant;
+    if MULTI_TENANTS.contains(&tenant.as_str()) {
+        return Err(AuthError::InvalidConfig(format!(
+            "app-only tokens are issued by a single tenant, not {tenant}"
+        )));
+    }
+
+    let token = oauth_client(OAuthProvider::Microsoft)?
+        .exchange_client_credentials()
+        .add_scope(Scope::new(format!(
+            "{}/.default",
+            graph::endpoints().graph_resource()
+        )))
+        .request_async(async_http_client)
+        .await
+        .map_err(|e| AuthError::TokenExchange(e.to_string()))?;
 
-    // Set up the config for the Microsoft Graph OAuth2 process.
-    let client = BasicClient::new(client_id, Some(client_secret), auth_url, Some(token_url))
-        .set_auth_type(AuthType::RequestBody)
-        .set_redirect_uri(RedirectUrl::new(
-            "http://localhost:3003/redirect".to_string(),
-        )?);
+    Ok(token.into())
+}
+
+/// Exchanges a Microsoft refresh token for a new Graph access token.
+pub async fn refresh_access_token(refresh_token: &str) -> Result<Token, AuthError> {
+    refresh_resource_token(refresh_token, Resource::Graph).await
+}
+
+/// Exchanges a Microsoft refresh token for an access token to `resource`.
+pub async fn refresh_resource_token(
+    refresh_token: &str,
+    resource: Resource,
+) -> Result<Token, AuthError> {
+    let token = oauth_client(OAuthProvider::Microsoft)?
+        .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
+        .add_scope(Scope::new(resource.scopes()))
+        .request_async(async_http_client)
+        .await
+        .map_err(|e| AuthError::TokenExchange(e.to_string()))?;
+
+    Ok(token.into())
+}
+
+/// Exchanges a refresh token issued by `provider` for a new access token, to
+/// Graph for Microsoft.
+pub async fn refresh_provider_token(
+    provider: OAuthProvider,
+    refresh_token: &str,
+) -> Result<Token, AuthError> {
+    if provider == OAuthProvider::Microsoft {
+        return refresh_access_token(refresh_token).await;
+    }
+
+    let token = oauth_client(provider)?
+        .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
+        .request_async(async_http_client)
+        .await
+        .map_err(|e| AuthError::TokenExchange(e.to_string()))?;
+
+    Ok(Token {
+        provider,
+        ..token.into()
+    })
+}
+
+/// Exchanges the refresh code of the token for a new token, which keeps the
+/// same refresh code unless a new one is issued.
+pub async fn refresh(token: &Token) -> Result<Token, AuthError> {
+    let refresh_code = token
+        .refresh_code
+        .as_deref()
+        .ok_or(AuthError::NoRefreshToken)?;
+    let mut refreshed = refresh_provider_token(token.provider, refresh_code).await?;
+    if refreshed.refresh_code.is_none() {
+        refreshed.refresh_code = Some(refresh_code.to_string());
+    }
+    Ok(refreshed)
+}
+
+/// Gets a token for IMAP clients signing in with XOAUTH2 out of the refresh
+/// code of the token, which keeps the same refresh code unless a new one is
+/// issued. Gmail tokens already are IMAP tokens.
+pub async fn imap_token(token: &Token) -> Result<Token, AuthError> {
+    let refresh_code = token
+        .refresh_code
+        .as_deref()
+        .ok_or(AuthError::NoRefreshToken)?;
+    let mut imap_token = match token.provider {
+        OAuthProvider::Microsoft => refresh_resource_token(refresh_code, Resource::Imap).await?,
+        OAuthProvider::Google => refresh_provider_token(token.provider, refresh_code).await?,
+    };
+    if imap_token.refresh_code.is_none() {
+        imap_token.refresh_code = Some(refresh_code.to_string());
+    }
+    Ok(imap_token)
+}
+
+/// The URL to sign in with Microsoft again, consenting to `scopes` on top of
+/// those of the sign in, for features needing more than was granted.
+pub fn consent_url(scopes: &[&str]) -> Result<Url, AuthError> {
+    let graph = graph::endpoints().graph_resource();
+    let extra_scopes = scopes
+        .iter()
+        .map(|scope| format!("{graph}/{scope}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let (url, _) = oauth_client(OAuthProvider::Microsoft)?
+        .authorize_url(CsrfToken::new_random)
+        .add_scope(Scope::new(format!(
+            "{} {extra_scopes}",
+            OAuthProvider::Microsoft.scopes()
+        )))
+        .url();
+    Ok(url)
+}
+
+/// Signs in with the browser, receiving the code on the local server the
+/// redirect URI points to, and exchanges it for a token.
+pub async fn auth(provider: OAuthProvider) -> Result<Token, AuthError> {
+    let client = oauth_client(provider)?;
 
     let (pkce_code_challenge, pkce_code_verifier) = PkceCodeChallenge::new_random_sha256();
 
     // Generate the authorization URL to which we'll redirect the user.
-    let (authorize_url, csrf_state) = client
+    let mut request = client
         .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new(
-            "openid profile email offline_access https://graph.microsoft.com/Mail.Read https://graph.microsoft.com/Mail.ReadWrite".to_string(),
-        ))
-        .set_pkce_challenge(pkce_code_challenge)
-        .url();
+        .add_scope(Scope::new(provider.scopes()))
+        .set_pkce_challenge(pkce_code_challenge);
+    if provider == OAuthProvider::Google {
+        // Google only issues refresh tokens when asked for offline access
+        request = request
+            .add_extra_param("access_type", "offline")
+            .add_extra_param("prompt", "consent");
+    }
+    let (authorize_url, csrf_state) = request.url();
+
+    let (sender, receiver) = oneshot::channel();
+    let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+    let app = Router::new()
+        .route(&config().redirect_path(), get(redirect_handler))
+        .with_state(RedirectState {
+            csrf_state: csrf_state.secret().clone(),
+            sender: Arc::new(Mutex::new(Some(sender))),
+        });
+    let addr = SocketAddr::from(([127, 0, 0, 1], config().redirect_port()));
+    let server = axum::Server::try_bind(&addr)
+        .map_err(|err| AuthError::Callback(format!("can't listen on {addr}: {err}")))?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(async {
+            shutdown_signal.await.ok();
+        });
+    let server = tokio::spawn(server);
 
     trace!("opening URL:\n{authorize_url}\n");
-    opener::open(authorize_url.as_str()).unwrap();
-
-    // A very naive implementation of the redirect server.
-    let listener = TcpListener::bind("127.0.0.1:3003").unwrap();
-    #[allow(clippy::manual_flatten)]
-    for stream in listener.incoming() {
-        if let Ok(mut stream) = stream {
-            let code;
-            let state;
-            {
-                let mut reader = BufReader::new(&stream);
-
-                let mut request_line = String::new();
-                reader.read_line(&mut request_line).unwrap();
-
-                trace!("Auth returned the following request line: {request_line}",);
-
-                let redirect_url = request_line.split_whitespace().nth(1).unwrap();
-                let url = Url::parse(&("http://localhost".to_string() + redirect_url)).unwrap();
-
-                let code_pair = url
-                    .query_pairs()
-                    .find(|pair| {
-                        let (ref key, _) = pair;
-                        key == "code"
-                    })
-                    .unwrap();
-
-                let (_, value) = code_pair;
-                code = AuthorizationCode::new(value.into_owned());
-
-                let state_pair = url
-                    .query_pairs()
-                    .find(|pair| {
-                        let (ref key, _) = pair;
-                        key == "state"
-                    })
-                    .unwrap();
-
-                let (_, value) = state_pair;
-                state = CsrfToken::new(value.into_owned());
-            }
+    if opener::open(authorize_url.as_str()).is_err() {
+        println!("Open this URL in your browser to sign in:\n{authorize_url}");
+    }
 
-            let message = "Go back to your terminal :)";
-            let response = format!(
-                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}",
-                message.len(),
-                message
-            );
-            stream.write_all(response.as_bytes()).unwrap();
-
-            trace!("Auth returned the following code:\n{}\n", code.secret());
-            trace!(
-                "Auth returned the following state:\n{} (expected `{}`)\n",
-                state.secret(),
-                csrf_state.secret()
-            );
-
-            // Exchange the code with a token.
-            let token = client
-                .exchange_code(code)
-                // Send the PKCE code verifier in the token request
-                .set_pkce_verifier(pkce_code_verifier)
-                .request(http_client)
-                .unwrap();
+    let outcome = match tokio::time::timeout(SIGN_IN_TIMEOUT, receiver).await {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(_)) => Err(AuthError::NoTokenPresent),
+        Err(_) => Err(AuthError::Timeout),
+    };
+    // Lets the page of the outcome reach the browser before stopping
+    shutdown.send(()).ok();
+    server.await.ok();
+    let code = outcome?;
+
+    trace!("Auth returned the following code:\n{}\n", code.secret());
+
+    // Exchange the code with a token.
+    let mut request = client
+        .exchange_code(code)
+        // Send the PKCE code verifier in the token request
+        .set_pkce_verifier(pkce_code_verifier);
+    if provider == OAuthProvider::Microsoft {
+        // The sign in consented to several resources, tokens are for one
+        request = request.add_extra_param("scope", Resource::Graph.scopes());
+    }
+    let token = request
+        .request_async(async_http_client)
+        .await
+        .map_err(|e| AuthError::TokenExchange(e.to_string()))?;
+
+    Ok(Token {
+        provider,
+        ..Token::from(token)
+    })
+}
 
-            let access_code = token.access_token().secret().to_string();
-            let refresh_code = token
-                .refresh_token()
-                .map(|token| token.secret().to_string());
-            let expires_in = token.expires_in();
-
-            // TODO: attempt to get the user email address
-            // let client = reqwest::blocking::Client::new();
-            // let body = client
-            //     .get("https://graph.microsoft.com/profile")
-            //     .header("Authorization", format!("Bearer {token}"))
-            //     .send()
-            //     .unwrap();
-            // println!("Body = {body:?}");
-            // let text = body.text().unwrap();
-            // println!("Text = {text:?}");
-
-            let expires_at = expires_in
-                .map(|expires_in| Utc::now() + chrono::Duration::from_std(expires_in).unwrap());
-            let token = Token {
-                access_code,
-                refresh_code,
-                expires_at,
-            };
-
-            return Ok(token);
+/// Hands the outcome of the sign in from [`redirect_handler`] to [`auth`].
+#[derive(Clone)]
+struct RedirectState {
+    csrf_state: String,
+    sender: Arc<Mutex<Option<oneshot::Sender<Result<AuthorizationCode, AuthError>>>>>,
+}
+
+async fn redirect_handler(
+    State(state): State<RedirectState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> (StatusCode, Html<String>) {
+    // Requests without the state of the sign in aren't its answer, possibly
+    // forged, so the sign in keeps waiting
+    if params.get("state") != Some(&state.csrf_state) {
+        return (
+            StatusCode::BAD_REQUEST,
+            redirect_page(
+                "Sign in failed",
+                "The request doesn't match the sign in, try again from your terminal.",
+            ),
+        );
+    }
+
+    let (status, page, outcome) = match (params.get("code"), params.get("error")) {
+        (_, Some(error)) => {
+            let description = params.get("error_description").unwrap_or(error);
+            (
+                StatusCode::UNAUTHORIZED,
+                redirect_page("Sign in failed", description),
+                Err(AuthError::Denied(description.to_string())),
+            )
         }
+        (Some(code), None) => (
+            StatusCode::OK,
+            redirect_page(
+                "Signed in",
+                "You can close this window and go back to your terminal.",
+            ),
+            Ok(AuthorizationCode::new(code.to_string())),
+        ),
+        (None, None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                redirect_page("Sign in failed", "No authorization code was received."),
+            )
+        }
+    };
+
+    if let Some(sender) = state.sender.lock().unwrap().take() {
+        sender.send(outcome).ok();
+    }
+    (status, page)
+}
+
+fn redirect_page(title: &str, message: &str) -> Html<String> {
+    Html(format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>postars</title></head>\
+        <body style=\"font-family: sans-serif; text-align: center; margin-top: 4em\">\
+        <h1>{}</h1><p>{}</p></body></html>",
+        html_escape(title),
+        html_escape(message)
+    ))
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_is_expiring() {
+        let token = |expires_at| Token {
+            access_code: "not-a-jwt".to_string(),
+            refresh_code: None,
+            expires_at,
+            provider: OAuthProvider::Microsoft,
+            scopes: Vec::new(),
+        };
+        assert!(token(Some(Utc::now())).is_expiring());
+        assert!(token(Some(Utc::now() + chrono::Duration::minutes(2))).is_expiring());
+        assert!(!token(Some(Utc::now() + chrono::Duration::hours(1))).is_expiring());
+        // Left for Graph to judge when nothing tells
+        assert!(!token(None).is_expiring());
     }
 
-    Err(AuthError::NoTokenPresent)
+    #[test]
+    fn test_oauth_provider() {
+        assert_eq!(
+            "Google".parse::<OAuthProvider>().unwrap(),
+            OAuthProvider::Google
+        );
+        assert!("yahoo".parse::<OAuthProvider>().is_err());
+        assert!(OAuthProvider::Google.scopes().contains(GMAIL_SCOPE));
+
+        // Tokens saved before providers were supported are Microsoft's
+        let token: Token =
+            serde_json::from_str(r#"{"access_code":"a","refresh_code":null,"expires_at":null}"#)
+                .unwrap();
+        assert_eq!(token.provider, OAuthProvider::Microsoft);
+    }
+
+    #[tokio::test]
+    async fn test_redirect_handler() {
+        let (sender, mut receiver) = oneshot::channel();
+        let state = RedirectState {
+            csrf_state: "s1".to_string(),
+            sender: Arc::new(Mutex::new(Some(sender))),
+        };
+        let params = |pairs: &[(&str, &str)]| {
+            Query(
+                pairs
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            )
+        };
+
+        // A forged request doesn't end the sign in
+        let (status, _) = redirect_handler(
+            State(state.clone()),
+            params(&[("state", "s2"), ("code", "c1")]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(receiver.try_recv().is_err());
+
+        let (status, Html(page)) = redirect_handler(
+            State(state),
+            params(&[
+                ("state", "s1"),
+                ("error", "access_denied"),
+                ("error_description", "<denied>"),
+            ]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(page.contains("&lt;denied&gt;"));
+        assert!(matches!(receiver.try_recv(), Ok(Err(AuthError::Denied(_)))));
+    }
+
+    #[test]
+    fn test_auth_config() {
+        let config = |tenant: &str, redirect_uri: &str, scopes: Option<&str>| AuthConfig {
+            tenant: tenant.to_string(),
+            redirect_uri: redirect_uri.to_string(),
+            scopes: scopes.map(str::to_string),
+        };
+        assert!(AuthConfig::default().validate().is_ok());
+        assert_eq!(AuthConfig::default().redirect_port(), 3003);
+
+        let single_tenant = config(
+            "contoso.onmicrosoft.com",
+            "https://mail.contoso.com/redirect",
+            Some("openid Mail.Read"),
+        );
+        assert!(single_tenant.validate().is_ok());
+        assert_eq!(single_tenant.redirect_port(), 443);
+
+        assert!(config("", DEFAULT_REDIRECT_URI, None).validate().is_err());
+        assert!(config("common/x", DEFAULT_REDIRECT_URI, None)
+            .validate()
+            .is_err());
+        assert!(config("common", "localhost:3003", None).validate().is_err());
+        assert!(config("common", DEFAULT_REDIRECT_URI, Some(" "))
+            .validate()
+            .is_err());
+    }
 }
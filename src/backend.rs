@@ -0,0 +1,49 @@
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::auth::OAuthProvider;
+
+#[derive(Debug, Error)]
+pub enum BackendError {
+    #[error("unsupported provider: {0}")]
+    UnsupportedProvider(String),
+}
+
+/// The services a linked account can be hosted on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Provider {
+    Graph,
+    /// Gmail over IMAP, authenticated with XOAUTH2
+    Gmail,
+}
+
+impl Provider {
+    /// The identity service issuing the tokens of the accounts.
+    pub fn oauth_provider(&self) -> OAuthProvider {
+        match self {
+            Provider::Graph => OAuthProvider::Microsoft,
+            Provider::Gmail => OAuthProvider::Google,
+        }
+    }
+
+    /// The name accounts store the provider under.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::Graph => "graph",
+            Provider::Gmail => "gmail",
+        }
+    }
+}
+
+impl FromStr for Provider {
+    type Err = BackendError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "graph" => Ok(Provider::Graph),
+            "gmail" => Ok(Provider::Gmail),
+            _ => Err(BackendError::UnsupportedProvider(s.to_string())),
+        }
+    }
+}
@@ -1,8 +1,24 @@
-use deadpool_postgres::{Config, CreatePoolError, Pool, PoolError, Runtime};
+use std::{
+    env,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use deadpool_postgres::{Config, CreatePoolError, Pool, PoolConfig, PoolError, Runtime};
+use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio_postgres::NoTls;
+use tokio_postgres::{error::SqlState, types::Json, NoTls};
+use tracing::warn;
 use url::Url;
+use utoipa::ToSchema;
+
+use crate::{
+    graph::{Body, Email},
+    metrics::DATABASE_METRICS,
+    rules::{Action, Condition},
+};
 
 pub type Result<T> = std::result::Result<T, DatabaseError>;
 
@@ -22,12 +38,87 @@ pub enum DatabaseError {
 
     #[error("migration error: {0}")]
     Migration(#[from] refinery::Error),
+
+    #[error("invalid database configuration: {0}")]
+    Config(String),
+}
+
+impl DatabaseError {
+    /// Whether running the transaction again can succeed, after it lost a
+    /// serialization conflict or a deadlock.
+    fn is_transient(&self) -> bool {
+        let DatabaseError::Pg(err) = self else {
+            return false;
+        };
+        err.code().is_some_and(|code| {
+            *code == SqlState::T_R_SERIALIZATION_FAILURE || *code == SqlState::T_R_DEADLOCK_DETECTED
+        })
+    }
+}
+
+/// Transactions failing with a transient error run again this many times.
+const TRANSACTION_RETRIES: u32 = 3;
+/// Wait before running a transaction again, doubled at each retry.
+const TRANSACTION_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Statements taking longer are logged, unless `DATABASE_SLOW_QUERY_MS` says
+/// otherwise.
+const DEFAULT_SLOW_QUERY_THRESHOLD: Duration = Duration::from_millis(500);
+
+static SLOW_QUERY_THRESHOLD: OnceLock<Duration> = OnceLock::new();
+
+/// Times a statement until dropped, recording it in the database metrics and
+/// logging it when it's slow. Statements are named after the function running
+/// them, like `User::find`.
+///
+/// ```ignore
+/// let _timer = QueryTimer::start("User::find");
+/// ```
+pub struct QueryTimer {
+    statement: &'static str,
+    started_at: Instant,
+}
+
+impl QueryTimer {
+    pub fn start(statement: &'static str) -> Self {
+        Self {
+            statement,
+            started_at: Instant::now(),
+        }
+    }
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        let elapsed = self.started_at.elapsed();
+        let threshold = SLOW_QUERY_THRESHOLD
+            .get()
+            .copied()
+            .unwrap_or(DEFAULT_SLOW_QUERY_THRESHOLD);
+        let slow = elapsed > threshold;
+        if slow {
+            warn!("Slow query {} took {elapsed:?}", self.statement);
+        }
+        DATABASE_METRICS.record_query(self.statement, elapsed, slow);
+    }
+}
+
+/// A migration of the binary, and when it was applied.
+#[derive(Debug)]
+pub struct MigrationStatus {
+    /// Like `V1__create_users_table`
+    pub name: String,
+    /// `None` while it's pending
+    pub applied_on: Option<DateTime<Utc>>,
 }
 
 #[derive(Clone)]
 pub struct Database {
     database_url: String,
     pool: Pool,
+    /// Pool of a read-only replica, for the queries which can lag a bit
+    /// behind the primary
+    read_pool: Option<Pool>,
 }
 
 mod embedded {
@@ -38,12 +129,84 @@ mod embedded {
 impl Database {
     pub async fn new(database_url: String) -> Result<Self> {
         let config = create_deadpool_config_from_url(&database_url)?;
+        if let Some(threshold) = env::var("DATABASE_SLOW_QUERY_MS")
+            .ok()
+            .filter(|value| !value.is_empty())
+        {
+            let threshold = threshold.parse().map(Duration::from_millis).map_err(|_| {
+                DatabaseError::Config(format!(
+                    "DATABASE_SLOW_QUERY_MS must be in milliseconds, got {threshold:?}"
+                ))
+            })?;
+            // Every database of the process shares it
+            let _ = SLOW_QUERY_THRESHOLD.set(threshold);
+        }
         let pool = config.create_pool(Some(Runtime::Tokio1), tokio_postgres::NoTls)?;
-        Ok(Self { database_url, pool })
+        Ok(Self {
+            database_url,
+            pool,
+            read_pool: None,
+        })
+    }
+
+    /// Sends the queries made through [`Self::read`] to the replica at
+    /// `database_url`.
+    pub fn with_read_replica(mut self, database_url: &str) -> Result<Self> {
+        let config = create_deadpool_config_from_url(database_url)?;
+        self.read_pool = Some(config.create_pool(Some(Runtime::Tokio1), tokio_postgres::NoTls)?);
+        Ok(self)
+    }
+
+    /// Applies the migrations the database doesn't have yet, returning their
+    /// names.
+    pub async fn migrate(&self) -> Result<Vec<String>> {
+        let mut client = self.connect().await?;
+        let report = embedded::migrations::runner()
+            .run_async(&mut client)
+            .await?;
+        Ok(report
+            .applied_migrations()
+            .iter()
+            .map(ToString::to_string)
+            .collect())
+    }
+
+    /// The migrations embedded in the binary, with when they were applied to
+    /// the database.
+    pub async fn migration_status(&self) -> Result<Vec<MigrationStatus>> {
+        let mut client = self.connect().await?;
+        let runner = embedded::migrations::runner();
+
+        // The history table only exists once migrations ran
+        let has_history: bool = client
+            .query_one(
+                "SELECT to_regclass('refinery_schema_history') IS NOT NULL",
+                &[],
+            )
+            .await?
+            .get(0);
+        let applied = match has_history {
+            true => runner.get_applied_migrations_async(&mut client).await?,
+            false => vec![],
+        };
+
+        Ok(runner
+            .get_migrations()
+            .iter()
+            .map(|migration| MigrationStatus {
+                name: migration.to_string(),
+                applied_on: applied
+                    .iter()
+                    .find(|applied| applied.version() == migration.version())
+                    .and_then(|applied| applied.applied_on())
+                    .and_then(|on| Utc.timestamp_opt(on.unix_timestamp(), 0).single()),
+            })
+            .collect())
     }
 
-    pub async fn migrate(&self) -> Result<()> {
-        let (mut client, connection) = tokio_postgres::connect(&self.database_url, NoTls).await?;
+    /// A connection of its own, outside of the pool, for the migrations.
+    async fn connect(&self) -> Result<tokio_postgres::Client> {
+        let (client, connection) = tokio_postgres::connect(&self.database_url, NoTls).await?;
 
         // Spawn a new tokio task to run the connection in the background.
         tokio::spawn(async move {
@@ -51,86 +214,1453 @@ impl Database {
                 eprintln!("connection error: {}", e);
             }
         });
-
-        embedded::migrations::runner()
-            .run_async(&mut client)
-            .await?;
-        Ok(())
+        Ok(client)
     }
 
     pub async fn get(&self) -> Result<deadpool_postgres::Client> {
-        Ok(self.pool.get().await?)
+        let started_at = Instant::now();
+        let client = self.pool.get().await;
+        DATABASE_METRICS.record_pool_wait("primary", started_at.elapsed());
+        Ok(client?)
+    }
+
+    /// Runs `f` in a transaction, committed once it succeeds. It runs again,
+    /// after a backoff, when it fails on a serialization failure or deadlock,
+    /// so it has to be safe to repeat.
+    ///
+    /// ```ignore
+    /// let user = db
+    ///     .transaction(|tx| {
+    ///         let email = email.clone();
+    ///         Box::pin(async move { User::register(tx, &email).await })
+    ///     })
+    ///     .await?;
+    /// ```
+    pub async fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: for<'a> Fn(&'a deadpool_postgres::Transaction<'a>) -> BoxFuture<'a, Result<T>>,
+    {
+        let mut client = self.get().await?;
+        let mut retries = 0;
+        loop {
+            let tx = client.transaction().await?;
+            let result = match f(&tx).await {
+                Ok(value) => tx
+                    .commit()
+                    .await
+                    .map(|()| value)
+                    .map_err(DatabaseError::from),
+                // Dropping the transaction rolls it back
+                Err(err) => Err(err),
+            };
+            match result {
+                Err(err) if retries < TRANSACTION_RETRIES && err.is_transient() => {
+                    let backoff = TRANSACTION_RETRY_BACKOFF * 2u32.pow(retries);
+                    retries += 1;
+                    warn!("Running a transaction again in {backoff:?} after: {err}");
+                    tokio::time::sleep(backoff).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Returns a client of the replica, or of the primary without one. Only for
+    /// reads which don't have to see the writes that just happened, like
+    /// listings.
+    pub async fn read(&self) -> Result<deadpool_postgres::Client> {
+        match &self.read_pool {
+            Some(pool) => {
+                let started_at = Instant::now();
+                let client = pool.get().await;
+                DATABASE_METRICS.record_pool_wait("replica", started_at.elapsed());
+                Ok(client?)
+            }
+            None => self.get().await,
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct User {
     pub id: Option<i32>,
     pub email: String,
     pub access_token: Option<String>,
     pub refresh_token: Option<String>,
+    /// The scopes granted to the access token
+    #[serde(default)]
+    pub scopes: Vec<String>,
 }
 
+// The user's own tokens are those of the account of their mailbox, the one
+// whose address is their email. App-only users don't have one. Queries writing
+// either table name their CTEs after it, to read the user back the same way.
+const USER_COLUMNS: &str = "users.id, users.email, accounts.access_token, \
+    accounts.refresh_token, COALESCE(accounts.scopes, '{}')";
+const USER_TABLES: &str = "users LEFT JOIN accounts \
+    ON accounts.user_id = users.id AND accounts.address = users.email";
+
 impl User {
     pub async fn find(client: &deadpool_postgres::Client, email: &str) -> Result<Option<Self>> {
+        let _timer = QueryTimer::start("User::find");
         let stmt = client
-            .prepare("SELECT id, email, access_token, refresh_token FROM users WHERE email = $1")
+            .prepare(&format!(
+                "SELECT {USER_COLUMNS} FROM {USER_TABLES} WHERE users.email = $1"
+            ))
             .await?;
         let rows = client.query(&stmt, &[&email]).await?;
-        Ok(rows.first().map(|row| Self {
+        Ok(rows.first().map(Self::from_row))
+    }
+
+    /// Registers the mailbox of `email` without any tokens, to be reached with
+    /// the app's own token.
+    pub async fn register(
+        client: &impl deadpool_postgres::GenericClient,
+        email: &str,
+    ) -> Result<Self> {
+        let _timer = QueryTimer::start("User::register");
+        let stmt = client
+            .prepare(&format!(
+                "WITH users AS (
+                    INSERT INTO users (email) VALUES ($1)
+                    ON CONFLICT (email) DO UPDATE SET email = EXCLUDED.email
+                    RETURNING id, email
+                )
+                SELECT {USER_COLUMNS} FROM {USER_TABLES}"
+            ))
+            .await?;
+        let row = client.query_one(&stmt, &[&email]).await?;
+        Ok(Self::from_row(&row))
+    }
+
+    /// Registers the user with the tokens of their mailbox, stored on its
+    /// account.
+    pub async fn upsert_with_tokens(
+        client: &impl deadpool_postgres::GenericClient,
+        email: &str,
+        access_token: &str,
+        refresh_token: &str,
+        scopes: &[String],
+    ) -> Result<Self> {
+        let _timer = QueryTimer::start("User::upsert_with_tokens");
+        let stmt = client
+            .prepare(&format!(
+                "WITH users AS (
+                    INSERT INTO users (email) VALUES ($1)
+                    ON CONFLICT (email) DO UPDATE SET email = EXCLUDED.email
+                    RETURNING id, email
+                ), accounts AS (
+                    INSERT INTO accounts (user_id, provider, address, access_token, refresh_token, scopes)
+                    SELECT id, 'graph', email, $2, $3, $4 FROM users
+                    ON CONFLICT (user_id, address)
+                    DO UPDATE SET provider = 'graph', access_token = $2, refresh_token = $3,
+                        scopes = $4, expires_at = NULL
+                    RETURNING user_id, address, access_token, refresh_token, scopes
+                )
+                SELECT {USER_COLUMNS} FROM {USER_TABLES}"
+            ))
+            .await?;
+        let row = client
+            .query_one(&stmt, &[&email, &access_token, &refresh_token, &scopes])
+            .await?;
+        Ok(Self::from_row(&row))
+    }
+
+    pub async fn update_tokens(
+        &self,
+        client: &deadpool_postgres::Client,
+        access_token: &str,
+        refresh_token: &str,
+        scopes: &[String],
+    ) -> Result<()> {
+        let _timer = QueryTimer::start("User::update_tokens");
+        let stmt = client
+            .prepare(
+                "UPDATE accounts SET access_token = $1, refresh_token = $2, scopes = $3
+                FROM users
+                WHERE accounts.user_id = users.id AND accounts.address = users.email
+                    AND users.email = $4",
+            )
+            .await?;
+        client
+            .execute(
+                &stmt,
+                &[&access_token, &refresh_token, &scopes, &self.email],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes the user, and with them everything stored for them, returning
+    /// whether they existed.
+    pub async fn delete(client: &impl deadpool_postgres::GenericClient, id: i32) -> Result<bool> {
+        let _timer = QueryTimer::start("User::delete");
+        let stmt = client.prepare("DELETE FROM users WHERE id = $1").await?;
+        Ok(client.execute(&stmt, &[&id]).await? > 0)
+    }
+
+    /// Returns when the user's rules were last applied to their new mail.
+    pub async fn rules_applied_at(
+        &self,
+        client: &deadpool_postgres::Client,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let _timer = QueryTimer::start("User::rules_applied_at");
+        let stmt = client
+            .prepare("SELECT rules_applied_at FROM users WHERE email = $1")
+            .await?;
+        let rows = client.query(&stmt, &[&self.email]).await?;
+        Ok(rows.first().and_then(|row| row.get(0)))
+    }
+
+    pub async fn set_rules_applied_at(
+        &self,
+        client: &deadpool_postgres::Client,
+        applied_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let _timer = QueryTimer::start("User::set_rules_applied_at");
+        let stmt = client
+            .prepare("UPDATE users SET rules_applied_at = $1 WHERE email = $2")
+            .await?;
+        client.execute(&stmt, &[&applied_at, &self.email]).await?;
+        Ok(())
+    }
+
+    /// Returns when the whole mailbox was last cached in `messages`, `None`
+    /// when it never was or changed since in ways the cache missed.
+    pub async fn messages_synced_at(
+        &self,
+        client: &deadpool_postgres::Client,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let _timer = QueryTimer::start("User::messages_synced_at");
+        let stmt = client
+            .prepare("SELECT messages_synced_at FROM users WHERE email = $1")
+            .await?;
+        let rows = client.query(&stmt, &[&self.email]).await?;
+        Ok(rows.first().and_then(|row| row.get(0)))
+    }
+
+    pub async fn set_messages_synced_at(
+        &self,
+        client: &deadpool_postgres::Client,
+        synced_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let _timer = QueryTimer::start("User::set_messages_synced_at");
+        let stmt = client
+            .prepare("UPDATE users SET messages_synced_at = $1 WHERE email = $2")
+            .await?;
+        client.execute(&stmt, &[&synced_at, &self.email]).await?;
+        Ok(())
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
             id: Some(row.get(0)),
             email: row.get(1),
             access_token: row.get(2),
             refresh_token: row.get(3),
-        }))
+            scopes: row.get(4),
+        }
     }
+}
 
-    pub async fn upsert_with_tokens(
+/// A mail account linked by a user, the tokens are never sent to clients. The
+/// user's own mailbox is one too, its address is their email.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct Account {
+    pub id: i32,
+    pub user_id: i32,
+    /// The service the account is hosted on, `graph` or `gmail`
+    pub provider: String,
+    pub address: String,
+    #[serde(skip_serializing)]
+    #[schema(write_only)]
+    pub access_token: String,
+    #[serde(skip_serializing)]
+    #[schema(write_only)]
+    pub refresh_token: String,
+    /// When the access token expires, when the provider told
+    #[serde(skip_serializing)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub settings: AccountSettings,
+}
+
+/// Preferences of the user for one of their linked accounts.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(default)]
+pub struct AccountSettings {
+    /// Label clients show instead of the address
+    pub name: Option<String>,
+    /// Color clients tell the account apart with, like `#0078d4`
+    pub color: Option<String>,
+}
+
+const ACCOUNT_COLUMNS: &str =
+    "id, user_id, provider, address, access_token, refresh_token, expires_at, settings";
+
+impl Account {
+    pub async fn list(client: &deadpool_postgres::Client, user_id: i32) -> Result<Vec<Self>> {
+        let _timer = QueryTimer::start("Account::list");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {ACCOUNT_COLUMNS} FROM accounts WHERE user_id = $1 ORDER BY id"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&user_id]).await?;
+        Ok(rows.iter().map(Self::from_row).collect())
+    }
+
+    pub async fn find(
         client: &deadpool_postgres::Client,
-        email: &str,
+        user_id: i32,
+        id: i32,
+    ) -> Result<Option<Self>> {
+        let _timer = QueryTimer::start("Account::find");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {ACCOUNT_COLUMNS} FROM accounts WHERE user_id = $1 AND id = $2"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&user_id, &id]).await?;
+        Ok(rows.first().map(Self::from_row))
+    }
+
+    /// Links the account to the user, or updates its tokens when it's already
+    /// linked.
+    pub async fn upsert(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        provider: &str,
+        address: &str,
         access_token: &str,
         refresh_token: &str,
+        expires_at: Option<DateTime<Utc>>,
     ) -> Result<Self> {
+        let _timer = QueryTimer::start("Account::upsert");
         let stmt = client
-            .prepare(
-                "INSERT INTO users (email, access_token, refresh_token) VALUES ($1, $2, $3)
-                ON CONFLICT (email) DO UPDATE SET access_token = $2, refresh_token = $3
-                RETURNING id, email, access_token, refresh_token",
-            )
+            .prepare(&format!(
+                "INSERT INTO accounts (user_id, provider, address, access_token, refresh_token, expires_at)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (user_id, address)
+                DO UPDATE SET provider = $2, access_token = $4, refresh_token = $5, expires_at = $6
+                RETURNING {ACCOUNT_COLUMNS}"
+            ))
             .await?;
-        let rows = client
-            .query(&stmt, &[&email, &access_token, &refresh_token])
+        let row = client
+            .query_one(
+                &stmt,
+                &[
+                    &user_id,
+                    &provider,
+                    &address,
+                    &access_token,
+                    &refresh_token,
+                    &expires_at,
+                ],
+            )
             .await?;
-        Ok(Self {
-            id: Some(rows.first().unwrap().get(0)),
-            email: rows.first().unwrap().get(1),
-            access_token: rows.first().unwrap().get(2),
-            refresh_token: rows.first().unwrap().get(3),
-        })
+        Ok(Self::from_row(&row))
     }
 
-    #[allow(unused)]
     pub async fn update_tokens(
         &self,
         client: &deadpool_postgres::Client,
         access_token: &str,
         refresh_token: &str,
+        expires_at: Option<DateTime<Utc>>,
     ) -> Result<()> {
+        let _timer = QueryTimer::start("Account::update_tokens");
+        let stmt = client
+            .prepare(
+                "UPDATE accounts SET access_token = $1, refresh_token = $2, expires_at = $3
+                WHERE id = $4",
+            )
+            .await?;
+        client
+            .execute(
+                &stmt,
+                &[&access_token, &refresh_token, &expires_at, &self.id],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn update_settings(
+        &mut self,
+        client: &deadpool_postgres::Client,
+        settings: AccountSettings,
+    ) -> Result<()> {
+        let _timer = QueryTimer::start("Account::update_settings");
+        let stmt = client
+            .prepare("UPDATE accounts SET settings = $1 WHERE id = $2")
+            .await?;
+        client.execute(&stmt, &[&Json(&settings), &self.id]).await?;
+        self.settings = settings;
+        Ok(())
+    }
+
+    /// Unlinks the account, returning whether it existed.
+    pub async fn delete(client: &deadpool_postgres::Client, user_id: i32, id: i32) -> Result<bool> {
+        let _timer = QueryTimer::start("Account::delete");
+        let stmt = client
+            .prepare("DELETE FROM accounts WHERE user_id = $1 AND id = $2")
+            .await?;
+        Ok(client.execute(&stmt, &[&user_id, &id]).await? > 0)
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            id: row.get(0),
+            user_id: row.get(1),
+            provider: row.get(2),
+            address: row.get(3),
+            access_token: row.get(4),
+            refresh_token: row.get(5),
+            expires_at: row.get(6),
+            settings: row.get::<_, Json<AccountSettings>>(7).0,
+        }
+    }
+}
+
+/// A user defined rule, applied in `position` order to incoming mail.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct Rule {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    /// All of them must match for the rule to apply
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<Action>,
+    pub enabled: bool,
+    pub position: i32,
+}
+
+const RULE_COLUMNS: &str = "id, user_id, name, conditions, actions, enabled, position";
+
+impl Rule {
+    pub async fn list(client: &deadpool_postgres::Client, user_id: i32) -> Result<Vec<Self>> {
+        let _timer = QueryTimer::start("Rule::list");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {RULE_COLUMNS} FROM rules WHERE user_id = $1 ORDER BY position, id"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&user_id]).await?;
+        Ok(rows.iter().map(Self::from_row).collect())
+    }
+
+    pub async fn find(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        id: i32,
+    ) -> Result<Option<Self>> {
+        let _timer = QueryTimer::start("Rule::find");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {RULE_COLUMNS} FROM rules WHERE user_id = $1 AND id = $2"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&user_id, &id]).await?;
+        Ok(rows.first().map(Self::from_row))
+    }
+
+    /// Creates the rule, after the existing ones when `position` is `None`.
+    pub async fn create(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        name: &str,
+        conditions: &[Condition],
+        actions: &[Action],
+        enabled: bool,
+        position: Option<i32>,
+    ) -> Result<Self> {
+        let _timer = QueryTimer::start("Rule::create");
+        let stmt = client
+            .prepare(&format!(
+                "INSERT INTO rules (user_id, name, conditions, actions, enabled, position)
+                VALUES ($1, $2, $3, $4, $5, COALESCE($6,
+                    (SELECT COALESCE(MAX(position) + 1, 0) FROM rules WHERE user_id = $1)))
+                RETURNING {RULE_COLUMNS}"
+            ))
+            .await?;
+        let row = client
+            .query_one(
+                &stmt,
+                &[
+                    &user_id,
+                    &name,
+                    &Json(conditions),
+                    &Json(actions),
+                    &enabled,
+                    &position,
+                ],
+            )
+            .await?;
+        Ok(Self::from_row(&row))
+    }
+
+    pub async fn update(&self, client: &deadpool_postgres::Client) -> Result<()> {
+        let _timer = QueryTimer::start("Rule::update");
+        let stmt = client
+            .prepare(
+                "UPDATE rules SET name = $1, conditions = $2, actions = $3, enabled = $4,
+                position = $5 WHERE id = $6",
+            )
+            .await?;
+        client
+            .execute(
+                &stmt,
+                &[
+                    &self.name,
+                    &Json(&self.conditions),
+                    &Json(&self.actions),
+                    &self.enabled,
+                    &self.position,
+                    &self.id,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Renumbers the rules of the user in the order of `ids`, the rules left
+    /// out keep their order after them.
+    pub async fn reorder(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        ids: &[i32],
+    ) -> Result<Vec<Self>> {
+        let _timer = QueryTimer::start("Rule::reorder");
+        let stmt = client
+            .prepare(
+                "UPDATE rules SET position = ordered.position
+                FROM (
+                    SELECT id, (ROW_NUMBER() OVER (
+                        ORDER BY array_position($2::int4[], id) NULLS LAST, position, id
+                    ) - 1)::int4 AS position
+                    FROM rules WHERE user_id = $1
+                ) ordered
+                WHERE rules.id = ordered.id AND rules.position <> ordered.position",
+            )
+            .await?;
+        client.execute(&stmt, &[&user_id, &ids]).await?;
+        Self::list(client, user_id).await
+    }
+
+    /// Deletes the rule, returning whether it existed.
+    pub async fn delete(client: &deadpool_postgres::Client, user_id: i32, id: i32) -> Result<bool> {
+        let _timer = QueryTimer::start("Rule::delete");
+        let stmt = client
+            .prepare("DELETE FROM rules WHERE user_id = $1 AND id = $2")
+            .await?;
+        Ok(client.execute(&stmt, &[&user_id, &id]).await? > 0)
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        let Json(conditions) = row.get(3);
+        let Json(actions) = row.get(4);
+        Self {
+            id: row.get(0),
+            user_id: row.get(1),
+            name: row.get(2),
+            conditions,
+            actions,
+            enabled: row.get(5),
+            position: row.get(6),
+        }
+    }
+}
+
+/// A signature appended to the emails the user sends, the default one unless
+/// they pick another.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct Signature {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub html: String,
+    /// Used on plain text emails, derived from `html` when missing
+    pub text: Option<String>,
+    pub is_default: bool,
+}
+
+const SIGNATURE_COLUMNS: &str = "id, user_id, name, html, text, is_default";
+
+impl Signature {
+    pub async fn list(client: &deadpool_postgres::Client, user_id: i32) -> Result<Vec<Self>> {
+        let _timer = QueryTimer::start("Signature::list");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {SIGNATURE_COLUMNS} FROM signatures WHERE user_id = $1 ORDER BY id"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&user_id]).await?;
+        Ok(rows.iter().map(Self::from_row).collect())
+    }
+
+    pub async fn find(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        id: i32,
+    ) -> Result<Option<Self>> {
+        let _timer = QueryTimer::start("Signature::find");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {SIGNATURE_COLUMNS} FROM signatures WHERE user_id = $1 AND id = $2"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&user_id, &id]).await?;
+        Ok(rows.first().map(Self::from_row))
+    }
+
+    pub async fn find_default(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+    ) -> Result<Option<Self>> {
+        let _timer = QueryTimer::start("Signature::find_default");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {SIGNATURE_COLUMNS} FROM signatures WHERE user_id = $1 AND is_default"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&user_id]).await?;
+        Ok(rows.first().map(Self::from_row))
+    }
+
+    /// Creates the signature, replacing the default one when `is_default`.
+    pub async fn create(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        name: &str,
+        html: &str,
+        text: Option<&str>,
+        is_default: bool,
+    ) -> Result<Self> {
+        let _timer = QueryTimer::start("Signature::create");
+        if is_default {
+            Self::clear_default(client, user_id, None).await?;
+        }
+
+        let stmt = client
+            .prepare(&format!(
+                "INSERT INTO signatures (user_id, name, html, text, is_default)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING {SIGNATURE_COLUMNS}"
+            ))
+            .await?;
+        let row = client
+            .query_one(&stmt, &[&user_id, &name, &html, &text, &is_default])
+            .await?;
+        Ok(Self::from_row(&row))
+    }
+
+    pub async fn update(&self, client: &deadpool_postgres::Client) -> Result<()> {
+        let _timer = QueryTimer::start("Signature::update");
+        if self.is_default {
+            Self::clear_default(client, self.user_id, Some(self.id)).await?;
+        }
+
         let stmt = client
-            .prepare("UPDATE users SET access_token = $1, refresh_token = $2 WHERE email = $3")
+            .prepare(
+                "UPDATE signatures SET name = $1, html = $2, text = $3, is_default = $4
+                WHERE id = $5",
+            )
             .await?;
         client
-            .execute(&stmt, &[&access_token, &refresh_token, &self.email])
+            .execute(
+                &stmt,
+                &[
+                    &self.name,
+                    &self.html,
+                    &self.text,
+                    &self.is_default,
+                    &self.id,
+                ],
+            )
             .await?;
         Ok(())
     }
+
+    /// Deletes the signature, returning whether it existed.
+    pub async fn delete(client: &deadpool_postgres::Client, user_id: i32, id: i32) -> Result<bool> {
+        let _timer = QueryTimer::start("Signature::delete");
+        let stmt = client
+            .prepare("DELETE FROM signatures WHERE user_id = $1 AND id = $2")
+            .await?;
+        Ok(client.execute(&stmt, &[&user_id, &id]).await? > 0)
+    }
+
+    /// Unsets the default signature of the user, other than `except`.
+    async fn clear_default(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        except: Option<i32>,
+    ) -> Result<()> {
+        let _timer = QueryTimer::start("Signature::clear_default");
+        let stmt = client
+            .prepare(
+                "UPDATE signatures SET is_default = FALSE
+                WHERE user_id = $1 AND is_default AND id IS DISTINCT FROM $2",
+            )
+            .await?;
+        client.execute(&stmt, &[&user_id, &except]).await?;
+        Ok(())
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            id: row.get(0),
+            user_id: row.get(1),
+            name: row.get(2),
+            html: row.get(3),
+            text: row.get(4),
+            is_default: row.get(5),
+        }
+    }
 }
 
-/// Creates a Deadpool configuration from a database URL.
-fn create_deadpool_config_from_url(url: &str) -> std::result::Result<Config, url::ParseError> {
+/// A Graph change notification subscription of a user, used to route the
+/// notifications Graph sends to them.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct WebhookSubscription {
+    pub id: String,
+    pub user_id: i32,
+    pub user_email: String,
+    /// Secret Graph sends back with each notification
+    #[serde(skip)]
+    pub client_state: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+const WEBHOOK_SUBSCRIPTION_COLUMNS: &str =
+    "subscriptions.id, user_id, users.email, client_state, expires_at";
+
+impl WebhookSubscription {
+    pub async fn list(client: &deadpool_postgres::Client, user_id: i32) -> Result<Vec<Self>> {
+        let _timer = QueryTimer::start("WebhookSubscription::list");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {WEBHOOK_SUBSCRIPTION_COLUMNS} FROM subscriptions
+                JOIN users ON users.id = subscriptions.user_id
+                WHERE user_id = $1 ORDER BY expires_at"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&user_id]).await?;
+        Ok(rows.iter().map(Self::from_row).collect())
+    }
+
+    /// Finds a subscription of any user, notifications only carry its id.
+    pub async fn find(client: &deadpool_postgres::Client, id: &str) -> Result<Option<Self>> {
+        let _timer = QueryTimer::start("WebhookSubscription::find");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {WEBHOOK_SUBSCRIPTION_COLUMNS} FROM subscriptions
+                JOIN users ON users.id = subscriptions.user_id
+                WHERE subscriptions.id = $1"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&id]).await?;
+        Ok(rows.first().map(Self::from_row))
+    }
+
+    pub async fn create(
+        client: &deadpool_postgres::Client,
+        id: &str,
+        user_id: i32,
+        client_state: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let _timer = QueryTimer::start("WebhookSubscription::create");
+        let stmt = client
+            .prepare(
+                "INSERT INTO subscriptions (id, user_id, client_state, expires_at)
+                VALUES ($1, $2, $3, $4)",
+            )
+            .await?;
+        client
+            .execute(&stmt, &[&id, &user_id, &client_state, &expires_at])
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_expires_at(
+        &mut self,
+        client: &deadpool_postgres::Client,
+        expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let _timer = QueryTimer::start("WebhookSubscription::set_expires_at");
+        let stmt = client
+            .prepare("UPDATE subscriptions SET expires_at = $1 WHERE id = $2")
+            .await?;
+        client.execute(&stmt, &[&expires_at, &self.id]).await?;
+        self.expires_at = expires_at;
+        Ok(())
+    }
+
+    /// Deletes the subscription, returning whether it existed.
+    pub async fn delete(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        id: &str,
+    ) -> Result<bool> {
+        let _timer = QueryTimer::start("WebhookSubscription::delete");
+        let stmt = client
+            .prepare("DELETE FROM subscriptions WHERE user_id = $1 AND id = $2")
+            .await?;
+        Ok(client.execute(&stmt, &[&user_id, &id]).await? > 0)
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            id: row.get(0),
+            user_id: row.get(1),
+            user_email: row.get(2),
+            client_state: row.get(3),
+            expires_at: row.get(4),
+        }
+    }
+}
+
+/// Where the incremental sync of a folder of the user stands.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct SyncState {
+    pub user_id: i32,
+    /// What the folder is synced for, [`SyncState::API`] or
+    /// [`SyncState::INDEX`]
+    pub consumer: String,
+    pub folder_id: String,
+    /// Token to get the changes made since the last sync from Graph
+    #[serde(skip)]
+    pub delta_token: Option<String>,
+    pub last_synced_at: Option<DateTime<Utc>>,
+    /// How many messages the last sync got, the whole folder when it started
+    /// over
+    pub item_count: i32,
+}
+
+const SYNC_STATE_COLUMNS: &str =
+    "user_id, consumer, folder_id, delta_token, last_synced_at, item_count";
+
+impl SyncState {
+    /// Syncs of the client of `/api/folders/{id}/delta`.
+    pub const API: &'static str = "api";
+    /// Syncs of the search index, by the `incremental_index` task.
+    pub const INDEX: &'static str = "index";
+
+    pub async fn list(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        consumer: &str,
+    ) -> Result<Vec<Self>> {
+        let _timer = QueryTimer::start("SyncState::list");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {SYNC_STATE_COLUMNS} FROM sync_state
+                WHERE user_id = $1 AND consumer = $2 ORDER BY folder_id"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&user_id, &consumer]).await?;
+        Ok(rows.iter().map(Self::from_row).collect())
+    }
+
+    pub async fn find(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        consumer: &str,
+        folder_id: &str,
+    ) -> Result<Option<Self>> {
+        let _timer = QueryTimer::start("SyncState::find");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {SYNC_STATE_COLUMNS} FROM sync_state
+                WHERE user_id = $1 AND consumer = $2 AND folder_id = $3"
+            ))
+            .await?;
+        let rows = client
+            .query(&stmt, &[&user_id, &consumer, &folder_id])
+            .await?;
+        Ok(rows.first().map(Self::from_row))
+    }
+
+    /// Records a sync of the folder which just ended, returning the new state.
+    pub async fn record(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        consumer: &str,
+        folder_id: &str,
+        delta_token: Option<&str>,
+        item_count: i32,
+    ) -> Result<Self> {
+        let _timer = QueryTimer::start("SyncState::record");
+        let stmt = client
+            .prepare(&format!(
+                "INSERT INTO sync_state
+                (user_id, consumer, folder_id, delta_token, last_synced_at, item_count)
+                VALUES ($1, $2, $3, $4, NOW(), $5)
+                ON CONFLICT (user_id, consumer, folder_id) DO UPDATE
+                SET delta_token = $4, last_synced_at = NOW(), item_count = $5
+                RETURNING {SYNC_STATE_COLUMNS}"
+            ))
+            .await?;
+        let row = client
+            .query_one(
+                &stmt,
+                &[&user_id, &consumer, &folder_id, &delta_token, &item_count],
+            )
+            .await?;
+        Ok(Self::from_row(&row))
+    }
+
+    /// Forgets the sync of the folder so the next one starts over, returning
+    /// whether there was one.
+    pub async fn reset(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        consumer: &str,
+        folder_id: &str,
+    ) -> Result<bool> {
+        let _timer = QueryTimer::start("SyncState::reset");
+        let stmt = client
+            .prepare(
+                "DELETE FROM sync_state WHERE user_id = $1 AND consumer = $2 AND folder_id = $3",
+            )
+            .await?;
+        Ok(client
+            .execute(&stmt, &[&user_id, &consumer, &folder_id])
+            .await?
+            > 0)
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            user_id: row.get(0),
+            consumer: row.get(1),
+            folder_id: row.get(2),
+            delta_token: row.get(3),
+            last_synced_at: row.get(4),
+            item_count: row.get(5),
+        }
+    }
+}
+
+/// The cached metadata of a message, to list the mailbox without asking Graph.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub id: String,
+    pub internet_message_id: Option<String>,
+    pub folder_id: String,
+    pub subject: String,
+    pub sender: Option<String>,
+    pub received_at: DateTime<Utc>,
+    pub is_read: bool,
+    pub flag_status: String,
+    /// The message as Graph lists it, without its body
+    pub email: Email,
+}
+
+impl Message {
+    /// Caches the email, without its body and attachments. Emails without a
+    /// valid received time can't be ordered and are left out.
+    pub fn from_email(email: &Email) -> Option<Self> {
+        let received_at = DateTime::parse_from_rfc3339(&email.received_date_time)
+            .ok()?
+            .with_timezone(&Utc);
+        let flag_status = serde_json::to_value(&email.flag.flag_status)
+            .ok()
+            .and_then(|status| status.as_str().map(str::to_string))
+            .unwrap_or_default();
+        Some(Self {
+            id: email.id.clone(),
+            internet_message_id: Some(email.internet_message_id.clone())
+                .filter(|id| !id.is_empty()),
+            folder_id: email.parent_folder_id.clone(),
+            subject: email.subject.clone(),
+            sender: email
+                .from
+                .as_ref()
+                .or(email.sender.as_ref())
+                .and_then(|sender| sender.email_address.address.clone()),
+            received_at,
+            is_read: email.is_read,
+            flag_status,
+            email: Email {
+                body: Body::default(),
+                attachments: None,
+                ..email.clone()
+            },
+        })
+    }
+
+    /// Caches the emails, replacing what was cached of them.
+    pub async fn upsert_many(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        emails: &[Email],
+    ) -> Result<()> {
+        let _timer = QueryTimer::start("Message::upsert_many");
+        let stmt = client
+            .prepare(
+                "INSERT INTO messages (user_id, id, internet_message_id, folder_id, subject,
+                    sender, received_at, is_read, flag_status, data)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                ON CONFLICT (user_id, id) DO UPDATE SET
+                    internet_message_id = $3, folder_id = $4, subject = $5, sender = $6,
+                    received_at = $7, is_read = $8, flag_status = $9, data = $10,
+                    synced_at = NOW()",
+            )
+            .await?;
+        for message in emails.iter().filter_map(Self::from_email) {
+            client
+                .execute(
+                    &stmt,
+                    &[
+                        &user_id,
+                        &message.id,
+                        &message.internet_message_id,
+                        &message.folder_id,
+                        &message.subject,
+                        &message.sender,
+                        &message.received_at,
+                        &message.is_read,
+                        &message.flag_status,
+                        &Json(&message.email),
+                    ],
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn delete_many(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        ids: &[String],
+    ) -> Result<()> {
+        let _timer = QueryTimer::start("Message::delete_many");
+        let stmt = client
+            .prepare("DELETE FROM messages WHERE user_id = $1 AND id = ANY($2)")
+            .await?;
+        client.execute(&stmt, &[&user_id, &ids]).await?;
+        Ok(())
+    }
+
+    /// Deletes the messages a full sync started at `started_at` didn't see,
+    /// which left the mailbox since they were cached.
+    pub async fn delete_synced_before(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        started_at: DateTime<Utc>,
+    ) -> Result<u64> {
+        let _timer = QueryTimer::start("Message::delete_synced_before");
+        let stmt = client
+            .prepare("DELETE FROM messages WHERE user_id = $1 AND synced_at < $2")
+            .await?;
+        Ok(client.execute(&stmt, &[&user_id, &started_at]).await?)
+    }
+
+    /// Returns a page of the cached emails, the latest first, along with how
+    /// many are cached.
+    pub async fn page(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<Email>, i64)> {
+        let _timer = QueryTimer::start("Message::page");
+        let stmt = client
+            .prepare(
+                "SELECT data FROM messages WHERE user_id = $1
+                ORDER BY received_at DESC, id LIMIT $2 OFFSET $3",
+            )
+            .await?;
+        let rows = client.query(&stmt, &[&user_id, &limit, &offset]).await?;
+        let emails = rows
+            .iter()
+            .map(|row| row.get::<_, Json<Email>>(0).0)
+            .collect();
+
+        let stmt = client
+            .prepare("SELECT COUNT(*) FROM messages WHERE user_id = $1")
+            .await?;
+        let total = client.query_one(&stmt, &[&user_id]).await?.get(0);
+        Ok((emails, total))
+    }
+}
+
+/// A browser session, signed in with the cookie holding the secret it's the
+/// hash of.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct Session {
+    /// The hash of the secret, which can't sign in by itself
+    pub id: String,
+    pub user_id: i32,
+    pub user_email: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// The browser the session was started from
+    pub user_agent: Option<String>,
+}
+
+/// Longer user agents are cut, they only help telling devices apart.
+pub const MAX_USER_AGENT_LENGTH: usize = 512;
+
+const SESSION_COLUMNS: &str =
+    "sessions.id, user_id, users.email, sessions.created_at, expires_at, user_agent";
+
+impl Session {
+    /// Finds a session which hasn't expired yet.
+    pub async fn find(client: &deadpool_postgres::Client, id: &str) -> Result<Option<Self>> {
+        let _timer = QueryTimer::start("Session::find");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {SESSION_COLUMNS} FROM sessions
+                JOIN users ON users.id = sessions.user_id
+                WHERE sessions.id = $1 AND expires_at > NOW()"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&id]).await?;
+        Ok(rows.first().map(Self::from_row))
+    }
+
+    /// The sessions of the user which haven't expired yet, the latest first.
+    pub async fn list(client: &deadpool_postgres::Client, user_id: i32) -> Result<Vec<Self>> {
+        let _timer = QueryTimer::start("Session::list");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {SESSION_COLUMNS} FROM sessions
+                JOIN users ON users.id = sessions.user_id
+                WHERE user_id = $1 AND expires_at > NOW()
+                ORDER BY sessions.created_at DESC"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&user_id]).await?;
+        Ok(rows.iter().map(Self::from_row).collect())
+    }
+
+    pub async fn create(
+        client: &impl deadpool_postgres::GenericClient,
+        id: &str,
+        user_id: i32,
+        expires_at: DateTime<Utc>,
+        user_agent: Option<&str>,
+    ) -> Result<Self> {
+        let _timer = QueryTimer::start("Session::create");
+        let user_agent = user_agent.map(|user_agent| {
+            user_agent
+                .chars()
+                .take(MAX_USER_AGENT_LENGTH)
+                .collect::<String>()
+        });
+        let stmt = client
+            .prepare(
+                "INSERT INTO sessions (id, user_id, expires_at, user_agent)
+                VALUES ($1, $2, $3, $4)
+                RETURNING id, user_id, (SELECT email FROM users WHERE users.id = user_id),
+                    created_at, expires_at, user_agent",
+            )
+            .await?;
+        let row = client
+            .query_one(&stmt, &[&id, &user_id, &expires_at, &user_agent])
+            .await?;
+        Ok(Self::from_row(&row))
+    }
+
+    /// Deletes the session, returning whether it existed.
+    pub async fn delete(client: &deadpool_postgres::Client, id: &str) -> Result<bool> {
+        let _timer = QueryTimer::start("Session::delete");
+        let stmt = client.prepare("DELETE FROM sessions WHERE id = $1").await?;
+        Ok(client.execute(&stmt, &[&id]).await? > 0)
+    }
+
+    /// Deletes a session of the user, returning whether it existed.
+    pub async fn delete_for_user(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        id: &str,
+    ) -> Result<bool> {
+        let _timer = QueryTimer::start("Session::delete_for_user");
+        let stmt = client
+            .prepare("DELETE FROM sessions WHERE user_id = $1 AND id = $2")
+            .await?;
+        Ok(client.execute(&stmt, &[&user_id, &id]).await? > 0)
+    }
+
+    /// Signs the user out of every device, returning how many sessions ended.
+    pub async fn delete_all(client: &deadpool_postgres::Client, user_id: i32) -> Result<u64> {
+        let _timer = QueryTimer::start("Session::delete_all");
+        let stmt = client
+            .prepare("DELETE FROM sessions WHERE user_id = $1")
+            .await?;
+        Ok(client.execute(&stmt, &[&user_id]).await?)
+    }
+
+    /// Deletes the sessions which expired, returning how many there were.
+    pub async fn delete_expired(client: &deadpool_postgres::Client) -> Result<u64> {
+        let _timer = QueryTimer::start("Session::delete_expired");
+        let stmt = client
+            .prepare("DELETE FROM sessions WHERE expires_at <= NOW()")
+            .await?;
+        Ok(client.execute(&stmt, &[]).await?)
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            id: row.get(0),
+            user_id: row.get(1),
+            user_email: row.get(2),
+            created_at: row.get(3),
+            expires_at: row.get(4),
+            user_agent: row.get(5),
+        }
+    }
+}
+
+/// A key scripts authenticate with as their user, instead of signing in.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct ApiKey {
+    pub id: i32,
+    #[serde(skip)]
+    pub user_id: i32,
+    #[serde(skip)]
+    pub user_email: String,
+    pub name: String,
+    /// The start of the key, to tell keys apart
+    pub prefix: String,
+    /// `read` and `write`
+    pub scopes: Vec<String>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+const API_KEY_COLUMNS: &str = "api_keys.id, user_id, users.email, name, prefix, api_keys.scopes, \
+    last_used_at, revoked_at, api_keys.created_at";
+
+impl ApiKey {
+    pub async fn list(client: &deadpool_postgres::Client, user_id: i32) -> Result<Vec<Self>> {
+        let _timer = QueryTimer::start("ApiKey::list");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {API_KEY_COLUMNS} FROM api_keys
+                JOIN users ON users.id = api_keys.user_id
+                WHERE user_id = $1 ORDER BY api_keys.id"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&user_id]).await?;
+        Ok(rows.iter().map(Self::from_row).collect())
+    }
+
+    /// Finds the key with the hash, unless it was revoked.
+    pub async fn find_active(
+        client: &deadpool_postgres::Client,
+        key_hash: &str,
+    ) -> Result<Option<Self>> {
+        let _timer = QueryTimer::start("ApiKey::find_active");
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {API_KEY_COLUMNS} FROM api_keys
+                JOIN users ON users.id = api_keys.user_id
+                WHERE key_hash = $1 AND revoked_at IS NULL"
+            ))
+            .await?;
+        let rows = client.query(&stmt, &[&key_hash]).await?;
+        Ok(rows.first().map(Self::from_row))
+    }
+
+    pub async fn create(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        name: &str,
+        key_hash: &str,
+        prefix: &str,
+        scopes: &[String],
+    ) -> Result<Self> {
+        let _timer = QueryTimer::start("ApiKey::create");
+        let stmt = client
+            .prepare(
+                "INSERT INTO api_keys (user_id, name, key_hash, prefix, scopes)
+                VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            )
+            .await?;
+        let id: i32 = client
+            .query_one(&stmt, &[&user_id, &name, &key_hash, &prefix, &scopes])
+            .await?
+            .get(0);
+
+        let stmt = client
+            .prepare(&format!(
+                "SELECT {API_KEY_COLUMNS} FROM api_keys
+                JOIN users ON users.id = api_keys.user_id
+                WHERE api_keys.id = $1"
+            ))
+            .await?;
+        let row = client.query_one(&stmt, &[&id]).await?;
+        Ok(Self::from_row(&row))
+    }
+
+    pub async fn touch(&self, client: &deadpool_postgres::Client) -> Result<()> {
+        let _timer = QueryTimer::start("ApiKey::touch");
+        let stmt = client
+            .prepare("UPDATE api_keys SET last_used_at = NOW() WHERE id = $1")
+            .await?;
+        client.execute(&stmt, &[&self.id]).await?;
+        Ok(())
+    }
+
+    /// Revokes the key, returning whether it was active.
+    pub async fn revoke(client: &deadpool_postgres::Client, user_id: i32, id: i32) -> Result<bool> {
+        let _timer = QueryTimer::start("ApiKey::revoke");
+        let stmt = client
+            .prepare(
+                "UPDATE api_keys SET revoked_at = NOW()
+                WHERE user_id = $1 AND id = $2 AND revoked_at IS NULL",
+            )
+            .await?;
+        Ok(client.execute(&stmt, &[&user_id, &id]).await? > 0)
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            id: row.get(0),
+            user_id: row.get(1),
+            user_email: row.get(2),
+            name: row.get(3),
+            prefix: row.get(4),
+            scopes: row.get(5),
+            last_used_at: row.get(6),
+            revoked_at: row.get(7),
+            created_at: row.get(8),
+        }
+    }
+}
+
+/// The response to a request sent with an `Idempotency-Key`, replayed when the
+/// client retries it.
+#[derive(Debug)]
+pub struct IdempotencyKey {
+    pub method: String,
+    pub path: String,
+    /// `None` while the first request is still being handled
+    pub status: Option<i16>,
+    pub content_type: Option<String>,
+    pub body: Option<Vec<u8>>,
+}
+
+/// How long a key can't be reused for another request.
+const IDEMPOTENCY_KEY_TTL: &str = "24 hours";
+
+impl IdempotencyKey {
+    /// Claims `key` for a request, returning false when an unexpired request
+    /// already holds it.
+    pub async fn claim(
+        client: &deadpool_postgres::Client,
+        owner: &str,
+        key: &str,
+        method: &str,
+        path: &str,
+    ) -> Result<bool> {
+        let _timer = QueryTimer::start("IdempotencyKey::claim");
+        // Expired keys of the owner are cleaned up along the way
+        let stmt = client
+            .prepare(&format!(
+                "DELETE FROM idempotency_keys
+                WHERE owner = $1 AND created_at < NOW() - INTERVAL '{IDEMPOTENCY_KEY_TTL}'"
+            ))
+            .await?;
+        client.execute(&stmt, &[&owner]).await?;
+
+        let stmt = client
+            .prepare(
+                "INSERT INTO idempotency_keys (owner, key, method, path)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (owner, key) DO NOTHING
+                RETURNING 1",
+            )
+            .await?;
+        let rows = client.query(&stmt, &[&owner, &key, &method, &path]).await?;
+        Ok(!rows.is_empty())
+    }
+
+    pub async fn find(
+        client: &deadpool_postgres::Client,
+        owner: &str,
+        key: &str,
+    ) -> Result<Option<Self>> {
+        let _timer = QueryTimer::start("IdempotencyKey::find");
+        let stmt = client
+            .prepare(
+                "SELECT method, path, status, content_type, body FROM idempotency_keys
+                WHERE owner = $1 AND key = $2",
+            )
+            .await?;
+        let rows = client.query(&stmt, &[&owner, &key]).await?;
+        Ok(rows.first().map(|row| Self {
+            method: row.get(0),
+            path: row.get(1),
+            status: row.get(2),
+            content_type: row.get(3),
+            body: row.get(4),
+        }))
+    }
+
+    /// Stores the response of the request holding `key`.
+    pub async fn complete(
+        client: &deadpool_postgres::Client,
+        owner: &str,
+        key: &str,
+        status: i16,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> Result<()> {
+        let _timer = QueryTimer::start("IdempotencyKey::complete");
+        let stmt = client
+            .prepare(
+                "UPDATE idempotency_keys SET status = $3, content_type = $4, body = $5
+                WHERE owner = $1 AND key = $2",
+            )
+            .await?;
+        client
+            .execute(&stmt, &[&owner, &key, &status, &content_type, &body])
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes the keys of the user, those of their linked accounts included,
+    /// returning how many there were.
+    pub async fn delete_for_user(
+        client: &impl deadpool_postgres::GenericClient,
+        email: &str,
+    ) -> Result<u64> {
+        let _timer = QueryTimer::start("IdempotencyKey::delete_for_user");
+        let stmt = client
+            .prepare(
+                "DELETE FROM idempotency_keys
+                WHERE owner = $1 OR starts_with(owner, $1 || '/')",
+            )
+            .await?;
+        Ok(client.execute(&stmt, &[&email]).await?)
+    }
+
+    /// Frees `key` so the request can be retried, after it failed on our end.
+    pub async fn release(client: &deadpool_postgres::Client, owner: &str, key: &str) -> Result<()> {
+        let _timer = QueryTimer::start("IdempotencyKey::release");
+        let stmt = client
+            .prepare("DELETE FROM idempotency_keys WHERE owner = $1 AND key = $2")
+            .await?;
+        client.execute(&stmt, &[&owner, &key]).await?;
+        Ok(())
+    }
+}
+
+/// Creates a Deadpool configuration from a database URL, with the pool sized
+/// by `DATABASE_POOL_SIZE` and `DATABASE_POOL_TIMEOUT`, in seconds, when set.
+///
+/// The `application_name`, `connect_timeout`, in seconds, and `options` query
+/// parameters are passed on to Postgres, others are ignored.
+fn create_deadpool_config_from_url(url: &str) -> Result<Config> {
     let parsed_url = Url::parse(url)?;
 
-    let config = Config {
+    let mut config = Config {
         user: Some(parsed_url.username().to_owned()),
         password: parsed_url.password().map(ToString::to_string),
         host: Some(parsed_url.host_str().unwrap().to_owned()),
@@ -144,10 +1674,87 @@ fn create_deadpool_config_from_url(url: &str) -> std::result::Result<Config, url
         ..Default::default()
     };
 
-    // TODO
-    // for (key, value) in parsed_url.query_pairs() {
-    //     config.options.push((key.to_owned(), value.to_owned()));
-    // }
+    for (key, value) in parsed_url.query_pairs() {
+        match key.as_ref() {
+            "application_name" => config.application_name = Some(value.into_owned()),
+            "connect_timeout" => config.connect_timeout = Some(parse_seconds(&key, &value)?),
+            "options" => config.options = Some(value.into_owned()),
+            _ => warn!("Ignoring the unsupported database URL parameter {key}"),
+        }
+    }
+
+    // Empty values, as left in `.env` files, are unset
+    let var = |name| env::var(name).ok().filter(|value| !value.is_empty());
+    let size = var("DATABASE_POOL_SIZE");
+    let timeout = var("DATABASE_POOL_TIMEOUT");
+    config.pool = pool_config(size.as_deref(), timeout.as_deref())?;
 
     Ok(config)
 }
+
+/// The pool settings, deadpool's defaults when neither is given.
+fn pool_config(size: Option<&str>, timeout: Option<&str>) -> Result<Option<PoolConfig>> {
+    if size.is_none() && timeout.is_none() {
+        return Ok(None);
+    }
+
+    let mut config = PoolConfig::default();
+    if let Some(size) = size {
+        config.max_size = match size.parse() {
+            Ok(size) if size > 0 => size,
+            _ => {
+                return Err(DatabaseError::Config(format!(
+                    "DATABASE_POOL_SIZE must be a positive number, got {size:?}"
+                )))
+            }
+        };
+    }
+    if let Some(timeout) = timeout {
+        config.timeouts.wait = Some(parse_seconds("DATABASE_POOL_TIMEOUT", timeout)?);
+    }
+    Ok(Some(config))
+}
+
+fn parse_seconds(name: &str, value: &str) -> Result<Duration> {
+    value
+        .parse()
+        .map(Duration::from_secs)
+        .map_err(|_| DatabaseError::Config(format!("{name} must be in seconds, got {value:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_deadpool_config_from_url() {
+        let config = create_deadpool_config_from_url(
+            "postgres://postars:secret@db:5433/mail?application_name=postars-api&connect_timeout=5&options=-c%20statement_timeout%3D10s&sslmode=disable",
+        )
+        .unwrap();
+        assert_eq!(config.user.as_deref(), Some("postars"));
+        assert_eq!(config.password.as_deref(), Some("secret"));
+        assert_eq!(config.host.as_deref(), Some("db"));
+        assert_eq!(config.port, Some(5433));
+        assert_eq!(config.dbname.as_deref(), Some("mail"));
+        assert_eq!(config.application_name.as_deref(), Some("postars-api"));
+        assert_eq!(config.connect_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(config.options.as_deref(), Some("-c statement_timeout=10s"));
+
+        assert!(
+            create_deadpool_config_from_url("postgres://db/mail?connect_timeout=soon").is_err()
+        );
+    }
+
+    #[test]
+    fn test_pool_config() {
+        assert!(pool_config(None, None).unwrap().is_none());
+
+        let config = pool_config(Some("32"), Some("5")).unwrap().unwrap();
+        assert_eq!(config.max_size, 32);
+        assert_eq!(config.timeouts.wait, Some(Duration::from_secs(5)));
+
+        assert!(pool_config(Some("0"), None).is_err());
+        assert!(pool_config(None, Some("-1")).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use anyhow::bail;
+use futures::{channel::mpsc, stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{AsyncMessage, NoTls};
+use tracing::{info, warn};
+
+use crate::database::QueryTimer;
+
+/// The Postgres channel mailbox changes are announced on.
+const CHANNEL: &str = "postars_mailbox_changes";
+
+/// Wait before listening again after the connection dropped.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A change to a message of a user, announced to every server so they can push
+/// it to the clients connected to them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailboxChange {
+    pub user_email: String,
+    /// `created`, `updated` or `deleted`
+    pub change_type: String,
+    /// Id of the message
+    pub id: String,
+}
+
+/// Announces the change to the servers listening, from any process. It's only
+/// sent once the transaction of `client`, if any, commits.
+pub async fn publish(
+    client: &impl deadpool_postgres::GenericClient,
+    change: &MailboxChange,
+) -> anyhow::Result<()> {
+    let payload = serde_json::to_string(change)?;
+    let _timer = QueryTimer::start("events::publish");
+    client
+        .execute("SELECT pg_notify($1, $2)", &[&CHANNEL, &payload])
+        .await?;
+    Ok(())
+}
+
+/// Calls `on_change` with the changes published by any process, listening
+/// again whenever the connection drops.
+pub async fn listen<F>(database_url: String, on_change: F)
+where
+    F: Fn(MailboxChange) + Send + Sync + 'static,
+{
+    loop {
+        if let Err(err) = listen_once(&database_url, &on_change).await {
+            warn!("Stopped listening to mailbox changes: {err}");
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn listen_once<F>(database_url: &str, on_change: &F) -> anyhow::Result<()>
+where
+    F: Fn(MailboxChange),
+{
+    let (client, mut connection) = tokio_postgres::connect(database_url, NoTls).await?;
+
+    // Notifications come out of the connection, which has to be polled for
+    // the client to work at all
+    let (sender, mut messages) = mpsc::unbounded();
+    let messages_stream = stream::poll_fn(move |cx| connection.poll_message(cx));
+    tokio::spawn(messages_stream.map(Ok).forward(sender));
+
+    client.batch_execute(&format!("LISTEN {CHANNEL}")).await?;
+    info!("Listening to mailbox changes");
+
+    while let Some(message) = messages.next().await {
+        if let AsyncMessage::Notification(notification) = message? {
+            match serde_json::from_str(notification.payload()) {
+                Ok(change) => on_change(change),
+                Err(err) => warn!("Ignoring a malformed mailbox change: {err}"),
+            }
+        }
+    }
+    bail!("the connection closed")
+}
@@ -0,0 +1,3833 @@
+use std::{
+    collections::HashMap,
+    io::{Cursor, SeekFrom},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use rand::Rng;
+use reqwest::{
+    header::RETRY_AFTER, Client, IntoUrl, Method, Proxy, RequestBuilder, Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tracing::{debug, debug_span, warn, Instrument};
+use url::Url;
+use utoipa::ToSchema;
+
+use crate::metrics::GRAPH_METRICS;
+
+pub mod notifications;
+
+/// Maximum number of requests Graph accepts in a single `$batch` call.
+const MAX_BATCH_SIZE: usize = 20;
+const ATTACHMENT_META_FIELDS: &str = "id,name,contentType,size,isInline";
+/// Timeout of Graph requests unless configured otherwise.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// `Prefer` header value asking for ids that survive moves between folders.
+const IMMUTABLE_ID_PREFERENCE: &str = "IdType=\"ImmutableId\"";
+const PREFER: &str = "Prefer";
+/// Number of items per page when streaming a whole collection.
+const STREAM_PAGE_SIZE: usize = 100;
+
+/// The message fields `Email` is made of, except for the body. Only meeting
+/// messages have `meetingMessageType`, so it's selected through their type.
+const EMAIL_FIELDS: &str = "id,createdDateTime,lastModifiedDateTime,receivedDateTime,\
+sentDateTime,hasAttachments,internetMessageId,subject,bodyPreview,importance,parentFolderId,\
+conversationId,conversationIndex,isDeliveryReceiptRequested,isReadReceiptRequested,isRead,\
+isDraft,webLink,inferenceClassification,sender,from,toRecipients,ccRecipients,bccRecipients,\
+replyTo,flag,categories,microsoft.graph.eventMessage/meetingMessageType";
+
+/// Folders Graph lets address by name whatever the language of the mailbox.
+const WELL_KNOWN_FOLDERS: &[&str] = &[
+    "archive",
+    "clutter",
+    "conflicts",
+    "conversationhistory",
+    "deleteditems",
+    "drafts",
+    "inbox",
+    "junkemail",
+    "localfailures",
+    "msgfolderroot",
+    "outbox",
+    "recoverableitemsdeletions",
+    "scheduled",
+    "searchfolders",
+    "sentitems",
+    "serverfailures",
+    "syncissues",
+];
+
+/// Attachments larger than this have to be sent through an upload session.
+pub const LARGE_ATTACHMENT_THRESHOLD: usize = 3 * 1024 * 1024;
+
+/// Size of each chunk sent to an upload session, Graph requires it to be a
+/// multiple of 320 KiB.
+const UPLOAD_CHUNK_SIZE: usize = 10 * 320 * 1024;
+
+/// Attempts at resuming an upload session after a chunk failed, in a row.
+const MAX_CHUNK_RETRIES: u32 = 3;
+
+/// Retries of a throttled or unavailable request before giving up.
+const MAX_RETRIES: u32 = 5;
+/// Delay before the first retry, doubled on every attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longest single wait, even when Graph asks for more with `Retry-After`.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+/// Requests are given up on after retrying for this long.
+const RETRY_MAX_TOTAL: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum GraphClientError {
+    #[error("HTTP Request Error: {0}")]
+    HttpRequest(#[from] reqwest::Error),
+
+    #[error("Request failed with status: {0}")]
+    Request(reqwest::StatusCode),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Failed to parse {0}: {1}")]
+    Parse(&'static str, Value),
+
+    #[error("Folder not found: {0}")]
+    FolderNotFound(String),
+
+    /// The message doesn't exist, or was already permanently deleted.
+    #[error("Message not found: {0}")]
+    MessageNotFound(String),
+
+    #[error("Graph API error: {0}")]
+    Api(GraphApiError),
+
+    #[error("Failed to get an access token: {0}")]
+    Token(anyhow::Error),
+
+    /// Reading the content to upload failed.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl GraphClientError {
+    /// Builds the error for a failed response, out of the error Graph describes
+    /// in its body when there's one.
+    async fn from_response(response: Response) -> Self {
+        let status = response.status();
+        match response.json::<Value>().await {
+            Ok(json) => GraphApiError::from_json(status, &json)
+                .map(GraphClientError::Api)
+                .unwrap_or(GraphClientError::Request(status)),
+            Err(_) => GraphClientError::Request(status),
+        }
+    }
+
+    /// The HTTP status Graph answered with, if it answered.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            GraphClientError::HttpRequest(err) => err.status(),
+            GraphClientError::Request(status) => Some(*status),
+            GraphClientError::Api(err) => Some(err.status),
+            GraphClientError::MessageNotFound(_) => Some(StatusCode::NOT_FOUND),
+            _ => None,
+        }
+    }
+}
+
+/// An error as described by Graph in the body of a failed response.
+#[derive(Debug, Clone)]
+pub struct GraphApiError {
+    pub status: StatusCode,
+    /// Like `ErrorItemNotFound` or `ApplicationThrottled`
+    pub code: String,
+    pub message: String,
+    /// Holds the ids of the request on Graph's side, useful to report issues
+    pub inner_error: Option<Value>,
+}
+
+impl GraphApiError {
+    fn from_json(status: StatusCode, json: &Value) -> Option<Self> {
+        let error = json.get("error")?;
+        Some(Self {
+            status,
+            code: error.get("code")?.as_str()?.to_string(),
+            message: error["message"].as_str().unwrap_or_default().to_string(),
+            inner_error: error.get("innerError").cloned(),
+        })
+    }
+}
+
+impl std::fmt::Display for GraphApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}: {}", self.status, self.code, self.message)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub business_phones: Vec<String>,
+    pub display_name: String,
+    pub given_name: String,
+    pub id: String,
+    pub job_title: Option<String>,
+    pub mail: String,
+    pub mobile_phone: Option<String>,
+    pub office_location: Option<String>,
+    pub preferred_language: Option<String>,
+    pub surname: String,
+    pub user_principal_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Folder {
+    pub child_folder_count: u32,
+    pub display_name: String,
+    pub id: String,
+    pub is_hidden: bool,
+    pub parent_folder_id: String,
+    pub size_in_bytes: u64,
+    pub total_item_count: u32,
+    pub unread_item_count: u32,
+    /// Only set when the folders are listed with `childFolders` expanded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub child_folders: Option<Vec<Folder>>,
+}
+
+/// An entry of the user's Outlook master category list.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Category {
+    pub id: String,
+    pub display_name: String,
+    /// One of Outlook's preset colors, like `preset0`, or `none`
+    pub color: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub id: String,
+    #[serde(deserialize_with = "deserialize_null_default")]
+    pub subject: String,
+    pub body_preview: String,
+    pub start: DateTimeTimeZone,
+    pub end: DateTimeTimeZone,
+    pub location: Option<Location>,
+    pub organizer: Option<EmailAddressWrapper>,
+    pub is_all_day: bool,
+    pub is_cancelled: bool,
+    pub response_requested: bool,
+    pub response_status: Option<ResponseStatus>,
+    pub web_link: String,
+}
+
+/// The user's mailbox settings, only the ones that are set are changed on
+/// updates.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MailboxSettings {
+    /// Time zone the user's dates are shown in, like `Pacific Standard Time`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_zone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub working_hours: Option<WorkingHours>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub automatic_replies_setting: Option<AutomaticRepliesSetting>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkingHours {
+    /// Like `monday`
+    pub days_of_week: Vec<String>,
+    /// Like `08:00:00.0000000`
+    pub start_time: String,
+    pub end_time: String,
+    pub time_zone: TimeZoneBase,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct TimeZoneBase {
+    pub name: String,
+}
+
+/// The out of office replies sent to incoming emails.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AutomaticRepliesSetting {
+    /// `disabled`, `alwaysEnabled` or `scheduled`
+    pub status: String,
+    /// Who gets the external reply: `none`, `contactsOnly` or `all`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_audience: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheduled_start_date_time: Option<DateTimeTimeZone>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheduled_end_date_time: Option<DateTimeTimeZone>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub internal_reply_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_reply_message: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DateTimeTimeZone {
+    pub date_time: String,
+    pub time_zone: String,
+}
+
+impl From<DateTime<Utc>> for DateTimeTimeZone {
+    fn from(date_time: DateTime<Utc>) -> Self {
+        Self {
+            date_time: date_time.format("%Y-%m-%dT%H:%M:%S").to_string(),
+            time_zone: "UTC".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Location {
+    #[serde(default)]
+    pub display_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ResponseStatus {
+    /// `none`, `organizer`, `tentativelyAccepted`, `accepted`, `declined` or
+    /// `notResponded`
+    pub response: String,
+    pub time: Option<String>,
+}
+
+/// How the user answers a meeting invite.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum EventResponse {
+    Accept,
+    Tentative,
+    Decline,
+}
+
+impl EventResponse {
+    fn action(&self) -> &'static str {
+        match self {
+            EventResponse::Accept => "accept",
+            EventResponse::Tentative => "tentativelyAccept",
+            EventResponse::Decline => "decline",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Email {
+    pub id: String,
+    pub created_date_time: String,
+    pub last_modified_date_time: String,
+    pub received_date_time: String,
+    pub sent_date_time: String,
+    pub has_attachments: bool,
+    pub internet_message_id: String,
+    #[serde(deserialize_with = "deserialize_null_default")]
+    pub subject: String,
+    pub body_preview: String,
+    pub importance: Importance,
+    pub parent_folder_id: String,
+    pub conversation_id: String,
+    pub conversation_index: String,
+    pub is_delivery_receipt_requested: Option<bool>,
+    pub is_read_receipt_requested: bool,
+    pub is_read: bool,
+    pub is_draft: bool,
+    pub web_link: String,
+    pub inference_classification: InferenceClassification,
+    /// Empty when listed without bodies
+    #[serde(default)]
+    pub body: Body,
+    pub sender: Option<EmailAddressWrapper>,
+    pub from: Option<EmailAddressWrapper>,
+    pub to_recipients: Vec<EmailAddressWrapper>,
+    pub cc_recipients: Vec<EmailAddressWrapper>,
+    pub bcc_recipients: Vec<EmailAddressWrapper>,
+    pub reply_to: Vec<EmailAddressWrapper>,
+    pub flag: Flag,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    /// Set on meeting invites and their updates, like `meetingRequest` or
+    /// `meetingCancelled`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meeting_message_type: Option<String>,
+    /// Only set when listed with [`GraphQuery::attachments`], which leaves out
+    /// their content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachments: Option<Vec<AttachmentMeta>>,
+}
+
+fn deserialize_null_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    T: Default + Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    let opt = Option::deserialize(deserializer)?;
+    Ok(opt.unwrap_or_default())
+}
+
+/// What became of a message asked to be moved.
+#[derive(Debug)]
+pub enum MoveOutcome {
+    Moved(Email),
+    /// The message was in the destination folder already, like when another
+    /// client moved it first and the id asked for went stale
+    AlreadyMoved(Email),
+}
+
+impl MoveOutcome {
+    /// The message as it is now, in the destination folder.
+    pub fn into_email(self) -> Email {
+        match self {
+            MoveOutcome::Moved(email) | MoveOutcome::AlreadyMoved(email) => email,
+        }
+    }
+}
+
+/// Changes made to a folder since a previous delta query.
+#[derive(Debug)]
+pub struct MessagesDelta {
+    /// Emails added to the folder or modified
+    pub changed: Vec<Email>,
+    /// Ids of the emails deleted or moved out of the folder
+    pub removed: Vec<String>,
+    /// Token to pass to the next query to get the changes made after this one
+    pub delta_token: String,
+}
+
+/// A server-side Outlook rule of the inbox, applied by Exchange as mail
+/// arrives. The conditions, actions and exceptions are kept as Graph describes
+/// them, like `{ "senderContains": ["news"] }` and `{ "markAsRead": true }`.
+/// Only the fields that are set are sent when creating or updating one.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    /// Order the rule is applied in, starting at 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub has_error: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_read_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub conditions: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub actions: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub exceptions: Option<Value>,
+}
+
+/// Makes the Focused Inbox always classify the emails of a sender as
+/// `focused` or `other`.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ClassificationOverride {
+    pub id: String,
+    pub classify_as: InferenceClassification,
+    pub sender_email_address: EmailAddress,
+}
+
+/// A subscription to change notifications, which Graph sends to
+/// `notification_url` until it expires.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Subscription {
+    pub id: String,
+    pub resource: String,
+    pub change_type: String,
+    pub notification_url: String,
+    pub expiration_date_time: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_state: Option<String>,
+}
+
+/// The fields email listings fetch.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum EmailFields {
+    #[default]
+    WithBody,
+    /// Leaves out the body, by far the largest part of a message
+    WithoutBody,
+}
+
+impl EmailFields {
+    /// The `$select` query parameter for these fields.
+    fn select(self) -> String {
+        match self {
+            EmailFields::WithBody => format!("$select={},body", EMAIL_FIELDS),
+            EmailFields::WithoutBody => format!("$select={}", EMAIL_FIELDS),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Body {
+    pub content_type: String,
+    pub content: String,
+}
+
+impl Body {
+    /// Converts an HTML body to plain text wrapped at `width` columns, keeping
+    /// links and lists readable. Text bodies are returned as they are.
+    pub fn into_text(self, width: usize) -> Self {
+        if !self.content_type.eq_ignore_ascii_case("html") {
+            return self;
+        }
+
+        Self {
+            content_type: "text".to_string(),
+            content: html2text::from_read(self.content.as_bytes(), width),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailAddressWrapper {
+    pub email_address: EmailAddress,
+}
+
+impl EmailAddressWrapper {
+    pub fn new(address: &str) -> Self {
+        Self {
+            email_address: EmailAddress {
+                name: address.to_string(),
+                address: Some(address.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailAddress {
+    pub name: String,
+    pub address: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Importance {
+    Low,
+    Normal,
+    High,
+    /// A value Graph added after this was written
+    #[serde(other)]
+    Unknown,
+}
+
+/// Whether the Focused Inbox puts the message in the Focused or the Other tab.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum InferenceClassification {
+    Focused,
+    Other,
+    /// A value Graph added after this was written
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum FlagStatus {
+    NotFlagged,
+    Flagged,
+    Complete,
+    /// A value Graph added after this was written
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Flag {
+    pub flag_status: FlagStatus,
+    /// When the follow-up starts, Graph requires it along with a due date
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_date_time: Option<DateTimeTimeZone>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub due_date_time: Option<DateTimeTimeZone>,
+}
+
+/// The subset of folder fields needed to show unread badges.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderCount {
+    pub id: String,
+    pub display_name: String,
+    pub total_item_count: u32,
+    pub unread_item_count: u32,
+}
+
+/// OData query options narrowing down a Graph collection, built with the
+/// setters. Several filters are combined with `and`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GraphQuery {
+    filters: Vec<String>,
+    order_by: Vec<String>,
+    top: Option<usize>,
+    skip: Option<usize>,
+    count: bool,
+    expand: Vec<String>,
+}
+
+impl GraphQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a `$filter` expression, like `isRead eq false`.
+    pub fn filter(mut self, expression: impl Into<String>) -> Self {
+        self.filters.push(expression.into());
+        self
+    }
+
+    /// Adds an `$orderby` property, optionally followed by `asc` or `desc`.
+    pub fn order_by(mut self, property: impl Into<String>) -> Self {
+        self.order_by.push(property.into());
+        self
+    }
+
+    pub fn top(mut self, top: usize) -> Self {
+        self.top = Some(top);
+        self
+    }
+
+    pub fn skip(mut self, skip: usize) -> Self {
+        self.skip = Some(skip);
+        self
+    }
+
+    /// Asks for the total number of items matching the query.
+    pub fn count(mut self) -> Self {
+        self.count = true;
+        self
+    }
+
+    /// Adds a relationship to `$expand`, like `childFolders`.
+    pub fn expand(mut self, relationship: impl Into<String>) -> Self {
+        self.expand.push(relationship.into());
+        self
+    }
+
+    /// Expands the metadata of the attachments of each message, like their
+    /// name and size, without their content.
+    pub fn attachments(self) -> Self {
+        self.expand(format!("attachments($select={})", ATTACHMENT_META_FIELDS))
+    }
+
+    /// Returns the query string for these options, without the leading `?`.
+    pub fn to_query_string(&self) -> String {
+        let filter = match self.filters.as_slice() {
+            [filter] => filter.clone(),
+            filters => filters
+                .iter()
+                .map(|filter| format!("({})", filter))
+                .collect::<Vec<_>>()
+                .join(" and "),
+        };
+
+        let mut params = Vec::new();
+        if !filter.is_empty() {
+            params.push(("$filter", filter));
+        }
+        if !self.order_by.is_empty() {
+            params.push(("$orderby", self.order_by.join(",")));
+        }
+        if let Some(top) = self.top {
+            params.push(("$top", top.to_string()));
+        }
+        if let Some(skip) = self.skip {
+            params.push(("$skip", skip.to_string()));
+        }
+        if self.count {
+            params.push(("$count", "true".to_string()));
+        }
+        if !self.expand.is_empty() {
+            params.push(("$expand", self.expand.join(",")));
+        }
+
+        params
+            .into_iter()
+            .map(|(name, value)| format!("{}={}", name, encode_query_value(&value)))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    /// Appends the query string to `url`, which may already have one.
+    fn apply(&self, url: String) -> String {
+        let query = self.to_query_string();
+        if query.is_empty() {
+            url
+        } else if url.contains('?') {
+            format!("{}&{}", url, query)
+        } else {
+            format!("{}?{}", url, query)
+        }
+    }
+}
+
+/// How much of a collection to read when following its pages.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PageOptions {
+    page_size: Option<usize>,
+    max_items: Option<usize>,
+}
+
+impl PageOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Items asked for per page, Graph's default page size when unset.
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = Some(page_size);
+        self
+    }
+
+    /// Stops fetching pages once this many items are read, the whole
+    /// collection is read when unset.
+    pub fn max_items(mut self, max_items: usize) -> Self {
+        self.max_items = Some(max_items);
+        self
+    }
+}
+
+/// A single page of a Graph collection.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Total number of items in the collection, when Graph reports it
+    pub total: Option<u64>,
+    pub has_more: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AttachmentMeta {
+    pub id: String,
+    pub name: String,
+    pub content_type: Option<String>,
+    pub size: u64,
+    pub is_inline: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadSession {
+    pub upload_url: String,
+    pub expiration_date_time: Option<String>,
+    #[serde(default)]
+    pub next_expected_ranges: Vec<String>,
+}
+
+/// A request that's part of a `$batch` call, `url` is relative to the API root.
+#[derive(Debug)]
+pub struct BatchRequest {
+    pub method: &'static str,
+    pub url: String,
+    pub body: Option<Value>,
+}
+
+impl BatchRequest {
+    pub fn new(method: &'static str, url: String) -> Self {
+        Self {
+            method,
+            url,
+            body: None,
+        }
+    }
+
+    pub fn with_body(mut self, body: Value) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    /// The request as part of a batch, `immutable_ids` has to be passed on as
+    /// the batch's own headers don't apply to its requests.
+    fn to_json(&self, id: usize, immutable_ids: bool) -> Value {
+        let mut json = json!({
+            "id": id.to_string(),
+            "method": self.method,
+            "url": self.url,
+        });
+        if let Some(body) = &self.body {
+            json["body"] = body.clone();
+            json["headers"]["Content-Type"] = json!("application/json");
+        }
+        if immutable_ids {
+            json["headers"]["Prefer"] = json!(IMMUTABLE_ID_PREFERENCE);
+        }
+        json
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchResponse {
+    pub id: String,
+    pub status: u16,
+    #[serde(default)]
+    pub body: Option<Value>,
+}
+
+/// The outcome of an operation on a single message of a bulk request.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct BulkResult {
+    pub id: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The id the message got when the operation changed it, like moves do
+    /// unless ids are immutable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_id: Option<String>,
+}
+
+impl BulkResult {
+    fn new(email_id: &str, response: BatchResponse) -> Self {
+        let error = if (200..300).contains(&response.status) {
+            None
+        } else {
+            Some(
+                response
+                    .body
+                    .as_ref()
+                    .and_then(|body| body.pointer("/error/message"))
+                    .and_then(Value::as_str)
+                    .unwrap_or("request failed")
+                    .to_string(),
+            )
+        };
+
+        let new_id = response
+            .body
+            .as_ref()
+            .and_then(|body| body["id"].as_str())
+            .filter(|id| *id != email_id)
+            .map(str::to_string);
+
+        Self {
+            id: email_id.to_string(),
+            status: response.status,
+            error,
+            new_id,
+        }
+    }
+}
+
+/// A message being composed, in the shape expected by the Graph `sendMail` action.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OutgoingMessage {
+    pub subject: String,
+    pub body: Body,
+    pub to_recipients: Vec<EmailAddressWrapper>,
+    pub cc_recipients: Vec<EmailAddressWrapper>,
+    pub bcc_recipients: Vec<EmailAddressWrapper>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<FileAttachment>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FileAttachment {
+    #[serde(rename = "@odata.type")]
+    pub odata_type: String,
+    pub name: String,
+    pub content_type: String,
+    /// Base64 encoded content of the file
+    pub content_bytes: String,
+}
+
+impl FileAttachment {
+    pub fn new(name: String, content_type: String, content_bytes: String) -> Self {
+        Self {
+            odata_type: "#microsoft.graph.fileAttachment".to_string(),
+            name,
+            content_type,
+            content_bytes,
+        }
+    }
+}
+
+/// Changes to apply to a draft message, only the fields that are set are sent.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DraftUpdate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<Body>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to_recipients: Option<Vec<EmailAddressWrapper>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cc_recipients: Option<Vec<EmailAddressWrapper>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bcc_recipients: Option<Vec<EmailAddressWrapper>>,
+}
+
+/// Changes to the properties of a message, built with the setters and applied
+/// with [`GraphClient::update_message`]. Only the fields that are set are sent.
+#[derive(Serialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MessagePatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_read: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    importance: Option<Importance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flag: Option<Flag>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    categories: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inference_classification: Option<InferenceClassification>,
+}
+
+impl MessagePatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(mut self, is_read: bool) -> Self {
+        self.is_read = Some(is_read);
+        self
+    }
+
+    pub fn importance(mut self, importance: Importance) -> Self {
+        self.importance = Some(importance);
+        self
+    }
+
+    pub fn flag(mut self, flag_status: FlagStatus) -> Self {
+        self.flag = Some(Flag {
+            flag_status,
+            start_date_time: None,
+            due_date_time: None,
+        });
+        self
+    }
+
+    /// Flags the message for follow-up from `start` until `due`.
+    pub fn flag_due(mut self, start: DateTime<Utc>, due: DateTime<Utc>) -> Self {
+        self.flag = Some(Flag {
+            flag_status: FlagStatus::Flagged,
+            start_date_time: Some(start.into()),
+            due_date_time: Some(due.into()),
+        });
+        self
+    }
+
+    /// Replaces all the categories of the message.
+    pub fn categories(mut self, categories: &[String]) -> Self {
+        self.categories = Some(categories.to_vec());
+        self
+    }
+
+    pub fn inference_classification(mut self, classification: InferenceClassification) -> Self {
+        self.inference_classification = Some(classification);
+        self
+    }
+}
+
+/// Inserts `content` at the start of the `<body>` of an HTML document, or at the
+/// very beginning when there's no body tag. Used to add the user's text on top of
+/// the quoted message on reply and forward drafts.
+pub fn prepend_to_html_body(html: &str, content: &str) -> String {
+    let body_start = html
+        .to_ascii_lowercase()
+        .find("<body")
+        .and_then(|start| html[start..].find('>').map(|end| start + end + 1));
+
+    match body_start {
+        Some(pos) => format!("{}{}{}", &html[..pos], content, &html[pos..]),
+        None => format!("{}{}", content, html),
+    }
+}
+
+/// Extracts the `$deltatoken` parameter of the delta link ending a delta query.
+fn delta_token_from_link(link: &str) -> Option<String> {
+    Url::parse(link)
+        .ok()?
+        .query_pairs()
+        .find(|(name, _)| name == "$deltatoken")
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Sort key of a message in its thread out of its base64 `conversationIndex`,
+/// which is the index of the message it replies to followed by a block of its
+/// own, so comparing the bytes puts replies after what they reply to.
+fn thread_position(conversation_index: &str) -> Vec<u8> {
+    base64::decode(conversation_index).unwrap_or_else(|_| conversation_index.as_bytes().to_vec())
+}
+
+/// Adds the `$top` and `$skip` of a page to a collection url, which already
+/// has a query string.
+fn page_url(url: &str, page_size: usize, skip: usize, count: bool) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let count = if count { "&$count=true" } else { "" };
+    format!("{url}{separator}$top={page_size}&$skip={skip}{count}")
+}
+
+/// Quotes a KQL query for `$search` and encodes it for the query string.
+fn search_param(query: &str) -> String {
+    let quoted = format!("\"{}\"", query.replace('\\', "\\\\").replace('"', "\\\""));
+    encode_query_value(&quoted)
+}
+
+/// Percent-encodes a query string value, spaces included as Graph doesn't
+/// take `+` for them.
+fn encode_query_value(value: &str) -> String {
+    let encoded: String = url::form_urlencoded::byte_serialize(value.as_bytes()).collect();
+    encoded.replace('+', "%20")
+}
+
+/// Returns the well-known name of the folder, also matching its English display
+/// name like `Junk Email` for `junkemail`.
+fn well_known_folder(name: &str) -> Option<&'static str> {
+    let name = name.replace(' ', "").to_lowercase();
+    WELL_KNOWN_FOLDERS
+        .iter()
+        .find(|well_known| **well_known == name)
+        .copied()
+}
+
+/// Returns the first byte an upload session expects next, out of ranges like
+/// `12345-` or `12345-55232`.
+fn next_expected_byte(ranges: &[String]) -> Option<usize> {
+    ranges
+        .iter()
+        .filter_map(|range| range.split('-').next()?.parse().ok())
+        .min()
+}
+
+/// Inserts `content` at the end of the `<body>` of an HTML document, or at the
+/// very end when there's no body tag.
+pub fn append_to_html_body(html: &str, content: &str) -> String {
+    match html.to_ascii_lowercase().rfind("</body") {
+        Some(pos) => format!("{}{}{}", &html[..pos], content, &html[pos..]),
+        None => format!("{}{}", html, content),
+    }
+}
+
+/// Sends Graph requests again when they're throttled (`429`) or the service is
+/// unavailable (`503`), waiting as long as `Retry-After` asks or backing off
+/// exponentially otherwise.
+#[async_trait]
+trait SendWithRetry {
+    async fn send_with_retry(self) -> Result<Response, reqwest::Error>;
+}
+
+#[async_trait]
+impl SendWithRetry for RequestBuilder {
+    async fn send_with_retry(self) -> Result<Response, reqwest::Error> {
+        let (method, endpoint) = self
+            .try_clone()
+            .and_then(|request| request.build().ok())
+            .map(|request| {
+                (
+                    request.method().to_string(),
+                    endpoint_label(request.url().path()),
+                )
+            })
+            .unwrap_or_else(|| ("-".to_string(), "-".to_string()));
+        let span = debug_span!("graph_request", %method, %endpoint);
+
+        async move {
+            let started_at = Instant::now();
+            let mut attempt = 0;
+            let mut request = self;
+
+            loop {
+                // Streamed bodies can't be sent twice
+                let Some(retry) = request.try_clone() else {
+                    return send_recorded(request, &method, &endpoint).await;
+                };
+                let response = send_recorded(request, &method, &endpoint).await?;
+                let status = response.status();
+                if !matches!(
+                    status,
+                    StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+                ) || attempt >= MAX_RETRIES
+                {
+                    return Ok(response);
+                }
+
+                let retry_after = response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.trim().parse().ok())
+                    .map(Duration::from_secs);
+                let delay = retry_delay(attempt, retry_after, rand::thread_rng().gen());
+                if started_at.elapsed() + delay > RETRY_MAX_TOTAL {
+                    warn!(
+                        "Giving up on {} after {attempt} retries, Graph answered {status}",
+                        response.url().path()
+                    );
+                    return Ok(response);
+                }
+
+                warn!(
+                    "Graph answered {status} to {}, retrying in {delay:?} (retry {} of {MAX_RETRIES})",
+                    response.url().path(),
+                    attempt + 1
+                );
+                GRAPH_METRICS.record_retry(status == StatusCode::TOO_MANY_REQUESTS);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                request = retry;
+            }
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+/// Sends a single attempt of a request, recording its status and latency.
+/// Requests that got no response are recorded with a `0` status.
+async fn send_recorded(
+    request: RequestBuilder,
+    method: &str,
+    endpoint: &str,
+) -> Result<Response, reqwest::Error> {
+    let sent_at = Instant::now();
+    let result = request.send().await;
+    let status = match &result {
+        Ok(response) => response.status().as_u16(),
+        Err(_) => 0,
+    };
+    let elapsed = sent_at.elapsed();
+    debug!("Graph answered {status} in {elapsed:?}");
+    GRAPH_METRICS.record_response(method, endpoint, status, elapsed);
+    result
+}
+
+/// The path of a Graph url without the API version and with ids replaced by
+/// `{id}`, so that metrics are grouped by endpoint rather than by resource.
+fn endpoint_label(path: &str) -> String {
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .skip_while(|segment| *segment == "v1.0" || *segment == "beta")
+        .map(|segment| {
+            if segment.len() >= 32 || segment.contains('=') || segment.contains('@') {
+                "{id}"
+            } else {
+                segment
+            }
+        })
+        .collect();
+    format!("/{}", segments.join("/"))
+}
+
+/// How long to wait before retry number `attempt`, starting at zero. Graph's
+/// `Retry-After` wins when present, otherwise the delay grows exponentially
+/// with up to half of it added at random, by `jitter` between 0 and 1, so
+/// throttled clients don't all come back at once.
+fn retry_delay(attempt: u32, retry_after: Option<Duration>, jitter: f64) -> Duration {
+    let delay = retry_after.unwrap_or_else(|| {
+        let backoff = RETRY_BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt));
+        backoff + backoff.mul_f64(jitter.clamp(0.0, 1.0) / 2.0)
+    });
+    delay.min(RETRY_MAX_DELAY)
+}
+
+/// The Microsoft cloud a tenant lives in. National clouds have their own Graph
+/// and login hosts, tokens from one aren't accepted by the others.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Cloud {
+    #[default]
+    Global,
+    /// US Government L4, also known as GCC High
+    UsGov,
+    /// US Government L5, for the Department of Defense
+    UsGovDod,
+    /// Microsoft 365 operated by 21Vianet
+    China,
+}
+
+impl Cloud {
+    /// The root of the Graph API, with its version.
+    pub fn graph_url(self) -> &'static str {
+        match self {
+            Cloud::Global => "https://graph.microsoft.com/v1.0",
+            Cloud::UsGov => "https://graph.microsoft.us/v1.0",
+            Cloud::UsGovDod => "https://dod-graph.microsoft.us/v1.0",
+            Cloud::China => "https://microsoftgraph.chinacloudapi.cn/v1.0",
+        }
+    }
+
+    /// The host of the Microsoft identity platform tokens are issued by.
+    pub fn login_url(self) -> &'static str {
+        match self {
+            Cloud::Global => "https://login.microsoftonline.com",
+            Cloud::UsGov | Cloud::UsGovDod => "https://login.microsoftonline.us",
+            Cloud::China => "https://login.chinacloudapi.cn",
+        }
+    }
+
+    /// The host of Exchange Online, which prefixes the scopes of its IMAP and
+    /// SMTP access.
+    pub fn outlook_url(self) -> &'static str {
+        match self {
+            Cloud::Global => "https://outlook.office.com",
+            Cloud::UsGov => "https://outlook.office365.us",
+            Cloud::UsGovDod => "https://webmail.apps.mil",
+            Cloud::China => "https://partner.outlook.cn",
+        }
+    }
+}
+
+impl FromStr for Cloud {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "global" => Ok(Cloud::Global),
+            "usgov" | "gcchigh" => Ok(Cloud::UsGov),
+            "usgovdod" | "dod" => Ok(Cloud::UsGovDod),
+            "china" | "21vianet" => Ok(Cloud::China),
+            _ => Err(format!(
+                "unknown cloud {s}, expected global, usgov, usgovdod or china"
+            )),
+        }
+    }
+}
+
+/// Where Graph and the login endpoints are, out of the tenant's cloud.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoints {
+    /// Like `https://graph.microsoft.com/v1.0`
+    pub graph: String,
+    /// Like `https://login.microsoftonline.com`
+    pub login: String,
+    /// Like `https://outlook.office.com`
+    pub outlook: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self::new(Cloud::Global, None)
+    }
+}
+
+impl Endpoints {
+    /// The endpoints of `cloud`, with Graph at `graph_url` instead when set,
+    /// like for the `beta` API or a proxy in front of it.
+    pub fn new(cloud: Cloud, graph_url: Option<String>) -> Self {
+        Self {
+            graph: graph_url
+                .map(|url| url.trim_end_matches('/').to_string())
+                .unwrap_or_else(|| cloud.graph_url().to_string()),
+            login: cloud.login_url().to_string(),
+            outlook: cloud.outlook_url().to_string(),
+        }
+    }
+
+    /// The Graph host without the API version, which prefixes the scopes
+    /// asked for, like `https://graph.microsoft.us/Mail.Read`.
+    pub fn graph_resource(&self) -> String {
+        Url::parse(&self.graph)
+            .map(|url| url.origin().ascii_serialization())
+            .unwrap_or_else(|_| self.graph.clone())
+    }
+}
+
+/// Settings of the HTTP client shared by every `GraphClient`.
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    /// Time a whole request, including reading the response, may take.
+    pub timeout: Duration,
+    /// Proxy all requests go through. Without one the `HTTP_PROXY`,
+    /// `HTTPS_PROXY` and `NO_PROXY` environment variables are honored.
+    pub proxy: Option<String>,
+    /// Asks Graph for immutable ids, which messages keep when moved between
+    /// folders, instead of ids that change with the folder.
+    pub immutable_ids: bool,
+    /// The Graph and login endpoints of the tenant's cloud.
+    pub endpoints: Endpoints,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+            proxy: None,
+            immutable_ids: false,
+            endpoints: Endpoints::default(),
+        }
+    }
+}
+
+impl HttpConfig {
+    pub fn build(&self) -> Result<Client, reqwest::Error> {
+        let mut builder = Client::builder()
+            .timeout(self.timeout)
+            .connect_timeout(CONNECT_TIMEOUT);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+        builder.build()
+    }
+}
+
+static HTTP_CLIENT: OnceLock<Client> = OnceLock::new();
+static IMMUTABLE_IDS: AtomicBool = AtomicBool::new(false);
+static ENDPOINTS: OnceLock<Endpoints> = OnceLock::new();
+
+/// Sets up the client `GraphClient::new` shares, so connections are pooled
+/// across requests. Has no effect once the client is in use.
+pub fn init_http_client(config: &HttpConfig) -> Result<(), reqwest::Error> {
+    let client = config.build()?;
+    IMMUTABLE_IDS.store(config.immutable_ids, Ordering::Relaxed);
+    if HTTP_CLIENT.set(client).is_err() || ENDPOINTS.set(config.endpoints.clone()).is_err() {
+        warn!("HTTP client already initialized, ignoring {config:?}");
+    }
+    Ok(())
+}
+
+/// The endpoints of the cloud set up with [`init_http_client`], the worldwide
+/// one by default.
+pub fn endpoints() -> &'static Endpoints {
+    ENDPOINTS.get_or_init(Endpoints::default)
+}
+
+/// The HTTP client shared across the process, cheap to clone.
+pub fn shared_http_client() -> Client {
+    HTTP_CLIENT
+        .get_or_init(|| {
+            HttpConfig::default()
+                .build()
+                .expect("default HTTP client should build")
+        })
+        .clone()
+}
+
+/// Supplies the access token of every request, so that long running work can
+/// refresh it along the way.
+#[async_trait]
+pub trait TokenProvider: Send + Sync {
+    async fn access_token(&self) -> anyhow::Result<String>;
+}
+
+/// A token used as is, for requests that won't outlive it.
+#[async_trait]
+impl TokenProvider for String {
+    async fn access_token(&self) -> anyhow::Result<String> {
+        Ok(self.clone())
+    }
+}
+
+/// A folder name within a mailbox.
+type FolderKey = (String, String);
+
+/// Folder ids resolved from names, kept for a while and shared across
+/// requests so that moving to "Archive" doesn't look the folder up every time.
+/// Entries are scoped by mailbox, and dropped whenever its folders change.
+#[derive(Clone, Debug)]
+pub struct FolderCache {
+    entries: Arc<Mutex<HashMap<FolderKey, (String, Instant)>>>,
+    ttl: Duration,
+}
+
+impl FolderCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    fn get(&self, mailbox: &str, folder_name: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (mailbox.to_string(), folder_name.to_string());
+        match entries.get(&key) {
+            Some((folder_id, cached_at)) if cached_at.elapsed() < self.ttl => {
+                Some(folder_id.clone())
+            }
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, mailbox: &str, folder_name: &str, folder_id: &str) {
+        self.entries.lock().unwrap().insert(
+            (mailbox.to_string(), folder_name.to_string()),
+            (folder_id.to_string(), Instant::now()),
+        );
+    }
+
+    fn invalidate(&self, mailbox: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|(cached_mailbox, _), _| cached_mailbox != mailbox);
+    }
+}
+
+pub struct GraphClientBuilder {
+    token_provider: Arc<dyn TokenProvider>,
+    client: Option<Client>,
+    timeout: Option<Duration>,
+    folder_cache: Option<(FolderCache, String)>,
+    base_url: Option<String>,
+    user_id: Option<String>,
+}
+
+impl GraphClientBuilder {
+    /// The client to send requests with, its connection pool is shared by
+    /// every clone.
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Overrides the client's timeout for each request.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Shares resolved folder ids with other clients acting on `mailbox`,
+    /// instead of keeping them for this client only.
+    pub fn folder_cache(mut self, cache: FolderCache, mailbox: impl Into<String>) -> Self {
+        self.folder_cache = Some((cache, mailbox.into()));
+        self
+    }
+
+    /// Sends requests to Graph at `base_url`, like
+    /// `https://graph.microsoft.us/v1.0`, instead of the configured endpoint.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    /// Acts on the mailbox of `user_id`, an id or user principal name, rather
+    /// than on the signed-in user's. App-only tokens have no signed-in user so
+    /// they require it.
+    pub fn user(mut self, user_id: impl Into<String>) -> Self {
+        self.user_id = Some(user_id.into());
+        self
+    }
+
+    pub fn build(self) -> GraphClient {
+        let (folder_cache, mailbox) = self
+            .folder_cache
+            .unwrap_or_else(|| (FolderCache::new(Duration::MAX), String::new()));
+        GraphClient {
+            client: self.client.unwrap_or_default(),
+            token_provider: self.token_provider,
+            timeout: self.timeout,
+            immutable_ids: IMMUTABLE_IDS.load(Ordering::Relaxed),
+            folder_cache,
+            mailbox,
+            base_url: self.base_url.unwrap_or_else(|| endpoints().graph.clone()),
+            mailbox_path: match self.user_id {
+                Some(user_id) => format!("users/{}", user_id),
+                None => "me".to_string(),
+            },
+        }
+    }
+}
+
+pub struct GraphClient {
+    client: Client,
+    token_provider: Arc<dyn TokenProvider>,
+    timeout: Option<Duration>,
+    immutable_ids: bool,
+    folder_cache: FolderCache,
+    /// Scopes the folder cache entries.
+    mailbox: String,
+    /// Root of the Graph API, with its version.
+    base_url: String,
+    /// `me`, or `users/{id}` when acting on another user's mailbox.
+    mailbox_path: String,
+}
+
+impl GraphClient {
+    /// A client acting with `access_token` over the shared HTTP client.
+    pub fn new(access_token: String) -> Self {
+        Self::with_token_provider(access_token)
+    }
+
+    /// A client getting its tokens from `token_provider`, over the shared HTTP
+    /// client.
+    pub fn with_token_provider(token_provider: impl TokenProvider + 'static) -> Self {
+        Self::builder(token_provider)
+            .client(shared_http_client())
+            .build()
+    }
+
+    pub fn builder(token_provider: impl TokenProvider + 'static) -> GraphClientBuilder {
+        GraphClientBuilder {
+            token_provider: Arc::new(token_provider),
+            client: None,
+            timeout: None,
+            folder_cache: None,
+            base_url: None,
+            user_id: None,
+        }
+    }
+
+    /// The url of the mailbox requests act on, like
+    /// `https://graph.microsoft.com/v1.0/me`.
+    fn mailbox_url(&self) -> String {
+        format!("{}/{}", self.base_url, self.mailbox_path)
+    }
+
+    /// Starts an authenticated request to Graph.
+    async fn request(
+        &self,
+        method: Method,
+        url: impl IntoUrl,
+    ) -> Result<RequestBuilder, GraphClientError> {
+        let access_token = self
+            .token_provider
+            .access_token()
+            .await
+            .map_err(GraphClientError::Token)?;
+        let mut request = self.client.request(method, url).bearer_auth(access_token);
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+        if self.immutable_ids {
+            request = request.header(PREFER, IMMUTABLE_ID_PREFERENCE);
+        }
+        Ok(request)
+    }
+
+    pub async fn get_user_folders(
+        &self,
+        query: &GraphQuery,
+    ) -> Result<Vec<Folder>, GraphClientError> {
+        let url = query.apply(format!("{}/mailFolders", self.mailbox_url()));
+        self.fetch_all_items::<Folder>(&url).await
+    }
+
+    pub async fn get_child_folders(
+        &self,
+        folder_id: &str,
+    ) -> Result<Vec<Folder>, GraphClientError> {
+        let url = format!(
+            "{}/mailFolders/{}/childFolders",
+            self.mailbox_url(),
+            folder_id
+        );
+        self.fetch_all_items::<Folder>(&url).await
+    }
+
+    pub async fn get_user_folder_counts(&self) -> Result<Vec<FolderCount>, GraphClientError> {
+        let url = format!(
+            "{}/mailFolders?$select=id,displayName,totalItemCount,unreadItemCount",
+            self.mailbox_url()
+        );
+        self.fetch_all_items::<FolderCount>(&url).await
+    }
+
+    pub async fn create_folder(
+        &mut self,
+        parent_id: Option<&str>,
+        display_name: &str,
+    ) -> Result<Folder, GraphClientError> {
+        let url = match parent_id {
+            Some(parent_id) => format!(
+                "{}/mailFolders/{}/childFolders",
+                self.mailbox_url(),
+                parent_id
+            ),
+            None => format!("{}/mailFolders", self.mailbox_url()),
+        };
+        let payload = json!({ "displayName": display_name });
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .json(&payload)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            self.folder_cache.invalidate(&self.mailbox);
+            let folder: Folder = response.json().await?;
+            Ok(folder)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Returns the id of the top level folder named `display_name`, creating it
+    /// when it doesn't exist yet.
+    pub async fn get_or_create_folder(
+        &mut self,
+        display_name: &str,
+    ) -> Result<String, GraphClientError> {
+        match self.get_folder_id_by_name(display_name).await {
+            Err(GraphClientError::FolderNotFound(_)) => {
+                Ok(self.create_folder(None, display_name).await?.id)
+            }
+            result => result,
+        }
+    }
+
+    pub async fn rename_folder(
+        &mut self,
+        folder_id: &str,
+        display_name: &str,
+    ) -> Result<Folder, GraphClientError> {
+        let url = format!("{}/mailFolders/{}", self.mailbox_url(), folder_id);
+        let payload = json!({ "displayName": display_name });
+
+        let response = self
+            .request(Method::PATCH, &url)
+            .await?
+            .json(&payload)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            self.folder_cache.invalidate(&self.mailbox);
+            let folder: Folder = response.json().await?;
+            Ok(folder)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    pub async fn move_folder(
+        &mut self,
+        folder_id: &str,
+        new_parent_id: &str,
+    ) -> Result<Folder, GraphClientError> {
+        let url = format!("{}/mailFolders/{}/move", self.mailbox_url(), folder_id);
+        let payload = json!({ "destinationId": new_parent_id });
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .json(&payload)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            self.folder_cache.invalidate(&self.mailbox);
+            let folder: Folder = response.json().await?;
+            Ok(folder)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    pub async fn delete_folder(&mut self, folder_id: &str) -> Result<(), GraphClientError> {
+        let url = format!("{}/mailFolders/{}", self.mailbox_url(), folder_id);
+
+        let response = self
+            .request(Method::DELETE, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            self.folder_cache.invalidate(&self.mailbox);
+            Ok(())
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Streams the user's emails a page at a time, in order, fetching up to
+    /// `concurrency` pages at once. The query shouldn't set `$top` or `$skip`.
+    pub fn stream_user_emails(
+        &self,
+        query: &GraphQuery,
+        fields: EmailFields,
+        concurrency: usize,
+    ) -> impl Stream<Item = Result<Vec<Email>, GraphClientError>> + '_ {
+        let url = query.apply(format!(
+            "{}/messages?{}",
+            self.mailbox_url(),
+            fields.select()
+        ));
+        self.paginate_concurrently::<Email>(url, STREAM_PAGE_SIZE, concurrency)
+            .map_ok(|page| page.items)
+    }
+
+    pub async fn get_user_emails_paginated(
+        &self,
+        initial_page: usize,
+        num_pages: usize,
+        fields: EmailFields,
+    ) -> Result<(Vec<Email>, bool), GraphClientError> {
+        let url = format!("{}/messages?{}", self.mailbox_url(), fields.select());
+        self.fetch_pages::<Email>(&url, initial_page, num_pages)
+            .await
+    }
+
+    /// Returns a single page of emails, the one `query` sets with `$top` and
+    /// `$skip`.
+    pub async fn get_user_emails_page(
+        &self,
+        query: &GraphQuery,
+        fields: EmailFields,
+    ) -> Result<Page<Email>, GraphClientError> {
+        let url = query.apply(format!(
+            "{}/messages?{}",
+            self.mailbox_url(),
+            fields.select()
+        ));
+        self.fetch_page::<Email>(&url).await
+    }
+
+    /// Returns the emails of a folder, following its pages up to the limit set
+    /// in `options`. The page size replaces any `$top` of the query.
+    pub async fn get_user_emails_from_folder(
+        &self,
+        folder_id: &str,
+        query: &GraphQuery,
+        fields: EmailFields,
+        options: PageOptions,
+    ) -> Result<Vec<Email>, GraphClientError> {
+        let query = match options.page_size {
+            Some(page_size) => query.clone().top(page_size),
+            None => query.clone(),
+        };
+        let url = query.apply(format!(
+            "{}/mailFolders/{}/messages?{}",
+            self.mailbox_url(),
+            folder_id,
+            fields.select()
+        ));
+        self.fetch_items::<Email>(&url, options.max_items).await
+    }
+
+    pub async fn get_user_emails_from_folder_by_name(
+        &mut self,
+        folder_name: &str,
+        query: &GraphQuery,
+        fields: EmailFields,
+        options: PageOptions,
+    ) -> Result<Vec<Email>, GraphClientError> {
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        self.get_user_emails_from_folder(&folder_id, query, fields, options)
+            .await
+    }
+
+    /// Searches the whole mailbox on Graph's side with a KQL query, like
+    /// `from:alice subject:invoice`, returning up to `top` of the best matches.
+    pub async fn search_messages(
+        &self,
+        query: &str,
+        top: usize,
+    ) -> Result<Vec<Email>, GraphClientError> {
+        let url = format!(
+            "{}/messages?$search={}&$top={}&{}",
+            self.mailbox_url(),
+            search_param(query),
+            top,
+            EmailFields::WithBody.select()
+        );
+        Ok(self.fetch_page::<Email>(&url).await?.items)
+    }
+
+    /// Returns the messages of a conversation, whatever folder they're in, in
+    /// thread order.
+    pub async fn get_conversation_messages(
+        &self,
+        conversation_id: &str,
+        fields: EmailFields,
+    ) -> Result<Vec<Email>, GraphClientError> {
+        let query = GraphQuery::new().filter(format!(
+            "conversationId eq '{}'",
+            conversation_id.replace('\'', "''")
+        ));
+        let url = query.apply(format!(
+            "{}/messages?{}",
+            self.mailbox_url(),
+            fields.select()
+        ));
+        // Graph can't order by conversationIndex along with this filter
+        let mut emails = self.fetch_all_items::<Email>(&url).await?;
+        emails.sort_by_cached_key(|email| thread_position(&email.conversation_index));
+        Ok(emails)
+    }
+
+    pub async fn get_email_by_id(&self, email_id: &str) -> Result<Email, GraphClientError> {
+        let url = format!("{}/messages/{}", self.mailbox_url(), email_id);
+        let response = self
+            .request(Method::GET, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let email: Email = response.json().await?;
+            Ok(email)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Returns the emails with these ids through `$batch`, along with the ids
+    /// of the ones which don't exist anymore, like after a delete or a move.
+    pub async fn get_emails_by_ids(
+        &self,
+        email_ids: &[String],
+        fields: EmailFields,
+    ) -> Result<(Vec<Email>, Vec<String>), GraphClientError> {
+        let requests = email_ids
+            .iter()
+            .map(|email_id| {
+                BatchRequest::new(
+                    "GET",
+                    format!(
+                        "/{}/messages/{}?{}",
+                        self.mailbox_path,
+                        email_id,
+                        fields.select()
+                    ),
+                )
+            })
+            .collect();
+
+        let mut emails = Vec::new();
+        let mut missing = Vec::new();
+        for (email_id, response) in email_ids.iter().zip(self.batch(requests).await?) {
+            match StatusCode::from_u16(response.status) {
+                Ok(status) if status.is_success() => {
+                    let body = response.body.unwrap_or_default();
+                    emails.push(serde_json::from_value(body)?);
+                }
+                Ok(StatusCode::NOT_FOUND) => missing.push(email_id.clone()),
+                Ok(status) => return Err(GraphClientError::Request(status)),
+                Err(_) => {
+                    return Err(GraphClientError::Parse(
+                        "batch status",
+                        json!(response.status),
+                    ))
+                }
+            }
+        }
+        Ok((emails, missing))
+    }
+
+    /// Returns the message as it was received, in RFC 822 format.
+    pub async fn get_message_mime(&self, email_id: &str) -> Result<Bytes, GraphClientError> {
+        let url = format!("{}/messages/{}/$value", self.mailbox_url(), email_id);
+        let response = self
+            .request(Method::GET, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(response.bytes().await?),
+            StatusCode::NOT_FOUND => Err(GraphClientError::MessageNotFound(email_id.to_string())),
+            _ => Err(GraphClientError::from_response(response).await),
+        }
+    }
+
+    /// Returns the id of the folder the email is currently in.
+    pub async fn get_email_folder_id(&self, email_id: &str) -> Result<String, GraphClientError> {
+        let url = format!(
+            "{}/messages/{}?$select=parentFolderId",
+            self.mailbox_url(),
+            email_id
+        );
+        let response = self
+            .request(Method::GET, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let message: Value = response.json().await?;
+            Ok(message["parentFolderId"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string())
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Applies `patch` to the message, returning it updated.
+    pub async fn update_message(
+        &self,
+        email_id: &str,
+        patch: &MessagePatch,
+    ) -> Result<Email, GraphClientError> {
+        let url = format!("{}/messages/{}", self.mailbox_url(), email_id);
+
+        let response = self
+            .request(Method::PATCH, &url)
+            .await?
+            .json(patch)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let email: Email = response.json().await?;
+            Ok(email)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    pub async fn set_read(&self, email_id: &str, is_read: bool) -> Result<Email, GraphClientError> {
+        self.update_message(email_id, &MessagePatch::new().read(is_read))
+            .await
+    }
+
+    pub async fn bulk_set_read(
+        &self,
+        email_ids: &[String],
+        is_read: bool,
+    ) -> Result<Vec<BulkResult>, GraphClientError> {
+        let requests = email_ids
+            .iter()
+            .map(|email_id| {
+                BatchRequest::new(
+                    "PATCH",
+                    format!("/{}/messages/{}", self.mailbox_path, email_id),
+                )
+                .with_body(json!({ "isRead": is_read }))
+            })
+            .collect();
+        self.bulk(email_ids, requests).await
+    }
+
+    pub async fn bulk_move(
+        &self,
+        email_ids: &[String],
+        folder_id: &str,
+    ) -> Result<Vec<BulkResult>, GraphClientError> {
+        let requests = email_ids
+            .iter()
+            .map(|email_id| {
+                BatchRequest::new(
+                    "POST",
+                    format!("/{}/messages/{}/move", self.mailbox_path, email_id),
+                )
+                .with_body(json!({ "destinationId": folder_id }))
+            })
+            .collect();
+        self.bulk(email_ids, requests).await
+    }
+
+    pub async fn bulk_move_by_name(
+        &mut self,
+        email_ids: &[String],
+        folder_name: &str,
+    ) -> Result<Vec<BulkResult>, GraphClientError> {
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        self.bulk_move(email_ids, &folder_id).await
+    }
+
+    pub async fn bulk_delete(
+        &self,
+        email_ids: &[String],
+    ) -> Result<Vec<BulkResult>, GraphClientError> {
+        let requests = email_ids
+            .iter()
+            .map(|email_id| {
+                BatchRequest::new(
+                    "DELETE",
+                    format!("/{}/messages/{}", self.mailbox_path, email_id),
+                )
+            })
+            .collect();
+        self.bulk(email_ids, requests).await
+    }
+
+    /// Sends the given requests through `$batch`, Graph accepts at most 20
+    /// requests per batch so they are split in chunks. Responses are returned in
+    /// the same order as the requests.
+    pub async fn batch(
+        &self,
+        requests: Vec<BatchRequest>,
+    ) -> Result<Vec<BatchResponse>, GraphClientError> {
+        let url = format!("{}/$batch", self.base_url);
+        let mut responses = Vec::with_capacity(requests.len());
+        let mut requests = requests.into_iter().enumerate().peekable();
+
+        while requests.peek().is_some() {
+            let chunk: Vec<Value> = requests
+                .by_ref()
+                .take(MAX_BATCH_SIZE)
+                .map(|(i, request)| request.to_json(i, self.immutable_ids))
+                .collect();
+
+            let response = self
+                .request(Method::POST, &url)
+                .await?
+                .json(&json!({ "requests": chunk }))
+                .send_with_retry()
+                .await?;
+
+            if !response.status().is_success() {
+                return Err(GraphClientError::from_response(response).await);
+            }
+
+            let json: Value = response.json().await?;
+            let mut chunk_responses: Vec<BatchResponse> =
+                serde_json::from_value(json["responses"].clone())?;
+            chunk_responses.sort_by_key(|r| r.id.parse::<usize>().unwrap_or(usize::MAX));
+            responses.extend(chunk_responses);
+        }
+
+        Ok(responses)
+    }
+
+    async fn bulk(
+        &self,
+        email_ids: &[String],
+        requests: Vec<BatchRequest>,
+    ) -> Result<Vec<BulkResult>, GraphClientError> {
+        let responses = self.batch(requests).await?;
+        Ok(email_ids
+            .iter()
+            .zip(responses)
+            .map(|(email_id, response)| BulkResult::new(email_id, response))
+            .collect())
+    }
+
+    pub async fn move_email_to_folder(
+        &self,
+        email_id: &str,
+        folder_id: &str,
+    ) -> Result<Email, GraphClientError> {
+        let url = format!("{}/messages/{}/move", self.mailbox_url(), email_id);
+        let payload = json!({ "destinationId": folder_id });
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .json(&payload)
+            .send_with_retry()
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(response.json().await?),
+            StatusCode::NOT_FOUND => Err(GraphClientError::MessageNotFound(email_id.to_string())),
+            _ => Err(GraphClientError::from_response(response).await),
+        }
+    }
+
+    pub async fn move_email_to_folder_by_name(
+        &mut self,
+        email_id: &str,
+        folder_name: &str,
+    ) -> Result<Email, GraphClientError> {
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        self.move_email_to_folder(email_id, &folder_id).await
+    }
+
+    /// Moves a message, tolerating ids gone stale because the message moved
+    /// in the meantime: it's found again by its `internetMessageId`, and is
+    /// either moved from where it is now or reported as already moved.
+    /// `folder_id` has to be an id, not a well-known name, to tell.
+    pub async fn move_message(
+        &self,
+        email_id: &str,
+        internet_message_id: Option<&str>,
+        folder_id: &str,
+    ) -> Result<MoveOutcome, GraphClientError> {
+        let err = match self.move_email_to_folder(email_id, folder_id).await {
+            Ok(email) => return Ok(MoveOutcome::Moved(email)),
+            Err(err @ GraphClientError::MessageNotFound(_)) => err,
+            Err(err) => return Err(err),
+        };
+        let Some(internet_message_id) = internet_message_id else {
+            return Err(err);
+        };
+
+        let copies = self
+            .find_by_internet_message_id(internet_message_id)
+            .await?;
+        if let Some(email) = copies
+            .iter()
+            .find(|email| email.parent_folder_id == folder_id)
+        {
+            debug!(
+                "{email_id} was already moved to {folder_id} as {}",
+                email.id
+            );
+            return Ok(MoveOutcome::AlreadyMoved(email.clone()));
+        }
+        match copies.into_iter().next() {
+            Some(email) => Ok(MoveOutcome::Moved(
+                self.move_email_to_folder(&email.id, folder_id).await?,
+            )),
+            None => Err(err),
+        }
+    }
+
+    pub async fn move_message_by_name(
+        &mut self,
+        email_id: &str,
+        internet_message_id: Option<&str>,
+        folder_name: &str,
+    ) -> Result<MoveOutcome, GraphClientError> {
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        self.move_message(email_id, internet_message_id, &folder_id)
+            .await
+    }
+
+    /// Returns the messages with the given `Message-ID` header, a message has
+    /// several copies when sent to oneself for instance.
+    pub async fn find_by_internet_message_id(
+        &self,
+        internet_message_id: &str,
+    ) -> Result<Vec<Email>, GraphClientError> {
+        let query = GraphQuery::new().filter(format!(
+            "internetMessageId eq '{}'",
+            internet_message_id.replace('\'', "''")
+        ));
+        let url = query.apply(format!(
+            "{}/messages?{}",
+            self.mailbox_url(),
+            EmailFields::WithBody.select()
+        ));
+        self.fetch_all_items::<Email>(&url).await
+    }
+
+    /// Forwards the email right away, without going through a draft.
+    pub async fn forward_email(
+        &self,
+        email_id: &str,
+        to_recipients: &[EmailAddressWrapper],
+        comment: &str,
+    ) -> Result<(), GraphClientError> {
+        let url = format!("{}/messages/{}/forward", self.mailbox_url(), email_id);
+        let payload = json!({ "comment": comment, "toRecipients": to_recipients });
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .json(&payload)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Creates an inbox rule moving every email from `address` to the folder.
+    pub async fn block_sender(
+        &self,
+        address: &str,
+        folder_id: &str,
+    ) -> Result<(), GraphClientError> {
+        let url = format!("{}/mailFolders/inbox/messageRules", self.mailbox_url());
+        let payload = json!({
+            "displayName": format!("Block {}", address),
+            "sequence": 1,
+            "isEnabled": true,
+            "conditions": { "senderContains": [address] },
+            "actions": { "moveToFolder": folder_id, "stopProcessingRules": true },
+        });
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .json(&payload)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Adds an Outlook category to the email, keeping the ones it already has.
+    pub async fn add_category(
+        &self,
+        email_id: &str,
+        category: &str,
+    ) -> Result<(), GraphClientError> {
+        let url = format!("{}/messages/{}", self.mailbox_url(), email_id);
+
+        let response = self
+            .request(Method::GET, format!("{}?$select=categories", url))
+            .await?
+            .send_with_retry()
+            .await?;
+        if !response.status().is_success() {
+            return Err(GraphClientError::from_response(response).await);
+        }
+
+        let message: Value = response.json().await?;
+        let mut categories: Vec<String> =
+            serde_json::from_value(message["categories"].clone()).unwrap_or_default();
+        if categories.iter().any(|c| c == category) {
+            return Ok(());
+        }
+        categories.push(category.to_string());
+
+        self.set_categories(email_id, &categories).await?;
+        Ok(())
+    }
+
+    /// Replaces the categories of the email.
+    pub async fn set_categories(
+        &self,
+        email_id: &str,
+        categories: &[String],
+    ) -> Result<Email, GraphClientError> {
+        self.update_message(email_id, &MessagePatch::new().categories(categories))
+            .await
+    }
+
+    /// Subscribes to the changes of the user's messages, Graph first checks
+    /// `notification_url` answers its validation request. With a
+    /// `certificate` the notifications include the messages.
+    pub async fn create_subscription(
+        &self,
+        notification_url: &str,
+        change_type: &str,
+        expiration: DateTime<Utc>,
+        client_state: &str,
+        certificate: Option<&notifications::Certificate>,
+    ) -> Result<Subscription, GraphClientError> {
+        let url = format!("{}/subscriptions", self.base_url);
+        let mut payload = json!({
+            "changeType": change_type,
+            "notificationUrl": notification_url,
+            "resource": format!("{}/messages", self.mailbox_path),
+            "expirationDateTime": expiration.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "clientState": client_state,
+        });
+        // Rich notifications carry the messages, encrypted for the certificate,
+        // with the fields listings have
+        if let Some(certificate) = certificate {
+            payload["resource"] = format!(
+                "{}/messages?{}",
+                self.mailbox_path,
+                EmailFields::WithoutBody.select()
+            )
+            .into();
+            payload["includeResourceData"] = true.into();
+            payload["encryptionCertificate"] = certificate.encoded.clone().into();
+            payload["encryptionCertificateId"] = certificate.id.clone().into();
+        }
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .json(&payload)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let subscription: Subscription = response.json().await?;
+            Ok(subscription)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Extends the subscription until `expiration`.
+    pub async fn renew_subscription(
+        &self,
+        subscription_id: &str,
+        expiration: DateTime<Utc>,
+    ) -> Result<Subscription, GraphClientError> {
+        let url = format!("{}/subscriptions/{}", self.base_url, subscription_id);
+        let payload = json!({
+            "expirationDateTime": expiration.to_rfc3339_opts(SecondsFormat::Secs, true),
+        });
+
+        let response = self
+            .request(Method::PATCH, &url)
+            .await?
+            .json(&payload)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let subscription: Subscription = response.json().await?;
+            Ok(subscription)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    pub async fn delete_subscription(&self, subscription_id: &str) -> Result<(), GraphClientError> {
+        let url = format!("{}/subscriptions/{}", self.base_url, subscription_id);
+
+        let response = self
+            .request(Method::DELETE, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Returns the changes made to the folder since the query that returned
+    /// `delta_token`, or every email in it without one.
+    pub async fn get_messages_delta(
+        &self,
+        folder_id: &str,
+        delta_token: Option<&str>,
+    ) -> Result<MessagesDelta, GraphClientError> {
+        let base_url = format!(
+            "{}/mailFolders/{}/messages/delta",
+            self.mailbox_url(),
+            folder_id
+        );
+        let mut url = match delta_token {
+            Some(token) => Url::parse_with_params(&base_url, &[("$deltatoken", token)]),
+            None => Url::parse(&base_url),
+        }
+        .map_err(|_| GraphClientError::Parse("delta url", Value::String(base_url)))?
+        .to_string();
+
+        let mut changed = Vec::new();
+        let mut removed = Vec::new();
+        loop {
+            let response = self
+                .request(Method::GET, &url)
+                .await?
+                .header(PREFER, "odata.maxpagesize=100")
+                .send_with_retry()
+                .await?;
+            if !response.status().is_success() {
+                return Err(GraphClientError::from_response(response).await);
+            }
+
+            let json: Value = response.json().await?;
+            let items = json["value"]
+                .as_array()
+                .ok_or_else(|| GraphClientError::Parse("delta", json.clone()))?;
+            for item in items {
+                if item.get("@removed").is_some() {
+                    if let Some(id) = item["id"].as_str() {
+                        removed.push(id.to_string());
+                    }
+                } else {
+                    changed.push(serde_json::from_value(item.clone())?);
+                }
+            }
+
+            if let Some(next_link) = json["@odata.nextLink"].as_str() {
+                url = next_link.to_string();
+                continue;
+            }
+
+            let delta_token = json["@odata.deltaLink"]
+                .as_str()
+                .and_then(delta_token_from_link)
+                .ok_or_else(|| GraphClientError::Parse("delta link", json.clone()))?;
+            return Ok(MessagesDelta {
+                changed,
+                removed,
+                delta_token,
+            });
+        }
+    }
+
+    pub async fn get_message_rules(&self) -> Result<Vec<MessageRule>, GraphClientError> {
+        let url = format!("{}/mailFolders/inbox/messageRules", self.mailbox_url());
+        self.fetch_all_items::<MessageRule>(&url).await
+    }
+
+    pub async fn create_message_rule(
+        &self,
+        rule: &MessageRule,
+    ) -> Result<MessageRule, GraphClientError> {
+        let url = format!("{}/mailFolders/inbox/messageRules", self.mailbox_url());
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .json(rule)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let rule: MessageRule = response.json().await?;
+            Ok(rule)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Changes the fields of the rule set in `rule`.
+    pub async fn update_message_rule(
+        &self,
+        rule_id: &str,
+        rule: &MessageRule,
+    ) -> Result<MessageRule, GraphClientError> {
+        let url = format!(
+            "{}/mailFolders/inbox/messageRules/{}",
+            self.mailbox_url(),
+            rule_id
+        );
+
+        let response = self
+            .request(Method::PATCH, &url)
+            .await?
+            .json(rule)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let rule: MessageRule = response.json().await?;
+            Ok(rule)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    pub async fn delete_message_rule(&self, rule_id: &str) -> Result<(), GraphClientError> {
+        let url = format!(
+            "{}/mailFolders/inbox/messageRules/{}",
+            self.mailbox_url(),
+            rule_id
+        );
+
+        let response = self
+            .request(Method::DELETE, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Moves the message to the Focused or the Other tab of the inbox.
+    pub async fn set_inference_classification(
+        &self,
+        email_id: &str,
+        classification: InferenceClassification,
+    ) -> Result<Email, GraphClientError> {
+        self.update_message(
+            email_id,
+            &MessagePatch::new().inference_classification(classification),
+        )
+        .await
+    }
+
+    pub async fn get_classification_overrides(
+        &self,
+    ) -> Result<Vec<ClassificationOverride>, GraphClientError> {
+        let url = format!("{}/inferenceClassification/overrides", self.mailbox_url());
+        self.fetch_all_items::<ClassificationOverride>(&url).await
+    }
+
+    /// Classifies the future emails of `sender` as `classify_as`.
+    pub async fn create_classification_override(
+        &self,
+        classify_as: InferenceClassification,
+        sender: &EmailAddress,
+    ) -> Result<ClassificationOverride, GraphClientError> {
+        let url = format!("{}/inferenceClassification/overrides", self.mailbox_url());
+        let payload = json!({
+            "classifyAs": classify_as,
+            "senderEmailAddress": sender,
+        });
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .json(&payload)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let classification_override: ClassificationOverride = response.json().await?;
+            Ok(classification_override)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    pub async fn delete_classification_override(
+        &self,
+        override_id: &str,
+    ) -> Result<(), GraphClientError> {
+        let url = format!(
+            "{}/inferenceClassification/overrides/{}",
+            self.mailbox_url(),
+            override_id
+        );
+
+        let response = self
+            .request(Method::DELETE, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Returns the user's master category list, the categories emails can be
+    /// assigned to.
+    pub async fn get_categories(&self) -> Result<Vec<Category>, GraphClientError> {
+        let url = format!("{}/outlook/masterCategories", self.mailbox_url());
+        let response = self
+            .request(Method::GET, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let json: Value = response.json().await?;
+            let categories = json["value"].clone();
+            serde_json::from_value(categories.clone())
+                .map_err(|_| GraphClientError::Parse("categories", categories))
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Returns the events of the user's default calendar, recurring events
+    /// appear once as the master of their series.
+    pub async fn list_events(&self, query: &GraphQuery) -> Result<Vec<Event>, GraphClientError> {
+        let url = query.apply(format!("{}/events", self.mailbox_url()));
+        self.fetch_all_items::<Event>(&url).await
+    }
+
+    pub async fn get_event(&self, event_id: &str) -> Result<Event, GraphClientError> {
+        let url = format!("{}/events/{}", self.mailbox_url(), event_id);
+        let response = self
+            .request(Method::GET, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(response.json().await?)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Returns the events of the user's calendars between `start` and `end`,
+    /// with recurring events expanded into their occurrences.
+    pub async fn get_calendar_view(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Event>, GraphClientError> {
+        let url = format!(
+            "{}/calendarView?startDateTime={}&endDateTime={}",
+            self.mailbox_url(),
+            start.to_rfc3339_opts(SecondsFormat::Secs, true),
+            end.to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+        self.fetch_all_items::<Event>(&url).await
+    }
+
+    /// Answers a meeting invite, letting the organizer know unless
+    /// `send_response` is false.
+    pub async fn respond_to_event(
+        &self,
+        event_id: &str,
+        response: EventResponse,
+        comment: &str,
+        send_response: bool,
+    ) -> Result<(), GraphClientError> {
+        let url = format!(
+            "{}/events/{}/{}",
+            self.mailbox_url(),
+            event_id,
+            response.action()
+        );
+        let payload = json!({ "comment": comment, "sendResponse": send_response });
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .json(&payload)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Sends a new message right away, keeping a copy in the Sent Items folder
+    /// when `save_to_sent_items`.
+    pub async fn send_mail(
+        &self,
+        message: &OutgoingMessage,
+        save_to_sent_items: bool,
+    ) -> Result<(), GraphClientError> {
+        let url = format!("{}/sendMail", self.mailbox_url());
+        let payload = json!({ "message": message, "saveToSentItems": save_to_sent_items });
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .json(&payload)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    pub async fn list_attachments(
+        &self,
+        email_id: &str,
+    ) -> Result<Vec<AttachmentMeta>, GraphClientError> {
+        let url = format!(
+            "{}/messages/{}/attachments?$select={}",
+            self.mailbox_url(),
+            email_id,
+            ATTACHMENT_META_FIELDS
+        );
+        self.fetch_all_items::<AttachmentMeta>(&url).await
+    }
+
+    pub async fn get_attachment(
+        &self,
+        email_id: &str,
+        attachment_id: &str,
+    ) -> Result<AttachmentMeta, GraphClientError> {
+        let url = format!(
+            "{}/messages/{}/attachments/{}?$select={}",
+            self.mailbox_url(),
+            email_id,
+            attachment_id,
+            ATTACHMENT_META_FIELDS
+        );
+        let response = self
+            .request(Method::GET, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let attachment: AttachmentMeta = response.json().await?;
+            Ok(attachment)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Returns the raw content of an attachment as a stream of chunks, so large
+    /// files don't need to be held in memory.
+    pub async fn get_attachment_content(
+        &self,
+        email_id: &str,
+        attachment_id: &str,
+    ) -> Result<impl Stream<Item = reqwest::Result<Bytes>>, GraphClientError> {
+        let url = format!(
+            "{}/messages/{}/attachments/{}/$value",
+            self.mailbox_url(),
+            email_id,
+            attachment_id
+        );
+        let response = self
+            .request(Method::GET, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(response.bytes_stream())
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Attaches a file to a draft, using an upload session when the file is too
+    /// large to be sent inline.
+    pub async fn add_attachment(
+        &self,
+        draft_id: &str,
+        name: &str,
+        content_type: &str,
+        content: &[u8],
+    ) -> Result<(), GraphClientError> {
+        if content.len() > LARGE_ATTACHMENT_THRESHOLD {
+            self.upload_large_attachment(
+                draft_id,
+                name,
+                content_type,
+                Cursor::new(content),
+                content.len(),
+            )
+            .await
+        } else {
+            self.add_file_attachment(draft_id, name, content_type, content)
+                .await
+                .map(|_| ())
+        }
+    }
+
+    pub async fn add_file_attachment(
+        &self,
+        draft_id: &str,
+        name: &str,
+        content_type: &str,
+        content: &[u8],
+    ) -> Result<AttachmentMeta, GraphClientError> {
+        let url = format!("{}/messages/{}/attachments", self.mailbox_url(), draft_id);
+        let attachment = FileAttachment::new(
+            name.to_string(),
+            content_type.to_string(),
+            base64::encode(content),
+        );
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .json(&attachment)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let attachment: AttachmentMeta = response.json().await?;
+            Ok(attachment)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    pub async fn create_upload_session(
+        &self,
+        draft_id: &str,
+        name: &str,
+        content_type: &str,
+        size: usize,
+    ) -> Result<UploadSession, GraphClientError> {
+        let url = format!(
+            "{}/messages/{}/attachments/createUploadSession",
+            self.mailbox_url(),
+            draft_id
+        );
+        let payload = json!({
+            "AttachmentItem": {
+                "attachmentType": "file",
+                "name": name,
+                "contentType": content_type,
+                "size": size,
+            }
+        });
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .json(&payload)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let session: UploadSession = response.json().await?;
+            Ok(session)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Attaches the `size` bytes of `content` to a draft through an upload
+    /// session, reading them a chunk at a time so the file doesn't have to be
+    /// held in memory.
+    pub async fn upload_large_attachment<R>(
+        &self,
+        draft_id: &str,
+        name: &str,
+        content_type: &str,
+        mut content: R,
+        size: usize,
+    ) -> Result<(), GraphClientError>
+    where
+        R: AsyncRead + AsyncSeek + Unpin,
+    {
+        let session = self
+            .create_upload_session(draft_id, name, content_type, size)
+            .await?;
+
+        let mut start = 0;
+        let mut failures = 0;
+        while start < size {
+            let end = (start + UPLOAD_CHUNK_SIZE).min(size);
+            let mut chunk = vec![0; end - start];
+            content.seek(SeekFrom::Start(start as u64)).await?;
+            content.read_exact(&mut chunk).await?;
+
+            // The upload URL is pre-authenticated, sending the bearer token is an error
+            let result = self
+                .client
+                .put(&session.upload_url)
+                .header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", start, end - 1, size),
+                )
+                .body(chunk)
+                .send_with_retry()
+                .await;
+
+            let error = match result {
+                Ok(response) if response.status().is_success() => {
+                    start = end;
+                    failures = 0;
+                    continue;
+                }
+                Ok(response) if response.status().is_client_error() => {
+                    return Err(GraphClientError::from_response(response).await);
+                }
+                Ok(response) => GraphClientError::from_response(response).await,
+                Err(err) => GraphClientError::HttpRequest(err),
+            };
+
+            failures += 1;
+            if failures > MAX_CHUNK_RETRIES {
+                return Err(error);
+            }
+            // The chunk may have made it even if the response didn't, so ask the
+            // session where to pick up from
+            warn!("Failed to upload {name} from byte {start}, resuming: {error}");
+            start = match self.get_upload_session(&session.upload_url).await {
+                // Nothing missing means the last chunk completed the upload
+                Ok(status) => next_expected_byte(&status.next_expected_ranges).unwrap_or(size),
+                Err(_) => start,
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Returns the status of an upload session, with the ranges still missing.
+    async fn get_upload_session(
+        &self,
+        upload_url: &str,
+    ) -> Result<UploadSession, GraphClientError> {
+        let response = self.client.get(upload_url).send_with_retry().await?;
+
+        if response.status().is_success() {
+            let session: UploadSession = response.json().await?;
+            Ok(session)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    pub async fn create_draft(&self, message: &OutgoingMessage) -> Result<Email, GraphClientError> {
+        let url = format!("{}/messages", self.mailbox_url());
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .json(message)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let email: Email = response.json().await?;
+            Ok(email)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    pub async fn delete_draft(&self, draft_id: &str) -> Result<(), GraphClientError> {
+        self.delete_message(draft_id).await
+    }
+
+    /// Moves a message to Deleted Items, where it can still be recovered from.
+    pub async fn delete_message(&self, email_id: &str) -> Result<(), GraphClientError> {
+        let url = format!("{}/messages/{}", self.mailbox_url(), email_id);
+
+        let response = self
+            .request(Method::DELETE, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::NOT_FOUND => Err(GraphClientError::MessageNotFound(email_id.to_string())),
+            _ => Err(GraphClientError::from_response(response).await),
+        }
+    }
+
+    /// Deletes a message for good, it can't be recovered from Deleted Items.
+    pub async fn permanently_delete_message(&self, email_id: &str) -> Result<(), GraphClientError> {
+        let url = format!(
+            "{}/messages/{}/permanentDelete",
+            self.mailbox_url(),
+            email_id
+        );
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .header("Content-Length", "0")
+            .send_with_retry()
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::NOT_FOUND => Err(GraphClientError::MessageNotFound(email_id.to_string())),
+            _ => Err(GraphClientError::from_response(response).await),
+        }
+    }
+
+    pub async fn create_reply(&self, email_id: &str) -> Result<Email, GraphClientError> {
+        self.create_response_draft(email_id, "createReply").await
+    }
+
+    pub async fn create_reply_all(&self, email_id: &str) -> Result<Email, GraphClientError> {
+        self.create_response_draft(email_id, "createReplyAll").await
+    }
+
+    pub async fn create_forward(&self, email_id: &str) -> Result<Email, GraphClientError> {
+        self.create_response_draft(email_id, "createForward").await
+    }
+
+    /// Replies to the sender with `comment` on top of the quoted email, through
+    /// a draft so Graph keeps the thread together.
+    pub async fn send_reply(&self, email_id: &str, comment: &str) -> Result<(), GraphClientError> {
+        let draft = self.create_reply(email_id).await?;
+        self.send_response_draft(draft, comment, DraftUpdate::default())
+            .await
+    }
+
+    /// Replies to the sender and every recipient, like `send_reply`.
+    pub async fn send_reply_all(
+        &self,
+        email_id: &str,
+        comment: &str,
+    ) -> Result<(), GraphClientError> {
+        let draft = self.create_reply_all(email_id).await?;
+        self.send_response_draft(draft, comment, DraftUpdate::default())
+            .await
+    }
+
+    /// Forwards the email to `to_recipients` with `comment` on top, along with
+    /// its attachments.
+    pub async fn send_forward(
+        &self,
+        email_id: &str,
+        to_recipients: &[EmailAddressWrapper],
+        comment: &str,
+    ) -> Result<(), GraphClientError> {
+        // Graph copies the original attachments into the forward draft
+        let draft = self.create_forward(email_id).await?;
+        let update = DraftUpdate {
+            to_recipients: Some(to_recipients.to_vec()),
+            ..Default::default()
+        };
+        self.send_response_draft(draft, comment, update).await
+    }
+
+    /// Adds `comment` on top of a reply or forward draft created by Graph,
+    /// applies any other changes in `update` and sends it.
+    async fn send_response_draft(
+        &self,
+        draft: Email,
+        comment: &str,
+        mut update: DraftUpdate,
+    ) -> Result<(), GraphClientError> {
+        update.body = Some(Body {
+            content_type: "html".to_string(),
+            content: prepend_to_html_body(&draft.body.content, comment),
+        });
+        self.update_draft(&draft.id, &update).await?;
+        self.send_draft(&draft.id).await
+    }
+
+    pub async fn update_draft(
+        &self,
+        draft_id: &str,
+        update: &DraftUpdate,
+    ) -> Result<Email, GraphClientError> {
+        let url = format!("{}/messages/{}", self.mailbox_url(), draft_id);
+
+        let response = self
+            .request(Method::PATCH, &url)
+            .await?
+            .json(update)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let email: Email = response.json().await?;
+            Ok(email)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    pub async fn send_draft(&self, draft_id: &str) -> Result<(), GraphClientError> {
+        let url = format!("{}/messages/{}/send", self.mailbox_url(), draft_id);
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .header("Content-Length", "0")
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    pub async fn get_user_profile(&self) -> Result<Profile, GraphClientError> {
+        let url = self.mailbox_url();
+        let response = self
+            .request(Method::GET, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let json: Profile = response.json().await?;
+            Ok(json)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    pub async fn get_mailbox_settings(&self) -> Result<MailboxSettings, GraphClientError> {
+        let url = format!(
+            "{}/mailboxSettings?$select=timeZone,workingHours,automaticRepliesSetting",
+            self.mailbox_url()
+        );
+        let response = self
+            .request(Method::GET, &url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let settings: MailboxSettings = response.json().await?;
+            Ok(settings)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Changes the settings that are set in `settings`, returning all of them.
+    pub async fn update_mailbox_settings(
+        &self,
+        settings: &MailboxSettings,
+    ) -> Result<MailboxSettings, GraphClientError> {
+        let url = format!("{}/mailboxSettings", self.mailbox_url());
+        let response = self
+            .request(Method::PATCH, &url)
+            .await?
+            .json(settings)
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let settings: MailboxSettings = response.json().await?;
+            Ok(settings)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Creates a draft in response to an existing message, using one of the Graph
+    /// `createReply`, `createReplyAll` or `createForward` actions.
+    async fn create_response_draft(
+        &self,
+        email_id: &str,
+        action: &str,
+    ) -> Result<Email, GraphClientError> {
+        let url = format!("{}/messages/{}/{}", self.mailbox_url(), email_id, action);
+
+        let response = self
+            .request(Method::POST, &url)
+            .await?
+            .header("Content-Length", "0")
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let email: Email = response.json().await?;
+            Ok(email)
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    /// Streams the pages of a Graph collection, following `@odata.nextLink` as
+    /// the pages are consumed instead of loading the whole collection first.
+    pub fn paginate<T: DeserializeOwned>(
+        &self,
+        url: String,
+    ) -> impl Stream<Item = Result<Page<T>, GraphClientError>> + '_ {
+        stream::try_unfold(Some(url), move |next_link| async move {
+            let Some(url) = next_link else {
+                return Ok(None);
+            };
+            let (page, next_link) = self.fetch_page_and_link::<T>(&url).await?;
+            Ok(Some((page, next_link)))
+        })
+    }
+
+    /// Like [`GraphClient::paginate`], but prefetching up to `concurrency`
+    /// pages at once while still yielding them in order. Pages are addressed by
+    /// `$skip` out of the total the first one reports, so the collection has to
+    /// support `$count`.
+    pub fn paginate_concurrently<T: DeserializeOwned + 'static>(
+        &self,
+        url: String,
+        page_size: usize,
+        concurrency: usize,
+    ) -> impl Stream<Item = Result<Page<T>, GraphClientError>> + '_ {
+        let first_url = page_url(&url, page_size, 0, true);
+        stream::once(async move { self.fetch_page::<T>(&first_url).await })
+            .map_ok(move |first| {
+                let num_pages = match first.total {
+                    Some(total) => (total as usize).div_ceil(page_size),
+                    None => {
+                        if first.has_more {
+                            warn!("No total count on {url}, only the first page is fetched");
+                        }
+                        1
+                    }
+                };
+                let url = url.clone();
+                let rest = stream::iter(1..num_pages)
+                    .map(move |page| {
+                        let url = page_url(&url, page_size, page * page_size, false);
+                        async move { self.fetch_page::<T>(&url).await }
+                    })
+                    .buffered(concurrency.max(1));
+                stream::once(async { Ok(first) }).chain(rest)
+            })
+            .try_flatten()
+    }
+
+    async fn fetch_all_items<T: DeserializeOwned>(
+        &self,
+        base_url: &str,
+    ) -> Result<Vec<T>, GraphClientError> {
+        self.fetch_items(base_url, None).await
+    }
+
+    /// Reads the items of a collection page by page, stopping at the page
+    /// reaching `max_items` rather than fetching the rest of them.
+    async fn fetch_items<T: DeserializeOwned>(
+        &self,
+        base_url: &str,
+        max_items: Option<usize>,
+    ) -> Result<Vec<T>, GraphClientError> {
+        let max_items = max_items.unwrap_or(usize::MAX);
+        let mut items = Vec::new();
+        let mut pages = Box::pin(self.paginate::<T>(base_url.to_string()));
+        while items.len() < max_items {
+            let Some(page) = pages.try_next().await? else {
+                break;
+            };
+            items.extend(page.items);
+        }
+        items.truncate(max_items);
+        Ok(items)
+    }
+
+    async fn fetch_page<T: DeserializeOwned>(
+        &self,
+        url: &str,
+    ) -> Result<Page<T>, GraphClientError> {
+        Ok(self.fetch_page_and_link(url).await?.0)
+    }
+
+    /// Fetches a page along with the link to the next one, if any.
+    async fn fetch_page_and_link<T: DeserializeOwned>(
+        &self,
+        url: &str,
+    ) -> Result<(Page<T>, Option<String>), GraphClientError> {
+        let response = self
+            .request(Method::GET, url)
+            .await?
+            .send_with_retry()
+            .await?;
+
+        if response.status().is_success() {
+            let json: Value = response.json().await?;
+            let item_values = json["value"]
+                .as_array()
+                .ok_or_else(|| GraphClientError::Parse("items", json.clone()))?;
+
+            let items: Vec<T> = item_values
+                .iter()
+                .map(|item_value| serde_json::from_value(item_value.clone()))
+                .collect::<Result<Vec<T>, _>>()?;
+            let next_link = json["@odata.nextLink"].as_str().map(str::to_string);
+
+            Ok((
+                Page {
+                    items,
+                    total: json["@odata.count"].as_u64(),
+                    has_more: next_link.is_some(),
+                },
+                next_link,
+            ))
+        } else {
+            Err(GraphClientError::from_response(response).await)
+        }
+    }
+
+    async fn fetch_pages<T: DeserializeOwned>(
+        &self,
+        base_url: &str,
+        initial_page: usize,
+        num_pages: usize,
+    ) -> Result<(Vec<T>, bool), GraphClientError> {
+        let separator = if base_url.contains('?') { '&' } else { '?' };
+        let url = format!(
+            "{}{}$skip={}",
+            base_url,
+            separator,
+            initial_page * num_pages
+        );
+
+        let mut items = Vec::new();
+        let mut has_more_pages = false;
+        let mut pages = Box::pin(self.paginate::<T>(url).take(num_pages));
+        while let Some(page) = pages.try_next().await? {
+            has_more_pages = page.has_more;
+            items.extend(page.items);
+        }
+
+        Ok((items, has_more_pages))
+    }
+
+    /// Resolves a folder by its well-known name, its display name ignoring case,
+    /// or its id. Nested folders are found by their path, like `Projects/2023`.
+    pub async fn get_folder_id_by_name(
+        &mut self,
+        folder_name: &str,
+    ) -> Result<String, GraphClientError> {
+        if let Some(folder_id) = self.folder_cache.get(&self.mailbox, folder_name) {
+            return Ok(folder_id);
+        }
+
+        let mut segments = folder_name
+            .split('/')
+            .map(str::trim)
+            .filter(|segment| !segment.is_empty());
+        let not_found = || GraphClientError::FolderNotFound(folder_name.to_string());
+        let top = segments.next().ok_or_else(not_found)?;
+        let folder_id = match self.find_top_folder(top).await? {
+            Some(mut folder_id) => {
+                for segment in segments {
+                    folder_id = self
+                        .get_child_folders(&folder_id)
+                        .await?
+                        .into_iter()
+                        .find(|f| f.display_name.to_lowercase() == segment.to_lowercase())
+                        .ok_or_else(not_found)?
+                        .id;
+                }
+                folder_id
+            }
+            // Ids and display names may contain slashes too
+            None if top != folder_name => self
+                .find_top_folder(folder_name)
+                .await?
+                .ok_or_else(not_found)?,
+            None => return Err(not_found()),
+        };
+
+        self.folder_cache
+            .insert(&self.mailbox, folder_name, &folder_id);
+        Ok(folder_id)
+    }
+
+    /// Whether the folder is Deleted Items, where emails go when they're
+    /// deleted without `permanent`.
+    pub async fn is_deleted_items(&mut self, folder_name: &str) -> Result<bool, GraphClientError> {
+        if well_known_folder(folder_name) == Some("deleteditems") {
+            return Ok(true);
+        }
+        Ok(self.get_folder_id_by_name(folder_name).await?
+            == self.get_folder_id_by_name("deleteditems").await?)
+    }
+
+    /// Finds the id of a top level folder by its well-known name, falling back
+    /// to its display name or id, as localized mailboxes name them differently.
+    async fn find_top_folder(&self, name: &str) -> Result<Option<String>, GraphClientError> {
+        if let Some(well_known) = well_known_folder(name) {
+            let url = format!(
+                "{}/mailFolders/{}?$select=id",
+                self.mailbox_url(),
+                well_known
+            );
+            let response = self
+                .request(Method::GET, &url)
+                .await?
+                .send_with_retry()
+                .await?;
+
+            match response.status() {
+                status if status.is_success() => {
+                    let folder: Value = response.json().await?;
+                    if let Some(id) = folder["id"].as_str() {
+                        return Ok(Some(id.to_string()));
+                    }
+                }
+                // Not every mailbox has all of them, like archive
+                StatusCode::NOT_FOUND => {}
+                _ => return Err(GraphClientError::from_response(response).await),
+            }
+        }
+
+        let folders = self.get_user_folders(&GraphQuery::new()).await?;
+        Ok(folders
+            .into_iter()
+            .find(|f| f.display_name.to_lowercase() == name.to_lowercase() || f.id == name)
+            .map(|f| f.id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_body() {
+        let body = r#"
+           {
+                "contentType": "html",
+                "content": "<html><head>"
+            }
+        "#;
+        let body: Body = serde_json::from_str(body).unwrap();
+        assert_eq!(body.content_type, "html");
+    }
+
+    #[test]
+    fn test_prepend_to_html_body() {
+        let html = r#"<html><head></head><body dir="ltr"><p>Original</p></body></html>"#;
+        assert_eq!(
+            prepend_to_html_body(html, "<p>Reply</p>"),
+            r#"<html><head></head><body dir="ltr"><p>Reply</p><p>Original</p></body></html>"#
+        );
+        assert_eq!(
+            prepend_to_html_body("<p>Original</p>", "<p>Reply</p>"),
+            "<p>Reply</p><p>Original</p>"
+        );
+        // Lowercasing İ takes more bytes, which mustn't shift the position
+        assert_eq!(
+            prepend_to_html_body("<title>İ</title><BODY><p>Original</p>", "<p>Reply</p>"),
+            "<title>İ</title><BODY><p>Reply</p><p>Original</p>"
+        );
+    }
+
+    #[test]
+    fn test_graph_api_error_from_json() {
+        let json = json!({
+            "error": {
+                "code": "ErrorItemNotFound",
+                "message": "The specified object was not found in the store.",
+                "innerError": { "request-id": "2c0d7e4a" }
+            }
+        });
+        let error = GraphApiError::from_json(StatusCode::NOT_FOUND, &json).unwrap();
+        assert_eq!(error.code, "ErrorItemNotFound");
+        assert_eq!(
+            error.message,
+            "The specified object was not found in the store."
+        );
+        assert_eq!(error.inner_error.unwrap()["request-id"], "2c0d7e4a");
+
+        assert!(GraphApiError::from_json(StatusCode::NOT_FOUND, &json!({})).is_none());
+    }
+
+    #[test]
+    fn test_next_expected_byte() {
+        let ranges = vec!["3276800-".to_string(), "1024-2047".to_string()];
+        assert_eq!(next_expected_byte(&ranges), Some(1024));
+        assert_eq!(next_expected_byte(&[]), None);
+    }
+
+    #[test]
+    fn test_message_patch_json() {
+        assert_eq!(
+            serde_json::to_value(MessagePatch::new()).unwrap(),
+            json!({})
+        );
+
+        let patch = MessagePatch::new()
+            .read(false)
+            .importance(Importance::High)
+            .flag(FlagStatus::Flagged)
+            .inference_classification(InferenceClassification::Focused);
+        assert_eq!(
+            serde_json::to_value(patch).unwrap(),
+            json!({
+                "isRead": false,
+                "importance": "high",
+                "flag": { "flagStatus": "flagged" },
+                "inferenceClassification": "focused",
+            })
+        );
+
+        let start = Utc.with_ymd_and_hms(2023, 4, 10, 9, 0, 0).unwrap();
+        let patch = MessagePatch::new().flag_due(start, start + chrono::Duration::days(1));
+        assert_eq!(
+            serde_json::to_value(patch).unwrap(),
+            json!({
+                "flag": {
+                    "flagStatus": "flagged",
+                    "startDateTime": { "dateTime": "2023-04-10T09:00:00", "timeZone": "UTC" },
+                    "dueDateTime": { "dateTime": "2023-04-11T09:00:00", "timeZone": "UTC" },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_well_known_folder() {
+        assert_eq!(well_known_folder("Junk Email"), Some("junkemail"));
+        assert_eq!(well_known_folder("deleteditems"), Some("deleteditems"));
+        assert_eq!(well_known_folder("Inbox"), Some("inbox"));
+        assert_eq!(well_known_folder("Lixo Eletrônico"), None);
+        assert_eq!(well_known_folder("Projects"), None);
+    }
+
+    #[test]
+    fn test_email_fields_select() {
+        let without_body = EmailFields::WithoutBody.select();
+        assert!(without_body.starts_with("$select=id,"));
+        assert!(!without_body.contains("body,") && !without_body.ends_with(",body"));
+        // Meeting invites and cancellations are told apart by it
+        assert!(without_body.contains(",microsoft.graph.eventMessage/meetingMessageType"));
+        assert_eq!(
+            EmailFields::WithBody.select(),
+            format!("{},body", without_body)
+        );
+    }
+
+    #[test]
+    fn test_graph_query_string() {
+        assert_eq!(GraphQuery::new().to_query_string(), "");
+
+        let query = GraphQuery::new()
+            .filter("isRead eq false")
+            .filter("from/emailAddress/address eq 'a@b.com'")
+            .order_by("receivedDateTime desc")
+            .top(10)
+            .skip(20)
+            .count()
+            .expand("childFolders");
+        assert_eq!(
+            query.to_query_string(),
+            "$filter=%28isRead%20eq%20false%29%20and%20%28from%2FemailAddress%2Faddress%20eq%20%27a%40b.com%27%29\
+            &$orderby=receivedDateTime%20desc&$top=10&$skip=20&$count=true&$expand=childFolders"
+        );
+        assert_eq!(
+            GraphQuery::new()
+                .top(5)
+                .apply("https://x/me/messages?$select=id".to_string()),
+            "https://x/me/messages?$select=id&$top=5"
+        );
+    }
+
+    #[test]
+    fn test_email_attachments() {
+        assert_eq!(
+            GraphQuery::new().attachments().to_query_string(),
+            "$expand=attachments%28%24select%3Did%2Cname%2CcontentType%2Csize%2CisInline%29"
+        );
+
+        let json = fs::read_to_string("src/fixtures/empty-subject.json").unwrap();
+        let mut json = serde_json::from_str::<Value>(&json).unwrap();
+        let email: Email = serde_json::from_value(json.clone()).unwrap();
+        assert!(email.attachments.is_none());
+
+        json["attachments"] = json!([{
+            "@odata.type": "#microsoft.graph.fileAttachment",
+            "id": "att-1",
+            "name": "invoice.pdf",
+            "contentType": "application/pdf",
+            "size": 52011,
+            "isInline": false
+        }]);
+        let email: Email = serde_json::from_value(json).unwrap();
+        let attachments = email.attachments.unwrap();
+        assert_eq!(attachments[0].name, "invoice.pdf");
+        assert_eq!(attachments[0].size, 52011);
+        assert!(!attachments[0].is_inline);
+    }
+
+    #[test]
+    fn test_search_param() {
+        assert_eq!(search_param("invoice"), "%22invoice%22");
+        assert_eq!(
+            search_param(r#"subject:"q1 report""#),
+            "%22subject%3A%5C%22q1%20report%5C%22%22"
+        );
+    }
+
+    #[test]
+    fn test_mailbox_settings() {
+        let json = json!({
+            "@odata.context": "https://graph.microsoft.com/v1.0/$metadata#Me/mailboxSettings",
+            "timeZone": "E. South America Standard Time",
+            "workingHours": {
+                "daysOfWeek": ["monday", "tuesday"],
+                "startTime": "08:00:00.0000000",
+                "endTime": "17:00:00.0000000",
+                "timeZone": { "name": "E. South America Standard Time" }
+            },
+            "automaticRepliesSetting": {
+                "status": "disabled",
+                "externalAudience": "all",
+                "internalReplyMessage": "",
+                "externalReplyMessage": ""
+            }
+        });
+        let settings: MailboxSettings = serde_json::from_value(json).unwrap();
+        assert_eq!(settings.working_hours.unwrap().days_of_week.len(), 2);
+        assert_eq!(
+            settings.automatic_replies_setting.unwrap().status,
+            "disabled"
+        );
+
+        let update = MailboxSettings {
+            time_zone: Some("UTC".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(update).unwrap(),
+            json!({ "timeZone": "UTC" })
+        );
+    }
+
+    #[test]
+    fn test_thread_position() {
+        let root = base64::encode([1u8; 22]);
+        let reply = base64::encode([&[1u8; 22][..], &[0u8; 5]].concat());
+        let later_reply = base64::encode([&[1u8; 22][..], &[200u8; 5]].concat());
+        let mut indexes = vec![&later_reply, &root, &reply];
+        indexes.sort_by_key(|index| thread_position(index));
+        assert_eq!(indexes, vec![&root, &reply, &later_reply]);
+    }
+
+    #[test]
+    fn test_folder_cache() {
+        let cache = FolderCache::new(Duration::from_secs(60));
+        cache.insert("a@example.com", "Archive", "1");
+        cache.insert("b@example.com", "Archive", "2");
+        assert_eq!(cache.get("a@example.com", "Archive").as_deref(), Some("1"));
+        assert_eq!(cache.get("a@example.com", "Inbox"), None);
+
+        cache.invalidate("a@example.com");
+        assert_eq!(cache.get("a@example.com", "Archive"), None);
+        assert_eq!(cache.get("b@example.com", "Archive").as_deref(), Some("2"));
+
+        let expired = FolderCache::new(Duration::ZERO);
+        expired.insert("a@example.com", "Archive", "1");
+        assert_eq!(expired.get("a@example.com", "Archive"), None);
+    }
+
+    #[test]
+    fn test_unknown_enum_values() {
+        let importance: Importance = serde_json::from_value(json!("high")).unwrap();
+        assert_eq!(importance, Importance::High);
+        let importance: Importance = serde_json::from_value(json!("urgent")).unwrap();
+        assert_eq!(importance, Importance::Unknown);
+        let status: FlagStatus = serde_json::from_value(json!("notFlagged")).unwrap();
+        assert_eq!(status, FlagStatus::NotFlagged);
+        assert_eq!(
+            serde_json::to_value(InferenceClassification::Other).unwrap(),
+            json!("other")
+        );
+    }
+
+    #[test]
+    fn test_endpoint_label() {
+        let id = "AAMkAGI2TG93AAA=";
+        assert_eq!(
+            endpoint_label(&format!("/v1.0/me/messages/{id}/move")),
+            "/me/messages/{id}/move"
+        );
+        assert_eq!(
+            endpoint_label(&format!("/v1.0/me/messages/{}", "A".repeat(150))),
+            "/me/messages/{id}"
+        );
+        assert_eq!(
+            endpoint_label("/v1.0/me/mailFolders/inbox/messages"),
+            "/me/mailFolders/inbox/messages"
+        );
+        assert_eq!(endpoint_label("/v1.0/$batch"), "/$batch");
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let endpoints = Endpoints::new("gcchigh".parse().unwrap(), None);
+        assert_eq!(endpoints.graph, "https://graph.microsoft.us/v1.0");
+        assert_eq!(endpoints.login, "https://login.microsoftonline.us");
+        assert_eq!(endpoints.graph_resource(), "https://graph.microsoft.us");
+
+        let endpoints = Endpoints::new(
+            Cloud::Global,
+            Some("https://graph.microsoft.com/beta/".to_string()),
+        );
+        assert_eq!(endpoints.graph, "https://graph.microsoft.com/beta");
+        assert_eq!(endpoints.graph_resource(), "https://graph.microsoft.com");
+        assert!("mars".parse::<Cloud>().is_err());
+    }
+
+    #[test]
+    fn test_mailbox_url() {
+        let graph = GraphClient::builder("token".to_string())
+            .base_url("https://x/v1.0")
+            .build();
+        assert_eq!(graph.mailbox_url(), "https://x/v1.0/me");
+
+        let graph = GraphClient::builder("token".to_string())
+            .base_url("https://x/v1.0")
+            .user("jane@example.com")
+            .build();
+        assert_eq!(graph.mailbox_url(), "https://x/v1.0/users/jane@example.com");
+    }
+
+    #[test]
+    fn test_page_url() {
+        assert_eq!(
+            page_url("https://x/me/messages?$select=id", 100, 0, true),
+            "https://x/me/messages?$select=id&$top=100&$skip=0&$count=true"
+        );
+        assert_eq!(
+            page_url("https://x/me/messages", 50, 150, false),
+            "https://x/me/messages?$top=50&$skip=150"
+        );
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(0, None, 0.0), Duration::from_millis(500));
+        assert_eq!(retry_delay(2, None, 0.0), Duration::from_secs(2));
+        assert_eq!(retry_delay(2, None, 1.0), Duration::from_secs(3));
+        assert_eq!(
+            retry_delay(0, Some(Duration::from_secs(7)), 1.0),
+            Duration::from_secs(7)
+        );
+        assert_eq!(retry_delay(10, None, 0.0), RETRY_MAX_DELAY);
+        assert_eq!(
+            retry_delay(0, Some(Duration::from_secs(120)), 0.0),
+            RETRY_MAX_DELAY
+        );
+    }
+
+    #[test]
+    fn test_delta_token_from_link() {
+        let link = "https://graph.microsoft.com/v1.0/me/mailFolders/inbox/messages/delta?$deltatoken=abc%3D%3D";
+        assert_eq!(delta_token_from_link(link), Some("abc==".to_string()));
+        assert_eq!(
+            delta_token_from_link("https://graph.microsoft.com/v1.0/me/messages"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_append_to_html_body() {
+        let html = r#"<html><body dir="ltr"><p>Hello</p></BODY></html>"#;
+        assert_eq!(
+            append_to_html_body(html, "<p>Bye</p>"),
+            r#"<html><body dir="ltr"><p>Hello</p><p>Bye</p></BODY></html>"#
+        );
+        assert_eq!(
+            append_to_html_body("<p>Hello</p>", "<p>Bye</p>"),
+            "<p>Hello</p><p>Bye</p>"
+        );
+    }
+
+    #[test]
+    fn test_body_into_text() {
+        let body = Body {
+            content_type: "html".to_string(),
+            content: "<html><body><p>Hello <b>there</b></p><ul><li>One</li></ul></body></html>"
+                .to_string(),
+        }
+        .into_text(80);
+        assert_eq!(body.content_type, "text");
+        assert_eq!(body.content, "Hello there\n\n* One\n");
+
+        let body = Body {
+            content_type: "text".to_string(),
+            content: "<b>not html</b>".to_string(),
+        }
+        .into_text(80);
+        assert_eq!(body.content, "<b>not html</b>");
+    }
+
+    #[test]
+    fn test_batch_request_json() {
+        let request = BatchRequest::new("PATCH", "/me/messages/abc".to_string())
+            .with_body(json!({ "isRead": true }));
+        assert_eq!(
+            request.to_json(3, false),
+            json!({
+                "id": "3",
+                "method": "PATCH",
+                "url": "/me/messages/abc",
+                "body": { "isRead": true },
+                "headers": { "Content-Type": "application/json" },
+            })
+        );
+
+        let request = BatchRequest::new("DELETE", "/me/messages/abc".to_string());
+        assert_eq!(
+            request.to_json(0, false),
+            json!({ "id": "0", "method": "DELETE", "url": "/me/messages/abc" })
+        );
+        assert_eq!(
+            request.to_json(1, true),
+            json!({
+                "id": "1",
+                "method": "DELETE",
+                "url": "/me/messages/abc",
+                "headers": { "Prefer": "IdType=\"ImmutableId\"" },
+            })
+        );
+    }
+
+    #[test]
+    fn test_bulk_result_error() {
+        let response: BatchResponse = serde_json::from_value(json!({
+            "id": "1",
+            "status": 404,
+            "body": { "error": { "code": "ErrorItemNotFound", "message": "Not found." } }
+        }))
+        .unwrap();
+        let result = BulkResult::new("abc", response);
+        assert_eq!(result.status, 404);
+        assert_eq!(result.error.as_deref(), Some("Not found."));
+        assert_eq!(result.new_id, None);
+    }
+
+    #[test]
+    fn test_bulk_result_new_id() {
+        let moved: BatchResponse = serde_json::from_value(json!({
+            "id": "0",
+            "status": 201,
+            "body": { "id": "def" }
+        }))
+        .unwrap();
+        let result = BulkResult::new("abc", moved);
+        assert_eq!(result.error, None);
+        assert_eq!(result.new_id.as_deref(), Some("def"));
+
+        let updated: BatchResponse = serde_json::from_value(json!({
+            "id": "1",
+            "status": 200,
+            "body": { "id": "abc" }
+        }))
+        .unwrap();
+        assert_eq!(BulkResult::new("abc", updated).new_id, None);
+    }
+
+    #[test]
+    fn test_parsing() {
+        let json = fs::read_to_string("src/fixtures/broken-sender.json").unwrap();
+        let json = serde_json::from_str::<Value>(&json).unwrap();
+        let emails_value = json["value"].as_array();
+        let email: Email = serde_json::from_value(emails_value.unwrap()[0].clone()).unwrap();
+        let sender = email.sender.unwrap();
+        assert_eq!(sender.email_address.name, "Giuliana Reggi");
+    }
+
+    #[test]
+    fn test_empty_subject() {
+        let json = fs::read_to_string("src/fixtures/empty-subject.json").unwrap();
+        let json = serde_json::from_str::<Value>(&json).unwrap();
+        let email: Email = serde_json::from_value(json).unwrap();
+        let sender = email.sender.unwrap();
+        assert_eq!(sender.email_address.name, "Sarah McFarlin");
+        assert_eq!(email.subject, "");
+    }
+
+    #[test]
+    fn test_no_address_on_sender() {
+        let json = fs::read_to_string("src/fixtures/empty-subject.json").unwrap();
+        let mut json = serde_json::from_str::<Value>(&json).unwrap();
+        if let Some(sender) = json.pointer_mut("/sender") {
+            if let Some(email_address) = sender.pointer_mut("/emailAddress") {
+                if let Some(sender_obj) = email_address.as_object_mut() {
+                    sender_obj.remove("address");
+                }
+            }
+        }
+        let email: Email = serde_json::from_value(json).unwrap();
+        let sender = email.sender.unwrap();
+        assert_eq!(sender.email_address.name, "Sarah McFarlin");
+        assert_eq!(email.subject, "");
+    }
+
+    #[test]
+    fn test_no_sender_no_from() {
+        let json = fs::read_to_string("src/fixtures/no-sender-no-from.json").unwrap();
+        let json = serde_json::from_str::<Value>(&json).unwrap();
+        let email: Email = serde_json::from_value(json).unwrap();
+        assert!(email.sender.is_none());
+        assert!(email.from.is_none());
+    }
+}
@@ -0,0 +1,286 @@
+use aes::Aes256;
+use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+use hmac::{Hmac, Mac, NewMac};
+use rsa::{pkcs8::FromPrivateKey, PaddingScheme, RsaPrivateKey};
+use serde::Deserialize;
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use thiserror::Error;
+
+/// Validation tokens are short sentences, anything longer isn't from Graph.
+const MAX_VALIDATION_TOKEN_LENGTH: usize = 1024;
+
+#[derive(Error, Debug)]
+pub enum NotificationError {
+    #[error("Invalid validation token")]
+    InvalidValidationToken,
+
+    #[error("Invalid notifications: {0}")]
+    Payload(#[from] serde_json::Error),
+
+    #[error("Invalid decryption key: {0}")]
+    Key(String),
+
+    #[error("Invalid certificate: {0}")]
+    Certificate(String),
+
+    #[error("Invalid encrypted content: {0}")]
+    Encoding(#[from] base64::DecodeError),
+
+    #[error("Failed to decrypt the content of the notification")]
+    Decryption,
+
+    #[error("The signature of the encrypted content doesn't match")]
+    Signature,
+}
+
+/// A batch of change notifications, as posted by Graph to the notification
+/// url of a subscription.
+#[derive(Deserialize, Debug)]
+pub struct ChangeNotifications {
+    pub value: Vec<ChangeNotification>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeNotification {
+    pub subscription_id: String,
+    /// The secret given when subscribing, proving the notification comes from
+    /// Graph
+    pub client_state: Option<String>,
+    /// `created`, `updated` or `deleted`
+    pub change_type: String,
+    pub resource: String,
+    pub resource_data: Option<Value>,
+    /// The resource itself, on subscriptions including it
+    #[serde(default)]
+    pub encrypted_content: Option<EncryptedContent>,
+}
+
+impl ChangeNotification {
+    /// Returns the id of the message that changed.
+    pub fn message_id(&self) -> Option<&str> {
+        self.resource_data
+            .as_ref()
+            .and_then(|data| data["id"].as_str())
+            .or_else(|| self.resource.rsplit('/').next())
+    }
+
+    /// Whether the notification carries the client state of the subscription,
+    /// compared in constant time.
+    pub fn verify_client_state(&self, expected: &str) -> bool {
+        self.client_state
+            .as_deref()
+            .is_some_and(|client_state| constant_time_eq(client_state, expected))
+    }
+}
+
+/// The resource data of a rich notification, encrypted with a symmetric key
+/// which is itself encrypted with the public key given when subscribing.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EncryptedContent {
+    /// Base64 of the AES-256 encrypted resource
+    pub data: String,
+    /// Base64 HMAC-SHA256 of `data`, keyed with the symmetric key
+    pub data_signature: String,
+    /// Base64 of the symmetric key, encrypted with RSA-OAEP
+    pub data_key: String,
+    /// The id given along with the certificate when subscribing, to pick its
+    /// private key when there are several
+    pub encryption_certificate_id: String,
+    pub encryption_certificate_thumbprint: String,
+}
+
+impl EncryptedContent {
+    /// Decrypts the resource with the private key, in PKCS#8 PEM, of the
+    /// certificate it was encrypted for, checking its signature first.
+    pub fn decrypt(&self, private_key_pem: &str) -> Result<Value, NotificationError> {
+        let private_key = RsaPrivateKey::from_pkcs8_pem(private_key_pem)
+            .map_err(|err| NotificationError::Key(err.to_string()))?;
+        let key = private_key
+            .decrypt(
+                PaddingScheme::new_oaep::<sha1::Sha1>(),
+                &base64::decode(&self.data_key)?,
+            )
+            .map_err(|_| NotificationError::Decryption)?;
+
+        let data = base64::decode(&self.data)?;
+        verify_signature(&key, &data, &base64::decode(&self.data_signature)?)?;
+        let content = decrypt_data(&key, &data)?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+}
+
+/// The certificate Graph encrypts the resource of rich notifications for,
+/// along with its private key.
+#[derive(Clone)]
+pub struct Certificate {
+    /// Base64 of the DER certificate, as Graph takes it when subscribing
+    pub encoded: String,
+    /// The SHA-1 thumbprint of the certificate, given to Graph as its id to
+    /// tell which certificate a notification was encrypted for
+    pub id: String,
+    /// In PKCS#8 PEM
+    private_key: String,
+}
+
+impl Certificate {
+    /// Reads the certificate and its private key, both in PEM. Only the first
+    /// certificate of a chain is used.
+    pub fn from_pem(
+        certificate_pem: &str,
+        private_key_pem: String,
+    ) -> Result<Self, NotificationError> {
+        let encoded: String = certificate_pem
+            .lines()
+            .map(str::trim)
+            .skip_while(|line| !line.starts_with("-----BEGIN CERTIFICATE"))
+            .skip(1)
+            .take_while(|line| !line.starts_with("-----END"))
+            .collect();
+        let der = base64::decode(&encoded)?;
+        if der.is_empty() {
+            return Err(NotificationError::Certificate(
+                "no certificate in the PEM".to_string(),
+            ));
+        }
+        // Checked now rather than on the first notification
+        RsaPrivateKey::from_pkcs8_pem(&private_key_pem)
+            .map_err(|err| NotificationError::Key(err.to_string()))?;
+
+        let id = Sha1::digest(&der)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        Ok(Self {
+            encoded,
+            id,
+            private_key: private_key_pem,
+        })
+    }
+
+    /// Decrypts the resource of a notification, `None` when it was encrypted
+    /// for another certificate.
+    pub fn decrypt(&self, content: &EncryptedContent) -> Option<Result<Value, NotificationError>> {
+        (content.encryption_certificate_id == self.id).then(|| content.decrypt(&self.private_key))
+    }
+}
+
+/// Returns the token to answer the validation request Graph sends when
+/// subscribing, which has to be echoed back as plain text. Tokens that
+/// couldn't have come from Graph are refused so the endpoint can't be used to
+/// reflect arbitrary content.
+pub fn validation_response(token: &str) -> Result<&str, NotificationError> {
+    if token.is_empty()
+        || token.len() > MAX_VALIDATION_TOKEN_LENGTH
+        || token
+            .chars()
+            .any(|c| c.is_control() || c == '<' || c == '>')
+    {
+        return Err(NotificationError::InvalidValidationToken);
+    }
+    Ok(token)
+}
+
+/// Parses the body of a notifications request.
+pub fn decode(body: &[u8]) -> Result<ChangeNotifications, NotificationError> {
+    Ok(serde_json::from_slice(body)?)
+}
+
+fn verify_signature(key: &[u8], data: &[u8], signature: &[u8]) -> Result<(), NotificationError> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).map_err(|_| NotificationError::Signature)?;
+    mac.update(data);
+    mac.verify(signature)
+        .map_err(|_| NotificationError::Signature)
+}
+
+/// Decrypts AES-256-CBC data with PKCS7 padding, Graph uses the first 16 bytes
+/// of the key as the IV.
+fn decrypt_data(key: &[u8], data: &[u8]) -> Result<Vec<u8>, NotificationError> {
+    let iv = key.get(..16).ok_or(NotificationError::Decryption)?;
+    Cbc::<Aes256, Pkcs7>::new_from_slices(key, iv)
+        .map_err(|_| NotificationError::Decryption)?
+        .decrypt_vec(data)
+        .map_err(|_| NotificationError::Decryption)
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_change_notification() {
+        let body = json!({
+            "value": [{
+                "subscriptionId": "sub-1",
+                "clientState": "secret",
+                "changeType": "created",
+                "resource": "Users/user-1/Messages/msg-1",
+                "resourceData": { "@odata.type": "#Microsoft.Graph.Message", "id": "msg-1" },
+                "tenantId": "tenant-1"
+            }]
+        });
+        let notifications = decode(body.to_string().as_bytes()).unwrap();
+        let notification = &notifications.value[0];
+        assert!(notification.verify_client_state("secret"));
+        assert!(!notification.verify_client_state("secreT"));
+        assert!(!notification.verify_client_state("secret2"));
+        assert_eq!(notification.message_id(), Some("msg-1"));
+
+        let without_data = ChangeNotification {
+            resource_data: None,
+            client_state: None,
+            ..notification.clone()
+        };
+        assert_eq!(without_data.message_id(), Some("msg-1"));
+        assert!(!without_data.verify_client_state("secret"));
+
+        assert!(decode(b"{").is_err());
+    }
+
+    #[test]
+    fn test_validation_response() {
+        let token = "Validation: Testing client application reachability for subscription Request-Id: 7b2d0a6d";
+        assert_eq!(validation_response(token).unwrap(), token);
+        assert!(validation_response("").is_err());
+        assert!(validation_response("<script>alert(1)</script>").is_err());
+        assert!(validation_response(&"a".repeat(2000)).is_err());
+    }
+
+    #[test]
+    fn test_certificate_from_pem() {
+        let missing = Certificate::from_pem("", String::new());
+        assert!(matches!(missing, Err(NotificationError::Certificate(_))));
+
+        let pem = "-----BEGIN CERTIFICATE-----\nAQID\n-----END CERTIFICATE-----\n";
+        let without_key = Certificate::from_pem(pem, "not a key".to_string());
+        assert!(matches!(without_key, Err(NotificationError::Key(_))));
+    }
+
+    #[test]
+    fn test_decrypt_data() {
+        let key = [7u8; 32];
+        let data = Cbc::<Aes256, Pkcs7>::new_from_slices(&key, &key[..16])
+            .unwrap()
+            .encrypt_vec(br#"{"id":"msg-1"}"#);
+        assert_eq!(decrypt_data(&key, &data).unwrap(), br#"{"id":"msg-1"}"#);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+        mac.update(&data);
+        let signature = mac.finalize().into_bytes();
+        assert!(verify_signature(&key, &data, &signature).is_ok());
+        assert!(verify_signature(&[8u8; 32], &data, &signature).is_err());
+    }
+}
@@ -1,18 +1,34 @@
-use std::{env, sync::Mutex};
+use std::{collections::HashSet, env, sync::Mutex, time::Duration};
 
 use base64::{encode_config, URL_SAFE_NO_PAD};
+use chrono::Utc;
+use futures::TryStreamExt;
 use meilisearch_sdk::Client;
 use postgres_queue::{TaskData, TaskError};
+use reqwest::StatusCode;
 use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
 use tokio::task::spawn_blocking;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
-    database::{Database, User},
-    graph::{Email, GraphClient},
+    database::{Database, Message, QueryTimer, SyncState, User},
+    graph::{Email, EmailFields, Folder, GraphClient, GraphClientError, GraphQuery, MessagesDelta},
+    token,
 };
 
+/// Name of the recurring queue task indexing the changes made to a mailbox
+/// since its last run.
+pub const INCREMENTAL_INDEX_TASK: &str = "incremental_index";
+
+/// How often the changes to an indexed mailbox are picked up.
+const INCREMENTAL_INDEX_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Number of pages of emails fetched at once while indexing a mailbox.
+const INDEX_CONCURRENCY: usize = 4;
+/// Pages of full emails can take a while to download.
+const INDEX_REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
 pub async fn full_index_handler_sync(task_id: i32, task_data: TaskData) -> Result<(), TaskError> {
     let fut = Mutex::new(Box::pin(full_index_handler(task_id, task_data)));
     spawn_blocking(move || {
@@ -30,6 +46,22 @@ fn generate_deterministic_key(id: &str) -> String {
     encode_config(hash, URL_SAFE_NO_PAD)
 }
 
+/// Turns emails into Meilisearch documents, keyed by a hash of their id.
+fn to_documents(emails: Vec<Email>) -> Vec<Value> {
+    emails
+        .into_iter()
+        .map(|email| {
+            let mut json = serde_json::to_value(email).unwrap();
+            let id = json["id"].as_str().unwrap();
+            let unique_id = generate_deterministic_key(id);
+            json.as_object_mut()
+                .unwrap()
+                .insert("uniqueId".to_string(), Value::String(unique_id));
+            json
+        })
+        .collect()
+}
+
 pub async fn full_index_handler(_task_id: i32, task_data: TaskData) -> Result<(), TaskError> {
     info!("Full index handler called: {task_data:#?}");
     let user_email = task_data.get("user_email").unwrap().as_str().unwrap();
@@ -45,54 +77,86 @@ pub async fn full_index_handler(_task_id: i32, task_data: TaskData) -> Result<()
 
     let database_url = std::env::var("DATABASE_URL").unwrap();
     let database = Database::new(database_url.clone()).await.unwrap();
-    let client = database.get().await.unwrap();
-    let user = User::find(&client, user_email).await.unwrap().unwrap();
-
-    let Some(token) = user.access_token else {
-        return Err(TaskError::Custom("No access token".to_string()));
-    };
+    let db_client = database.get().await.unwrap();
+    let user = User::find(&db_client, user_email).await.unwrap().unwrap();
+    let user_id = user.id.unwrap();
+    let started_at = Utc::now();
 
     let endpoint = env::var("SEARCH_ENDPOINT").expect("missing SEARCH_ENDPOINT");
     let master_key = env::var("SEARCH_MASTER_KEY").expect("missing SEARCH_MASTER_KEY");
     info!("Connecting to Meilisearch at {}", endpoint);
     let client = Client::new(endpoint, master_key);
-    let graph = GraphClient::new(token);
-
-    let (emails, has_more) = if has_pagination {
-        graph
-            .get_user_emails_paginated(start_page as usize, num_pages as usize)
-            .await
-            .unwrap()
-    } else {
-        (graph.get_user_emails().await.unwrap(), false)
+    // Indexing a large mailbox can outlive the access token
+    let mut graph = token::mailbox_client(database, user_email)
+        .timeout(INDEX_REQUEST_TIMEOUT)
+        .build();
+    // Deleted emails are cached but kept out of search
+    let deleted_items = graph
+        .get_folder_id_by_name("deleteditems")
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+    let searchable = |emails: Vec<Email>| -> Vec<Email> {
+        emails
+            .into_iter()
+            .filter(|email| email.parent_folder_id != deleted_items)
+            .collect()
     };
 
-    let documents = emails
-        .into_iter()
-        .map(|email| {
-            let mut json = serde_json::to_value(email).unwrap();
-            let id = json["id"].as_str().unwrap();
-            let unique_id = generate_deterministic_key(id);
-            json.as_object_mut()
-                .unwrap()
-                .insert("uniqueId".to_string(), Value::String(unique_id));
-            json
-        })
-        .collect::<Vec<Value>>();
-
-    info!(
-        "Indexing {} emails. Has more? {}",
-        documents.len(),
+    let index = client.index(format!("emails_{user_id}"));
+    let has_more = if has_pagination {
+        let (emails, has_more) = graph
+            .get_user_emails_paginated(
+                start_page as usize,
+                num_pages as usize,
+                EmailFields::WithBody,
+            )
+            .await
+            .unwrap();
+        info!("Indexing {} emails. Has more? {}", emails.len(), has_more);
+        Message::upsert_many(&db_client, user_id, &emails)
+            .await
+            .map_err(|e| TaskError::Custom(e.to_string()))?;
+        let result = index
+            .add_documents(&to_documents(searchable(emails)), Some("uniqueId"))
+            .await
+            .unwrap();
+        info!("Meilisearch result: {:#?}", result);
         has_more
-    );
+    } else {
+        // Each page is indexed as it arrives, instead of holding the whole
+        // mailbox in memory
+        let mut pages = Box::pin(graph.stream_user_emails(
+            &GraphQuery::new(),
+            EmailFields::WithBody,
+            INDEX_CONCURRENCY,
+        ));
+        while let Some(emails) = pages
+            .try_next()
+            .await
+            .map_err(|e| TaskError::Custom(e.to_string()))?
+        {
+            info!("Indexing {} emails", emails.len());
+            Message::upsert_many(&db_client, user_id, &emails)
+                .await
+                .map_err(|e| TaskError::Custom(e.to_string()))?;
+            let result = index
+                .add_documents(&to_documents(searchable(emails)), Some("uniqueId"))
+                .await
+                .unwrap();
+            info!("Meilisearch result: {:#?}", result);
+        }
 
-    // Add emails to Meilisearch
-    let result = client
-        .index(format!("emails_{}", user.id.unwrap()))
-        .add_documents(&documents, Some("uniqueId"))
-        .await
-        .unwrap();
-    info!("Meilisearch result: {:#?}", result);
+        // Whatever the sync didn't see again is gone from the mailbox, and the
+        // listings can be served from the cache from now on
+        let removed = Message::delete_synced_before(&db_client, user_id, started_at)
+            .await
+            .map_err(|e| TaskError::Custom(e.to_string()))?;
+        info!("Removed {removed} emails gone from the mailbox from the cache");
+        user.set_messages_synced_at(&db_client, Some(started_at))
+            .await
+            .map_err(|e| TaskError::Custom(e.to_string()))?;
+        false
+    };
 
     // enqueue next task if has_more
     if has_more {
@@ -113,17 +177,241 @@ pub async fn full_index_handler(_task_id: i32, task_data: TaskData) -> Result<()
             None,
         )
         .await?;
+    } else {
+        // The whole mailbox is in the index, from now on only its changes are
+        schedule_incremental(&db_client, user_email)
+            .await
+            .map_err(|e| TaskError::Custom(e.to_string()))?;
     }
 
     Ok(())
 }
 
-pub async fn search(user_email: &str, term: &str) -> anyhow::Result<Vec<Email>> {
-    let database_url = std::env::var("DATABASE_URL").unwrap();
-    let database = Database::new(database_url.clone()).await.unwrap();
-    let client = database.get().await.unwrap();
-    let user = User::find(&client, user_email).await.unwrap().unwrap();
+/// Schedules the recurring task indexing the changes to the user's mailbox,
+/// unless it's already scheduled.
+pub async fn schedule_incremental(
+    client: &deadpool_postgres::Client,
+    user_email: &str,
+) -> anyhow::Result<()> {
+    let _timer = QueryTimer::start("index::schedule_incremental");
+    let scheduled = client
+        .query_opt(
+            "SELECT id FROM task_queue
+            WHERE name = $1 AND task_data->>'user_email' = $2 AND status IN ('queued', 'processing')
+            LIMIT 1",
+            &[&INCREMENTAL_INDEX_TASK, &user_email],
+        )
+        .await?
+        .is_some();
+    if !scheduled {
+        postgres_queue::enqueue(
+            client,
+            INCREMENTAL_INDEX_TASK,
+            json!({ "user_email": user_email }),
+            Utc::now(),
+            Some(INCREMENTAL_INDEX_INTERVAL),
+        )
+        .await?;
+    }
+    Ok(())
+}
 
+pub async fn incremental_index_handler_sync(
+    task_id: i32,
+    task_data: TaskData,
+) -> Result<(), TaskError> {
+    let fut = Mutex::new(Box::pin(incremental_index_handler(task_id, task_data)));
+    spawn_blocking(move || {
+        let mut guard = fut.lock().unwrap();
+        futures::executor::block_on(&mut *guard)
+    })
+    .await
+    .map_err(|e| TaskError::Custom(e.to_string()))?
+}
+
+pub async fn incremental_index_handler(task_id: i32, task_data: TaskData) -> Result<(), TaskError> {
+    let user_email = task_data["user_email"]
+        .as_str()
+        .ok_or_else(|| TaskError::Custom("missing user_email".to_string()))?;
+    info!("Indexing the changes to {user_email} (task {task_id})");
+
+    index_changes(user_email)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))
+}
+
+/// Indexes the emails changed in each folder since the last run, with Graph
+/// delta queries, and deletes the ones removed from the mailbox. Folders
+/// without a delta token yet are indexed whole.
+async fn index_changes(user_email: &str) -> anyhow::Result<()> {
+    let database = Database::new(env::var("DATABASE_URL")?).await?;
+    let db_client = database.get().await?;
+    let Some(user) = User::find(&db_client, user_email).await? else {
+        anyhow::bail!("user {user_email} not found");
+    };
+    let Some(user_id) = user.id else {
+        anyhow::bail!("user {user_email} has no id");
+    };
+    let started_at = Utc::now();
+
+    let client = Client::new(env::var("SEARCH_ENDPOINT")?, env::var("SEARCH_MASTER_KEY")?);
+    let index = client.index(format!("emails_{user_id}"));
+    let mut graph = token::mailbox_client(database, user_email)
+        .timeout(INDEX_REQUEST_TIMEOUT)
+        .build();
+
+    // A message moved between folders is removed from one and changed in the
+    // other, so it's only deleted when no folder still has it. Deleted Items
+    // stays in the cache but out of the index.
+    let mut indexed = HashSet::new();
+    let mut trashed = HashSet::new();
+    let mut removed = Vec::new();
+    for (folder_id, in_deleted_items) in folder_ids(&mut graph).await? {
+        let delta = folder_delta(&graph, &db_client, user_id, &folder_id).await?;
+        let item_count = (delta.changed.len() + delta.removed.len()) as i32;
+        info!(
+            "Folder {folder_id} has {} changed and {} removed emails",
+            delta.changed.len(),
+            delta.removed.len()
+        );
+
+        removed.extend(delta.removed);
+        Message::upsert_many(&db_client, user_id, &delta.changed).await?;
+        let ids = delta.changed.iter().map(|email| email.id.clone());
+        if in_deleted_items {
+            trashed.extend(ids);
+        } else if !delta.changed.is_empty() {
+            indexed.extend(ids);
+            index
+                .add_documents(&to_documents(delta.changed), Some("uniqueId"))
+                .await?;
+        }
+        // Only once the changes are in, a failure above retries them
+        SyncState::record(
+            &db_client,
+            user_id,
+            SyncState::INDEX,
+            &folder_id,
+            Some(&delta.delta_token),
+            item_count,
+        )
+        .await?;
+    }
+
+    removed.retain(|id| !indexed.contains(id));
+    let unindexed: HashSet<&String> = removed.iter().chain(&trashed).collect();
+    let unindexed: Vec<String> = unindexed.into_iter().cloned().collect();
+    if !unindexed.is_empty() {
+        info!(
+            "Deleting {} emails gone or deleted from the index",
+            unindexed.len()
+        );
+        delete_documents(user_id, &unindexed).await?;
+    }
+    removed.retain(|id| !trashed.contains(id));
+    Message::delete_many(&db_client, user_id, &removed).await?;
+
+    // Every folder is caught up, so listings can be served from the cache
+    user.set_messages_synced_at(&db_client, Some(started_at))
+        .await?;
+    Ok(())
+}
+
+/// The changes to the folder since the last run, or all of its emails when
+/// it's new or Graph forgot its delta token. Other errors are returned, for the
+/// task to run again from the same token.
+async fn folder_delta(
+    graph: &GraphClient,
+    db_client: &deadpool_postgres::Client,
+    user_id: i32,
+    folder_id: &str,
+) -> anyhow::Result<MessagesDelta> {
+    let delta_token = SyncState::find(db_client, user_id, SyncState::INDEX, folder_id)
+        .await?
+        .and_then(|state| state.delta_token);
+    match graph
+        .get_messages_delta(folder_id, delta_token.as_deref())
+        .await
+    {
+        Err(err) if delta_token.is_some() && is_sync_state_expired(&err) => {
+            warn!("Indexing folder {folder_id} again from scratch after: {err}");
+            Ok(graph.get_messages_delta(folder_id, None).await?)
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Whether Graph doesn't know the delta token anymore, which it answers with
+/// `410 Gone` or a `syncStateNotFound` error.
+fn is_sync_state_expired(err: &GraphClientError) -> bool {
+    if err.status() == Some(StatusCode::GONE) {
+        return true;
+    }
+    matches!(err, GraphClientError::Api(err) if err.code.eq_ignore_ascii_case("syncStateNotFound"))
+}
+
+/// The ids of every folder of the mailbox, child folders included, with
+/// whether they're in Deleted Items.
+async fn folder_ids(graph: &mut GraphClient) -> Result<Vec<(String, bool)>, GraphClientError> {
+    let deleted_items = graph.get_folder_id_by_name("deleteditems").await?;
+    let mut folders: Vec<(Folder, bool)> = graph
+        .get_user_folders(&GraphQuery::new())
+        .await?
+        .into_iter()
+        .map(|folder| {
+            let in_deleted_items = folder.id == deleted_items;
+            (folder, in_deleted_items)
+        })
+        .collect();
+    let mut ids = Vec::new();
+    while let Some((folder, in_deleted_items)) = folders.pop() {
+        if folder.child_folder_count > 0 {
+            let children = graph.get_child_folders(&folder.id).await?;
+            folders.extend(children.into_iter().map(|child| (child, in_deleted_items)));
+        }
+        ids.push((folder.id, in_deleted_items));
+    }
+    Ok(ids)
+}
+
+/// Deletes the emails from the index of the user's mailbox, returning false
+/// when search isn't configured.
+pub async fn delete_documents(user_id: i32, email_ids: &[String]) -> anyhow::Result<bool> {
+    let (Ok(endpoint), Ok(master_key)) =
+        (env::var("SEARCH_ENDPOINT"), env::var("SEARCH_MASTER_KEY"))
+    else {
+        return Ok(false);
+    };
+    if email_ids.is_empty() {
+        return Ok(true);
+    }
+    let keys: Vec<String> = email_ids
+        .iter()
+        .map(|id| generate_deterministic_key(id))
+        .collect();
+    Client::new(endpoint, master_key)
+        .index(format!("emails_{user_id}"))
+        .delete_documents(&keys)
+        .await?;
+    Ok(true)
+}
+
+/// Deletes the index of the user's mailbox, returning false when search isn't
+/// configured.
+pub async fn delete_index(user_id: i32) -> anyhow::Result<bool> {
+    let (Ok(endpoint), Ok(master_key)) =
+        (env::var("SEARCH_ENDPOINT"), env::var("SEARCH_MASTER_KEY"))
+    else {
+        return Ok(false);
+    };
+    let client = Client::new(endpoint, master_key);
+    // Meilisearch deletes the index in the background, and ignores it if
+    // there's none
+    client.index(format!("emails_{user_id}")).delete().await?;
+    Ok(true)
+}
+
+pub async fn search(user: &User, term: &str) -> anyhow::Result<Vec<Email>> {
     let endpoint = env::var("SEARCH_ENDPOINT").expect("missing SEARCH_ENDPOINT");
     let master_key = env::var("SEARCH_MASTER_KEY").expect("missing SEARCH_MASTER_KEY");
     info!("Connecting to Meilisearch at {}", endpoint);
@@ -137,3 +425,21 @@ pub async fn search(user_email: &str, term: &str) -> anyhow::Result<Vec<Email>>
     let emails: Vec<Email> = results.hits.into_iter().map(|hit| hit.result).collect();
     Ok(emails)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_sync_state_expired() {
+        assert!(is_sync_state_expired(&GraphClientError::Request(
+            StatusCode::GONE
+        )));
+        assert!(!is_sync_state_expired(&GraphClientError::Request(
+            StatusCode::TOO_MANY_REQUESTS
+        )));
+        assert!(!is_sync_state_expired(&GraphClientError::Request(
+            StatusCode::SERVICE_UNAVAILABLE
+        )));
+    }
+}
@@ -1,20 +1,36 @@
 mod api;
 mod auth;
+mod backend;
 mod database;
+mod events;
 mod graph;
 mod index;
+mod metrics;
+mod rules;
+mod send_later;
+mod snooze;
 mod token;
+mod token_store;
+mod user_data;
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
-use api::Server;
+use anyhow::bail;
+use api::{
+    Admins, CorsConfig, NotificationCertificate, NotificationUrl, RateLimit, Server, TokenValidator,
+};
 use clap::{Parser, Subcommand};
 use dotenvy::dotenv;
 use postgres_queue::{initialize_database, TaskRegistry};
-use tracing::info;
+use tracing::{info, warn};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
-use crate::auth::Token;
+use crate::{
+    auth::{AuthConfig, OAuthProvider, Token},
+    database::{Database, User},
+    graph::{notifications::Certificate, Cloud, Endpoints, HttpConfig},
+    token_store::TokenStore,
+};
 
 #[derive(Parser, Debug)]
 pub struct Cli {
@@ -23,6 +39,62 @@ pub struct Cli {
 
     #[arg(short, long)]
     debug: bool,
+
+    /// Timeout of each request to Graph, in seconds
+    #[arg(long, env = "HTTP_TIMEOUT", default_value = "60", global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    http_timeout: u64,
+
+    /// Proxy for the requests to Graph, `HTTP_PROXY` and `HTTPS_PROXY` are
+    /// honored when unset
+    #[arg(long, env = "GRAPH_PROXY", global = true)]
+    http_proxy: Option<String>,
+
+    /// Use immutable message ids, which survive moves between folders.
+    /// Switching it on or off changes every id, the search index has to be
+    /// rebuilt
+    #[arg(long, env = "IMMUTABLE_IDS", global = true)]
+    immutable_ids: bool,
+
+    /// Microsoft cloud of the tenant: `global`, `usgov` (GCC High),
+    /// `usgovdod` or `china` (21Vianet)
+    #[arg(long, env = "GRAPH_CLOUD", default_value = "global", global = true)]
+    cloud: Cloud,
+
+    /// Root of the Graph API, overriding the one of the cloud, like
+    /// `https://graph.microsoft.com/beta`
+    #[arg(long, env = "GRAPH_API_BASE_URL", global = true)]
+    graph_base_url: Option<String>,
+
+    /// Reach mailboxes in background tasks with the app's own token, through
+    /// the client credentials grant on the `TENANT_ID` tenant, instead of the
+    /// tokens of their users. Mailboxes are registered by admins
+    #[arg(long, env = "APP_ONLY", global = true)]
+    app_only: bool,
+
+    /// Tenant users sign in with: `common`, `organizations`, `consumers`, or
+    /// the id or domain of the tenant of a single tenant app registration
+    #[arg(long, env = "TENANT_ID", default_value = auth::DEFAULT_TENANT, global = true)]
+    tenant: String,
+
+    /// Redirect URI registered with the app, the sign in of `auth set` is
+    /// received on its port
+    #[arg(long, env = "OAUTH_REDIRECT_URI", default_value = auth::DEFAULT_REDIRECT_URI, global = true)]
+    redirect_uri: String,
+
+    /// Scopes requested when signing in with Microsoft, space separated,
+    /// instead of the mail ones
+    #[arg(long, env = "OAUTH_SCOPES", global = true)]
+    scopes: Option<String>,
+
+    /// Where `auth set` saves the token: `file`, a plaintext file in the
+    /// config directory, or `keyring`, the system keyring
+    #[arg(long, env = "TOKEN_STORE", default_value = "file", global = true)]
+    token_store: TokenStore,
+
+    /// Saved account to act as, added with `auth add`. `serve` and `workers`
+    /// register its tokens so background tasks run for its mailbox
+    #[arg(long, env = "POSTARS_ACCOUNT", global = true)]
+    account: Option<String>,
 }
 
 #[derive(Subcommand, Clone, Debug)]
@@ -34,6 +106,85 @@ enum Command {
 
         #[arg(short, long, env = "DATABASE_URL")]
         database_url: String,
+
+        /// Read-only replica of the database, listings are read from it
+        #[arg(long, env = "DATABASE_READ_URL")]
+        database_read_url: Option<String>,
+
+        /// Number of requests a user can make within the rate limit window
+        #[arg(long, env = "RATE_LIMIT_BURST", default_value = "120", value_parser = clap::value_parser!(u32).range(1..))]
+        rate_limit_burst: u32,
+
+        /// Rate limit window, in seconds
+        #[arg(long, env = "RATE_LIMIT_WINDOW", default_value = "60", value_parser = clap::value_parser!(u64).range(1..))]
+        rate_limit_window: u64,
+
+        /// Origins allowed to make cross-origin requests, `*` allows any
+        #[arg(
+            long,
+            env = "CORS_ALLOWED_ORIGINS",
+            default_value = "*",
+            value_delimiter = ','
+        )]
+        cors_allowed_origins: Vec<String>,
+
+        /// Methods allowed in cross-origin requests, `*` allows any
+        #[arg(
+            long,
+            env = "CORS_ALLOWED_METHODS",
+            default_value = "*",
+            value_delimiter = ','
+        )]
+        cors_allowed_methods: Vec<String>,
+
+        /// Headers allowed in cross-origin requests, `*` allows any
+        #[arg(
+            long,
+            env = "CORS_ALLOWED_HEADERS",
+            default_value = "*",
+            value_delimiter = ','
+        )]
+        cors_allowed_headers: Vec<String>,
+
+        /// Allow cross-origin requests with credentials such as cookies
+        #[arg(long, env = "CORS_ALLOW_CREDENTIALS")]
+        cors_allow_credentials: bool,
+
+        /// Emails of the users allowed to use the admin routes
+        #[arg(long, env = "ADMIN_EMAILS", value_delimiter = ',')]
+        admin_emails: Vec<String>,
+
+        /// Public URL of `/api/notifications` that Graph sends change
+        /// notifications to, like `https://postars.example.com/api/notifications`
+        #[arg(long, env = "NOTIFICATION_URL")]
+        notification_url: Option<String>,
+
+        /// PEM certificate Graph encrypts the messages included in change
+        /// notifications for, requires `--notification-key`
+        #[arg(long, env = "NOTIFICATION_CERT", requires = "notification_key")]
+        notification_cert: Option<PathBuf>,
+
+        /// PKCS#8 PEM private key of the notification certificate
+        #[arg(long, env = "NOTIFICATION_KEY", requires = "notification_cert")]
+        notification_key: Option<PathBuf>,
+
+        /// PEM certificate to serve HTTPS with, requires `--tls-key`
+        #[arg(long, env = "TLS_CERT", requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+
+        /// PEM private key of the TLS certificate
+        #[arg(long, env = "TLS_KEY", requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+
+        /// Trust bearer tokens without checking they're signed by Microsoft,
+        /// for development against made up tokens. Never in production
+        #[arg(long, env = "INSECURE_SKIP_TOKEN_VALIDATION")]
+        insecure_skip_token_validation: bool,
+
+        /// Don't apply migrations when starting, for deployments running
+        /// `db migrate` beforehand
+        #[arg(long, env = "SKIP_MIGRATIONS")]
+        skip_migrations: bool,
     },
     Auth {
         #[command(subcommand)]
@@ -53,12 +204,67 @@ enum Command {
         task_name: String,
         task_data: Option<String>,
     },
+    User {
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum DbCommand {
+    /// Applies the migrations the database doesn't have yet
+    Migrate {
+        #[arg(short, long, env = "DATABASE_URL")]
+        database_url: String,
+    },
+    /// Lists the migrations, applied or pending
+    Status {
+        #[arg(short, long, env = "DATABASE_URL")]
+        database_url: String,
+    },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum UserCommand {
+    /// Deletes everything stored about a user: their tokens, settings, cached
+    /// messages, queued tasks and search index
+    Delete {
+        email: String,
+
+        #[arg(short, long, env = "DATABASE_URL")]
+        database_url: String,
+    },
 }
 
 #[derive(Subcommand, Clone, Debug)]
 enum AuthCommand {
+    /// Prints the token of `--account`, or the unnamed one
     Get,
-    Set,
+    /// Prints an IMAP access token of `--account`, or the unnamed one, for
+    /// IMAP clients signing in with XOAUTH2 like himalaya
+    Imap,
+    /// Signs in and saves the token as `--account`, or the unnamed one
+    Set {
+        /// Who to sign in with: `microsoft`, or `google` for Gmail
+        #[arg(long, default_value = "microsoft")]
+        provider: OAuthProvider,
+    },
+    /// Signs in and saves the token as a named account
+    Add {
+        name: String,
+
+        /// Who to sign in with: `microsoft`, or `google` for Gmail
+        #[arg(long, default_value = "microsoft")]
+        provider: OAuthProvider,
+    },
+    /// Lists the named accounts
+    List,
+    /// Forgets the token of a named account
+    Remove { name: String },
 }
 
 #[tokio::main]
@@ -67,22 +273,129 @@ async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     setup_logging(&cli)?;
+    graph::init_http_client(&HttpConfig {
+        timeout: Duration::from_secs(cli.http_timeout),
+        proxy: cli.http_proxy.clone(),
+        immutable_ids: cli.immutable_ids,
+        endpoints: Endpoints::new(cli.cloud, cli.graph_base_url.clone()),
+    })?;
+    token::set_app_only(cli.app_only);
+    auth::init(AuthConfig {
+        tenant: cli.tenant.clone(),
+        redirect_uri: cli.redirect_uri.clone(),
+        scopes: cli.scopes.clone(),
+    })?;
 
     match cli.command {
-        Command::Serve { bind, database_url } => Ok(serve(bind, database_url).await?),
+        Command::Serve {
+            bind,
+            database_url,
+            database_read_url,
+            rate_limit_burst,
+            rate_limit_window,
+            cors_allowed_origins,
+            cors_allowed_methods,
+            cors_allowed_headers,
+            cors_allow_credentials,
+            admin_emails,
+            notification_url,
+            notification_cert,
+            notification_key,
+            tls_cert,
+            tls_key,
+            insecure_skip_token_validation,
+            skip_migrations,
+        } => {
+            let rate_limit =
+                RateLimit::new(rate_limit_burst, Duration::from_secs(rate_limit_window));
+            let cors = CorsConfig::new(
+                &cors_allowed_origins,
+                &cors_allowed_methods,
+                &cors_allowed_headers,
+                cors_allow_credentials,
+            )?;
+            let tls = tls_cert.zip(tls_key);
+            let admins = Admins::new(admin_emails);
+            let notification_url = NotificationUrl::new(notification_url);
+            let notification_certificate = match notification_cert.zip(notification_key) {
+                Some((cert_path, key_path)) => Some(Certificate::from_pem(
+                    &std::fs::read_to_string(cert_path)?,
+                    std::fs::read_to_string(key_path)?,
+                )?),
+                None => None,
+            };
+            let notification_certificate = NotificationCertificate::new(notification_certificate);
+            if let Some(account) = &cli.account {
+                register_account(&database_url, cli.token_store, account).await?;
+            }
+            let token_validator = if insecure_skip_token_validation {
+                warn!("Bearer tokens are trusted without validation");
+                TokenValidator::insecure()
+            } else {
+                TokenValidator::new()
+            };
+            Ok(serve(
+                bind,
+                database_url,
+                database_read_url,
+                rate_limit,
+                cors,
+                admins,
+                notification_url,
+                notification_certificate,
+                token_validator,
+                tls,
+                !skip_migrations,
+            )
+            .await?)
+        }
         Command::Auth { command } => match command {
-            AuthCommand::Set => auth().await,
+            AuthCommand::Set { provider } => {
+                auth(provider, cli.token_store, cli.account.as_deref()).await
+            }
+            AuthCommand::Add { name, provider } => {
+                auth(provider, cli.token_store, Some(&name)).await
+            }
             AuthCommand::Get => {
-                let token: Token = confy::load("postars", None)?;
+                let token = saved_token(cli.token_store, cli.account.as_deref()).await?;
                 let json = serde_json::to_string_pretty(&token)?;
                 println!("{}", json);
                 Ok(())
             }
+            AuthCommand::Imap => {
+                let account = cli.account.as_deref();
+                let mut token = cli.token_store.load(account)?;
+                let imap_token = auth::imap_token(&token).await?;
+                if imap_token.refresh_code != token.refresh_code {
+                    token.refresh_code = imap_token.refresh_code;
+                    cli.token_store.store(account, &token)?;
+                }
+                println!("{}", imap_token.access_code);
+                Ok(())
+            }
+            AuthCommand::List => {
+                for name in cli.token_store.accounts()? {
+                    let token = cli.token_store.load(Some(&name))?;
+                    println!("{name}\t{:?}", token.provider);
+                }
+                Ok(())
+            }
+            AuthCommand::Remove { name } => {
+                if cli.token_store.remove(&name)? {
+                    println!("Account {name} removed.");
+                } else {
+                    println!("No account named {name}.");
+                }
+                Ok(())
+            }
         },
         Command::Workers {
             num_workers,
             database_url,
         } => {
+            if let Some(account) = &cli.account {
+                register_account(&database_url, cli.token_store, account).await?;
+            }
             info!("Starting {} workers...", num_workers);
 
             let pool = postgres_queue::connect(&database_url)
@@ -95,6 +408,22 @@ async fn main() -> anyhow::Result<()> {
 
             let mut registry = TaskRegistry::new();
             registry.register_task("full_index".to_string(), index::full_index_handler_sync);
+            registry.register_task(
+                index::INCREMENTAL_INDEX_TASK.to_string(),
+                index::incremental_index_handler_sync,
+            );
+            registry.register_task(
+                rules::APPLY_RULES_TASK.to_string(),
+                rules::apply_rules_handler_sync,
+            );
+            registry.register_task(
+                snooze::UNSNOOZE_TASK.to_string(),
+                snooze::unsnooze_handler_sync,
+            );
+            registry.register_task(
+                send_later::SEND_EMAIL_TASK.to_string(),
+                send_later::send_email_handler_sync,
+            );
 
             let tasks = registry
                 .run(&pool, num_workers)
@@ -138,6 +467,43 @@ async fn main() -> anyhow::Result<()> {
 
             Ok(())
         }
+        Command::Db { command } => match command {
+            DbCommand::Migrate { database_url } => {
+                let database = Database::new(database_url).await?;
+                let applied = database.migrate().await?;
+                if applied.is_empty() {
+                    println!("The database is up to date.");
+                }
+                for name in applied {
+                    println!("Applied {name}");
+                }
+                Ok(())
+            }
+            DbCommand::Status { database_url } => {
+                let database = Database::new(database_url).await?;
+                for migration in database.migration_status().await? {
+                    match migration.applied_on {
+                        Some(applied_on) => println!("{}  applied {applied_on}", migration.name),
+                        None => println!("{}  pending", migration.name),
+                    }
+                }
+                Ok(())
+            }
+        },
+        Command::User { command } => match command {
+            UserCommand::Delete {
+                email,
+                database_url,
+            } => {
+                let database = Database::new(database_url).await?;
+                if user_data::delete_user(&database, &email).await? {
+                    println!("User {email} deleted.");
+                } else {
+                    println!("No user {email}.");
+                }
+                Ok(())
+            }
+        },
     }
 }
 
@@ -157,14 +523,94 @@ fn setup_logging(cli: &Cli) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn serve(bind: SocketAddr, database_url: String) -> anyhow::Result<()> {
-    Server::new(bind, database_url).start().await
+#[allow(clippy::too_many_arguments)]
+async fn serve(
+    bind: SocketAddr,
+    database_url: String,
+    database_read_url: Option<String>,
+    rate_limit: RateLimit,
+    cors: CorsConfig,
+    admins: Admins,
+    notification_url: NotificationUrl,
+    notification_certificate: NotificationCertificate,
+    token_validator: TokenValidator,
+    tls: Option<(PathBuf, PathBuf)>,
+    migrate: bool,
+) -> anyhow::Result<()> {
+    let mut server = Server::new(bind, database_url)
+        .with_migrations(migrate)
+        .with_rate_limit(rate_limit)
+        .with_cors(cors)
+        .with_admins(admins)
+        .with_notification_url(notification_url)
+        .with_notification_certificate(notification_certificate)
+        .with_token_validator(token_validator);
+    if let Some(database_read_url) = database_read_url {
+        server = server.with_read_database_url(database_read_url);
+    }
+    if let Some((cert_path, key_path)) = tls {
+        server = server.with_tls(cert_path, key_path);
+    }
+    server.start().await
 }
 
-async fn auth() -> anyhow::Result<()> {
-    let token = tokio::task::spawn_blocking(auth::auth).await??;
-    confy::store("postars", None, token)?;
+async fn auth(
+    provider: OAuthProvider,
+    store: TokenStore,
+    account: Option<&str>,
+) -> anyhow::Result<()> {
+    let token = auth::auth(provider).await?;
+    store.store(account, &token)?;
     println!("Auth saved.");
 
     Ok(())
 }
+
+/// Returns the saved token, refreshing and saving it first when it's about to
+/// expire.
+async fn saved_token(store: TokenStore, account: Option<&str>) -> anyhow::Result<Token> {
+    let token = store.load(account)?;
+    if token.access_code.is_empty() || !token.is_expiring() {
+        return Ok(token);
+    }
+
+    info!("Saved token expired, refreshing...");
+    let token = auth::refresh(&token).await?;
+    store.store(account, &token)?;
+    Ok(token)
+}
+
+/// Registers the tokens of a saved account as those of its user, so background
+/// tasks can act on the mailbox.
+async fn register_account(
+    database_url: &str,
+    store: TokenStore,
+    account: &str,
+) -> anyhow::Result<()> {
+    let token = saved_token(store, Some(account)).await?;
+    let Some(refresh_code) = token.refresh_code.as_deref() else {
+        bail!("account {account} isn't saved, add it with `auth add {account}`");
+    };
+    if token.provider != OAuthProvider::Microsoft {
+        bail!("account {account} isn't a Microsoft account");
+    }
+
+    let email = token.provider.address(&token.access_code).await?;
+    info!("Acting as account {account} ({email})...");
+    let database = Database::new(database_url.to_string()).await?;
+    database.migrate().await?;
+    database
+        .transaction(|tx| {
+            let (email, access_code, scopes) = (
+                email.clone(),
+                token.access_code.clone(),
+                token.scopes.clone(),
+            );
+            let refresh_code = refresh_code.to_string();
+            Box::pin(async move {
+                User::upsert_with_tokens(tx, &email, &access_code, &refresh_code, &scopes).await
+            })
+        })
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Counters of the calls made to Graph, rendered in the Prometheus text format
+/// by `/metrics`.
+pub struct GraphMetrics {
+    requests: Mutex<BTreeMap<RequestKey, RequestStats>>,
+    retries: AtomicU64,
+    throttled: AtomicU64,
+}
+
+/// Method, endpoint with its ids left out, and status of a request.
+type RequestKey = (String, String, u16);
+
+#[derive(Default)]
+struct RequestStats {
+    count: u64,
+    seconds: f64,
+}
+
+pub static GRAPH_METRICS: GraphMetrics = GraphMetrics::new();
+
+/// Upper bounds, in seconds, of the buckets of the database histograms.
+const DATABASE_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+/// Histograms of the time spent on the database, rendered next to the Graph
+/// metrics by `/metrics`.
+pub struct DatabaseMetrics {
+    /// By statement name, like `User::find`
+    queries: Mutex<BTreeMap<String, Histogram>>,
+    /// By pool, `primary` or `replica`
+    pool_waits: Mutex<BTreeMap<&'static str, Histogram>>,
+    slow_queries: Mutex<BTreeMap<String, u64>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations in each of [`DATABASE_BUCKETS`], not cumulative
+    buckets: [u64; DATABASE_BUCKETS.len()],
+    count: u64,
+    seconds: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = DATABASE_BUCKETS.iter().position(|le| seconds <= *le) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.seconds += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (le, count) in DATABASE_BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.seconds);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+pub static DATABASE_METRICS: DatabaseMetrics = DatabaseMetrics::new();
+
+impl GraphMetrics {
+    const fn new() -> Self {
+        Self {
+            requests: Mutex::new(BTreeMap::new()),
+            retries: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a response, `elapsed` being the time until it arrived.
+    pub fn record_response(&self, method: &str, endpoint: &str, status: u16, elapsed: Duration) {
+        let mut requests = self.requests.lock().unwrap();
+        let stats = requests
+            .entry((method.to_string(), endpoint.to_string(), status))
+            .or_default();
+        stats.count += 1;
+        stats.seconds += elapsed.as_secs_f64();
+    }
+
+    /// Records a request sent again, `throttled` when Graph answered `429`.
+    pub fn record_retry(&self, throttled: bool) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        if throttled {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let requests = self.requests.lock().unwrap();
+
+        out.push_str("# HELP graph_requests_total Responses received from Graph.\n");
+        out.push_str("# TYPE graph_requests_total counter\n");
+        for ((method, endpoint, status), stats) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "graph_requests_total{{method=\"{method}\",endpoint=\"{endpoint}\",status=\"{status}\"}} {}",
+                stats.count
+            );
+        }
+
+        out.push_str("# HELP graph_request_duration_seconds Time until Graph responded.\n");
+        out.push_str("# TYPE graph_request_duration_seconds summary\n");
+        for ((method, endpoint, status), stats) in requests.iter() {
+            let labels = format!("method=\"{method}\",endpoint=\"{endpoint}\",status=\"{status}\"");
+            let _ = writeln!(
+                out,
+                "graph_request_duration_seconds_sum{{{labels}}} {}",
+                stats.seconds
+            );
+            let _ = writeln!(
+                out,
+                "graph_request_duration_seconds_count{{{labels}}} {}",
+                stats.count
+            );
+        }
+
+        out.push_str("# HELP graph_retries_total Requests sent again after a 429 or 503.\n");
+        out.push_str("# TYPE graph_retries_total counter\n");
+        let _ = writeln!(
+            out,
+            "graph_retries_total {}",
+            self.retries.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP graph_throttled_total Requests Graph throttled with a 429.\n");
+        out.push_str("# TYPE graph_throttled_total counter\n");
+        let _ = writeln!(
+            out,
+            "graph_throttled_total {}",
+            self.throttled.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+impl DatabaseMetrics {
+    const fn new() -> Self {
+        Self {
+            queries: Mutex::new(BTreeMap::new()),
+            pool_waits: Mutex::new(BTreeMap::new()),
+            slow_queries: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records the time `statement` took, and whether it was over the slow
+    /// query threshold.
+    pub fn record_query(&self, statement: &str, elapsed: Duration, slow: bool) {
+        self.queries
+            .lock()
+            .unwrap()
+            .entry(statement.to_string())
+            .or_default()
+            .observe(elapsed);
+        if slow {
+            *self
+                .slow_queries
+                .lock()
+                .unwrap()
+                .entry(statement.to_string())
+                .or_default() += 1;
+        }
+    }
+
+    /// Records the time spent waiting for a connection of `pool`.
+    pub fn record_pool_wait(&self, pool: &'static str, elapsed: Duration) {
+        self.pool_waits
+            .lock()
+            .unwrap()
+            .entry(pool)
+            .or_default()
+            .observe(elapsed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP database_query_duration_seconds Time the statements took.\n");
+        out.push_str("# TYPE database_query_duration_seconds histogram\n");
+        for (statement, histogram) in self.queries.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "database_query_duration_seconds",
+                &format!("statement=\"{statement}\""),
+            );
+        }
+
+        out.push_str(
+            "# HELP database_slow_queries_total Statements over the slow query threshold.\n",
+        );
+        out.push_str("# TYPE database_slow_queries_total counter\n");
+        for (statement, count) in self.slow_queries.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "database_slow_queries_total{{statement=\"{statement}\"}} {count}"
+            );
+        }
+
+        out.push_str(
+            "# HELP database_pool_wait_seconds Time spent waiting for a pooled connection.\n",
+        );
+        out.push_str("# TYPE database_pool_wait_seconds histogram\n");
+        for (pool, histogram) in self.pool_waits.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "database_pool_wait_seconds",
+                &format!("pool=\"{pool}\""),
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = GraphMetrics::new();
+        metrics.record_response("GET", "/me/messages", 200, Duration::from_millis(250));
+        metrics.record_response("GET", "/me/messages", 200, Duration::from_millis(750));
+        metrics.record_retry(true);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            "graph_requests_total{method=\"GET\",endpoint=\"/me/messages\",status=\"200\"} 2\n"
+        ));
+        assert!(rendered.contains(
+            "graph_request_duration_seconds_sum{method=\"GET\",endpoint=\"/me/messages\",status=\"200\"} 1\n"
+        ));
+        assert!(rendered.contains("graph_retries_total 1\n"));
+        assert!(rendered.contains("graph_throttled_total 1\n"));
+    }
+
+    #[test]
+    fn test_render_database() {
+        let metrics = DatabaseMetrics::new();
+        metrics.record_query("User::find", Duration::from_millis(3), false);
+        metrics.record_query("User::find", Duration::from_millis(700), true);
+        metrics.record_query("User::find", Duration::from_secs(10), true);
+        metrics.record_pool_wait("primary", Duration::from_millis(20));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            "database_query_duration_seconds_bucket{statement=\"User::find\",le=\"0.005\"} 1\n"
+        ));
+        assert!(rendered.contains(
+            "database_query_duration_seconds_bucket{statement=\"User::find\",le=\"1\"} 2\n"
+        ));
+        assert!(rendered.contains(
+            "database_query_duration_seconds_bucket{statement=\"User::find\",le=\"+Inf\"} 3\n"
+        ));
+        assert!(rendered
+            .contains("database_query_duration_seconds_count{statement=\"User::find\"} 3\n"));
+        assert!(rendered.contains("database_slow_queries_total{statement=\"User::find\"} 2\n"));
+        assert!(rendered
+            .contains("database_pool_wait_seconds_bucket{pool=\"primary\",le=\"0.025\"} 1\n"));
+    }
+}
@@ -0,0 +1,319 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use postgres_queue::{TaskData, TaskError, TaskId};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::task::spawn_blocking;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    database::{Database, QueryTimer, Rule, User},
+    graph::{
+        Email, EmailAddressWrapper, EmailFields, GraphClient, GraphClientError, GraphQuery,
+        PageOptions,
+    },
+    token,
+};
+
+/// Name of the recurring queue task applying a user's rules to new mail.
+pub const APPLY_RULES_TASK: &str = "apply_rules";
+
+/// How often new mail is checked against the rules.
+const APPLY_RULES_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// Folder rules without a folder condition apply to.
+const INBOX: &str = "inbox";
+
+/// Emails read per request when listing the mail that arrived since the last
+/// run.
+const RULES_PAGE_SIZE: usize = 100;
+
+/// A condition an email must meet for a rule to apply, text comparisons ignore
+/// case.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    /// The sender's address or name contains `value`
+    SenderContains { value: String },
+    /// The subject contains `value`
+    SubjectContains { value: String },
+    /// The email is in the folder, rules without this condition apply to the
+    /// inbox
+    InFolder { folder_id: String },
+}
+
+impl Condition {
+    fn matches(&self, email: &Email) -> bool {
+        match self {
+            Condition::SenderContains { value } => {
+                let value = value.to_lowercase();
+                [&email.from, &email.sender]
+                    .into_iter()
+                    .flatten()
+                    .any(|sender| {
+                        let address = sender.email_address.address.as_deref().unwrap_or_default();
+                        address.to_lowercase().contains(&value)
+                            || sender.email_address.name.to_lowercase().contains(&value)
+                    })
+            }
+            Condition::SubjectContains { value } => {
+                email.subject.to_lowercase().contains(&value.to_lowercase())
+            }
+            Condition::InFolder { folder_id } => &email.parent_folder_id == folder_id,
+        }
+    }
+}
+
+/// What a rule does to the emails it applies to, in order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    /// Moves the email to the folder, by display name or id. Later rules don't
+    /// apply to moved emails.
+    Move {
+        folder: String,
+    },
+    MarkRead,
+    /// Adds an Outlook category
+    Label {
+        category: String,
+    },
+    Forward {
+        to: Vec<String>,
+    },
+}
+
+impl Rule {
+    /// Whether the rule applies to `email`, found in the inbox when `in_inbox`.
+    pub fn matches(&self, email: &Email, in_inbox: bool) -> bool {
+        let has_folder = self
+            .conditions
+            .iter()
+            .any(|condition| matches!(condition, Condition::InFolder { .. }));
+
+        self.enabled
+            && (has_folder || in_inbox)
+            && self
+                .conditions
+                .iter()
+                .all(|condition| condition.matches(email))
+    }
+}
+
+/// Runs the actions of every matching rule on `email`, until one of them moves
+/// it out of its folder.
+async fn apply_rules(
+    graph: &mut GraphClient,
+    rules: &[Rule],
+    email: &Email,
+    in_inbox: bool,
+) -> Result<(), GraphClientError> {
+    for rule in rules.iter().filter(|rule| rule.matches(email, in_inbox)) {
+        info!("Applying rule {} to {}", rule.name, email.id);
+        let mut moved = false;
+        let mut email_id = email.id.clone();
+
+        for action in &rule.actions {
+            match action {
+                Action::Move { folder } => {
+                    // Moved messages get a new id
+                    email_id = graph
+                        .move_message_by_name(&email_id, Some(&email.internet_message_id), folder)
+                        .await?
+                        .into_email()
+                        .id;
+                    moved = true;
+                }
+                Action::MarkRead => {
+                    graph.set_read(&email_id, true).await?;
+                }
+                Action::Label { category } => graph.add_category(&email_id, category).await?,
+                Action::Forward { to } => {
+                    let to: Vec<_> = to.iter().map(|a| EmailAddressWrapper::new(a)).collect();
+                    graph.forward_email(&email_id, &to, "").await?;
+                }
+            }
+        }
+
+        if moved {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Schedules the recurring task applying the user's rules, unless it's already
+/// scheduled.
+pub async fn schedule(client: &deadpool_postgres::Client, user_email: &str) -> anyhow::Result<()> {
+    let _timer = QueryTimer::start("rules::schedule");
+    let scheduled = client
+        .query_opt(
+            "SELECT id FROM task_queue
+            WHERE name = $1 AND task_data->>'user_email' = $2 AND status IN ('queued', 'processing')
+            LIMIT 1",
+            &[&APPLY_RULES_TASK, &user_email],
+        )
+        .await?
+        .is_some();
+    if !scheduled {
+        postgres_queue::enqueue(
+            client,
+            APPLY_RULES_TASK,
+            json!({ "user_email": user_email }),
+            Utc::now(),
+            Some(APPLY_RULES_INTERVAL),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+pub async fn apply_rules_handler_sync(
+    task_id: TaskId,
+    task_data: TaskData,
+) -> Result<(), TaskError> {
+    let fut = Mutex::new(Box::pin(apply_rules_handler(task_id, task_data)));
+    spawn_blocking(move || {
+        let mut guard = fut.lock().unwrap();
+        futures::executor::block_on(&mut *guard)
+    })
+    .await
+    .map_err(|e| TaskError::Custom(e.to_string()))?
+}
+
+pub async fn apply_rules_handler(task_id: TaskId, task_data: TaskData) -> Result<(), TaskError> {
+    let user_email = task_data["user_email"]
+        .as_str()
+        .ok_or_else(|| TaskError::Custom("missing user_email".to_string()))?;
+    info!("Applying rules for {user_email} (task {task_id})");
+
+    run(user_email)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))
+}
+
+async fn run(user_email: &str) -> anyhow::Result<()> {
+    let database = Database::new(std::env::var("DATABASE_URL")?).await?;
+    let client = database.get().await?;
+    let Some(user) = User::find(&client, user_email).await? else {
+        anyhow::bail!("user {user_email} not found");
+    };
+    let Some(user_id) = user.id else {
+        anyhow::bail!("user {user_email} has no id");
+    };
+
+    let rules: Vec<Rule> = Rule::list(&client, user_id)
+        .await?
+        .into_iter()
+        .filter(|rule| rule.enabled)
+        .collect();
+    let started_at = Utc::now();
+    if rules.is_empty() {
+        user.set_rules_applied_at(&client, started_at).await?;
+        return Ok(());
+    }
+
+    // On the first run only mail that just arrived is considered
+    let since = user
+        .rules_applied_at(&client)
+        .await?
+        .unwrap_or(started_at - Duration::from_std(APPLY_RULES_INTERVAL)?);
+
+    let mut graph = token::mailbox_client(database, &user.email).build();
+    let mut folders = vec![INBOX.to_string()];
+    for rule in &rules {
+        for condition in &rule.conditions {
+            if let Condition::InFolder { folder_id } = condition {
+                if !folders.contains(folder_id) {
+                    folders.push(folder_id.clone());
+                }
+            }
+        }
+    }
+
+    let query = GraphQuery::new().filter(format!(
+        "receivedDateTime ge {}",
+        since.to_rfc3339_opts(SecondsFormat::Secs, true)
+    ));
+    let options = PageOptions::new().page_size(RULES_PAGE_SIZE);
+    for folder in folders {
+        let emails = match graph
+            .get_user_emails_from_folder(&folder, &query, EmailFields::WithoutBody, options)
+            .await
+        {
+            Ok(emails) => emails,
+            Err(err) => {
+                warn!("Can't list folder {folder} to apply rules: {err}");
+                continue;
+            }
+        };
+
+        for email in emails.iter().filter(|email| received_after(email, since)) {
+            if let Err(err) = apply_rules(&mut graph, &rules, email, folder == INBOX).await {
+                warn!("Failed to apply rules to {}: {err}", email.id);
+            }
+        }
+    }
+
+    user.set_rules_applied_at(&client, started_at).await?;
+    Ok(())
+}
+
+fn received_after(email: &Email, since: DateTime<Utc>) -> bool {
+    DateTime::parse_from_rfc3339(&email.received_date_time)
+        .map(|received| received >= since)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn rule(conditions: Vec<Condition>) -> Rule {
+        Rule {
+            id: 1,
+            user_id: 1,
+            name: "test".to_string(),
+            conditions,
+            actions: vec![Action::MarkRead],
+            enabled: true,
+            position: 0,
+        }
+    }
+
+    #[test]
+    fn test_rule_matches() {
+        let json = fs::read_to_string("src/fixtures/empty-subject.json").unwrap();
+        let email: Email = serde_json::from_str(&json).unwrap();
+        let sender = "sara.mc@omnidriven.me".to_string();
+
+        let by_sender = rule(vec![Condition::SenderContains {
+            value: "mcfarlin".to_string(),
+        }]);
+        assert!(by_sender.matches(&email, true));
+        // Rules without a folder only apply to the inbox
+        assert!(!by_sender.matches(&email, false));
+
+        let in_folder = rule(vec![Condition::InFolder {
+            folder_id: email.parent_folder_id.clone(),
+        }]);
+        assert!(in_folder.matches(&email, false));
+
+        let by_subject = rule(vec![
+            Condition::SenderContains { value: sender },
+            Condition::SubjectContains {
+                value: "no such subject".to_string(),
+            },
+        ]);
+        assert!(!by_subject.matches(&email, true));
+
+        let mut disabled = rule(vec![]);
+        disabled.enabled = false;
+        assert!(!disabled.matches(&email, true));
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use postgres_queue::{TaskData, TaskError, TaskId};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+use crate::{
+    database::{Database, QueryTimer},
+    graph::OutgoingMessage,
+    token,
+};
+
+/// Name of the queue task sending a scheduled email.
+pub const SEND_EMAIL_TASK: &str = "send_email";
+
+/// The composed message waits in the task data until it's sent.
+#[derive(Debug, Serialize, Deserialize)]
+struct SendEmailTask {
+    user_email: String,
+    message: OutgoingMessage,
+    #[serde(default = "default_save_to_sent_items")]
+    save_to_sent_items: bool,
+}
+
+fn default_save_to_sent_items() -> bool {
+    true
+}
+
+/// Schedules sending `message` at `send_at`, returning the id of the queued
+/// task.
+pub async fn schedule(
+    client: &deadpool_postgres::Client,
+    user_email: &str,
+    message: OutgoingMessage,
+    save_to_sent_items: bool,
+    send_at: DateTime<Utc>,
+) -> anyhow::Result<TaskId> {
+    let task_data = serde_json::to_value(SendEmailTask {
+        user_email: user_email.to_string(),
+        message,
+        save_to_sent_items,
+    })?;
+    Ok(postgres_queue::enqueue(client, SEND_EMAIL_TASK, task_data, send_at, None).await?)
+}
+
+/// Cancels a scheduled email of the user, returning false when there's no such
+/// email or it's already being sent.
+pub async fn cancel(
+    client: &deadpool_postgres::Client,
+    user_email: &str,
+    task_id: TaskId,
+) -> anyhow::Result<bool> {
+    let _timer = QueryTimer::start("send_later::cancel");
+    let deleted = client
+        .execute(
+            "DELETE FROM task_queue
+            WHERE id = $1 AND name = $2 AND task_data->>'user_email' = $3 AND status = 'queued'",
+            &[&task_id, &SEND_EMAIL_TASK, &user_email],
+        )
+        .await?;
+    Ok(deleted > 0)
+}
+
+pub async fn send_email_handler_sync(
+    task_id: TaskId,
+    task_data: TaskData,
+) -> Result<(), TaskError> {
+    let fut = Mutex::new(Box::pin(send_email_handler(task_id, task_data)));
+    spawn_blocking(move || {
+        let mut guard = fut.lock().unwrap();
+        futures::executor::block_on(&mut *guard)
+    })
+    .await
+    .map_err(|e| TaskError::Custom(e.to_string()))?
+}
+
+pub async fn send_email_handler(task_id: TaskId, task_data: TaskData) -> Result<(), TaskError> {
+    let task: SendEmailTask = serde_json::from_value(task_data)?;
+    info!(
+        "Sending scheduled email to {:?} for {} (task {task_id})",
+        task.message.to_recipients, task.user_email
+    );
+
+    let database_url =
+        std::env::var("DATABASE_URL").map_err(|e| TaskError::Custom(e.to_string()))?;
+    let database = Database::new(database_url)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+    // The stored access token has likely expired by the time the email is due
+    let graph = token::mailbox_client(database, &task.user_email).build();
+    graph
+        .send_mail(&task.message, task.save_to_sent_items)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))
+}
@@ -0,0 +1,97 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use postgres_queue::{TaskData, TaskError, TaskId};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+use crate::{
+    database::Database,
+    graph::{Email, GraphClient, MoveOutcome},
+    token,
+};
+
+/// Name of the queue task bringing snoozed emails back to the inbox.
+pub const UNSNOOZE_TASK: &str = "unsnooze";
+
+/// Folder snoozed emails wait in, created on first use.
+const SNOOZED_FOLDER: &str = "Snoozed";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UnsnoozeTask {
+    user_email: String,
+    email_id: String,
+    /// Finds the email again if it moved while snoozed, missing on tasks
+    /// queued before it was kept
+    #[serde(default)]
+    internet_message_id: Option<String>,
+}
+
+/// Moves the email to the Snoozed folder and schedules moving it back to the
+/// inbox at `until`, returning the moved email and the id of the queued task.
+pub async fn snooze(
+    graph: &mut GraphClient,
+    client: &deadpool_postgres::Client,
+    user_email: &str,
+    email_id: &str,
+    until: DateTime<Utc>,
+) -> anyhow::Result<(Email, TaskId)> {
+    let folder_id = graph.get_or_create_folder(SNOOZED_FOLDER).await?;
+    let email = graph.move_email_to_folder(email_id, &folder_id).await?;
+
+    // The message id changes when it moves, the task needs the new one
+    let task_data = serde_json::to_value(UnsnoozeTask {
+        user_email: user_email.to_string(),
+        email_id: email.id.clone(),
+        internet_message_id: Some(email.internet_message_id.clone()),
+    })?;
+    let task_id = postgres_queue::enqueue(client, UNSNOOZE_TASK, task_data, until, None).await?;
+
+    Ok((email, task_id))
+}
+
+pub async fn unsnooze_handler_sync(task_id: TaskId, task_data: TaskData) -> Result<(), TaskError> {
+    let fut = Mutex::new(Box::pin(unsnooze_handler(task_id, task_data)));
+    spawn_blocking(move || {
+        let mut guard = fut.lock().unwrap();
+        futures::executor::block_on(&mut *guard)
+    })
+    .await
+    .map_err(|e| TaskError::Custom(e.to_string()))?
+}
+
+pub async fn unsnooze_handler(task_id: TaskId, task_data: TaskData) -> Result<(), TaskError> {
+    let task: UnsnoozeTask = serde_json::from_value(task_data)?;
+    info!(
+        "Unsnoozing {} for {} (task {task_id})",
+        task.email_id, task.user_email
+    );
+
+    let database_url =
+        std::env::var("DATABASE_URL").map_err(|e| TaskError::Custom(e.to_string()))?;
+    let database = Database::new(database_url)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+    // Snoozes outlive access tokens, so a fresh one is likely needed
+    let mut graph = token::mailbox_client(database, &task.user_email).build();
+    match graph
+        .move_message_by_name(&task.email_id, task.internet_message_id.as_deref(), "inbox")
+        .await
+    {
+        Ok(MoveOutcome::Moved(_)) => Ok(()),
+        Ok(MoveOutcome::AlreadyMoved(_)) => {
+            info!(
+                "Snoozed email {} is back in the inbox already",
+                task.email_id
+            );
+            Ok(())
+        }
+        // The user moved or deleted the email in the meantime
+        Err(err) if err.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+            info!("Snoozed email {} no longer exists", task.email_id);
+            Ok(())
+        }
+        Err(err) => Err(TaskError::Custom(err.to_string())),
+    }
+}
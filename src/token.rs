@@ -1,7 +1,45 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, OnceLock,
+};
+
 use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::{
+    auth::{self, refresh_access_token, Token},
+    database::{Database, User},
+    graph::{self, GraphClient, GraphClientBuilder, TokenProvider},
+};
+
+static APP_ONLY: AtomicBool = AtomicBool::new(false);
+static APP_TOKENS: OnceLock<AppTokenProvider> = OnceLock::new();
+
+/// Makes background work reach mailboxes with the app's own token, through
+/// the client credentials grant, instead of the tokens users registered.
+pub fn set_app_only(app_only: bool) {
+    APP_ONLY.store(app_only, Ordering::Relaxed);
+}
+
+/// A client acting on the user's mailbox on their behalf: with the app's token
+/// in app-only mode, or with the tokens they registered otherwise.
+pub fn mailbox_client(database: Database, email: &str) -> GraphClientBuilder {
+    let builder = if APP_ONLY.load(Ordering::Relaxed) {
+        GraphClient::builder(APP_TOKENS.get_or_init(AppTokenProvider::default).clone()).user(email)
+    } else {
+        GraphClient::builder(UserTokenProvider::new(database, email.to_string()))
+    };
+    builder.client(graph::shared_http_client())
+}
+
+/// Tokens expiring within this window are refreshed ahead of time.
+const EXPIRATION_LEEWAY: i64 = 60;
 
 pub fn get_payload(token: &str) -> Result<serde_json::Value> {
-    let str = token.split('.').nth(1).unwrap();
+    let str = token.split('.').nth(1).ok_or(anyhow!("invalid token"))?;
     let decoded = base64::decode_config(str, base64::URL_SAFE_NO_PAD)?;
     let json = String::from_utf8(decoded)?;
     let value: serde_json::Value = serde_json::from_str(&json)?;
@@ -13,3 +51,166 @@ pub fn get_payload_field(token: &str, field: &str) -> Result<String> {
     let field = value.get(field).ok_or(anyhow!("invalid token"))?;
     Ok(field.as_str().unwrap().to_string())
 }
+
+/// Returns the expiration time of the token from its `exp` claim.
+pub fn get_expiration(token: &str) -> Result<DateTime<Utc>> {
+    let value = get_payload(token)?;
+    let exp = value
+        .get("exp")
+        .and_then(|exp| exp.as_i64())
+        .ok_or(anyhow!("token has no expiration"))?;
+    Utc.timestamp_opt(exp, 0)
+        .single()
+        .ok_or(anyhow!("invalid token expiration"))
+}
+
+/// Whether the token expires within the leeway. Tokens we can't decode are left
+/// for Graph to judge.
+pub fn is_expiring(token: &str) -> bool {
+    match get_expiration(token) {
+        Ok(expires_at) => expires_soon(expires_at),
+        Err(_) => false,
+    }
+}
+
+/// The delegated scopes granted to the token, from its `scp` claim. Empty for
+/// tokens that can't be decoded.
+pub fn granted_scopes(token: &str) -> Vec<String> {
+    get_payload_field(token, "scp")
+        .map(|scp| scp.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// The `required` scopes that aren't `granted`. A `ReadWrite` scope grants the
+/// `Read` one of the same resource.
+pub fn missing_scopes<'a>(granted: &[String], required: &[&'a str]) -> Vec<&'a str> {
+    required
+        .iter()
+        .copied()
+        .filter(|required| {
+            let read_write = required
+                .strip_suffix(".Read")
+                .map(|resource| format!("{resource}.ReadWrite"));
+            !granted
+                .iter()
+                .map(|granted| short_scope(granted))
+                .any(|granted| {
+                    granted.eq_ignore_ascii_case(required)
+                        || read_write
+                            .as_deref()
+                            .is_some_and(|read_write| granted.eq_ignore_ascii_case(read_write))
+                })
+        })
+        .collect()
+}
+
+/// Scopes of token responses are qualified with their resource, like
+/// `https://graph.microsoft.com/Mail.Read`, unlike those of the `scp` claim.
+fn short_scope(scope: &str) -> &str {
+    match scope.rsplit_once('/') {
+        Some((resource, name)) if resource.starts_with("https://") && !name.is_empty() => name,
+        _ => scope,
+    }
+}
+
+/// Whether `expires_at` falls within the leeway.
+pub fn expires_soon(expires_at: DateTime<Utc>) -> bool {
+    expires_at <= Utc::now() + Duration::seconds(EXPIRATION_LEEWAY)
+}
+
+/// Provides the access token stored for a user, refreshing it with their
+/// refresh token, and storing the new pair, whenever it's about to expire.
+pub struct UserTokenProvider {
+    db: Database,
+    email: String,
+    access_token: Mutex<Option<String>>,
+}
+
+impl UserTokenProvider {
+    pub fn new(db: Database, email: String) -> Self {
+        Self {
+            db,
+            email,
+            access_token: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl TokenProvider for UserTokenProvider {
+    async fn access_token(&self) -> Result<String> {
+        // Held while refreshing so concurrent requests refresh only once
+        let mut access_token = self.access_token.lock().await;
+        if let Some(token) = access_token.as_ref().filter(|token| !is_expiring(token)) {
+            return Ok(token.clone());
+        }
+
+        let client = self.db.get().await?;
+        let user = User::find(&client, &self.email)
+            .await?
+            .ok_or_else(|| anyhow!("user {} not found", self.email))?;
+        // Another worker or request may have refreshed it already
+        if let Some(token) = user
+            .access_token
+            .as_ref()
+            .filter(|token| !is_expiring(token))
+        {
+            *access_token = Some(token.clone());
+            return Ok(token.clone());
+        }
+
+        let Some(refresh_token) = user.refresh_token.as_deref() else {
+            return Err(anyhow!("no refresh token for {}", self.email));
+        };
+        info!("Access token for {} expired, refreshing...", self.email);
+        let token = refresh_access_token(refresh_token).await?;
+        let refresh_token = token.refresh_code.as_deref().unwrap_or(refresh_token);
+        user.update_tokens(&client, &token.access_code, refresh_token, &token.scopes)
+            .await?;
+
+        *access_token = Some(token.access_code.clone());
+        Ok(token.access_code)
+    }
+}
+
+/// Provides the app-only token, shared by every mailbox, requesting a new one
+/// whenever it's about to expire.
+#[derive(Clone, Default)]
+pub struct AppTokenProvider {
+    token: Arc<Mutex<Option<Token>>>,
+}
+
+#[async_trait]
+impl TokenProvider for AppTokenProvider {
+    async fn access_token(&self) -> Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some(token) = token.as_ref().filter(|token| !token.is_expiring()) {
+            return Ok(token.access_code.clone());
+        }
+
+        info!("Requesting an app-only access token...");
+        let new_token = auth::app_token().await?;
+        let access_code = new_token.access_code.clone();
+        *token = Some(new_token);
+        Ok(access_code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_scopes() {
+        let granted = vec![
+            "https://graph.microsoft.com/Mail.ReadWrite".to_string(),
+            "Calendars.ReadWrite".to_string(),
+            "openid".to_string(),
+        ];
+        assert!(missing_scopes(&granted, &["Mail.Read", "calendars.read"]).is_empty());
+        assert_eq!(
+            missing_scopes(&granted, &["Contacts.Read", "Mail.Send"]),
+            vec!["Contacts.Read", "Mail.Send"]
+        );
+    }
+}
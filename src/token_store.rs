@@ -0,0 +1,145 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Token;
+
+const APP_NAME: &str = "postars";
+/// The keyring entry holding the token, under the app's service name.
+const KEYRING_USER: &str = "auth_token";
+/// The config listing the names of the saved accounts.
+const ACCOUNTS_CONFIG: &str = "accounts";
+
+/// Where the CLI keeps the token saved by `auth set`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TokenStore {
+    /// confy's plaintext TOML file, in the config directory
+    #[default]
+    File,
+    /// The system keyring: Keychain, Secret Service or the Windows Credential
+    /// Manager
+    Keyring,
+}
+
+/// The names of the saved accounts, keyrings can't list their entries.
+#[derive(Default, Serialize, Deserialize)]
+struct Accounts {
+    names: Vec<String>,
+}
+
+impl TokenStore {
+    /// Loads the token saved for `account`, or the unnamed one, an empty one
+    /// when none was saved.
+    pub fn load(&self, account: Option<&str>) -> Result<Token> {
+        match self {
+            TokenStore::File => Ok(confy::load(APP_NAME, config_name(account)?.as_deref())?),
+            TokenStore::Keyring => match keyring_entry(account)?.get_password() {
+                Ok(json) => Ok(serde_json::from_str(&json)?),
+                Err(keyring::Error::NoEntry) => Ok(Token::default()),
+                Err(err) => Err(err.into()),
+            },
+        }
+    }
+
+    pub fn store(&self, account: Option<&str>, token: &Token) -> Result<()> {
+        let config_name = config_name(account)?;
+        match self {
+            TokenStore::File => confy::store(APP_NAME, config_name.as_deref(), token)?,
+            TokenStore::Keyring => {
+                keyring_entry(account)?.set_password(&serde_json::to_string(token)?)?;
+                // Don't leave a plaintext copy behind from before the switch
+                confy::store(APP_NAME, config_name.as_deref(), Token::default())?;
+            }
+        }
+
+        if let Some(account) = account {
+            let mut accounts: Accounts = confy::load(APP_NAME, ACCOUNTS_CONFIG)?;
+            if !accounts.names.iter().any(|name| name == account) {
+                accounts.names.push(account.to_string());
+                confy::store(APP_NAME, ACCOUNTS_CONFIG, accounts)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the token of `account`, returning whether it was saved.
+    pub fn remove(&self, account: &str) -> Result<bool> {
+        let config_name = config_name(Some(account))?;
+        if *self == TokenStore::Keyring {
+            match keyring_entry(Some(account))?.delete_password() {
+                Ok(()) | Err(keyring::Error::NoEntry) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let path = confy::get_configuration_file_path(APP_NAME, config_name.as_deref())?;
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+
+        let mut accounts: Accounts = confy::load(APP_NAME, ACCOUNTS_CONFIG)?;
+        let count = accounts.names.len();
+        accounts.names.retain(|name| name != account);
+        let removed = accounts.names.len() < count;
+        confy::store(APP_NAME, ACCOUNTS_CONFIG, accounts)?;
+        Ok(removed)
+    }
+
+    /// The names of the saved accounts, in the order they were added.
+    pub fn accounts(&self) -> Result<Vec<String>> {
+        let accounts: Accounts = confy::load(APP_NAME, ACCOUNTS_CONFIG)?;
+        Ok(accounts.names)
+    }
+}
+
+impl FromStr for TokenStore {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(TokenStore::File),
+            "keyring" => Ok(TokenStore::Keyring),
+            _ => Err(anyhow!("unknown token store: {s}, use `file` or `keyring`")),
+        }
+    }
+}
+
+/// The confy config of the account, the default one for the unnamed account.
+/// Names end up in file names so they're kept to letters, digits, `-` and `_`.
+fn config_name(account: Option<&str>) -> Result<Option<String>> {
+    let Some(account) = account else {
+        return Ok(None);
+    };
+    if account.is_empty()
+        || !account
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("invalid account name {account:?}, use letters, digits, `-` and `_`");
+    }
+    Ok(Some(format!("account-{account}")))
+}
+
+fn keyring_entry(account: Option<&str>) -> Result<keyring::Entry> {
+    let user = match account {
+        Some(account) => format!("{KEYRING_USER}:{account}"),
+        None => KEYRING_USER.to_string(),
+    };
+    Ok(keyring::Entry::new(APP_NAME, &user)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_name() {
+        assert_eq!(config_name(None).unwrap(), None);
+        assert_eq!(
+            config_name(Some("work")).unwrap().as_deref(),
+            Some("account-work")
+        );
+        assert!(config_name(Some("")).is_err());
+        assert!(config_name(Some("../work")).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use reqwest::StatusCode;
+use tracing::info;
+
+use crate::{
+    database::{Database, IdempotencyKey, User, WebhookSubscription},
+    index, token,
+};
+
+/// Deletes everything kept about the user: their subscriptions on Graph, their
+/// queued tasks, the index of their mailbox and their rows, which cascade to
+/// their sessions, keys, accounts, rules, signatures and cached messages.
+/// Returns false when there's no such user.
+pub async fn delete_user(database: &Database, email: &str) -> anyhow::Result<bool> {
+    let client = database.get().await?;
+    let Some(user) = User::find(&client, email).await? else {
+        return Ok(false);
+    };
+    let user_id = user.id.unwrap();
+    info!("Deleting the data of {email}...");
+
+    // Graph would keep notifying about the mailbox until they expire. They're
+    // deleted there first, when it fails nothing is deleted yet to retry with
+    let subscriptions = WebhookSubscription::list(&client, user_id).await?;
+    if !subscriptions.is_empty() {
+        let graph = token::mailbox_client(database.clone(), email).build();
+        for subscription in &subscriptions {
+            match graph.delete_subscription(&subscription.id).await {
+                // Already expired on Graph's side
+                Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => {}
+                result => result?,
+            }
+        }
+        info!(
+            "Deleted {} subscriptions of {email} on Graph",
+            subscriptions.len()
+        );
+    }
+
+    // Tasks are deleted first so none of them brings data back
+    let tasks = postgres_queue::delete_tasks_with_data(&client, "user_email", email).await?;
+    info!("Deleted {tasks} queued tasks of {email}");
+
+    if index::delete_index(user_id).await? {
+        info!("Deleted the search index of {email}");
+    }
+
+    let (keys, deleted) = database
+        .transaction(|tx| {
+            let email = email.to_string();
+            Box::pin(async move {
+                let keys = IdempotencyKey::delete_for_user(tx, &email).await?;
+                Ok((keys, User::delete(tx, user_id).await?))
+            })
+        })
+        .await?;
+    info!("Deleted {keys} idempotency keys and the rows of {email}");
+
+    Ok(deleted)
+}